@@ -6,19 +6,32 @@ CREATE EXTENSION IF NOT EXISTS "uuid-ossp";
 CREATE TABLE IF NOT EXISTS prompt_logs (
     id UUID PRIMARY KEY DEFAULT uuid_generate_v4(),
     timestamp TIMESTAMPTZ NOT NULL DEFAULT NOW(),
-    original_input TEXT NOT NULL,
+    model TEXT,
+    -- NULL on rows logged with LOG_STORE_RAW=false (mappings are in pii_mappings_encrypted)
+    original_input TEXT,
     masked_input TEXT NOT NULL,
     rag_context TEXT,
     llm_output TEXT NOT NULL,
-    final_output TEXT NOT NULL,
+    final_output TEXT,
     pii_mappings JSONB NOT NULL,
+    pii_mappings_encrypted BYTEA,
+    pii_count INTEGER,
+    dangerous_patterns_removed INTEGER,
+    latency_ms BIGINT,
+    prompt_tokens INTEGER,
+    completion_tokens INTEGER,
+    estimated_cost_usd DOUBLE PRECISION,
+    search_vector tsvector GENERATED ALWAYS AS
+        (to_tsvector('english', original_input || ' ' || final_output)) STORED,
     created_at TIMESTAMPTZ DEFAULT NOW()
 );
 
 -- Indexes for better query performance
 CREATE INDEX IF NOT EXISTS idx_prompt_logs_timestamp ON prompt_logs(timestamp DESC);
+CREATE INDEX IF NOT EXISTS idx_prompt_logs_model ON prompt_logs(model);
 CREATE INDEX IF NOT EXISTS idx_prompt_logs_pii_mappings ON prompt_logs USING GIN(pii_mappings);
-CREATE INDEX IF NOT EXISTS idx_prompt_logs_search ON prompt_logs USING gin(to_tsvector('english', original_input || ' ' || final_output));
+CREATE INDEX IF NOT EXISTS idx_prompt_logs_pii_count ON prompt_logs(pii_count);
+CREATE INDEX IF NOT EXISTS idx_search_vector ON prompt_logs USING GIN(search_vector);
 
 -- Documents table (for RAG metadata)
 CREATE TABLE IF NOT EXISTS documents (
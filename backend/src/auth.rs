@@ -0,0 +1,157 @@
+use std::collections::HashSet;
+use std::sync::Arc;
+
+use axum::{
+    extract::{Request, State},
+    http::{header, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+
+/// `/api/v1/*` のAPIキー認証設定
+#[derive(Debug, Clone, Default)]
+pub struct AuthConfig {
+    keys: HashSet<String>,
+    disabled: bool,
+    /// 暗号化されたログを復号して読めるキー（`keys` の部分集合）
+    log_decrypt_keys: HashSet<String>,
+}
+
+/// 認証済みリクエストのAPIキー。後段のハンドラがextensionから参照できる
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct ApiKey(pub String);
+
+impl AuthConfig {
+    /// `API_KEYS=key1,key2` で有効なキーを指定する。
+    /// 開発時は `AUTH_DISABLED=true` で認証をスキップできる。
+    /// `LOG_DECRYPT_KEYS` はそのうちログの復号を許すキー
+    pub fn from_env() -> Self {
+        let disabled = std::env::var("AUTH_DISABLED")
+            .map(|v| v.eq_ignore_ascii_case("true") || v == "1")
+            .unwrap_or(false);
+        if disabled {
+            return Self::disabled();
+        }
+        let keys = std::env::var("API_KEYS").unwrap_or_default();
+        let decrypt_keys = std::env::var("LOG_DECRYPT_KEYS").unwrap_or_default();
+        Self::new(split_keys(&keys)).with_log_decrypt_keys(split_keys(&decrypt_keys))
+    }
+
+    pub fn new(keys: impl IntoIterator<Item = String>) -> Self {
+        Self { keys: keys.into_iter().collect(), disabled: false, log_decrypt_keys: HashSet::new() }
+    }
+
+    pub fn disabled() -> Self {
+        Self { keys: HashSet::new(), disabled: true, log_decrypt_keys: HashSet::new() }
+    }
+
+    /// 有効なキーに含まれないものは無視する
+    pub fn with_log_decrypt_keys(mut self, keys: impl IntoIterator<Item = String>) -> Self {
+        self.log_decrypt_keys = keys.into_iter().filter(|k| self.keys.contains(k)).collect();
+        self
+    }
+
+    /// 認証無効時はキーがないので常に拒否する（fail closed）
+    pub fn can_decrypt_logs(&self, key: Option<&ApiKey>) -> bool {
+        key.is_some_and(|ApiKey(k)| self.log_decrypt_keys.contains(k))
+    }
+
+    pub fn is_disabled(&self) -> bool {
+        self.disabled
+    }
+
+    pub fn key_count(&self) -> usize {
+        self.keys.len()
+    }
+
+    /// `Authorization` ヘッダ値から有効なキーを取り出す
+    fn authorize(&self, header_value: Option<&str>) -> Option<ApiKey> {
+        let key = header_value?.strip_prefix("Bearer ")?.trim();
+        self.keys.contains(key).then(|| ApiKey(key.to_string()))
+    }
+}
+
+fn split_keys(value: &str) -> impl Iterator<Item = String> + '_ {
+    value.split(',').map(str::trim).filter(|k| !k.is_empty()).map(String::from)
+}
+
+/// `Authorization: Bearer <key>` を検証するミドルウェア。
+/// キー未設定で認証が有効な場合はすべて拒否する（fail closed）
+pub async fn require_api_key(
+    State(config): State<Arc<AuthConfig>>,
+    mut request: Request,
+    next: Next,
+) -> Response {
+    if config.is_disabled() {
+        return next.run(request).await;
+    }
+
+    let header_value = request.headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok());
+
+    match config.authorize(header_value) {
+        Some(key) => {
+            request.extensions_mut().insert(key);
+            next.run(request).await
+        }
+        None => (
+            StatusCode::UNAUTHORIZED,
+            [(header::WWW_AUTHENTICATE, "Bearer")],
+            "Invalid or missing API key",
+        ).into_response(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{Router, body::Body, middleware, routing::get};
+    use tower::ServiceExt;
+
+    fn app(config: AuthConfig) -> Router {
+        let protected = Router::new()
+            .route("/api/v1/logs", get(|| async { "logs" }))
+            .route_layer(middleware::from_fn_with_state(Arc::new(config), require_api_key));
+        Router::new()
+            .merge(protected)
+            .route("/api/health", get(|| async { "ok" }))
+    }
+
+    async fn status(app: Router, uri: &str, auth: Option<&str>) -> StatusCode {
+        let mut builder = Request::builder().uri(uri);
+        if let Some(auth) = auth {
+            builder = builder.header(header::AUTHORIZATION, auth);
+        }
+        app.oneshot(builder.body(Body::empty()).unwrap()).await.unwrap().status()
+    }
+
+    #[tokio::test]
+    async fn test_valid_and_invalid_keys() {
+        let app = app(AuthConfig::new(["secret-1".to_string(), "secret-2".to_string()]));
+
+        assert_eq!(status(app.clone(), "/api/v1/logs", Some("Bearer secret-2")).await, StatusCode::OK);
+        assert_eq!(status(app.clone(), "/api/v1/logs", Some("Bearer wrong")).await, StatusCode::UNAUTHORIZED);
+        assert_eq!(status(app.clone(), "/api/v1/logs", Some("secret-1")).await, StatusCode::UNAUTHORIZED);
+        assert_eq!(status(app.clone(), "/api/v1/logs", None).await, StatusCode::UNAUTHORIZED);
+        assert_eq!(status(app, "/api/health", None).await, StatusCode::OK);
+    }
+
+    #[test]
+    fn test_log_decrypt_keys_are_a_subset_of_api_keys() {
+        let config = AuthConfig::new(["reader".to_string(), "writer".to_string()])
+            .with_log_decrypt_keys(["reader".to_string(), "unknown".to_string()]);
+
+        assert!(config.can_decrypt_logs(Some(&ApiKey("reader".to_string()))));
+        assert!(!config.can_decrypt_logs(Some(&ApiKey("writer".to_string()))));
+        assert!(!config.can_decrypt_logs(Some(&ApiKey("unknown".to_string()))));
+        assert!(!config.can_decrypt_logs(None));
+        assert!(!AuthConfig::disabled().can_decrypt_logs(None));
+    }
+
+    #[tokio::test]
+    async fn test_auth_disabled_allows_all() {
+        let app = app(AuthConfig::disabled());
+        assert_eq!(status(app, "/api/v1/logs", None).await, StatusCode::OK);
+    }
+}
@@ -1,11 +1,11 @@
 use anyhow::Result;
 use clap::Parser;
 use indicatif::{ProgressBar, ProgressStyle};
-use sha2::{Sha256, Digest};
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
 use llm_proxy::rag::embeddings::EmbeddingGenerator;
-use llm_proxy::rag::vector_store::VectorStore;
+use llm_proxy::rag::vector_store::{distance_from_env, VectorStore};
+use llm_proxy::rag::index_manager::{chunk_id, file_id};
 use llm_proxy::indexer::walker::{walk_directory, SupportedFormat};
 use llm_proxy::indexer::extractor::extract_text;
 use llm_proxy::indexer::chunker::chunk_text;
@@ -35,13 +35,6 @@ struct Args {
     chunk_overlap: usize,
 }
 
-fn file_id(path: &PathBuf) -> String {
-    let mut hasher = Sha256::new();
-    hasher.update(path.to_string_lossy().as_bytes());
-    let result = hasher.finalize();
-    hex::encode(&result[..8])
-}
-
 #[tokio::main]
 async fn main() -> Result<()> {
     dotenv::dotenv().ok();
@@ -54,10 +47,10 @@ async fn main() -> Result<()> {
     }
 
     println!("Initializing embedding model...");
-    let embeddings = EmbeddingGenerator::new().await?;
+    let embeddings = EmbeddingGenerator::from_env().await?;
 
     println!("Connecting to Qdrant at {}...", args.qdrant_url);
-    let vector_store = VectorStore::new(&args.qdrant_url, &args.collection).await?;
+    let vector_store = VectorStore::new(&args.qdrant_url, &args.collection, embeddings.dimension() as u64, distance_from_env()).await?;
 
     println!("Scanning directory: {}", args.dir.display());
     let files = walk_directory(&args.dir);
@@ -118,7 +111,7 @@ async fn main() -> Result<()> {
 }
 
 async fn process_file(
-    path: &PathBuf,
+    path: &Path,
     format: SupportedFormat,
     embeddings: &EmbeddingGenerator,
     vector_store: &VectorStore,
@@ -136,18 +129,22 @@ async fn process_file(
     let batch_size = 32;
     for batch in chunks.chunks(batch_size) {
         let texts: Vec<String> = batch.iter().map(|c| c.text.clone()).collect();
-        let embeddings_batch = embeddings.generate(texts)?;
-
-        for (chunk, embedding) in batch.iter().zip(embeddings_batch.into_iter()) {
-            let chunk_id = format!("{}_{}", path_id, chunk.chunk_index);
-            let metadata = serde_json::json!({
-                "file_path": path.to_string_lossy(),
-                "chunk_index": chunk.chunk_index,
-                "format": format!("{:?}", format),
-            });
-
-            vector_store.add_document(&chunk_id, &chunk.text, embedding, metadata).await?;
-        }
+        let embeddings_batch = embeddings.embed_documents(texts)?;
+
+        let points = batch.iter().zip(embeddings_batch)
+            .map(|(chunk, embedding)| {
+                let metadata = serde_json::json!({
+                    "file_id": path_id,
+                    "file_path": path.to_string_lossy(),
+                    "chunk_index": chunk.chunk_index,
+                    "start_offset": chunk.start_offset,
+                    "end_offset": chunk.end_offset,
+                    "format": format!("{:?}", format),
+                });
+                (chunk_id(&path_id, chunk.chunk_index), chunk.text.clone(), embedding, metadata)
+            })
+            .collect();
+        vector_store.add_documents(points).await?;
     }
 
     Ok(chunks.len())
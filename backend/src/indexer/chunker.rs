@@ -1,7 +1,19 @@
+use serde::{Deserialize, Serialize};
+
 #[derive(Debug, Clone)]
 pub struct TextChunk {
     pub text: String,
     pub chunk_index: usize,
+    /// 分割元テキストでのバイト範囲。Markdownでは先頭に付けた見出しを含まず、セクション本文だけを指す
+    pub start_offset: usize,
+    pub end_offset: usize,
+}
+
+/// `text[start..end]` から前後の空白を除いたバイト範囲
+fn trimmed_range(text: &str, start: usize, end: usize) -> (usize, usize) {
+    let segment = &text[start..end];
+    let leading = segment.len() - segment.trim_start().len();
+    (start + leading, start + segment.trim_end().len())
 }
 
 /// バイト位置をchar境界に切り上げる
@@ -28,25 +40,143 @@ fn floor_char_boundary(text: &str, byte_pos: usize) -> usize {
     pos
 }
 
+/// `max_chunk_size` / `overlap` の単位
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ChunkBy {
+    /// UTF-8のバイト数（従来の挙動）
+    #[default]
+    Chars,
+    /// 埋め込みモデルのトークナイザーでのトークン数
+    Tokens,
+}
+
+/// インデクサーのチャンク分割設定
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ChunkSettings {
+    pub chunk_by: ChunkBy,
+    pub max_chunk_size: usize,
+    pub overlap: usize,
+}
+
+impl Default for ChunkSettings {
+    fn default() -> Self {
+        Self { chunk_by: ChunkBy::Chars, max_chunk_size: 1000, overlap: 200 }
+    }
+}
+
+impl ChunkSettings {
+    /// bge-small は512トークンまでなので、トークン単位の既定値は小さめ
+    const DEFAULT_TOKENS: (usize, usize) = (400, 80);
+
+    /// `RAG_CHUNK_BY=tokens` で単位を切り替え、`RAG_CHUNK_SIZE` / `RAG_CHUNK_OVERLAP`
+    /// でサイズを上書きする（選んだ単位で指定）
+    pub fn from_env() -> Self {
+        let chunk_by = match std::env::var("RAG_CHUNK_BY").as_deref() {
+            Ok("tokens") => ChunkBy::Tokens,
+            _ => ChunkBy::Chars,
+        };
+        let (default_size, default_overlap) = match chunk_by {
+            ChunkBy::Chars => (Self::default().max_chunk_size, Self::default().overlap),
+            ChunkBy::Tokens => Self::DEFAULT_TOKENS,
+        };
+        let read = |name: &str| std::env::var(name).ok().and_then(|v| v.parse::<usize>().ok());
+        let max_chunk_size = read("RAG_CHUNK_SIZE").filter(|&n| n > 0).unwrap_or(default_size);
+        let overlap = read("RAG_CHUNK_OVERLAP").unwrap_or(default_overlap).min(max_chunk_size / 2);
+        Self { chunk_by, max_chunk_size, overlap }
+    }
+
+    fn tokenizer<'a>(&self, tokenizer: &'a dyn Tokenize) -> Option<&'a dyn Tokenize> {
+        (self.chunk_by == ChunkBy::Tokens).then_some(tokenizer)
+    }
+
+    pub fn chunk(&self, text: &str, tokenizer: &dyn Tokenize) -> Vec<TextChunk> {
+        split(text, self.max_chunk_size, self.overlap, self.tokenizer(tokenizer))
+    }
+
+    pub fn chunk_markdown(&self, text: &str, tokenizer: &dyn Tokenize) -> Vec<TextChunk> {
+        split_markdown(text, self.max_chunk_size, self.overlap, self.tokenizer(tokenizer))
+    }
+}
+
+/// 各トークンの開始バイト位置（昇順）
+pub trait Tokenize {
+    fn token_starts(&self, text: &str) -> Vec<usize>;
+}
+
+/// 1つのテキストでのチャンクサイズの測り方
+enum Units {
+    Bytes,
+    /// 各トークンの開始位置
+    Tokens(Vec<usize>),
+}
+
+impl Units {
+    fn new(text: &str, tokenizer: Option<&dyn Tokenize>) -> Self {
+        match tokenizer {
+            Some(t) => Units::Tokens(t.token_starts(text)),
+            None => Units::Bytes,
+        }
+    }
+
+    fn len(&self, text: &str) -> usize {
+        match self {
+            Units::Bytes => text.len(),
+            Units::Tokens(starts) => starts.len(),
+        }
+    }
+
+    /// `start` から `n` 単位後の位置
+    fn forward(&self, text: &str, start: usize, n: usize) -> usize {
+        match self {
+            Units::Bytes => ceil_char_boundary(text, (start + n).min(text.len())),
+            Units::Tokens(starts) => {
+                let i = starts.partition_point(|&b| b < start);
+                starts.get(i + n).copied().unwrap_or(text.len())
+            }
+        }
+    }
+
+    /// `end` から `n` 単位前の位置
+    fn back(&self, text: &str, end: usize, n: usize) -> usize {
+        match self {
+            Units::Bytes => if end > n { floor_char_boundary(text, end - n) } else { end },
+            Units::Tokens(starts) => {
+                let i = starts.partition_point(|&b| b < end);
+                if n > 0 && i > n { starts[i - n] } else { end }
+            }
+        }
+    }
+}
+
 pub fn chunk_text(text: &str, max_chunk_size: usize, overlap: usize) -> Vec<TextChunk> {
-    let text = text.trim();
-    if text.is_empty() {
+    split(text, max_chunk_size, overlap, None)
+}
+
+fn split(text: &str, max_chunk_size: usize, overlap: usize, tokenizer: Option<&dyn Tokenize>) -> Vec<TextChunk> {
+    let (text_start, text_end) = trimmed_range(text, 0, text.len());
+    if text_start == text_end {
         return Vec::new();
     }
 
-    if text.len() <= max_chunk_size {
+    let units = Units::new(text, tokenizer);
+    if units.len(&text[text_start..text_end]) <= max_chunk_size {
         return vec![TextChunk {
-            text: text.to_string(),
+            text: text[text_start..text_end].to_string(),
             chunk_index: 0,
+            start_offset: text_start,
+            end_offset: text_end,
         }];
     }
 
     let mut chunks = Vec::new();
-    let mut start = 0;
+    let mut start = text_start;
     let mut chunk_index = 0;
+    // 位置は元のテキスト基準のまま、末尾の空白だけ範囲外にする
+    let text = &text[..text_end];
 
     while start < text.len() {
-        let end = ceil_char_boundary(text, (start + max_chunk_size).min(text.len()));
+        let end = units.forward(text, start, max_chunk_size);
 
         let actual_end = if end < text.len() {
             find_break_point(text, start, end)
@@ -54,20 +184,18 @@ pub fn chunk_text(text: &str, max_chunk_size: usize, overlap: usize) -> Vec<Text
             end
         };
 
-        let chunk_text = text[start..actual_end].trim().to_string();
-        if !chunk_text.is_empty() {
+        let (chunk_start, chunk_end) = trimmed_range(text, start, actual_end);
+        if chunk_start < chunk_end {
             chunks.push(TextChunk {
-                text: chunk_text,
+                text: text[chunk_start..chunk_end].to_string(),
                 chunk_index,
+                start_offset: chunk_start,
+                end_offset: chunk_end,
             });
             chunk_index += 1;
         }
 
-        let next_start = if actual_end > overlap {
-            floor_char_boundary(text, actual_end - overlap)
-        } else {
-            actual_end
-        };
+        let next_start = units.back(text, actual_end, overlap);
 
         if next_start <= start {
             start = actual_end;
@@ -79,6 +207,76 @@ pub fn chunk_text(text: &str, max_chunk_size: usize, overlap: usize) -> Vec<Text
     chunks
 }
 
+/// `## 見出し` 形式ならレベルとタイトルを返す
+fn parse_heading(line: &str) -> Option<(usize, &str)> {
+    let level = line.bytes().take_while(|&b| b == b'#').count();
+    if level == 0 || level > 6 {
+        return None;
+    }
+    let rest = &line[level..];
+    if !rest.is_empty() && !rest.starts_with([' ', '\t']) {
+        return None;
+    }
+    Some((level, rest.trim().trim_end_matches('#').trim_end()))
+}
+
+/// Markdownを見出しで区切ってから、各セクション内を長さで分割する。
+/// 深い階層のチャンクでもどのセクションか分かるよう、祖先の見出しを先頭に付ける
+pub fn chunk_markdown(text: &str, max_chunk_size: usize, overlap: usize) -> Vec<TextChunk> {
+    split_markdown(text, max_chunk_size, overlap, None)
+}
+
+fn split_markdown(text: &str, max_chunk_size: usize, overlap: usize, tokenizer: Option<&dyn Tokenize>) -> Vec<TextChunk> {
+    // (見出しプレフィックス, 本文の開始, 本文の終了)
+    let mut sections: Vec<(String, usize, usize)> = Vec::new();
+    let mut headings: Vec<(usize, String)> = Vec::new();
+    let mut body_start = 0;
+    let mut pos = 0;
+    let mut in_fence = false;
+
+    let flush = |headings: &[(usize, String)], start: usize, end: usize, sections: &mut Vec<(String, usize, usize)>| {
+        if !text[start..end].trim().is_empty() {
+            let prefix = headings.iter()
+                .map(|(level, title)| format!("{} {}", "#".repeat(*level), title))
+                .collect::<Vec<_>>()
+                .join("\n");
+            sections.push((prefix, start, end));
+        }
+    };
+
+    for raw in text.split_inclusive('\n') {
+        let line = raw.trim_end_matches(['\r', '\n']);
+        if line.trim_start().starts_with("```") {
+            in_fence = !in_fence;
+        }
+        if let Some((level, title)) = (!in_fence).then(|| parse_heading(line)).flatten() {
+            flush(&headings, body_start, pos, &mut sections);
+            headings.retain(|(l, _)| *l < level);
+            headings.push((level, title.to_string()));
+            body_start = pos + raw.len();
+        }
+        pos += raw.len();
+    }
+    flush(&headings, body_start, pos, &mut sections);
+
+    let mut chunks = Vec::new();
+    for (prefix, start, end) in sections {
+        // 見出し分を差し引いた残りで本文を分割する
+        let prefix_len = if prefix.is_empty() { 0 } else { Units::new(&prefix, tokenizer).len(&prefix) + 1 };
+        let budget = max_chunk_size.saturating_sub(prefix_len).max(max_chunk_size / 2);
+        for piece in split(&text[start..end], budget, overlap.min(budget / 2), tokenizer) {
+            let chunk_text = if prefix.is_empty() { piece.text } else { format!("{}\n{}", prefix, piece.text) };
+            chunks.push(TextChunk {
+                text: chunk_text,
+                chunk_index: chunks.len(),
+                start_offset: start + piece.start_offset,
+                end_offset: start + piece.end_offset,
+            });
+        }
+    }
+    chunks
+}
+
 fn find_break_point(text: &str, start: usize, max_end: usize) -> usize {
     let segment = &text[start..max_end];
 
@@ -132,6 +330,83 @@ mod tests {
         assert_eq!(chunks[0].text, "short");
     }
 
+    #[test]
+    fn test_markdown_chunk_keeps_section_title() {
+        let section = "申請は上長の承認を得てから提出してください。".repeat(20);
+        let text = format!(
+            "# 社内規程\n\n概要です。\n\n## 経費精算\n\n{}\n\n### 交通費\n\n{}\n\n```\n# not a heading\n```\n",
+            section, section
+        );
+        let chunks = chunk_markdown(&text, 300, 50);
+
+        assert_eq!(chunks[0].text, "# 社内規程\n概要です。");
+        let deep: Vec<_> = chunks.iter().filter(|c| c.text.contains("### 交通費")).collect();
+        assert!(deep.len() > 1);
+        for chunk in &deep {
+            assert!(chunk.text.starts_with("# 社内規程\n## 経費精算\n### 交通費\n"));
+        }
+        assert!(chunks.last().unwrap().text.contains("# not a heading"));
+        assert!(chunks.iter().enumerate().all(|(i, c)| c.chunk_index == i));
+        assert!(chunks.iter().all(|c| c.text.len() <= 300 + 3));
+    }
+
+    /// 1文字1トークン、ASCIIは単語で1トークン
+    struct CharTokenizer;
+
+    impl Tokenize for CharTokenizer {
+        fn token_starts(&self, text: &str) -> Vec<usize> {
+            let mut starts = Vec::new();
+            let mut prev_ascii_word = false;
+            for (i, c) in text.char_indices() {
+                let ascii_word = c.is_ascii_alphanumeric();
+                let continues_word = ascii_word && prev_ascii_word;
+                if !c.is_whitespace() && !continues_word {
+                    starts.push(i);
+                }
+                prev_ascii_word = ascii_word;
+            }
+            starts
+        }
+    }
+
+    #[test]
+    fn test_token_chunking_counts_tokens_not_bytes() {
+        let text = "申請は上長の承認を得てから提出してください。".repeat(50);
+        let by_chars = ChunkSettings { chunk_by: ChunkBy::Chars, max_chunk_size: 300, overlap: 0 };
+        let by_tokens = ChunkSettings { chunk_by: ChunkBy::Tokens, ..by_chars };
+
+        // 日本語は1文字3バイトなので、バイト単位だと約3倍のチャンクになる
+        let char_chunks = by_chars.chunk(&text, &CharTokenizer);
+        let token_chunks = by_tokens.chunk(&text, &CharTokenizer);
+        assert!(char_chunks.len() > 2 * token_chunks.len());
+        for chunk in &token_chunks {
+            assert!(CharTokenizer.token_starts(&chunk.text).len() <= 300);
+        }
+
+        // ASCIIは単語単位なので逆にバイト単位より大きいチャンクになる
+        let english = "Expense reports need manager approval. ".repeat(50);
+        assert!(by_tokens.chunk(&english, &CharTokenizer).len() < by_chars.chunk(&english, &CharTokenizer).len());
+    }
+
+    #[test]
+    fn test_chunk_offsets_point_into_source() {
+        let text = format!("  \n{}\n\n", "経費精算の手順です。承認後に提出します。".repeat(40));
+        let chunks = chunk_text(&text, 200, 40);
+        assert!(chunks.len() > 1);
+
+        let mut prev_start = 0;
+        for chunk in &chunks {
+            assert!(chunk.start_offset < chunk.end_offset && chunk.end_offset <= text.len());
+            assert!(chunk.start_offset >= prev_start);
+            assert_eq!(&text[chunk.start_offset..chunk.end_offset], chunk.text);
+            prev_start = chunk.start_offset;
+        }
+
+        let md = "# 規程\r\n\r\n本文です。\r\n";
+        let chunk = &chunk_markdown(md, 100, 10)[0];
+        assert_eq!(&md[chunk.start_offset..chunk.end_offset], "本文です。");
+    }
+
     #[test]
     fn test_empty_text() {
         let chunks = chunk_text("", 100, 10);
@@ -1,6 +1,7 @@
 use std::path::Path;
 use std::io::Read;
 use anyhow::{Result, Context};
+use chardetng::{EncodingDetector, Iso2022JpDetection, Utf8Detection};
 use super::walker::SupportedFormat;
 
 pub fn extract_text(path: &Path, format: SupportedFormat) -> Result<String> {
@@ -10,18 +11,73 @@ pub fn extract_text(path: &Path, format: SupportedFormat) -> Result<String> {
         SupportedFormat::Docx => extract_docx(path),
         SupportedFormat::Xlsx => extract_xlsx(path),
         SupportedFormat::Pptx => extract_pptx(path),
+        SupportedFormat::Html => extract_html(path),
+        SupportedFormat::Image => extract_image(path),
     }
 }
 
-fn extract_plain_text(path: &Path) -> Result<String> {
-    std::fs::read_to_string(path)
-        .with_context(|| format!("Failed to read text file: {}", path.display()))
+/// インデックスせずに抽出だけ試す（アップロード時に結果をすぐ返すため）。
+/// 空の結果やパーサーのpanicもエラーとして返す
+pub fn validate_extraction(path: &Path, format: SupportedFormat) -> Result<(), String> {
+    match std::panic::catch_unwind(|| extract_text(path, format)) {
+        Ok(Ok(text)) if text.trim().is_empty() => Err("No extractable text found".to_string()),
+        Ok(Ok(_)) => Ok(()),
+        Ok(Err(e)) => Err(format!("{:#}", e)),
+        Err(_) => Err("Extractor panicked while parsing the file".to_string()),
+    }
+}
+
+pub fn extract_plain_text(path: &Path) -> Result<String> {
+    let bytes = std::fs::read(path)
+        .with_context(|| format!("Failed to read text file: {}", path.display()))?;
+    Ok(decode_text(&bytes))
+}
+
+/// UTF-8ならそのまま、それ以外はレガシーエンコーディング（Shift-JIS、EUC-JPなど）を推定してデコードする。
+/// デコードできないバイトは失敗にせず置換文字にする
+fn decode_text(bytes: &[u8]) -> String {
+    let bytes = bytes.strip_prefix(b"\xEF\xBB\xBF").unwrap_or(bytes);
+    if let Ok(text) = std::str::from_utf8(bytes) {
+        return text.to_string();
+    }
+    let mut detector = EncodingDetector::new(Iso2022JpDetection::Allow);
+    detector.feed(bytes, true);
+    let encoding = detector.guess(None, Utf8Detection::Allow);
+    let (text, _, had_errors) = encoding.decode(bytes);
+    if had_errors {
+        tracing::debug!("Lossy decode as {}", encoding.name());
+    }
+    text.into_owned()
 }
 
+/// これ未満の文字数しか取れないPDFはスキャン画像とみなしてOCRする
+#[cfg(feature = "ocr")]
+const MIN_PDF_TEXT_CHARS: usize = 20;
+
 fn extract_pdf(path: &Path) -> Result<String> {
     let text = pdf_extract::extract_text(path)
-        .with_context(|| format!("Failed to extract PDF text: {}", path.display()))?;
-    Ok(text)
+        .with_context(|| format!("Failed to extract PDF text: {}", path.display()));
+
+    #[cfg(feature = "ocr")]
+    {
+        let has_text = text.as_ref().is_ok_and(|t| t.trim().chars().count() >= MIN_PDF_TEXT_CHARS);
+        if !has_text {
+            tracing::info!("Little or no text layer in {}, falling back to OCR", path.display());
+            return super::ocr::ocr_pdf(&super::ocr::OcrConfig::from_env(), path);
+        }
+    }
+
+    text
+}
+
+#[cfg(feature = "ocr")]
+fn extract_image(path: &Path) -> Result<String> {
+    super::ocr::ocr_image(&super::ocr::OcrConfig::from_env(), path)
+}
+
+#[cfg(not(feature = "ocr"))]
+fn extract_image(path: &Path) -> Result<String> {
+    anyhow::bail!("OCR support is not enabled (build with --features ocr): {}", path.display())
 }
 
 fn extract_docx(path: &Path) -> Result<String> {
@@ -46,24 +102,29 @@ fn extract_xlsx(path: &Path) -> Result<String> {
     let mut workbook: Xlsx<_> = open_workbook(path)
         .with_context(|| format!("Failed to open XLSX: {}", path.display()))?;
 
-    let mut all_text = Vec::new();
+    let mut sheets = Vec::new();
     let sheet_names: Vec<String> = workbook.sheet_names().to_vec();
 
     for sheet_name in sheet_names {
         if let Ok(range) = workbook.worksheet_range(&sheet_name) {
+            let mut lines = Vec::new();
             for row in range.rows() {
                 let row_text: Vec<String> = row.iter()
                     .map(|cell| cell.to_string())
                     .filter(|s| !s.is_empty())
                     .collect();
                 if !row_text.is_empty() {
-                    all_text.push(row_text.join("\t"));
+                    lines.push(row_text.join("\t"));
                 }
             }
+            // シート名を見出しにして、どの表の値か分かるようにする
+            if !lines.is_empty() {
+                sheets.push(format!("### {}\n{}", sheet_name, lines.join("\n")));
+            }
         }
     }
 
-    Ok(all_text.join("\n"))
+    Ok(sheets.join("\n\n"))
 }
 
 fn extract_pptx(path: &Path) -> Result<String> {
@@ -91,6 +152,32 @@ fn extract_pptx(path: &Path) -> Result<String> {
     Ok(all_text.join("\n\n"))
 }
 
+fn extract_html(path: &Path) -> Result<String> {
+    let bytes = std::fs::read(path)
+        .with_context(|| format!("Failed to read HTML: {}", path.display()))?;
+    Ok(html_to_text(&decode_text(&bytes)))
+}
+
+/// HTMLの表示テキストをテキストノードごとに1行で返す。
+/// 文字参照はパーサーがデコードし、script/style/noscript/templateの中身は除く
+fn html_to_text(html: &str) -> String {
+    const HIDDEN: &[&str] = &["script", "style", "noscript", "template"];
+
+    let document = scraper::Html::parse_document(html);
+    let mut lines = Vec::new();
+    for node in document.root_element().descendants() {
+        let Some(text) = node.value().as_text() else { continue };
+        let hidden = node.ancestors().any(|a| {
+            a.value().as_element().is_some_and(|e| HIDDEN.contains(&e.name()))
+        });
+        let text = text.trim();
+        if !hidden && !text.is_empty() {
+            lines.push(text.to_string());
+        }
+    }
+    lines.join("\n")
+}
+
 fn extract_text_from_xml(xml: &str, tag: &str) -> String {
     let open_tag = format!("<{}", tag);
     let close_tag = format!("</{}>", tag);
@@ -118,3 +205,134 @@ fn extract_text_from_xml(xml: &str, tag: &str) -> String {
 
     texts.join(" ")
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_validate_malformed_pdf() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("broken.pdf");
+        std::fs::write(&path, b"this is not a pdf").unwrap();
+
+        let err = validate_extraction(&path, SupportedFormat::Pdf).unwrap_err();
+        assert!(!err.is_empty());
+    }
+
+    #[test]
+    fn test_html_extracts_visible_text_only() {
+        let html = r#"<!DOCTYPE html>
+<html>
+<head>
+  <title>経費精算ガイド</title>
+  <style>body { color: red; }</style>
+  <script>var secret = "do not index";</script>
+</head>
+<body>
+  <h1>経費精算</h1>
+  <p>領収書は&lt;30日以内&gt;に提出 &amp; 承認を受けること。</p>
+  <noscript>JavaScriptを有効にしてください</noscript>
+</body>
+</html>"#;
+
+        let text = html_to_text(html);
+        assert_eq!(text, "経費精算ガイド\n経費精算\n領収書は<30日以内>に提出 & 承認を受けること。");
+    }
+
+    #[cfg(not(feature = "ocr"))]
+    #[test]
+    fn test_image_without_ocr_feature_is_clear_error() {
+        assert_eq!(SupportedFormat::from_extension("png").map(|_| ()), None);
+        let err = extract_text(Path::new("scan.png"), SupportedFormat::Image).unwrap_err();
+        assert!(err.to_string().contains("--features ocr"));
+    }
+
+    #[test]
+    fn test_shift_jis_and_euc_jp_text_decoded() {
+        let original = "就業規則 第3条：社員は始業時刻の10分前までに出社すること。";
+        let dir = tempfile::tempdir().unwrap();
+
+        for (name, encoding) in [("sjis.txt", encoding_rs::SHIFT_JIS), ("euc.txt", encoding_rs::EUC_JP)] {
+            let (bytes, _, _) = encoding.encode(original);
+            assert!(std::str::from_utf8(&bytes).is_err());
+            let path = dir.path().join(name);
+            std::fs::write(&path, &bytes).unwrap();
+
+            assert_eq!(extract_text(&path, SupportedFormat::PlainText).unwrap(), original, "{}", name);
+        }
+    }
+
+    /// Minimal workbook with inline-string cells, one entry per sheet.
+    fn write_xlsx(path: &Path, sheets: &[(&str, &[&[&str]])]) {
+        use std::io::Write;
+        use zip::write::SimpleFileOptions;
+
+        let mut zip = zip::ZipWriter::new(std::fs::File::create(path).unwrap());
+        let options = SimpleFileOptions::default();
+        let mut put = |name: &str, body: String| {
+            zip.start_file(name, options).unwrap();
+            zip.write_all(body.as_bytes()).unwrap();
+        };
+
+        let overrides: String = (1..=sheets.len()).map(|i| format!(
+            r#"<Override PartName="/xl/worksheets/sheet{i}.xml" ContentType="application/vnd.openxmlformats-officedocument.spreadsheetml.worksheet+xml"/>"#
+        )).collect();
+        put("[Content_Types].xml", format!(
+            r#"<?xml version="1.0" encoding="UTF-8"?><Types xmlns="http://schemas.openxmlformats.org/package/2006/content-types"><Default Extension="rels" ContentType="application/vnd.openxmlformats-package.relationships+xml"/><Default Extension="xml" ContentType="application/xml"/><Override PartName="/xl/workbook.xml" ContentType="application/vnd.openxmlformats-officedocument.spreadsheetml.sheet.main+xml"/>{overrides}</Types>"#
+        ));
+        put("_rels/.rels", r#"<?xml version="1.0" encoding="UTF-8"?><Relationships xmlns="http://schemas.openxmlformats.org/package/2006/relationships"><Relationship Id="rId1" Type="http://schemas.openxmlformats.org/officeDocument/2006/relationships/officeDocument" Target="xl/workbook.xml"/></Relationships>"#.to_string());
+
+        let sheet_entries: String = sheets.iter().enumerate().map(|(i, (name, _))| format!(
+            r#"<sheet name="{name}" sheetId="{id}" r:id="rId{id}"/>"#, id = i + 1
+        )).collect();
+        put("xl/workbook.xml", format!(
+            r#"<?xml version="1.0" encoding="UTF-8"?><workbook xmlns="http://schemas.openxmlformats.org/spreadsheetml/2006/main" xmlns:r="http://schemas.openxmlformats.org/officeDocument/2006/relationships"><sheets>{sheet_entries}</sheets></workbook>"#
+        ));
+        let rels: String = (1..=sheets.len()).map(|i| format!(
+            r#"<Relationship Id="rId{i}" Type="http://schemas.openxmlformats.org/officeDocument/2006/relationships/worksheet" Target="worksheets/sheet{i}.xml"/>"#
+        )).collect();
+        put("xl/_rels/workbook.xml.rels", format!(
+            r#"<?xml version="1.0" encoding="UTF-8"?><Relationships xmlns="http://schemas.openxmlformats.org/package/2006/relationships">{rels}</Relationships>"#
+        ));
+
+        for (i, (_, rows)) in sheets.iter().enumerate() {
+            let rows_xml: String = rows.iter().enumerate().map(|(r, cells)| {
+                let cells_xml: String = cells.iter().enumerate().map(|(c, v)| format!(
+                    r#"<c r="{col}{row}" t="inlineStr"><is><t>{v}</t></is></c>"#,
+                    col = (b'A' + c as u8) as char, row = r + 1
+                )).collect();
+                format!(r#"<row r="{}">{}</row>"#, r + 1, cells_xml)
+            }).collect();
+            put(&format!("xl/worksheets/sheet{}.xml", i + 1), format!(
+                r#"<?xml version="1.0" encoding="UTF-8"?><worksheet xmlns="http://schemas.openxmlformats.org/spreadsheetml/2006/main"><sheetData>{rows_xml}</sheetData></worksheet>"#
+            ));
+        }
+        zip.finish().unwrap();
+    }
+
+    #[test]
+    fn test_xlsx_sheets_are_labelled() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("budget.xlsx");
+        write_xlsx(&path, &[
+            ("売上", &[&["月", "金額"], &["4月", "120"]]),
+            ("空", &[]),
+            ("経費", &[&["交通費", "35"]]),
+        ]);
+
+        let text = extract_text(&path, SupportedFormat::Xlsx).unwrap();
+        assert_eq!(text, "### 売上\n月\t金額\n4月\t120\n\n### 経費\n交通費\t35");
+    }
+
+    #[test]
+    fn test_validate_plain_text() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("note.txt");
+        std::fs::write(&path, "hello").unwrap();
+        assert!(validate_extraction(&path, SupportedFormat::PlainText).is_ok());
+
+        std::fs::write(&path, "   ").unwrap();
+        assert!(validate_extraction(&path, SupportedFormat::PlainText).is_err());
+    }
+}
@@ -1,3 +1,5 @@
 pub mod walker;
 pub mod chunker;
 pub mod extractor;
+#[cfg(feature = "ocr")]
+pub mod ocr;
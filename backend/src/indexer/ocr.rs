@@ -0,0 +1,114 @@
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use anyhow::{Context, Result};
+
+/// 外部OCRツールの設定。`OCR_TESSERACT_PATH` / `OCR_PDFTOPPM_PATH` でPATH上のコマンドを、
+/// `OCR_LANG` でtesseractの言語を上書きする
+#[derive(Debug, Clone)]
+pub struct OcrConfig {
+    pub tesseract: PathBuf,
+    pub pdftoppm: PathBuf,
+    pub lang: String,
+}
+
+impl OcrConfig {
+    pub fn from_env() -> Self {
+        Self {
+            tesseract: std::env::var("OCR_TESSERACT_PATH").unwrap_or_else(|_| "tesseract".to_string()).into(),
+            pdftoppm: std::env::var("OCR_PDFTOPPM_PATH").unwrap_or_else(|_| "pdftoppm".to_string()).into(),
+            lang: std::env::var("OCR_LANG").unwrap_or_else(|_| "jpn+eng".to_string()),
+        }
+    }
+}
+
+/// コマンドを実行する。見つからない場合は設定すべき環境変数をエラーに含める
+fn run(program: &Path, args: &[&std::ffi::OsStr], env_var: &str) -> Result<Vec<u8>> {
+    let output = Command::new(program).args(args).output().map_err(|e| {
+        if e.kind() == std::io::ErrorKind::NotFound {
+            anyhow::anyhow!("OCR unavailable: `{}` not found (install it or set {})", program.display(), env_var)
+        } else {
+            anyhow::anyhow!("Failed to run {}: {}", program.display(), e)
+        }
+    })?;
+    if !output.status.success() {
+        anyhow::bail!("{} failed: {}", program.display(), String::from_utf8_lossy(&output.stderr).trim());
+    }
+    Ok(output.stdout)
+}
+
+pub fn ocr_image(config: &OcrConfig, path: &Path) -> Result<String> {
+    let stdout = run(
+        &config.tesseract,
+        &[path.as_os_str(), "stdout".as_ref(), "-l".as_ref(), config.lang.as_ref()],
+        "OCR_TESSERACT_PATH",
+    ).with_context(|| format!("OCR failed: {}", path.display()))?;
+    Ok(String::from_utf8_lossy(&stdout).trim().to_string())
+}
+
+/// pdftoppmで各ページをPNGにしてから、ページ順にOCRする
+pub fn ocr_pdf(config: &OcrConfig, path: &Path) -> Result<String> {
+    let work_dir = std::env::temp_dir().join(format!("llm-proxy-ocr-{}", uuid::Uuid::new_v4().simple()));
+    std::fs::create_dir_all(&work_dir)?;
+    let result = ocr_pdf_in(config, path, &work_dir);
+    let _ = std::fs::remove_dir_all(&work_dir);
+    result
+}
+
+fn ocr_pdf_in(config: &OcrConfig, path: &Path, work_dir: &Path) -> Result<String> {
+    let prefix = work_dir.join("page");
+    run(
+        &config.pdftoppm,
+        &["-r".as_ref(), "300".as_ref(), "-png".as_ref(), path.as_os_str(), prefix.as_os_str()],
+        "OCR_PDFTOPPM_PATH",
+    ).with_context(|| format!("Failed to render PDF pages: {}", path.display()))?;
+
+    // pdftoppmはページ番号をゼロ埋めするので、ファイル名順がページ順
+    let mut pages: Vec<PathBuf> = std::fs::read_dir(work_dir)?
+        .filter_map(|e| e.ok().map(|e| e.path()))
+        .filter(|p| p.extension().is_some_and(|ext| ext == "png"))
+        .collect();
+    pages.sort();
+
+    let mut texts = Vec::new();
+    for page in &pages {
+        let text = ocr_image(config, page)?;
+        if !text.is_empty() {
+            texts.push(text);
+        }
+    }
+    Ok(texts.join("\n\n"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    #[ignore = "needs tesseract and pdftoppm installed"]
+    fn test_ocr_reads_scanned_samples() {
+        let config = OcrConfig {
+            tesseract: "tesseract".into(),
+            pdftoppm: "pdftoppm".into(),
+            lang: "eng".to_string(),
+        };
+        let testdata = Path::new(env!("CARGO_MANIFEST_DIR")).join("testdata");
+
+        let pdf = ocr_pdf(&config, &testdata.join("scanned_sample.pdf")).unwrap();
+        assert!(pdf.contains("INVOICE"), "{}", pdf);
+
+        let image = ocr_image(&config, &testdata.join("scanned_sample.png")).unwrap();
+        assert!(image.contains("INVOICE"), "{}", image);
+    }
+
+    #[test]
+    fn test_missing_binary_names_env_var() {
+        let config = OcrConfig {
+            tesseract: "/nonexistent/tesseract".into(),
+            pdftoppm: "pdftoppm".into(),
+            lang: "eng".to_string(),
+        };
+        let err = ocr_image(&config, Path::new("scan.png")).unwrap_err();
+        assert!(format!("{:#}", err).contains("OCR_TESSERACT_PATH"), "{:#}", err);
+    }
+}
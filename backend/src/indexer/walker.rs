@@ -1,6 +1,9 @@
 use std::path::{Path, PathBuf};
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
 use walkdir::WalkDir;
 
+use crate::rag::index_state::STATE_FILE_NAME;
+
 #[derive(Debug, Clone, Copy)]
 pub enum SupportedFormat {
     PlainText,
@@ -8,33 +11,85 @@ pub enum SupportedFormat {
     Docx,
     Xlsx,
     Pptx,
+    Html,
+    /// png/jpg、`ocr` feature有効時のみ
+    Image,
 }
 
+/// 形式ごとの対応拡張子（小文字）。`from_extension` とAPIの一覧はここだけを見る
+const FORMAT_EXTENSIONS: &[(SupportedFormat, &[&str])] = &[
+    (SupportedFormat::PlainText, &["txt", "md", "rs", "py", "js", "ts", "json", "yaml", "yml", "toml"]),
+    (SupportedFormat::Pdf, &["pdf"]),
+    (SupportedFormat::Docx, &["docx"]),
+    (SupportedFormat::Xlsx, &["xlsx"]),
+    (SupportedFormat::Pptx, &["pptx"]),
+    (SupportedFormat::Html, &["html", "htm"]),
+    (SupportedFormat::Image, &["png", "jpg", "jpeg"]),
+];
+
 impl SupportedFormat {
     pub fn from_extension(ext: &str) -> Option<Self> {
-        match ext.to_lowercase().as_str() {
-            "txt" | "md" | "rs" | "py" | "js" | "ts" | "json" | "yaml" | "yml" | "toml" => {
-                Some(Self::PlainText)
-            }
-            "pdf" => Some(Self::Pdf),
-            "docx" => Some(Self::Docx),
-            "xlsx" => Some(Self::Xlsx),
-            "pptx" => Some(Self::Pptx),
-            _ => None,
+        let ext = ext.to_lowercase();
+        Self::supported()
+            .find(|(_, exts)| exts.contains(&ext.as_str()))
+            .map(|(format, _)| format)
+    }
+
+    /// このビルドで受け付ける形式と拡張子
+    pub fn supported() -> impl Iterator<Item = (Self, &'static [&'static str])> {
+        FORMAT_EXTENSIONS.iter().copied().filter(|(format, _)| format.is_available())
+    }
+
+    fn is_available(&self) -> bool {
+        !matches!(self, Self::Image) || cfg!(feature = "ocr")
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::PlainText => "plain_text",
+            Self::Pdf => "pdf",
+            Self::Docx => "docx",
+            Self::Xlsx => "xlsx",
+            Self::Pptx => "pptx",
+            Self::Html => "html",
+            Self::Image => "image",
         }
     }
 }
 
+/// 除外パターン（gitignore形式）を書くファイル。upload_dir直下に置く
+pub const IGNORE_FILE_NAME: &str = ".ragignore";
+
+/// Patterns from `<dir>/.ragignore`; empty if the file is missing.
+fn load_ignore(dir: &Path) -> Gitignore {
+    let mut builder = GitignoreBuilder::new(dir);
+    let path = dir.join(IGNORE_FILE_NAME);
+    if path.is_file() {
+        if let Some(e) = builder.add(&path) {
+            tracing::warn!("Invalid pattern in {}: {}", path.display(), e);
+        }
+    }
+    builder.build().unwrap_or_else(|e| {
+        tracing::warn!("Ignoring {}: {}", path.display(), e);
+        Gitignore::empty()
+    })
+}
+
 pub fn walk_directory(dir: &Path) -> Vec<(PathBuf, SupportedFormat)> {
+    let ignore = load_ignore(dir);
+    let base = dir.canonicalize().unwrap_or_else(|_| dir.to_path_buf());
     WalkDir::new(dir)
         .follow_links(true)
         .into_iter()
         .filter_entry(|entry| {
             // Skip .versions directories entirely
             entry.file_name().to_string_lossy() != ".versions"
+                && !ignore.matched(entry.path(), entry.file_type().is_dir()).is_ignore()
+                && (!entry.path_is_symlink() || stays_within(entry.path(), &base))
         })
         .filter_map(|entry| entry.ok())
         .filter(|entry| entry.file_type().is_file())
+        .filter(|entry| entry.file_name().to_string_lossy() != STATE_FILE_NAME)
         .filter_map(|entry| {
             let path = entry.into_path();
             let ext = path.extension()?.to_str()?;
@@ -43,3 +98,60 @@ pub fn walk_directory(dir: &Path) -> Vec<(PathBuf, SupportedFormat)> {
         })
         .collect()
 }
+
+/// Symlinks are followed only while their target is inside the upload dir.
+fn stays_within(path: &Path, base: &Path) -> bool {
+    path.canonicalize().is_ok_and(|real| real.starts_with(base))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_supported_formats_match_from_extension() {
+        let exts: Vec<&str> = SupportedFormat::supported().flat_map(|(_, exts)| exts.iter().copied()).collect();
+        assert!(exts.contains(&"pdf"));
+        assert!(exts.contains(&"docx"));
+        assert_eq!(exts.contains(&"png"), cfg!(feature = "ocr"));
+        for ext in exts {
+            assert!(SupportedFormat::from_extension(ext).is_some(), "{}", ext);
+        }
+        assert!(matches!(SupportedFormat::from_extension("PDF"), Some(SupportedFormat::Pdf)));
+        assert!(SupportedFormat::from_extension("exe").is_none());
+    }
+
+    #[test]
+    fn test_ragignore_excludes_matching_paths() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir(dir.path().join("drafts")).unwrap();
+        std::fs::write(dir.path().join("drafts/wip.md"), "下書き").unwrap();
+        std::fs::write(dir.path().join("guide.md"), "公開版").unwrap();
+        std::fs::write(dir.path().join("big.log.txt"), "ログ").unwrap();
+        std::fs::write(dir.path().join(IGNORE_FILE_NAME), "drafts/**\n*.log.txt\n").unwrap();
+
+        let files: Vec<PathBuf> = walk_directory(dir.path()).into_iter().map(|(p, _)| p).collect();
+        assert_eq!(files, vec![dir.path().join("guide.md")]);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_symlinks_leaving_dir_are_not_followed() {
+        use std::os::unix::fs::symlink;
+
+        let dir = tempfile::tempdir().unwrap();
+        let outside = tempfile::tempdir().unwrap();
+        std::fs::write(outside.path().join("secret.txt"), "外部").unwrap();
+        std::fs::create_dir(dir.path().join("docs")).unwrap();
+        std::fs::write(dir.path().join("docs/manual.md"), "手順").unwrap();
+        symlink(outside.path(), dir.path().join("escape")).unwrap();
+        symlink(outside.path().join("secret.txt"), dir.path().join("secret_link.txt")).unwrap();
+        symlink(dir.path().join("docs"), dir.path().join("docs_link")).unwrap();
+
+        let mut names: Vec<String> = walk_directory(dir.path()).into_iter()
+            .map(|(p, _)| p.strip_prefix(dir.path()).unwrap().to_string_lossy().to_string())
+            .collect();
+        names.sort();
+        assert_eq!(names, vec!["docs/manual.md", "docs_link/manual.md"]);
+    }
+}
@@ -4,3 +4,7 @@ pub mod rag;
 pub mod proxy;
 pub mod logger;
 pub mod indexer;
+pub mod auth;
+pub mod rate_limit;
+pub mod telemetry;
+pub mod pricing;
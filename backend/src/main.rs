@@ -1,12 +1,20 @@
 use axum::{
     Router,
     routing::{get, post, put, delete},
-    extract::{State, Query, Multipart, Path},
+    extract::{State, Query, Multipart, Path, ConnectInfo, Request, DefaultBodyLimit},
+    Extension,
     Json,
-    http::StatusCode,
+    body::Body,
+    http::{StatusCode, header},
+    response::{IntoResponse, Response, sse::{Event, KeepAlive, Sse}},
+    middleware,
 };
+use futures::{Stream, StreamExt};
+use std::convert::Infallible;
+use std::net::SocketAddr;
 use std::path::PathBuf;
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 use tokio::sync::Mutex;
 use tower_http::cors::{CorsLayer, Any};
 use axum::http::Method;
@@ -15,27 +23,51 @@ use uuid::Uuid;
 use chrono::Utc;
 
 use llm_proxy::models::{
-    ChatRequest, ChatResponse, ModelInfo, DocumentUpload,
-    LogQuery, LogResponse, LogEntry,
-    IndexStatusResponse, IndexConfigUpdate, UploadResponse,
-    DirEntry, CreateDirRequest, CreateFileRequest, ListFilesQuery,
+    ChatRequest, ChatResponse, ModelInfo, DocumentUpload, BatchDocumentResult, BatchDocumentResponse, EmbeddingsRequest,
+    LogQuery, LogResponse, LogEntry, DeleteLogsQuery, StatsQuery, LogStats,
+    IndexStatusResponse, SupportedFormatInfo, FileChunksResponse, IndexConfigUpdate, UploadResponse, UploadQuery, FileValidation,
+    DirEntry, CreateDirRequest, CreateFileRequest, UpdateFileRequest, VersionDiffQuery, ListFilesQuery, MoveRequest, CopyRequest,
+    DeleteFileQuery, RestoreRequest, IndexFileRequest,
+    FileSearchQuery, FileSearchHit,
     FileVersionHistory, RollbackRequest, RollbackResponse,
+    RagSearchRequest, RagSearchResponse, SanitizerFinding, PIIEntitySpan,
 };
-use llm_proxy::filters::pii_detector::PIIDetector;
-use llm_proxy::filters::output_sanitizer::OutputSanitizer;
-use llm_proxy::rag::RAGEngine;
-use llm_proxy::rag::index_manager::IndexManager;
-use llm_proxy::proxy::LiteLLMProxy;
-use llm_proxy::logger::Logger;
-use llm_proxy::indexer::walker::SupportedFormat;
-use llm_proxy::rag::versioning;
+use llm_proxy::filters::pii_detector::{mask_entities, PIIDetector, PIIConfig};
+use llm_proxy::filters::output_sanitizer::{OutputSanitizer, SanitizerMode};
+use llm_proxy::rag::{self, RAGEngine, CollectionAllowlist, CollectionNotAllowed, SearchOptions};
+use llm_proxy::rag::query_expansion::QueryExpander;
+use llm_proxy::rag::rerank::Reranker;
+use llm_proxy::rag::index_manager::{IndexManager, IndexingInProgress, ChunkLimit};
+use llm_proxy::rag::index_events::IndexStreamItem;
+use llm_proxy::rag::index_watcher;
+use llm_proxy::proxy::{self, LiteLLMProxy, ModelFallbacks, RetryPolicy, UpstreamTimeout};
+use llm_proxy::logger::{self, Logger, InvalidDateError, InvalidSearchError};
+use llm_proxy::indexer::walker::{walk_directory, SupportedFormat};
+use llm_proxy::indexer::extractor;
+use llm_proxy::indexer::chunker::ChunkSettings;
+use llm_proxy::rag::{files::{self, UploadLimits}, versioning};
+use llm_proxy::telemetry;
+use llm_proxy::auth::{self, AuthConfig, ApiKey};
+use llm_proxy::rate_limit::RateLimiter;
+use llm_proxy::pricing::PricingTable;
+use metrics_exporter_prometheus::PrometheusHandle;
 
 struct AppState {
+    pii_config: PIIConfig,
     pii_detector: Mutex<PIIDetector>,
     rag_engine: Option<RAGEngine>,
     index_manager: Option<Arc<IndexManager>>,
     litellm_proxy: LiteLLMProxy,
     logger: Logger,
+    metrics: PrometheusHandle,
+    rate_limiter: Option<RateLimiter>,
+    sanitizer: OutputSanitizer,
+    sanitizer_mode: SanitizerMode,
+    rag_min_score: f32,
+    rag_top_k: rag::TopKLimits,
+    pricing: PricingTable,
+    upload_limits: UploadLimits,
+    auth_config: Arc<AuthConfig>,
 }
 
 #[tokio::main]
@@ -62,15 +94,69 @@ async fn main() -> Result<()> {
     // アップロードディレクトリ作成
     let upload_path = PathBuf::from(&upload_dir);
     std::fs::create_dir_all(&upload_path)?;
+    let upload_path = upload_path.canonicalize()?;
+
+    let metrics = telemetry::install()?;
+    {
+        // ヒストグラムの古いサンプルを定期的に整理する
+        let metrics = metrics.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(Duration::from_secs(5));
+            loop {
+                interval.tick().await;
+                metrics.run_upkeep();
+            }
+        });
+    }
 
     // コンポーネント初期化
-    let logger = Logger::new(&database_url).await?;
+    let mut logger = Logger::new(&database_url).await?;
     logger.init_schema().await?;
+    if let Some(cipher) = logger::cipher_from_env()? {
+        logger = logger.with_redaction(cipher);
+        tracing::info!("LOG_STORE_RAW=false: logging masked text only, PII mappings encrypted");
+    }
+
+    if let Some(days) = std::env::var("LOG_RETENTION_DAYS").ok().and_then(|v| v.parse::<i64>().ok()) {
+        Logger::start_retention(logger.clone(), days);
+        tracing::info!("Log retention enabled: deleting logs older than {} days", days);
+    }
+
+    if let Some(max) = versioning::max_versions_from_env() {
+        versioning::configure_max_versions(max);
+        tracing::info!("Keeping up to {} versions per file", max.max(1));
+    }
 
     let rag_engine = match RAGEngine::new(&qdrant_url, "documents").await {
         Ok(engine) => {
             tracing::info!("RAG engine initialized successfully");
-            Some(engine)
+            let engine = engine
+                .with_collections(CollectionAllowlist::from_env())
+                .with_keyword_weight(rag::keyword_weight_from_env())
+                .with_dedup_threshold(rag::dedup_threshold_from_env())
+                .with_source_root(&upload_path);
+            let engine = match Reranker::from_env() {
+                Ok(Some(reranker)) => {
+                    tracing::info!("RAG rerank enabled ({} candidates)", reranker.candidates());
+                    engine.with_reranker(reranker)
+                }
+                Ok(None) => engine,
+                Err(e) => {
+                    tracing::warn!("Rerank disabled: {}", e);
+                    engine
+                }
+            };
+            match QueryExpander::from_env() {
+                Ok(Some(expander)) => {
+                    tracing::info!("Query synonym expansion enabled");
+                    Some(engine.with_query_expander(expander))
+                }
+                Ok(None) => Some(engine),
+                Err(e) => {
+                    tracing::warn!("Query expansion disabled: {}", e);
+                    Some(engine)
+                }
+            }
         }
         Err(e) => {
             tracing::warn!("RAG engine initialization failed (continuing without RAG): {}", e);
@@ -80,13 +166,36 @@ async fn main() -> Result<()> {
 
     // IndexManager初期化
     let index_manager = if let Some(ref engine) = rag_engine {
-        let manager = Arc::new(IndexManager::new(
+        let mut manager = IndexManager::new(
             upload_path,
             engine.embeddings.clone(),
             engine.vector_store.clone(),
             60,
-        ));
+        );
+        if let Some(capacity) = std::env::var("INDEX_EVENTS_BUFFER").ok().and_then(|v| v.parse::<usize>().ok()) {
+            manager = manager.with_event_capacity(capacity);
+        }
+        if let Some(concurrency) = std::env::var("INDEX_CONCURRENCY").ok().and_then(|v| v.parse::<usize>().ok()) {
+            manager = manager.with_concurrency(concurrency);
+        }
+        if std::env::var("RAG_STAGED_REINDEX").map(|v| v == "true").unwrap_or(false) {
+            tracing::info!("Staged reindexing enabled (staging collection + alias swap)");
+            manager = manager.with_staged_reindex(true);
+        }
+        let chunking = ChunkSettings::from_env();
+        tracing::info!("Chunking: {} / {} overlap ({:?})", chunking.max_chunk_size, chunking.overlap, chunking.chunk_by);
+        manager = manager.with_chunking(chunking);
+        if let Some(limit) = ChunkLimit::from_env() {
+            tracing::info!("Chunk limit per file: {} ({:?} on overflow)", limit.max_chunks_per_file, limit.on_overflow);
+            manager = manager.with_chunk_limit(limit);
+        }
+        let manager = Arc::new(manager);
         IndexManager::start_scheduler(manager.clone());
+        if std::env::var("INDEX_WATCH").map(|v| v == "true").unwrap_or(false) {
+            if let Err(e) = index_watcher::start(manager.clone()) {
+                tracing::warn!("File watcher disabled: {}", e);
+            }
+        }
         tracing::info!("Index manager initialized with 60-minute auto-index");
         Some(manager)
     } else {
@@ -95,14 +204,68 @@ async fn main() -> Result<()> {
     };
 
     let litellm_api_key = std::env::var("LITELLM_API_KEY").ok();
-    let litellm_proxy = LiteLLMProxy::new(litellm_url, litellm_api_key);
+    let litellm_timeout = std::env::var("LITELLM_TIMEOUT_SECS").ok()
+        .and_then(|v| v.parse().ok())
+        .map(Duration::from_secs)
+        .unwrap_or(proxy::DEFAULT_TIMEOUT);
+    let litellm_proxy = LiteLLMProxy::new(litellm_url, litellm_api_key)
+        .with_retry(RetryPolicy::from_env())
+        .with_timeout(litellm_timeout);
+    let model_fallbacks = ModelFallbacks::from_env();
+    if !model_fallbacks.is_empty() {
+        tracing::info!("Model fallbacks: {:?}", model_fallbacks);
+    }
+    let litellm_proxy = litellm_proxy.with_fallbacks(model_fallbacks);
+
+    let rate_limiter = RateLimiter::from_env();
+    if let Some(ref limiter) = rate_limiter {
+        tracing::info!("Chat rate limit: {} requests/minute per client", limiter.per_minute());
+    }
+
+    let sanitizer = OutputSanitizer::from_env()?;
+    tracing::info!("Output sanitizer loaded with {} rules", sanitizer.rule_count());
+
+    let pii_config = PIIConfig::from_env()?;
+    tracing::info!("PII categories enabled: {:?}", pii_config.enabled_categories());
+    if pii_config.allowlist_len() > 0 {
+        tracing::info!("PII allowlist loaded with {} entries", pii_config.allowlist_len());
+    }
+
+    let pricing = PricingTable::from_env();
+    if !pricing.is_empty() {
+        tracing::info!("Cost estimation enabled for {} models", pricing.len());
+    }
+
+    let rag_top_k = rag::TopKLimits::from_env();
+    tracing::info!("RAG context: {} chunks by default, up to {} per request", rag_top_k.default, rag_top_k.max);
+
+    let upload_limits = UploadLimits::from_env();
+    tracing::info!("Upload limits: {} bytes per file, {} per request",
+        upload_limits.max_file_bytes, upload_limits.max_request_bytes);
+
+    let auth_config = Arc::new(AuthConfig::from_env());
+    if auth_config.is_disabled() {
+        tracing::warn!("API key authentication is disabled (AUTH_DISABLED=true)");
+    } else if auth_config.key_count() == 0 {
+        tracing::warn!("API_KEYS is empty: all /api/v1 requests will be rejected");
+    }
 
     let state = Arc::new(AppState {
-        pii_detector: Mutex::new(PIIDetector::new()),
+        pii_detector: Mutex::new(PIIDetector::new(pii_config.clone())),
+        pii_config,
         rag_engine,
         index_manager,
         litellm_proxy,
         logger,
+        metrics,
+        rate_limiter,
+        sanitizer,
+        sanitizer_mode: SanitizerMode::from_env(),
+        rag_min_score: rag::min_score_from_env(),
+        rag_top_k,
+        pricing,
+        upload_limits,
+        auth_config: auth_config.clone(),
     });
 
     // CORS設定
@@ -111,123 +274,236 @@ async fn main() -> Result<()> {
         .allow_methods([Method::GET, Method::POST, Method::PUT, Method::DELETE, Method::OPTIONS])
         .allow_headers(Any);
 
-    // ルーター設定
-    let app = Router::new()
-        .route("/api/v1/chat/completions", post(chat_completion_handler))
+    // ルーター設定（/api/v1 はAPIキー認証必須）
+    let api_routes = Router::new()
+        .route("/api/v1/chat/completions", post(chat_completion_handler)
+            .layer(middleware::from_fn_with_state(state.clone(), rate_limit)))
         .route("/api/v1/models", get(list_models_handler))
+        .route("/api/v1/embeddings", post(embeddings_handler))
         .route("/api/v1/documents", post(add_document_handler))
-        .route("/api/v1/logs", get(query_logs_handler))
-        .route("/api/v1/rag/upload", post(rag_upload_handler))
+        .route("/api/v1/documents/batch", post(add_documents_batch_handler))
+        .route("/api/v1/documents/{id}", delete(delete_document_handler))
+        .route("/api/v1/logs", get(query_logs_handler).delete(delete_logs_handler))
+        .route("/api/v1/logs/export", get(export_logs_handler))
+        .route("/api/v1/stats", get(stats_handler))
+        .route("/api/v1/rag/upload", post(rag_upload_handler)
+            .layer(DefaultBodyLimit::max(upload_limits.max_request_bytes)))
         .route("/api/v1/rag/files", get(rag_list_files_handler))
-        .route("/api/v1/rag/files/{filename}", delete(rag_delete_file_handler))
+        .route("/api/v1/rag/search-files", get(rag_search_files_handler))
+        .route("/api/v1/rag/files/{filename}", delete(rag_delete_file_handler).put(rag_update_file_handler))
         .route("/api/v1/rag/mkdir", post(rag_mkdir_handler))
         .route("/api/v1/rag/files/create", post(rag_create_file_handler))
+        .route("/api/v1/rag/move", post(rag_move_handler))
+        .route("/api/v1/rag/copy", post(rag_copy_handler))
+        .route("/api/v1/rag/restore", post(rag_restore_handler))
+        .route("/api/v1/rag/files/{path}/download", get(rag_file_download_handler))
+        .route("/api/v1/rag/files/{path}/content", get(rag_file_content_handler))
+        .route("/api/v1/rag/files/{path}/chunks", get(rag_file_chunks_handler))
         .route("/api/v1/rag/files/{path}/versions", get(rag_file_versions_handler))
+        .route("/api/v1/rag/files/{path}/versions/{version}", get(rag_file_version_content_handler))
+        .route("/api/v1/rag/files/{path}/diff", get(rag_file_diff_handler))
         .route("/api/v1/rag/files/{path}/rollback", post(rag_file_rollback_handler))
         .route("/api/v1/rag/index", post(rag_trigger_index_handler))
+        .route("/api/v1/rag/index/retry-failed", post(rag_retry_failed_handler))
+        .route("/api/v1/rag/index/file", post(rag_index_file_handler))
+        .route("/api/v1/rag/search", post(rag_search_handler))
         .route("/api/v1/rag/status", get(rag_status_handler))
+        .route("/api/v1/rag/formats", get(rag_formats_handler))
+        .route("/api/v1/rag/index/events", get(rag_index_events_handler))
         .route("/api/v1/rag/config", put(rag_config_handler))
+        .route_layer(middleware::from_fn_with_state(auth_config, auth::require_api_key));
+
+    let app = Router::new()
+        .merge(api_routes)
         .route("/api/health", get(health_check))
+        .route("/metrics", get(metrics_handler))
         .layer(cors)
         .with_state(state);
 
     let listener = tokio::net::TcpListener::bind("0.0.0.0:8080").await?;
     tracing::info!("Backend server listening on {}", listener.local_addr()?);
 
-    axum::serve(listener, app).await?;
+    axum::serve(listener, app.into_make_service_with_connect_info::<SocketAddr>()).await?;
 
     Ok(())
 }
 
 // ===== Chat Handlers =====
 
-async fn chat_completion_handler(
+/// APIキー（認証無効時はクライアントIP）ごとのレート制限
+async fn rate_limit(
     State(state): State<Arc<AppState>>,
-    Json(mut request): Json<ChatRequest>,
-) -> Result<Json<ChatResponse>, (StatusCode, String)> {
-    let request_id = Uuid::new_v4();
-
-    let user_message = request.messages.iter()
-        .filter(|m| m.role == "user")
-        .last()
-        .ok_or((StatusCode::BAD_REQUEST, "No user message found".to_string()))?;
-
-    let original_content = user_message.content.clone();
-
-    // ① RAG検索（生テキストで検索 → 精度を維持）
-    let rag_context = if let Some(ref rag_engine) = state.rag_engine {
-        rag_engine
-            .retrieve_context(&original_content, 3)
-            .await
-            .map_err(|e| {
-                tracing::error!("RAG error: {}", e);
-                (StatusCode::INTERNAL_SERVER_ERROR, format!("RAG error: {}", e))
-            })?
-    } else {
-        String::new()
+    request: Request,
+    next: middleware::Next,
+) -> Response {
+    let Some(ref limiter) = state.rate_limiter else {
+        return next.run(request).await;
     };
 
-    // ② Input Filter: PII置換（入力テキスト + RAGコンテキスト両方をマスク）
-    let text_to_mask = if !rag_context.is_empty() {
-        format!("{}{}", rag_context, original_content)
+    let client = if let Some(ApiKey(key)) = request.extensions().get::<ApiKey>() {
+        format!("key:{}", key)
+    } else if let Some(ConnectInfo(addr)) = request.extensions().get::<ConnectInfo<SocketAddr>>() {
+        format!("ip:{}", addr.ip())
     } else {
-        original_content.clone()
+        "unknown".to_string()
     };
 
-    let (masked_content, mappings) = {
+    match limiter.check(&client).await {
+        Ok(()) => next.run(request).await,
+        Err(retry_after) => {
+            let secs = retry_after.as_secs_f64().ceil().max(1.0) as u64;
+            (
+                StatusCode::TOO_MANY_REQUESTS,
+                [(header::RETRY_AFTER, secs.to_string())],
+                "Rate limit exceeded",
+            ).into_response()
+        }
+    }
+}
+
+async fn chat_completion_handler(
+    State(state): State<Arc<AppState>>,
+    Json(mut request): Json<ChatRequest>,
+) -> Result<Json<ChatResponse>, (StatusCode, String)> {
+    let request_id = Uuid::new_v4();
+    let model = request.model.clone();
+    telemetry::record_chat_request();
+
+    // axum drops this future when the client disconnects, which aborts the
+    // upstream call below before anything is logged.
+
+    let original_content = request.last_user_content()
+        .ok_or((StatusCode::BAD_REQUEST, "No user message found".to_string()))?
+        .to_string();
+    // LLMに送る本文（llm_message_override指定時は差し替え）
+    let llm_content = request.llm_user_content()
+        .unwrap_or(&original_content)
+        .to_string();
+
+    // ① RAG検索（生テキストで検索 → 精度を維持）。use_rag: false なら検索しない
+    let rag_context = rag::chat_context(
+        state.rag_engine.as_ref(),
+        &request,
+        &original_content,
+        state.rag_top_k.resolve(request.rag_top_k),
+        state.rag_min_score,
+    )
+    .await
+    .map_err(|e| rag_error("RAG error", e))?;
+
+    // ② Input Filter: PII置換（LLMに送る本文 + RAGコンテキスト両方を同じ対応表でマスク）
+    let (masked_context, masked_content, content_entities, mappings) = {
         let mut detector = state.pii_detector.lock().await;
-        detector.detect_and_mask(&text_to_mask)
+        let (mut entities, mappings) = detector.detect_entities_all(&[&rag_context, &llm_content]);
+        let content_entities = entities.pop().unwrap_or_default();
+        let context_entities = entities.pop().unwrap_or_default();
+        (
+            mask_entities(&rag_context, &context_entities),
+            mask_entities(&llm_content, &content_entities),
+            content_entities,
+            mappings,
+        )
     };
 
     tracing::info!("Masked {} PII entities for request {}", mappings.len(), request_id);
+    telemetry::record_pii_masked(mappings.len());
 
-    // マスク済みテキストでLLMに送信
-    if let Some(last_msg) = request.messages.iter_mut()
-        .filter(|m| m.role == "user")
-        .last() {
-        last_msg.content = masked_content.clone();
-    }
+    // マスク済みテキストでLLMに送信。コンテキストはユーザー発話ではなくsystemメッセージで渡す
+    request.set_last_user_content(masked_content.clone());
+    request.add_system_context(&masked_context);
 
     // ③ LLM呼び出し
-    let llm_response = state.litellm_proxy
-        .chat_completion(request)
+    let llm_started = Instant::now();
+    let (llm_response, served_model) = state.litellm_proxy
+        .chat_completion_with_fallback(request)
         .await
         .map_err(|e| {
+            if e.downcast_ref::<UpstreamTimeout>().is_some() {
+                tracing::error!("LiteLLM timeout for request {}: {}", request_id, e);
+                return (StatusCode::GATEWAY_TIMEOUT, e.to_string());
+            }
             tracing::error!("LiteLLM error: {}", e);
             (StatusCode::BAD_GATEWAY, format!("LiteLLM error: {}", e))
         })?;
+    if served_model != model {
+        tracing::info!("Request {} served by fallback model {} (requested {})", request_id, served_model, model);
+    }
+    let llm_elapsed = llm_started.elapsed();
+    telemetry::record_llm_latency(llm_elapsed);
+    let latency_ms = llm_elapsed.as_millis() as i64;
 
     // ④ Output Filter: PII復元（架空名→実名）
     let mut final_response = llm_response.clone();
-    if let Some(choice) = final_response.choices.first_mut() {
-        let detector = state.pii_detector.lock().await;
-        choice.message.content = detector.unmask(&choice.message.content, &mappings);
-    }
-
-    // ⑤ Output Filter: 危険コマンド除去
-    if let Some(choice) = final_response.choices.first_mut() {
-        let (sanitized, removed) = OutputSanitizer::sanitize(&choice.message.content);
-        if !removed.is_empty() {
-            tracing::warn!("Removed {} dangerous patterns from response {}: {:?}",
-                removed.len(), request_id, removed);
+    final_response.pii_masked_count = Some(mappings.len());
+    final_response.pii_entities = Some(content_entities.iter()
+        .map(|e| PIIEntitySpan {
+            category: e.category.name().to_string(),
+            byte_start: e.start,
+            byte_end: e.end,
+        })
+        .collect());
+    let estimated_cost_usd = llm_response.usage.as_ref()
+        .and_then(|usage| state.pricing.estimate(&served_model, usage));
+    final_response.estimated_cost_usd = estimated_cost_usd;
+    state.pii_detector.lock().await
+        .unmask_choices(&mut final_response.choices, &mappings);
+
+    // ⑤ Output Filter: 危険コマンド除去（reportモードでは検出のみ）
+    let mut all_findings = Vec::new();
+    let mut removed_count = 0;
+    for choice in final_response.choices.iter_mut() {
+        match state.sanitizer_mode {
+            SanitizerMode::Inline => {
+                let (sanitized, removed) = state.sanitizer.sanitize(&choice.message.content);
+                removed_count += removed.len();
+                if !removed.is_empty() {
+                    tracing::warn!("Removed {} dangerous patterns from response {} choice {}: {:?}",
+                        removed.len(), request_id, choice.index, removed);
+                }
+                choice.message.content = sanitized;
+            }
+            SanitizerMode::Report => {
+                let findings = state.sanitizer.find(&choice.message.content);
+                if !findings.is_empty() {
+                    tracing::warn!("Found {} dangerous patterns in response {} choice {}",
+                        findings.len(), request_id, choice.index);
+                }
+                all_findings.extend(findings.into_iter()
+                    .map(|f| SanitizerFinding { choice_index: choice.index, ..f }));
+            }
         }
-        choice.message.content = sanitized;
+    }
+    if matches!(state.sanitizer_mode, SanitizerMode::Report) && !final_response.choices.is_empty() {
+        final_response.sanitizer_findings = Some(all_findings);
     }
 
     // ⑥ ログ保存
     let log_entry = LogEntry {
         id: request_id,
         timestamp: Utc::now(),
-        original_input: original_content,
+        model: Some(served_model),
+        original_input: Some(original_content),
         masked_input: masked_content,
-        rag_context: if rag_context.is_empty() { None } else { Some(rag_context) },
+        // 生ログを残さない設定ではコンテキストもマスク済みのものを保存する
+        rag_context: match (rag_context.is_empty(), state.logger.stores_raw()) {
+            (true, _) => None,
+            (false, true) => Some(rag_context),
+            (false, false) => Some(masked_context),
+        },
         llm_output: llm_response.choices.first()
             .map(|c| c.message.content.clone())
             .unwrap_or_default(),
-        final_output: final_response.choices.first()
+        final_output: Some(final_response.choices.first()
             .map(|c| c.message.content.clone())
-            .unwrap_or_default(),
+            .unwrap_or_default()),
         pii_mappings: serde_json::to_value(&mappings).unwrap(),
+        latency_ms: Some(latency_ms),
+        prompt_tokens: llm_response.usage.as_ref().map(|u| u.prompt_tokens as i32),
+        completion_tokens: llm_response.usage.as_ref().map(|u| u.completion_tokens as i32),
+        estimated_cost_usd,
+        dangerous_patterns_removed: Some(removed_count as i32),
+        pii_count: None,
+        pii_mappings_encrypted: None,
     };
 
     state.logger.log_request(log_entry)
@@ -281,6 +557,56 @@ async fn list_models_handler() -> Json<Vec<ModelInfo>> {
     ])
 }
 
+/// `model` 省略時・RAGの埋め込みモデル指定時はローカルで、それ以外はLiteLLMに転送する
+async fn embeddings_handler(
+    State(state): State<Arc<AppState>>,
+    Json(mut request): Json<EmbeddingsRequest>,
+) -> Result<Json<serde_json::Value>, (StatusCode, String)> {
+    let started = Instant::now();
+    let local = state.rag_engine.as_ref()
+        .map(|engine| engine.embeddings.clone())
+        .filter(|embeddings| request.model.as_deref().is_none_or(|m| m == embeddings.model_name()));
+
+    if let Some(embeddings) = local {
+        let texts = request.input.into_vec();
+        if texts.is_empty() {
+            return Err((StatusCode::BAD_REQUEST, "input must not be empty".to_string()));
+        }
+        let count = texts.len();
+        // CPU-bound inference; keep it off the async workers
+        let response = tokio::task::spawn_blocking(move || embeddings.openai_embeddings(texts))
+            .await
+            .unwrap_or_else(|e| Err(anyhow::anyhow!("Embedding task failed: {}", e)))
+            .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, format!("Embedding error: {}", e)))?;
+        tracing::info!("Embedded {} inputs with {} in {:?}", count, response.model, started.elapsed());
+        return Ok(Json(serde_json::to_value(response).unwrap()));
+    }
+
+    let model = request.model.clone()
+        .ok_or((StatusCode::BAD_REQUEST, "model is required (no local embedding model)".to_string()))?;
+    // チャットと同じく、外部へ出す本文はPIIをマスクしてから
+    let masked = state.pii_detector.lock().await.mask_embedding_input(&mut request.input);
+    telemetry::record_pii_masked(masked);
+    let response = state.litellm_proxy.embeddings(&request).await.map_err(|e| {
+        if let Some(upstream) = e.downcast_ref::<proxy::UpstreamStatus>() {
+            return (upstream.status, upstream.body.clone());
+        }
+        tracing::error!("LiteLLM embeddings error: {}", e);
+        (StatusCode::BAD_GATEWAY, format!("LiteLLM error: {}", e))
+    })?;
+    tracing::info!("Forwarded embeddings request for {} in {:?}", model, started.elapsed());
+    Ok(Json(response))
+}
+
+/// 許可されていないコレクション指定は400、それ以外は500
+fn rag_error(context: &str, e: anyhow::Error) -> (StatusCode, String) {
+    if e.downcast_ref::<CollectionNotAllowed>().is_some() {
+        return (StatusCode::BAD_REQUEST, e.to_string());
+    }
+    tracing::error!("{}: {}", context, e);
+    (StatusCode::INTERNAL_SERVER_ERROR, format!("RAG error: {}", e))
+}
+
 async fn add_document_handler(
     State(state): State<Arc<AppState>>,
     Json(payload): Json<DocumentUpload>,
@@ -293,12 +619,9 @@ async fn add_document_handler(
 
     if let Some(ref rag_engine) = state.rag_engine {
         rag_engine
-            .add_document(&id, &payload.content, metadata)
+            .add_document(&id, &payload.content, metadata, payload.collection.as_deref())
             .await
-            .map_err(|e| {
-                tracing::error!("RAG document add error: {}", e);
-                (StatusCode::INTERNAL_SERVER_ERROR, format!("RAG error: {}", e))
-            })?;
+            .map_err(|e| rag_error("RAG document add error", e))?;
     } else {
         return Err((StatusCode::SERVICE_UNAVAILABLE, "RAG engine not available".to_string()));
     }
@@ -309,70 +632,229 @@ async fn add_document_handler(
     })))
 }
 
+/// 複数ドキュメントの一括追加。埋め込みとupsertはまとめて行い、結果は要素ごとに返す
+async fn add_documents_batch_handler(
+    State(state): State<Arc<AppState>>,
+    Json(payload): Json<Vec<DocumentUpload>>,
+) -> Result<Json<BatchDocumentResponse>, (StatusCode, String)> {
+    let rag_engine = state.rag_engine.as_ref()
+        .ok_or((StatusCode::SERVICE_UNAVAILABLE, "RAG engine not available".to_string()))?;
+
+    let documents: Vec<rag::NewDocument> = payload.into_iter()
+        .map(|doc| rag::NewDocument {
+            id: doc.id.unwrap_or_else(|| Uuid::new_v4().to_string()),
+            metadata: serde_json::json!({
+                "title": doc.title,
+                "category": doc.category,
+            }),
+            text: doc.content,
+            collection: doc.collection,
+        })
+        .collect();
+    let ids: Vec<String> = documents.iter().map(|d| d.id.clone()).collect();
+
+    let outcomes = rag_engine.add_documents(documents).await;
+    let results: Vec<BatchDocumentResult> = ids.into_iter().zip(outcomes).enumerate()
+        .map(|(index, (id, outcome))| match outcome {
+            Ok(()) => BatchDocumentResult { index, id, status: "success".to_string(), error: None },
+            Err(e) => {
+                tracing::warn!("RAG batch document {} failed: {}", id, e);
+                BatchDocumentResult { index, id, status: "error".to_string(), error: Some(e) }
+            }
+        })
+        .collect();
+    let failed = results.iter().filter(|r| r.error.is_some()).count();
+
+    Ok(Json(BatchDocumentResponse {
+        succeeded: results.len() - failed,
+        failed,
+        results,
+    }))
+}
+
+/// 個別ドキュメントの削除（削除依頼・忘れられる権利への対応用）
+async fn delete_document_handler(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<String>,
+) -> Result<Json<serde_json::Value>, (StatusCode, String)> {
+    let rag_engine = state.rag_engine.as_ref()
+        .ok_or((StatusCode::SERVICE_UNAVAILABLE, "RAG engine not available".to_string()))?;
+
+    let deleted = rag_engine.vector_store
+        .delete_document(&id)
+        .await
+        .map_err(|e| {
+            tracing::error!("RAG document delete error: {}", e);
+            (StatusCode::INTERNAL_SERVER_ERROR, format!("RAG error: {}", e))
+        })?;
+    if !deleted {
+        return Err((StatusCode::NOT_FOUND, format!("Document not found: {}", id)));
+    }
+
+    Ok(Json(serde_json::json!({
+        "status": "deleted",
+        "id": id
+    })))
+}
+
 async fn query_logs_handler(
     State(state): State<Arc<AppState>>,
+    api_key: Option<Extension<ApiKey>>,
     Query(query): Query<LogQuery>,
 ) -> Result<Json<LogResponse>, (StatusCode, String)> {
-    let response = state.logger
+    let decrypt = query.decrypt;
+    if decrypt && !state.auth_config.can_decrypt_logs(api_key.as_deref()) {
+        return Err((StatusCode::FORBIDDEN, "This API key is not allowed to decrypt logs".to_string()));
+    }
+
+    let mut response = state.logger
         .query_logs(query)
         .await
         .map_err(|e| {
+            if e.downcast_ref::<InvalidDateError>().is_some() || e.downcast_ref::<InvalidSearchError>().is_some() {
+                return (StatusCode::BAD_REQUEST, e.to_string());
+            }
             tracing::error!("Query logs error: {}", e);
             (StatusCode::INTERNAL_SERVER_ERROR, format!("Query error: {}", e))
         })?;
+    if decrypt {
+        state.logger.decrypt_entries(&mut response.logs).map_err(|e| {
+            tracing::error!("Log decryption error: {}", e);
+            (StatusCode::INTERNAL_SERVER_ERROR, format!("Decryption error: {}", e))
+        })?;
+    }
 
     Ok(Json(response))
 }
 
+/// ダッシュボード用の集計（期間指定可）
+async fn stats_handler(
+    State(state): State<Arc<AppState>>,
+    Query(query): Query<StatsQuery>,
+) -> Result<Json<LogStats>, (StatusCode, String)> {
+    let stats = state.logger
+        .stats(query)
+        .await
+        .map_err(|e| {
+            if e.downcast_ref::<InvalidDateError>().is_some() {
+                return (StatusCode::BAD_REQUEST, e.to_string());
+            }
+            tracing::error!("Stats query error: {}", e);
+            (StatusCode::INTERNAL_SERVER_ERROR, format!("Query error: {}", e))
+        })?;
+
+    Ok(Json(stats))
+}
+
+async fn export_logs_handler(
+    State(state): State<Arc<AppState>>,
+    Query(query): Query<LogQuery>,
+) -> Result<Response, (StatusCode, String)> {
+    let csv = state.logger
+        .export_csv(query)
+        .map_err(|e| (StatusCode::BAD_REQUEST, e.to_string()))?;
+
+    Ok((
+        [
+            (header::CONTENT_TYPE, "text/csv; charset=utf-8"),
+            (header::CONTENT_DISPOSITION, "attachment; filename=\"prompt_logs.csv\""),
+        ],
+        Body::from_stream(csv),
+    ).into_response())
+}
+
+async fn delete_logs_handler(
+    State(state): State<Arc<AppState>>,
+    Query(query): Query<DeleteLogsQuery>,
+) -> Result<Json<serde_json::Value>, (StatusCode, String)> {
+    let before = logger::parse_date("before", &query.before)
+        .map_err(|e| (StatusCode::BAD_REQUEST, e.to_string()))?;
+
+    let deleted = state.logger
+        .delete_logs_before(before)
+        .await
+        .map_err(|e| {
+            tracing::error!("Delete logs error: {}", e);
+            (StatusCode::INTERNAL_SERVER_ERROR, format!("Delete error: {}", e))
+        })?;
+
+    Ok(Json(serde_json::json!({
+        "status": "deleted",
+        "deleted": deleted
+    })))
+}
+
 // ===== RAG Management Handlers =====
 
 async fn rag_upload_handler(
     State(state): State<Arc<AppState>>,
-    Query(query): Query<ListFilesQuery>,
+    Query(query): Query<UploadQuery>,
     mut multipart: Multipart,
 ) -> Result<Json<UploadResponse>, (StatusCode, String)> {
     let manager = state.index_manager.as_ref()
         .ok_or((StatusCode::SERVICE_UNAVAILABLE, "RAG engine not available".to_string()))?;
 
     let relative = query.path.as_deref().unwrap_or("");
-    let upload_dir = manager.safe_resolve(relative)
-        .map_err(|e| (StatusCode::BAD_REQUEST, e))?;
+    if !manager.safe_resolve(relative).map_err(|e| (StatusCode::BAD_REQUEST, e))?.is_dir() {
+        return Err((StatusCode::BAD_REQUEST, "Not a directory".to_string()));
+    }
 
     let mut uploaded_files = Vec::new();
+    let mut validation = Vec::new();
 
     while let Some(field) = multipart.next_field().await.map_err(|e| {
-        (StatusCode::BAD_REQUEST, format!("Multipart error: {}", e))
+        (e.status(), format!("Multipart error: {}", e))
     })? {
         let file_name = field.file_name()
             .ok_or((StatusCode::BAD_REQUEST, "Missing file name".to_string()))?
             .to_string();
+        files::validate_file_name(&file_name)
+            .map_err(|e| (StatusCode::BAD_REQUEST, e))?;
 
         // Validate extension
         let ext = std::path::Path::new(&file_name)
             .extension()
             .and_then(|e| e.to_str())
             .unwrap_or("");
-        if SupportedFormat::from_extension(ext).is_none() {
-            return Err((StatusCode::BAD_REQUEST, format!("Unsupported file type: .{}", ext)));
-        }
+        let format = SupportedFormat::from_extension(ext)
+            .ok_or_else(|| (StatusCode::BAD_REQUEST, format!("Unsupported file type: .{}", ext)))?;
 
-        let data = field.bytes().await.map_err(|e| {
-            (StatusCode::BAD_REQUEST, format!("Failed to read file data: {}", e))
-        })?;
+        let dest_relative = if relative.is_empty() {
+            file_name.clone()
+        } else {
+            format!("{}/{}", relative.trim_end_matches('/'), file_name)
+        };
+        let dest = manager.safe_resolve_new(&dest_relative)
+            .map_err(|e| (StatusCode::BAD_REQUEST, e))?;
 
-        let dest = upload_dir.join(&file_name);
+        let tmp = files::stream_to_temp(field, &dest, state.upload_limits.max_file_bytes).await
+            .map_err(|e| (e.status(), format!("{}: {}", file_name, e)))?;
 
         // Auto-version existing file before overwrite
         if dest.exists() && dest.is_file() {
-            if let Err(e) = versioning::save_version(&dest, "Auto-saved before upload overwrite") {
+            let comment = query.comment.as_deref().unwrap_or("Auto-saved before upload overwrite");
+            if let Err(e) = versioning::save_version(&dest, comment) {
                 tracing::warn!("Failed to save version before overwrite: {}", e);
             }
         }
 
-        std::fs::write(&dest, &data).map_err(|e| {
+        tokio::fs::rename(&tmp, &dest).await.map_err(|e| {
+            let _ = std::fs::remove_file(&tmp);
             (StatusCode::INTERNAL_SERVER_ERROR, format!("Failed to save file: {}", e))
         })?;
 
+        // Optional: extract now (without indexing) so broken files are reported immediately
+        if query.validate {
+            let result = tokio::task::spawn_blocking(move || extractor::validate_extraction(&dest, format))
+                .await
+                .unwrap_or_else(|e| Err(format!("Validation task failed: {}", e)));
+            validation.push(FileValidation {
+                name: file_name.clone(),
+                extractable: result.is_ok(),
+                error: result.err(),
+            });
+        }
+
         uploaded_files.push(file_name);
     }
 
@@ -381,6 +863,7 @@ async fn rag_upload_handler(
     Ok(Json(UploadResponse {
         uploaded_files,
         total_files_in_dir: total_files,
+        validation: if query.validate { Some(validation) } else { None },
     }))
 }
 
@@ -398,9 +881,20 @@ async fn rag_list_files_handler(
     Ok(Json(entries))
 }
 
+async fn rag_search_files_handler(
+    State(state): State<Arc<AppState>>,
+    Query(query): Query<FileSearchQuery>,
+) -> Result<Json<Vec<FileSearchHit>>, (StatusCode, String)> {
+    let manager = state.index_manager.as_ref()
+        .ok_or((StatusCode::SERVICE_UNAVAILABLE, "RAG engine not available".to_string()))?;
+
+    Ok(Json(manager.search_files(&query.q)))
+}
+
 async fn rag_delete_file_handler(
     State(state): State<Arc<AppState>>,
     Path(filename): Path<String>,
+    Query(query): Query<DeleteFileQuery>,
 ) -> Result<Json<serde_json::Value>, (StatusCode, String)> {
     let manager = state.index_manager.as_ref()
         .ok_or((StatusCode::SERVICE_UNAVAILABLE, "RAG engine not available".to_string()))?;
@@ -411,11 +905,20 @@ async fn rag_delete_file_handler(
         return Err((StatusCode::NOT_FOUND, format!("Not found: {}", filename)));
     }
 
+    // Listed before deleting; their chunks are dropped once the files are gone
+    let indexed: Vec<PathBuf> = if target.is_dir() {
+        walk_directory(&target).into_iter().map(|(path, _)| path).collect()
+    } else {
+        vec![target.clone()]
+    };
+
+    // Directories are always removed together with the histories inside them
+    let purged = query.purge || target.is_dir();
     if target.is_dir() {
         std::fs::remove_dir_all(&target).map_err(|e| {
             (StatusCode::INTERNAL_SERVER_ERROR, format!("Failed to delete directory: {}", e))
         })?;
-    } else {
+    } else if query.purge {
         // Clean up versions before deleting the file
         if let Err(e) = versioning::delete_versions(&target) {
             tracing::warn!("Failed to clean up versions: {}", e);
@@ -423,14 +926,68 @@ async fn rag_delete_file_handler(
         std::fs::remove_file(&target).map_err(|e| {
             (StatusCode::INTERNAL_SERVER_ERROR, format!("Failed to delete file: {}", e))
         })?;
+    } else {
+        files::soft_delete(&target).map_err(|e| {
+            (StatusCode::INTERNAL_SERVER_ERROR, format!("Failed to delete file: {}", e))
+        })?;
+    }
+
+    // 検索に削除済みファイルが出ないようにする。失敗しても次回のインデックスで掃除される
+    for path in &indexed {
+        if let Err(e) = manager.remove_file_chunks(path).await {
+            tracing::warn!("Failed to remove chunks of {}: {}", path.display(), e);
+        }
     }
 
     Ok(Json(serde_json::json!({
-        "status": "deleted",
+        "status": if purged { "purged" } else { "deleted" },
         "path": filename
     })))
 }
 
+async fn rag_restore_handler(
+    State(state): State<Arc<AppState>>,
+    Json(req): Json<RestoreRequest>,
+) -> Result<Json<serde_json::Value>, (StatusCode, String)> {
+    let manager = state.index_manager.as_ref()
+        .ok_or((StatusCode::SERVICE_UNAVAILABLE, "RAG engine not available".to_string()))?;
+
+    let target = manager.safe_resolve_new(&req.path)
+        .map_err(|e| (StatusCode::BAD_REQUEST, e))?;
+    if target.exists() {
+        return Err((StatusCode::CONFLICT, format!("Already exists: {}", req.path)));
+    }
+
+    let version = files::restore(&target).map_err(|e| {
+        if e.downcast_ref::<files::NothingToRestore>().is_some() {
+            (StatusCode::NOT_FOUND, format!("Nothing to restore: {}", req.path))
+        } else {
+            (StatusCode::INTERNAL_SERVER_ERROR, format!("Failed to restore file: {}", e))
+        }
+    })?;
+
+    // 削除時に外したチャンクを戻す。ファイル自体は復元済みなので失敗はログのみ
+    let ext = target.extension().and_then(|e| e.to_str()).unwrap_or("");
+    let chunks = if SupportedFormat::from_extension(ext).is_some() {
+        match manager.run_index_file(&target).await {
+            Ok(chunks) => Some(chunks),
+            Err(e) => {
+                tracing::warn!("Reindexing restored {} failed: {}", req.path, e);
+                None
+            }
+        }
+    } else {
+        None
+    };
+
+    Ok(Json(serde_json::json!({
+        "status": "restored",
+        "path": req.path,
+        "restored_version": version,
+        "chunks": chunks
+    })))
+}
+
 async fn rag_mkdir_handler(
     State(state): State<Arc<AppState>>,
     Json(req): Json<CreateDirRequest>,
@@ -479,6 +1036,144 @@ async fn rag_create_file_handler(
     })))
 }
 
+async fn rag_move_handler(
+    State(state): State<Arc<AppState>>,
+    Json(req): Json<MoveRequest>,
+) -> Result<Json<serde_json::Value>, (StatusCode, String)> {
+    let manager = state.index_manager.as_ref()
+        .ok_or((StatusCode::SERVICE_UNAVAILABLE, "RAG engine not available".to_string()))?;
+
+    let source = manager.safe_resolve(&req.from)
+        .map_err(|_| (StatusCode::NOT_FOUND, format!("Not found: {}", req.from)))?;
+    if manager.upload_dir().canonicalize().is_ok_and(|base| base == source) {
+        return Err((StatusCode::BAD_REQUEST, "Cannot move the upload directory".to_string()));
+    }
+    let target = manager.safe_resolve_new(&req.to)
+        .map_err(|e| (StatusCode::BAD_REQUEST, e))?;
+
+    if target.exists() {
+        return Err((StatusCode::CONFLICT, format!("Already exists: {}", req.to)));
+    }
+
+    files::move_path(&source, &target)
+        .map_err(|e| (StatusCode::BAD_REQUEST, format!("Failed to move: {}", e)))?;
+
+    Ok(Json(serde_json::json!({
+        "status": "moved",
+        "from": req.from,
+        "to": req.to
+    })))
+}
+
+async fn rag_copy_handler(
+    State(state): State<Arc<AppState>>,
+    Json(req): Json<CopyRequest>,
+) -> Result<Json<serde_json::Value>, (StatusCode, String)> {
+    let manager = state.index_manager.as_ref()
+        .ok_or((StatusCode::SERVICE_UNAVAILABLE, "RAG engine not available".to_string()))?;
+
+    let source = resolve_existing_file(manager, &req.from)?;
+    let target = manager.safe_resolve_new(&req.to)
+        .map_err(|e| (StatusCode::BAD_REQUEST, e))?;
+
+    if target.exists() {
+        return Err((StatusCode::CONFLICT, format!("Already exists: {}", req.to)));
+    }
+
+    files::copy_file(&source, &target).map_err(|e| {
+        (StatusCode::INTERNAL_SERVER_ERROR, format!("Failed to copy file: {}", e))
+    })?;
+
+    Ok(Json(serde_json::json!({
+        "status": "copied",
+        "from": req.from,
+        "to": req.to
+    })))
+}
+
+/// Existing regular file under upload_dir; anything else is 404.
+fn resolve_existing_file(manager: &IndexManager, path: &str) -> Result<PathBuf, (StatusCode, String)> {
+    manager.safe_resolve(path)
+        .ok()
+        .filter(|p| p.is_file())
+        .ok_or((StatusCode::NOT_FOUND, format!("Not found: {}", path)))
+}
+
+async fn rag_file_download_handler(
+    State(state): State<Arc<AppState>>,
+    Path(path): Path<String>,
+) -> Result<Response, (StatusCode, String)> {
+    let manager = state.index_manager.as_ref()
+        .ok_or((StatusCode::SERVICE_UNAVAILABLE, "RAG engine not available".to_string()))?;
+
+    let file_path = resolve_existing_file(manager, &path)?;
+    let (content_type, body) = files::download(&file_path).await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, format!("Failed to read file: {}", e)))?;
+
+    Ok((
+        [
+            (header::CONTENT_TYPE, content_type.to_string()),
+            (header::CONTENT_DISPOSITION, files::content_disposition(&file_path)),
+        ],
+        body,
+    ).into_response())
+}
+
+async fn rag_file_content_handler(
+    State(state): State<Arc<AppState>>,
+    Path(path): Path<String>,
+) -> Result<Response, (StatusCode, String)> {
+    let manager = state.index_manager.as_ref()
+        .ok_or((StatusCode::SERVICE_UNAVAILABLE, "RAG engine not available".to_string()))?;
+
+    let file_path = resolve_existing_file(manager, &path)?;
+    let text = files::read_text(&file_path)
+        .ok_or((StatusCode::UNSUPPORTED_MEDIA_TYPE, format!("Not a text file: {}", path)))?
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, format!("Failed to read file: {}", e)))?;
+
+    Ok(([(header::CONTENT_TYPE, "text/plain; charset=utf-8")], text).into_response())
+}
+
+async fn rag_update_file_handler(
+    State(state): State<Arc<AppState>>,
+    Path(path): Path<String>,
+    Json(req): Json<UpdateFileRequest>,
+) -> Result<Json<serde_json::Value>, (StatusCode, String)> {
+    let manager = state.index_manager.as_ref()
+        .ok_or((StatusCode::SERVICE_UNAVAILABLE, "RAG engine not available".to_string()))?;
+
+    let file_path = resolve_existing_file(manager, &path)?;
+    let comment = req.comment.as_deref().unwrap_or("Auto-saved before edit");
+    let version = files::update_file(&file_path, &req.content, comment).map_err(|e| {
+        (StatusCode::INTERNAL_SERVER_ERROR, format!("Failed to update file: {}", e))
+    })?;
+
+    Ok(Json(serde_json::json!({
+        "status": "updated",
+        "path": path,
+        "saved_version": version
+    })))
+}
+
+/// What the vector store currently holds for one file, for debugging retrieval.
+async fn rag_file_chunks_handler(
+    State(state): State<Arc<AppState>>,
+    Path(path): Path<String>,
+) -> Result<Json<FileChunksResponse>, (StatusCode, String)> {
+    let manager = state.index_manager.as_ref()
+        .ok_or((StatusCode::SERVICE_UNAVAILABLE, "RAG engine not available".to_string()))?;
+
+    let file_path = resolve_existing_file(manager, &path)?;
+    let chunks = manager.file_chunks(&file_path).await
+        .map_err(|e| rag_error("RAG chunk listing error", e))?;
+
+    Ok(Json(FileChunksResponse {
+        file_path: path,
+        file_id: manager.file_id_of(&file_path),
+        chunks,
+    }))
+}
+
 async fn rag_file_versions_handler(
     State(state): State<Arc<AppState>>,
     Path(path): Path<String>,
@@ -499,6 +1194,58 @@ async fn rag_file_versions_handler(
     Ok(Json(history))
 }
 
+/// Raw content of one stored version as an attachment (never rendered
+/// inline, so an uploaded HTML version can't run in the API origin); the
+/// active file is left untouched.
+async fn rag_file_version_content_handler(
+    State(state): State<Arc<AppState>>,
+    Path((path, version)): Path<(String, u32)>,
+) -> Result<Response, (StatusCode, String)> {
+    let manager = state.index_manager.as_ref()
+        .ok_or((StatusCode::SERVICE_UNAVAILABLE, "RAG engine not available".to_string()))?;
+
+    let file_path = resolve_existing_file(manager, &path)?;
+    let version_file = versioning::version_path(&file_path, version).map_err(|e| {
+        if e.downcast_ref::<versioning::VersionNotFound>().is_some() {
+            (StatusCode::NOT_FOUND, e.to_string())
+        } else {
+            (StatusCode::INTERNAL_SERVER_ERROR, format!("Failed to read version: {}", e))
+        }
+    })?;
+    let (content_type, body) = files::download(&version_file).await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, format!("Failed to read version: {}", e)))?;
+
+    Ok((
+        [
+            (header::CONTENT_TYPE, content_type.to_string()),
+            (header::CONTENT_DISPOSITION, files::content_disposition(&version_file)),
+        ],
+        body,
+    ).into_response())
+}
+
+async fn rag_file_diff_handler(
+    State(state): State<Arc<AppState>>,
+    Path(path): Path<String>,
+    Query(query): Query<VersionDiffQuery>,
+) -> Result<Response, (StatusCode, String)> {
+    let manager = state.index_manager.as_ref()
+        .ok_or((StatusCode::SERVICE_UNAVAILABLE, "RAG engine not available".to_string()))?;
+
+    let file_path = resolve_existing_file(manager, &path)?;
+    let diff = files::diff_versions(&file_path, query.from, query.to).map_err(|e| {
+        if e.downcast_ref::<versioning::VersionNotFound>().is_some() {
+            (StatusCode::NOT_FOUND, e.to_string())
+        } else if e.downcast_ref::<files::NotTextFile>().is_some() {
+            (StatusCode::UNSUPPORTED_MEDIA_TYPE, e.to_string())
+        } else {
+            (StatusCode::INTERNAL_SERVER_ERROR, format!("Failed to diff versions: {}", e))
+        }
+    })?;
+
+    Ok(([(header::CONTENT_TYPE, "text/x-diff; charset=utf-8")], diff).into_response())
+}
+
 async fn rag_file_rollback_handler(
     State(state): State<Arc<AppState>>,
     Path(path): Path<String>,
@@ -518,16 +1265,14 @@ async fn rag_file_rollback_handler(
         .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, format!("Rollback failed: {}", e)))?;
 
     let mut reindex_triggered = false;
-    if req.reindex {
-        if !manager.is_indexing().await {
-            let manager_clone = manager.clone();
-            tokio::spawn(async move {
-                if let Err(e) = manager_clone.run_index().await {
-                    tracing::error!("Re-index after rollback failed: {}", e);
-                }
-            });
-            reindex_triggered = true;
-        }
+    if req.reindex && !manager.is_indexing().await {
+        let manager_clone = manager.clone();
+        tokio::spawn(async move {
+            if let Err(e) = manager_clone.run_index().await {
+                tracing::error!("Re-index after rollback failed: {}", e);
+            }
+        });
+        reindex_triggered = true;
     }
 
     Ok(Json(RollbackResponse {
@@ -559,6 +1304,118 @@ async fn rag_trigger_index_handler(
     }))))
 }
 
+/// Reindex one file (e.g. right after editing it) without a full walk.
+async fn rag_index_file_handler(
+    State(state): State<Arc<AppState>>,
+    Json(req): Json<IndexFileRequest>,
+) -> Result<Json<serde_json::Value>, (StatusCode, String)> {
+    let manager = state.index_manager.as_ref()
+        .ok_or((StatusCode::SERVICE_UNAVAILABLE, "RAG engine not available".to_string()))?;
+
+    let file_path = resolve_existing_file(manager, &req.path)?;
+    let ext = file_path.extension().and_then(|e| e.to_str()).unwrap_or("");
+    if SupportedFormat::from_extension(ext).is_none() {
+        return Err((StatusCode::BAD_REQUEST, format!("Unsupported file type: .{}", ext)));
+    }
+
+    let chunks = manager.run_index_file(&file_path).await.map_err(|e| {
+        // Checked inside the indexer's lock, so a run starting meanwhile can't slip past
+        if e.downcast_ref::<IndexingInProgress>().is_some() {
+            return (StatusCode::CONFLICT, e.to_string());
+        }
+        tracing::error!("Reindexing {} failed: {}", req.path, e);
+        (StatusCode::INTERNAL_SERVER_ERROR, e.to_string())
+    })?;
+
+    Ok(Json(serde_json::json!({
+        "status": "indexed",
+        "path": req.path,
+        "chunks": chunks
+    })))
+}
+
+/// インデックス進捗のSSE。遅いクライアントは古いイベントが破棄され、
+/// `lagged` イベントを受け取ったら `/api/v1/rag/status` で再同期する
+async fn rag_index_events_handler(
+    State(state): State<Arc<AppState>>,
+) -> Result<Sse<impl Stream<Item = Result<Event, Infallible>>>, (StatusCode, String)> {
+    let manager = state.index_manager.as_ref()
+        .ok_or((StatusCode::SERVICE_UNAVAILABLE, "RAG engine not available".to_string()))?;
+
+    let stream = manager.subscribe_events().map(|item| {
+        let event = match item {
+            IndexStreamItem::Event(event) => Event::default()
+                .event("progress")
+                .json_data(event)
+                .unwrap_or_default(),
+            IndexStreamItem::Lagged { missed } => Event::default()
+                .event("lagged")
+                .data(serde_json::json!({ "missed": missed, "resync": "/api/v1/rag/status" }).to_string()),
+        };
+        Ok(event)
+    });
+
+    Ok(Sse::new(stream).keep_alive(KeepAlive::default()))
+}
+
+async fn rag_retry_failed_handler(
+    State(state): State<Arc<AppState>>,
+) -> Result<(StatusCode, Json<serde_json::Value>), (StatusCode, String)> {
+    let manager = state.index_manager.as_ref()
+        .ok_or((StatusCode::SERVICE_UNAVAILABLE, "RAG engine not available".to_string()))?;
+
+    if manager.is_indexing().await {
+        return Err((StatusCode::CONFLICT, "Indexing already in progress".to_string()));
+    }
+
+    let retry_count = manager.failed_paths().await.len();
+
+    let manager_clone = manager.clone();
+    tokio::spawn(async move {
+        if let Err(e) = manager_clone.run_retry_failed().await {
+            tracing::error!("Retry of failed files failed: {}", e);
+        }
+    });
+
+    Ok((StatusCode::ACCEPTED, Json(serde_json::json!({
+        "status": "retry_started",
+        "files": retry_count
+    }))))
+}
+
+/// チャットを介さずに検索結果（スコア・メタデータ付き）を確認するためのデバッグ用
+async fn rag_search_handler(
+    State(state): State<Arc<AppState>>,
+    Json(req): Json<RagSearchRequest>,
+) -> Result<Json<RagSearchResponse>, (StatusCode, String)> {
+    let rag_engine = state.rag_engine.as_ref()
+        .ok_or((StatusCode::SERVICE_UNAVAILABLE, "RAG engine not available".to_string()))?;
+
+    if req.query.trim().is_empty() {
+        return Err((StatusCode::BAD_REQUEST, "query must not be empty".to_string()));
+    }
+
+    let hits = rag_engine
+        .search(&req.query, state.rag_top_k.resolve(req.top_k), SearchOptions {
+            category: req.category.as_deref(),
+            collection: req.collection.as_deref(),
+            hybrid: req.hybrid,
+        })
+        .await
+        .map_err(|e| rag_error("RAG search error", e))?;
+
+    Ok(Json(RagSearchResponse { query: req.query, hits }))
+}
+
+async fn rag_formats_handler() -> Json<Vec<SupportedFormatInfo>> {
+    Json(SupportedFormat::supported()
+        .map(|(format, extensions)| SupportedFormatInfo {
+            format: format.name().to_string(),
+            extensions: extensions.iter().map(|e| e.to_string()).collect(),
+        })
+        .collect())
+}
+
 async fn rag_status_handler(
     State(state): State<Arc<AppState>>,
 ) -> Result<Json<IndexStatusResponse>, (StatusCode, String)> {
@@ -573,9 +1430,17 @@ async fn rag_status_handler(
         total_files: status.total_files,
         total_chunks: status.total_chunks,
         failed_files: status.failed_files,
+        skipped_files: status.skipped_files,
+        reindexed_files: status.reindexed_files,
+        truncated_files: status.truncated_files,
         auto_index_interval_minutes: status.auto_index_interval_minutes,
+        chunk_size: status.chunk_size,
+        chunk_overlap: status.chunk_overlap,
         upload_dir: manager.upload_dir().to_string_lossy().to_string(),
         last_error: status.last_error,
+        current_file: status.current_file,
+        files_processed: status.files_processed,
+        files_total: status.files_total,
     }))
 }
 
@@ -586,24 +1451,65 @@ async fn rag_config_handler(
     let manager = state.index_manager.as_ref()
         .ok_or((StatusCode::SERVICE_UNAVAILABLE, "RAG engine not available".to_string()))?;
 
-    manager.set_interval(config.auto_index_interval_minutes).await;
+    if config.chunk_size.is_some() || config.chunk_overlap.is_some() {
+        manager.set_chunking(config.chunk_size, config.chunk_overlap).await
+            .map_err(|e| (StatusCode::BAD_REQUEST, e.to_string()))?;
+    }
+    if let Some(minutes) = config.auto_index_interval_minutes {
+        manager.set_interval(minutes).await;
+    }
 
+    let status = manager.get_status().await;
     Ok(Json(serde_json::json!({
         "status": "updated",
-        "auto_index_interval_minutes": config.auto_index_interval_minutes
+        "auto_index_interval_minutes": status.auto_index_interval_minutes,
+        "chunk_size": status.chunk_size,
+        "chunk_overlap": status.chunk_overlap,
     })))
 }
 
 // ===== Health Check =====
 
-async fn health_check(State(state): State<Arc<AppState>>) -> Json<serde_json::Value> {
-    let litellm_healthy = state.litellm_proxy.health_check().await.unwrap_or(false);
+async fn metrics_handler(State(state): State<Arc<AppState>>) -> impl IntoResponse {
+    (
+        [(header::CONTENT_TYPE, "text/plain; version=0.0.4; charset=utf-8")],
+        state.metrics.render(),
+    )
+}
+
+async fn health_check(State(state): State<Arc<AppState>>) -> (StatusCode, Json<serde_json::Value>) {
+    let qdrant_probe = async {
+        match &state.rag_engine {
+            Some(engine) => engine.vector_store.health().await,
+            None => false,
+        }
+    };
+    let (litellm_healthy, postgres_healthy, qdrant_healthy) = tokio::join!(
+        async { state.litellm_proxy.health_check().await.unwrap_or(false) },
+        state.logger.health(),
+        qdrant_probe,
+    );
+
+    // LiteLLMとPostgresは必須。QdrantはRAGなしで継続できるためdegraded扱い
+    let (code, status) = if !litellm_healthy || !postgres_healthy {
+        (StatusCode::SERVICE_UNAVAILABLE, "unhealthy")
+    } else if !qdrant_healthy {
+        (StatusCode::OK, "degraded")
+    } else {
+        (StatusCode::OK, "healthy")
+    };
 
-    Json(serde_json::json!({
-        "status": "healthy",
+    (code, Json(serde_json::json!({
+        "status": status,
         "timestamp": Utc::now().to_rfc3339(),
         "services": {
-            "litellm": litellm_healthy
-        }
-    }))
+            "litellm": litellm_healthy,
+            "postgres": postgres_healthy,
+            "qdrant": qdrant_healthy
+        },
+        "pii_categories": state.pii_config.enabled_categories()
+            .iter()
+            .map(|c| c.name())
+            .collect::<Vec<_>>()
+    })))
 }
@@ -13,12 +13,160 @@ pub struct ChatRequest {
     pub max_tokens: Option<u32>,
     #[serde(default)]
     pub stream: Option<bool>,
+    /// OpenAI互換のサンプリング設定。指定されたときだけLiteLLMに転送する
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub top_p: Option<f32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub presence_penalty: Option<f32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub frequency_penalty: Option<f32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub stop: Option<StopSequences>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub seed: Option<i64>,
+    /// 生成する候補数。各候補ともPII復元・サニタイズされる
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub n: Option<u32>,
+    /// Function calling. Forwarded verbatim.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tools: Option<serde_json::Value>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tool_choice: Option<serde_json::Value>,
+    /// LLMに送るユーザーメッセージを差し替える（RAG検索は元のメッセージで行う）。
+    /// LiteLLMには転送しない。
+    #[serde(default, skip_serializing)]
+    pub llm_message_override: Option<String>,
+    /// RAG検索を `metadata.category` が一致するドキュメントに限定する。
+    /// LiteLLMには転送しない。
+    #[serde(default, skip_serializing)]
+    pub rag_category: Option<String>,
+    /// RAG検索先のコレクション（未指定ならデフォルト）。LiteLLMには転送しない。
+    #[serde(default, skip_serializing)]
+    pub collection: Option<String>,
+    /// RAG検索でキーワード完全一致も併用する。LiteLLMには転送しない。
+    #[serde(default, skip_serializing)]
+    pub rag_hybrid: bool,
+    /// コンテキストに入れるチャンク数（未指定ならサーバー既定値、上限で切り詰め）。
+    /// LiteLLMには転送しない。
+    #[serde(default, skip_serializing)]
+    pub rag_top_k: Option<u64>,
+    /// `false` でRAG検索をしない（創作など）。未指定なら検索する。LiteLLMには転送しない。
+    #[serde(default, skip_serializing)]
+    pub use_rag: Option<bool>,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+/// `stop` accepts a single string or a list, as in the OpenAI API.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum StopSequences {
+    One(String),
+    Many(Vec<String>),
+}
+
+impl ChatRequest {
+    /// RAG検索をするか（`use_rag` 未指定なら する）
+    pub fn use_rag(&self) -> bool {
+        self.use_rag.unwrap_or(true)
+    }
+
+    /// 最後のユーザーメッセージ。RAG検索には常にこの生テキストを使う
+    pub fn last_user_content(&self) -> Option<&str> {
+        self.messages.iter()
+            .rfind(|m| m.role == "user")
+            .map(|m| m.content.as_str())
+    }
+
+    /// LLMに転送するユーザーメッセージ。overrideがあればそちらを優先
+    pub fn llm_user_content(&self) -> Option<&str> {
+        self.llm_message_override.as_deref().or_else(|| self.last_user_content())
+    }
+
+    pub fn set_last_user_content(&mut self, content: String) {
+        if let Some(last) = self.messages.iter_mut().rfind(|m| m.role == "user") {
+            last.content = content;
+        }
+    }
+
+    /// RAGコンテキストをsystemメッセージとして渡す。ユーザーの発話には混ぜない。
+    /// 先頭にsystemメッセージがあれば末尾に追記する
+    pub fn add_system_context(&mut self, context: &str) {
+        let context = context.trim_end();
+        if context.is_empty() {
+            return;
+        }
+        match self.messages.first_mut() {
+            Some(first) if first.role == "system" => {
+                first.content = format!("{}\n\n{}", first.content.trim_end(), context);
+            }
+            _ => self.messages.insert(0, Message {
+                role: "system".to_string(),
+                content: context.to_string(),
+                ..Default::default()
+            }),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct Message {
     pub role: String,
+    /// tool_calls だけを返すassistantメッセージでは null になるので空文字で受ける
+    #[serde(default, deserialize_with = "null_as_empty")]
     pub content: String,
+    /// OpenAI形式のツール呼び出し。構造化データなのでPIIマスク・復元の対象外で、そのまま転送する
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tool_calls: Option<serde_json::Value>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tool_call_id: Option<String>,
+}
+
+fn null_as_empty<'de, D: serde::Deserializer<'de>>(deserializer: D) -> Result<String, D::Error> {
+    Ok(Option::<String>::deserialize(deserializer)?.unwrap_or_default())
+}
+
+/// OpenAI互換 `/embeddings` のリクエスト。`model` 省略時はRAGと同じモデルで埋め込む
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EmbeddingsRequest {
+    pub input: EmbeddingInput,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub model: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum EmbeddingInput {
+    One(String),
+    Many(Vec<String>),
+}
+
+impl EmbeddingInput {
+    pub fn into_vec(self) -> Vec<String> {
+        match self {
+            Self::One(text) => vec![text],
+            Self::Many(texts) => texts,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EmbeddingsResponse {
+    pub object: String,
+    pub data: Vec<EmbeddingObject>,
+    pub model: String,
+    pub usage: EmbeddingsUsage,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EmbeddingObject {
+    pub object: String,
+    pub index: usize,
+    pub embedding: Vec<f32>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EmbeddingsUsage {
+    pub prompt_tokens: u32,
+    pub total_tokens: u32,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -28,6 +176,81 @@ pub struct ChatResponse {
     pub created: i64,
     pub model: String,
     pub choices: Vec<Choice>,
+    /// Number of PII entities masked before the request was forwarded.
+    /// Proxy-specific, so omitted unless set to stay OpenAI-compatible.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pii_masked_count: Option<usize>,
+    /// Where the masked entities were in the forwarded user message, for highlighting.
+    /// Real values are never included.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pii_entities: Option<Vec<PIIEntitySpan>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub usage: Option<Usage>,
+    /// `usage` priced with `MODEL_PRICING`; proxy-specific like `pii_masked_count`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub estimated_cost_usd: Option<f64>,
+    /// Dangerous spans found in the reply when the sanitizer runs in
+    /// report mode (the text itself is left untouched).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sanitizer_findings: Option<Vec<SanitizerFinding>>,
+}
+
+/// A retrieved chunk with its similarity score and stored metadata
+/// (`file_path`, `chunk_index`, `category`, ...).
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SearchHit {
+    pub text: String,
+    pub score: f32,
+    pub metadata: serde_json::Value,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct RagSearchRequest {
+    pub query: String,
+    #[serde(default)]
+    pub top_k: Option<u64>,
+    #[serde(default)]
+    pub category: Option<String>,
+    #[serde(default)]
+    pub collection: Option<String>,
+    #[serde(default)]
+    pub hybrid: bool,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct RagSearchResponse {
+    pub query: String,
+    pub hits: Vec<SearchHit>,
+}
+
+/// A dangerous pattern located in LLM output. Offsets are byte positions
+/// into the message content of choice `choice_index`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SanitizerFinding {
+    pub category: String,
+    pub matched_text: String,
+    pub byte_start: usize,
+    pub byte_end: usize,
+    #[serde(default)]
+    pub choice_index: u32,
+}
+
+/// A masked PII entity in the user message forwarded to the LLM. Offsets
+/// are byte positions into that text: `llm_message_override` when the
+/// request sets it, otherwise the last user message as the client sent it.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PIIEntitySpan {
+    pub category: String,
+    pub byte_start: usize,
+    pub byte_end: usize,
+}
+
+/// Token counts reported by the upstream in the OpenAI `usage` block.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Usage {
+    pub prompt_tokens: u32,
+    pub completion_tokens: u32,
+    pub total_tokens: u32,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -51,6 +274,28 @@ pub struct DocumentUpload {
     pub title: String,
     pub content: String,
     pub category: Option<String>,
+    /// 追加先のコレクション（未指定ならデフォルト）
+    #[serde(default)]
+    pub collection: Option<String>,
+}
+
+/// Outcome of one entry in `POST /api/v1/documents/batch`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BatchDocumentResult {
+    /// Position in the request array
+    pub index: usize,
+    pub id: String,
+    /// "success" or "error"
+    pub status: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BatchDocumentResponse {
+    pub succeeded: usize,
+    pub failed: usize,
+    pub results: Vec<BatchDocumentResult>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -62,25 +307,108 @@ pub struct DocumentResponse {
     pub created_at: DateTime<Utc>,
 }
 
+/// How `LogQuery::search_term` is matched.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogSearchMode {
+    /// Case-insensitive substring (`ILIKE '%term%'`)
+    #[default]
+    Ilike,
+    /// Postgres full-text search; the term is `to_tsquery` syntax
+    Fts,
+}
+
+/// Order of `GET /api/v1/logs` results (newest first within ties).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LogSort {
+    #[default]
+    Timestamp,
+    /// Most masked PII entities first
+    PiiCount,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct LogQuery {
     pub start_date: Option<String>,
     pub end_date: Option<String>,
     pub search_term: Option<String>,
+    #[serde(default)]
+    pub mode: LogSearchMode,
+    pub model: Option<String>,
+    /// Only rows that masked at least this many PII entities
+    pub min_pii_count: Option<i32>,
+    #[serde(default)]
+    pub sort: LogSort,
+    /// Restore raw input/output of redacted rows (needs a `LOG_DECRYPT_KEYS` key)
+    #[serde(default)]
+    pub decrypt: bool,
     pub limit: Option<i64>,
     pub offset: Option<i64>,
 }
 
+#[derive(Debug, Clone, Deserialize)]
+pub struct DeleteLogsQuery {
+    pub before: String,
+}
+
+/// Date range for `GET /api/v1/stats` (both ends optional, inclusive).
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct StatsQuery {
+    pub start_date: Option<String>,
+    pub end_date: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ModelRequestCount {
+    /// `None` for rows logged before the model column existed
+    pub model: Option<String>,
+    pub requests: i64,
+}
+
+/// Aggregates over `prompt_logs` for the dashboard.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct LogStats {
+    pub total_requests: i64,
+    /// Most-used model first
+    pub requests_per_model: Vec<ModelRequestCount>,
+    pub pii_entities_masked: i64,
+    pub dangerous_patterns_removed: i64,
+    /// `None` when no row in the range has a latency
+    pub avg_latency_ms: Option<f64>,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize, sqlx::FromRow)]
 pub struct LogEntry {
     pub id: Uuid,
     pub timestamp: DateTime<Utc>,
-    pub original_input: String,
+    /// Model that answered (the fallback, if one was used);
+    /// `None` for rows logged before the column existed.
+    pub model: Option<String>,
+    /// `None` when logged with `LOG_STORE_RAW=false` (restored by `?decrypt=true`)
+    pub original_input: Option<String>,
     pub masked_input: String,
     pub rag_context: Option<String>,
     pub llm_output: String,
-    pub final_output: String,
+    /// `None` when logged with `LOG_STORE_RAW=false` (restored by `?decrypt=true`)
+    pub final_output: Option<String>,
+    /// Empty object when the mappings were stored encrypted
     pub pii_mappings: serde_json::Value,
+    /// Upstream LLM round-trip time.
+    pub latency_ms: Option<i64>,
+    pub prompt_tokens: Option<i32>,
+    pub completion_tokens: Option<i32>,
+    pub estimated_cost_usd: Option<f64>,
+    /// Patterns the output sanitizer stripped (always 0 in report mode).
+    pub dangerous_patterns_removed: Option<i32>,
+    /// Read from the `pii_count` column; `log_request` derives it from the mappings.
+    #[serde(default)]
+    #[sqlx(default)]
+    pub pii_count: Option<i32>,
+    /// Set by `Logger` on redacted rows; leave `None` when logging.
+    #[serde(skip)]
+    #[sqlx(default)]
+    pub pii_mappings_encrypted: Option<Vec<u8>>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -130,27 +458,91 @@ pub struct FileInfo {
     pub modified_at: DateTime<Utc>,
 }
 
+/// A file that failed to index, with the extraction/embedding error.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FileError {
+    pub name: String,
+    pub reason: String,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct IndexStatusResponse {
     pub is_indexing: bool,
     pub last_indexed_at: Option<DateTime<Utc>>,
     pub total_files: usize,
     pub total_chunks: usize,
-    pub failed_files: Vec<String>,
+    pub failed_files: Vec<FileError>,
+    pub skipped_files: usize,
+    pub reindexed_files: usize,
+    pub truncated_files: Vec<String>,
     pub auto_index_interval_minutes: u64,
+    pub chunk_size: usize,
+    pub chunk_overlap: usize,
     pub upload_dir: String,
     pub last_error: Option<String>,
+    pub current_file: Option<String>,
+    pub files_processed: usize,
+    pub files_total: usize,
+}
+
+/// Extensions accepted for one `SupportedFormat`, for upload pickers.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SupportedFormatInfo {
+    pub format: String,
+    pub extensions: Vec<String>,
+}
+
+/// One indexed chunk of a file as stored in the vector store.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FileChunk {
+    pub id: String,
+    pub chunk_index: usize,
+    pub text: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FileChunksResponse {
+    pub file_path: String,
+    pub file_id: String,
+    pub chunks: Vec<FileChunk>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct IndexConfigUpdate {
-    pub auto_index_interval_minutes: u64,
+    /// Fields left out keep their current value.
+    #[serde(default)]
+    pub auto_index_interval_minutes: Option<u64>,
+    #[serde(default)]
+    pub chunk_size: Option<usize>,
+    #[serde(default)]
+    pub chunk_overlap: Option<usize>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct UploadResponse {
     pub uploaded_files: Vec<String>,
     pub total_files_in_dir: usize,
+    /// Present only when the upload was made with `?validate=true`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub validation: Option<Vec<FileValidation>>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FileValidation {
+    pub name: String,
+    pub extractable: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct UploadQuery {
+    pub path: Option<String>,
+    #[serde(default)]
+    pub validate: bool,
+    /// 既存ファイルを上書きするとき、保存される旧バージョンのコメント
+    #[serde(default)]
+    pub comment: Option<String>,
 }
 
 // Directory browsing types
@@ -169,6 +561,37 @@ pub struct DirEntry {
     pub version_count: Option<u32>,
 }
 
+/// Both paths are relative to upload_dir.
+#[derive(Debug, Clone, Deserialize)]
+pub struct MoveRequest {
+    pub from: String,
+    pub to: String,
+}
+
+/// Both paths are relative to upload_dir.
+#[derive(Debug, Clone, Deserialize)]
+pub struct CopyRequest {
+    pub from: String,
+    pub to: String,
+}
+
+/// `purge=true` で履歴ごと完全に削除する（既定はソフト削除）
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct DeleteFileQuery {
+    #[serde(default)]
+    pub purge: bool,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct RestoreRequest {
+    pub path: String,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct IndexFileRequest {
+    pub path: String,
+}
+
 #[derive(Debug, Clone, Deserialize)]
 pub struct CreateDirRequest {
     pub path: String,
@@ -180,11 +603,41 @@ pub struct CreateFileRequest {
     pub content: String,
 }
 
+/// `to` 省略時は現在のファイルと比較する
+#[derive(Debug, Clone, Deserialize)]
+pub struct VersionDiffQuery {
+    pub from: u32,
+    #[serde(default)]
+    pub to: Option<u32>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct UpdateFileRequest {
+    pub content: String,
+    /// 上書き前に保存するバージョンのコメント
+    #[serde(default)]
+    pub comment: Option<String>,
+}
+
 #[derive(Debug, Clone, Deserialize)]
 pub struct ListFilesQuery {
     pub path: Option<String>,
 }
 
+#[derive(Debug, Clone, Deserialize)]
+pub struct FileSearchQuery {
+    #[serde(default)]
+    pub q: String,
+}
+
+/// ファイル名検索の結果。`path` は upload_dir からの相対パス
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FileSearchHit {
+    pub path: String,
+    #[serde(flatten)]
+    pub entry: DirEntry,
+}
+
 // Version management types
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -221,3 +674,192 @@ pub struct RollbackResponse {
     pub rolled_back_to: u32,
     pub reindex_triggered: bool,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample_response() -> ChatResponse {
+        ChatResponse {
+            id: "chatcmpl-1".to_string(),
+            object: "chat.completion".to_string(),
+            created: 0,
+            model: "gpt-4".to_string(),
+            choices: vec![],
+            pii_masked_count: None,
+            pii_entities: None,
+            usage: None,
+            estimated_cost_usd: None,
+            sanitizer_findings: None,
+        }
+    }
+
+    #[test]
+    fn test_tool_calls_round_trip_untouched() {
+        let mut request: ChatRequest = serde_json::from_value(serde_json::json!({
+            "model": "gpt-4",
+            "messages": [{"role": "user", "content": "田中さんの予定を調べて"}],
+            "tools": [{"type": "function", "function": {"name": "get_schedule", "parameters": {"type": "object"}}}],
+            "tool_choice": "auto"
+        })).unwrap();
+        request.set_last_user_content("佐藤さんの予定を調べて".to_string());
+        let body = serde_json::to_value(&request).unwrap();
+        assert_eq!(body["tools"][0]["function"]["name"], "get_schedule");
+        assert_eq!(body["tool_choice"], "auto");
+
+        let tool_calls = serde_json::json!([{
+            "id": "call_1",
+            "type": "function",
+            "function": {"name": "get_schedule", "arguments": "{\"person\": \"佐藤 一郎\"}"}
+        }]);
+        let mut response: ChatResponse = serde_json::from_value(serde_json::json!({
+            "id": "chatcmpl-1",
+            "object": "chat.completion",
+            "created": 0,
+            "model": "gpt-4",
+            "choices": [{
+                "index": 0,
+                "message": {"role": "assistant", "content": null, "tool_calls": tool_calls},
+                "finish_reason": "tool_calls"
+            }]
+        })).unwrap();
+
+        // 本文の復元はtool_callsの引数に触れない
+        let mappings = std::collections::HashMap::from([("佐藤 一郎".to_string(), "田中 太郎".to_string())]);
+        crate::filters::pii_detector::PIIDetector::default().unmask_choices(&mut response.choices, &mappings);
+
+        let body = serde_json::to_value(&response).unwrap();
+        assert_eq!(body["choices"][0]["message"]["tool_calls"], tool_calls);
+        assert_eq!(body["choices"][0]["message"]["content"], "");
+        assert_eq!(body["choices"][0]["finish_reason"], "tool_calls");
+    }
+
+    #[test]
+    fn test_rag_context_sent_as_system_message() {
+        let mut request: ChatRequest = serde_json::from_value(serde_json::json!({
+            "model": "gpt-4",
+            "messages": [{"role": "user", "content": "有給休暇は何日？"}]
+        })).unwrap();
+        request.add_system_context("関連情報:\n年次有給休暇は20日付与する。\n\n");
+
+        let body = serde_json::to_value(&request).unwrap();
+        assert_eq!(body["messages"][0]["role"], "system");
+        assert_eq!(body["messages"][0]["content"], "関連情報:\n年次有給休暇は20日付与する。");
+        assert_eq!(body["messages"][1]["content"], "有給休暇は何日？");
+
+        // 既存のsystemメッセージには追記する
+        let mut request: ChatRequest = serde_json::from_value(serde_json::json!({
+            "model": "gpt-4",
+            "messages": [
+                {"role": "system", "content": "丁寧に答えてください。"},
+                {"role": "user", "content": "有給休暇は何日？"}
+            ]
+        })).unwrap();
+        request.add_system_context("関連情報:\n年次有給休暇は20日付与する。");
+        assert_eq!(request.messages.len(), 2);
+        assert_eq!(request.messages[0].content, "丁寧に答えてください。\n\n関連情報:\n年次有給休暇は20日付与する。");
+    }
+
+    #[test]
+    fn test_sampling_params_forwarded_only_when_set() {
+        let mut request: ChatRequest = serde_json::from_value(serde_json::json!({
+            "model": "gpt-4",
+            "messages": [{"role": "user", "content": "田中さんに連絡して"}],
+            "top_p": 0.9,
+            "stop": ["\n\n", "END"],
+            "seed": 42,
+            "n": 2
+        })).unwrap();
+
+        // PIIマスク後の差し替えでも設定は変わらない
+        request.set_last_user_content("佐藤さんに連絡して".to_string());
+        let body = serde_json::to_value(&request).unwrap();
+
+        assert_eq!(body["top_p"], 0.9f32 as f64);
+        assert_eq!(body["stop"], serde_json::json!(["\n\n", "END"]));
+        assert_eq!(body["seed"], 42);
+        assert_eq!(body["n"], 2);
+        assert_eq!(body["messages"][0]["content"], "佐藤さんに連絡して");
+        for unset in ["presence_penalty", "frequency_penalty"] {
+            assert!(body.get(unset).is_none(), "{} should not be sent", unset);
+        }
+
+        let single: ChatRequest = serde_json::from_value(serde_json::json!({
+            "model": "gpt-4", "messages": [], "stop": "END"
+        })).unwrap();
+        assert_eq!(single.stop, Some(StopSequences::One("END".to_string())));
+    }
+
+    #[test]
+    fn test_llm_message_override_only_affects_forwarded_message() {
+        let mut request: ChatRequest = serde_json::from_value(serde_json::json!({
+            "model": "gpt-4",
+            "messages": [
+                {"role": "user", "content": "前の質問"},
+                {"role": "user", "content": "有給休暇の申請方法を詳しく教えてください"}
+            ],
+            "llm_message_override": "有給休暇の申請方法を要約して"
+        })).unwrap();
+
+        assert_eq!(request.last_user_content(), Some("有給休暇の申請方法を詳しく教えてください"));
+        assert_eq!(request.llm_user_content(), Some("有給休暇の申請方法を要約して"));
+
+        let forwarded = request.llm_user_content().unwrap().to_string();
+        request.set_last_user_content(forwarded);
+        let json = serde_json::to_value(&request).unwrap();
+        assert!(json.get("llm_message_override").is_none());
+        assert_eq!(json["messages"][0]["content"], "前の質問");
+        assert_eq!(json["messages"][1]["content"], "有給休暇の申請方法を要約して");
+    }
+
+    #[test]
+    fn test_llm_user_content_defaults_to_original() {
+        let request: ChatRequest = serde_json::from_value(serde_json::json!({
+            "model": "gpt-4",
+            "messages": [{"role": "user", "content": "こんにちは"}]
+        })).unwrap();
+        assert_eq!(request.llm_user_content(), Some("こんにちは"));
+    }
+
+    #[test]
+    fn test_pii_masked_count_omitted_when_unset() {
+        let json = serde_json::to_value(sample_response()).unwrap();
+        assert!(json.get("pii_masked_count").is_none());
+    }
+
+    #[test]
+    fn test_pii_masked_count_serialized_when_set() {
+        let mut response = sample_response();
+        response.pii_masked_count = Some(3);
+        let json = serde_json::to_value(response).unwrap();
+        assert_eq!(json["pii_masked_count"], 3);
+    }
+
+    #[test]
+    fn test_pii_entities_serialized_without_real_values() {
+        let mut response = sample_response();
+        response.pii_entities = Some(vec![PIIEntitySpan {
+            category: "person".to_string(),
+            byte_start: 0,
+            byte_end: 13,
+        }]);
+        let json = serde_json::to_value(&response).unwrap();
+        assert_eq!(json["pii_entities"], serde_json::json!([{"category": "person", "byte_start": 0, "byte_end": 13}]));
+        assert!(serde_json::to_value(sample_response()).unwrap().get("pii_entities").is_none());
+    }
+
+    #[test]
+    fn test_usage_parsed_from_upstream_response() {
+        let body = r#"{
+            "id": "chatcmpl-1", "object": "chat.completion", "created": 0, "model": "gpt-4",
+            "choices": [],
+            "usage": {"prompt_tokens": 12, "completion_tokens": 34, "total_tokens": 46}
+        }"#;
+        let response: ChatResponse = serde_json::from_str(body).unwrap();
+        assert_eq!(response.estimated_cost_usd, None);
+        let usage = response.usage.unwrap();
+        assert_eq!(usage.prompt_tokens, 12);
+        assert_eq!(usage.completion_tokens, 34);
+        assert_eq!(usage.total_tokens, 46);
+    }
+}
@@ -0,0 +1,73 @@
+use std::collections::HashMap;
+
+use crate::models::Usage;
+
+/// USD per 1,000 tokens.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ModelPrice {
+    pub prompt_per_1k: f64,
+    pub completion_per_1k: f64,
+}
+
+/// モデルごとの単価表。載っていないモデルはコストを出さない
+#[derive(Debug, Clone, Default)]
+pub struct PricingTable {
+    prices: HashMap<String, ModelPrice>,
+}
+
+impl PricingTable {
+    /// `model:prompt:completion`, comma-separated, e.g.
+    /// `gpt-4:0.03:0.06,gpt-3.5-turbo:0.0005:0.0015`. Malformed entries are skipped.
+    pub fn parse(spec: &str) -> Self {
+        let prices = spec.split(',')
+            .filter_map(|entry| {
+                let mut parts = entry.trim().rsplitn(3, ':');
+                let completion_per_1k = parts.next()?.trim().parse().ok()?;
+                let prompt_per_1k = parts.next()?.trim().parse().ok()?;
+                let model = parts.next()?.trim();
+                (!model.is_empty()).then(|| (model.to_string(), ModelPrice { prompt_per_1k, completion_per_1k }))
+            })
+            .collect();
+        Self { prices }
+    }
+
+    /// `MODEL_PRICING`
+    pub fn from_env() -> Self {
+        std::env::var("MODEL_PRICING").map(|v| Self::parse(&v)).unwrap_or_default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.prices.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.prices.is_empty()
+    }
+
+    /// Estimated cost in USD, if the model has a price.
+    pub fn estimate(&self, model: &str, usage: &Usage) -> Option<f64> {
+        let price = self.prices.get(model)?;
+        Some(
+            usage.prompt_tokens as f64 / 1000.0 * price.prompt_per_1k
+                + usage.completion_tokens as f64 / 1000.0 * price.completion_per_1k,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cost_estimated_from_usage() {
+        // rsplitn なのでモデル名に ':' を含んでもよい
+        let table = PricingTable::parse("gpt-4:0.03:0.06, ollama:llama3:0:0,broken:x:1");
+        assert_eq!(table.len(), 2);
+
+        let usage = Usage { prompt_tokens: 1000, completion_tokens: 500, total_tokens: 1500 };
+        let cost = table.estimate("gpt-4", &usage).unwrap();
+        assert!((cost - 0.06).abs() < 1e-9, "{}", cost);
+        assert_eq!(table.estimate("ollama:llama3", &usage), Some(0.0));
+        assert_eq!(table.estimate("unknown", &usage), None);
+    }
+}
@@ -1,42 +1,243 @@
+use std::collections::HashMap;
+use std::time::Duration;
+
 use anyhow::Result;
-use reqwest::Client;
-use crate::models::{ChatRequest, ChatResponse};
+use reqwest::{Client, StatusCode};
+use crate::models::{ChatRequest, ChatResponse, EmbeddingsRequest};
+
+/// No response from LiteLLM within the configured timeout.
+#[derive(Debug, thiserror::Error)]
+#[error("LiteLLM did not respond within {0:?}")]
+pub struct UpstreamTimeout(pub Duration);
+
+/// LiteLLM answered with a non-success status.
+#[derive(Debug, thiserror::Error)]
+#[error("LiteLLM request failed: {status} - {body}")]
+pub struct UpstreamStatus {
+    pub status: StatusCode,
+    pub body: String,
+}
+
+/// 一時的な失敗（5xx・接続エラー）だけ指数バックオフで再試行する
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RetryPolicy {
+    /// Total tries including the first; 1 disables retrying.
+    pub max_attempts: u32,
+    pub base_delay: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self { max_attempts: 3, base_delay: Duration::from_millis(500) }
+    }
+}
+
+impl RetryPolicy {
+    /// `LITELLM_MAX_ATTEMPTS` / `LITELLM_RETRY_BASE_MS`
+    pub fn from_env() -> Self {
+        let default = Self::default();
+        let max_attempts = std::env::var("LITELLM_MAX_ATTEMPTS").ok()
+            .and_then(|v| v.parse::<u32>().ok())
+            .unwrap_or(default.max_attempts)
+            .max(1);
+        let base_delay = std::env::var("LITELLM_RETRY_BASE_MS").ok()
+            .and_then(|v| v.parse().ok())
+            .map(Duration::from_millis)
+            .unwrap_or(default.base_delay);
+        Self { max_attempts, base_delay }
+    }
+
+    /// `base * 2^(attempt-1)`, scaled by a random factor in [0.5, 1.5).
+    fn delay(&self, attempt: u32) -> Duration {
+        let backoff = self.base_delay.saturating_mul(1 << (attempt - 1).min(16));
+        backoff.mul_f64(rand::random_range(0.5..1.5))
+    }
+}
+
+/// 4xxやレスポンスの解析失敗は再送しても直らない。
+/// タイムアウトも、遅い上流に同じ待ち時間を重ねるだけなので再送しない
+fn is_retryable(error: &anyhow::Error) -> bool {
+    if let Some(upstream) = error.downcast_ref::<UpstreamStatus>() {
+        return upstream.status.is_server_error();
+    }
+    error.downcast_ref::<reqwest::Error>()
+        .is_some_and(|e| e.is_connect())
+}
+
+/// Models to try, in order, when the requested one is unavailable.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ModelFallbacks {
+    chains: HashMap<String, Vec<String>>,
+}
+
+impl ModelFallbacks {
+    /// `primary:fallback1:fallback2`, several chains separated by commas.
+    pub fn parse(spec: &str) -> Self {
+        let chains = spec.split(',')
+            .filter_map(|chain| {
+                let mut models = chain.split(':').map(str::trim).filter(|m| !m.is_empty()).map(String::from);
+                let primary = models.next()?;
+                let fallbacks: Vec<String> = models.collect();
+                (!fallbacks.is_empty()).then_some((primary, fallbacks))
+            })
+            .collect();
+        Self { chains }
+    }
+
+    /// `MODEL_FALLBACKS=gpt-4:gpt-3.5-turbo`
+    pub fn from_env() -> Self {
+        std::env::var("MODEL_FALLBACKS").map(|v| Self::parse(&v)).unwrap_or_default()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.chains.is_empty()
+    }
+
+    /// The requested model followed by its fallbacks.
+    fn candidates(&self, model: &str) -> Vec<String> {
+        std::iter::once(model.to_string())
+            .chain(self.chains.get(model).into_iter().flatten().cloned())
+            .collect()
+    }
+}
+
+/// 混雑(429)や上流障害(5xx)なら別モデルで通る見込みがある
+fn should_fall_back(error: &anyhow::Error) -> bool {
+    error.downcast_ref::<UpstreamStatus>().is_some_and(|upstream| {
+        upstream.status == StatusCode::TOO_MANY_REQUESTS || upstream.status.is_server_error()
+    })
+}
+
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(120);
+
+fn build_client(timeout: Duration) -> Client {
+    Client::builder()
+        .timeout(timeout)
+        .build()
+        .expect("Failed to build HTTP client")
+}
 
 pub struct LiteLLMProxy {
     client: Client,
     base_url: String,
     api_key: Option<String>,
+    retry: RetryPolicy,
+    timeout: Duration,
+    fallbacks: ModelFallbacks,
 }
 
 impl LiteLLMProxy {
     pub fn new(base_url: String, api_key: Option<String>) -> Self {
         Self {
-            client: Client::new(),
+            client: build_client(DEFAULT_TIMEOUT),
             base_url,
             api_key,
+            retry: RetryPolicy::default(),
+            timeout: DEFAULT_TIMEOUT,
+            fallbacks: ModelFallbacks::default(),
         }
     }
 
+    pub fn with_fallbacks(mut self, fallbacks: ModelFallbacks) -> Self {
+        self.fallbacks = fallbacks;
+        self
+    }
+
+    /// Per-attempt limit for the whole upstream call, body included.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.client = build_client(timeout);
+        self.timeout = timeout;
+        self
+    }
+
+    pub fn with_retry(mut self, retry: RetryPolicy) -> Self {
+        self.retry = retry;
+        self
+    }
+
+    /// Send the request upstream. axum drops the handler future when the
+    /// client disconnects; dropping this future with it closes the upstream
+    /// connection, so LiteLLM stops generating for a client that is gone.
     pub async fn chat_completion(&self, request: ChatRequest) -> Result<ChatResponse> {
+        self.send_with_retry(&request).await
+    }
+
+    /// Like `chat_completion`, but on 429/5xx moves on to the next model of
+    /// the request's fallback chain. Also returns the model that answered.
+    pub async fn chat_completion_with_fallback(&self, mut request: ChatRequest) -> Result<(ChatResponse, String)> {
+        let candidates = self.fallbacks.candidates(&request.model);
+        let mut remaining = candidates.len();
+        for model in candidates {
+            remaining -= 1;
+            request.model = model.clone();
+            match self.chat_completion(request.clone()).await {
+                Err(e) if remaining > 0 && should_fall_back(&e) => {
+                    tracing::warn!("Model {} unavailable, falling back: {}", model, e);
+                }
+                result => return result.map(|response| (response, model)),
+            }
+        }
+        unreachable!("candidates always include the requested model")
+    }
+
+    async fn send_with_retry(&self, request: &ChatRequest) -> Result<ChatResponse> {
+        let mut attempt = 1;
+        loop {
+            match self.send_chat_completion(request).await {
+                Err(e) if attempt < self.retry.max_attempts && is_retryable(&e) => {
+                    let delay = self.retry.delay(attempt);
+                    tracing::warn!("LiteLLM attempt {} failed, retrying in {:?}: {}", attempt, delay, e);
+                    tokio::time::sleep(delay).await;
+                    attempt += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    async fn send_chat_completion(&self, request: &ChatRequest) -> Result<ChatResponse> {
         let url = format!("{}/chat/completions", self.base_url);
 
-        let mut req = self.client.post(&url).json(&request);
+        let mut req = self.client.post(&url).json(request);
         if let Some(ref key) = self.api_key {
             req = req.bearer_auth(key);
         }
 
-        let response = req.send().await?;
+        let timed_out = |e: reqwest::Error| -> anyhow::Error {
+            if e.is_timeout() { UpstreamTimeout(self.timeout).into() } else { e.into() }
+        };
+        let response = req.send().await.map_err(timed_out)?;
 
         if !response.status().is_success() {
             let status = response.status();
-            let error_text = response.text().await?;
-            anyhow::bail!("LiteLLM request failed: {} - {}", status, error_text);
+            let body = response.text().await.map_err(timed_out)?;
+            return Err(UpstreamStatus { status, body }.into());
         }
 
-        let chat_response: ChatResponse = response.json().await?;
+        let chat_response: ChatResponse = response.json().await.map_err(timed_out)?;
         Ok(chat_response)
     }
 
+    /// Forward an embeddings request to LiteLLM and return its body as is.
+    pub async fn embeddings(&self, request: &EmbeddingsRequest) -> Result<serde_json::Value> {
+        let url = format!("{}/embeddings", self.base_url);
+
+        let mut req = self.client.post(&url).json(request);
+        if let Some(ref key) = self.api_key {
+            req = req.bearer_auth(key);
+        }
+
+        let response = req.send().await.map_err(|e| -> anyhow::Error {
+            if e.is_timeout() { UpstreamTimeout(self.timeout).into() } else { e.into() }
+        })?;
+        if !response.status().is_success() {
+            let status = response.status();
+            let body = response.text().await?;
+            return Err(UpstreamStatus { status, body }.into());
+        }
+        Ok(response.json().await?)
+    }
+
     pub async fn health_check(&self) -> Result<bool> {
         let url = format!("{}/health/liveliness", self.base_url);
 
@@ -48,3 +249,208 @@ impl LiteLLMProxy {
         Ok(response.status().is_success())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+    use axum::{Router, routing::post, Json};
+    use axum::http::StatusCode as MockStatus;
+    use crate::models::Message;
+
+    /// Sets the flag when the mock handler's future is dropped.
+    struct DropFlag(Arc<AtomicBool>);
+
+    impl Drop for DropFlag {
+        fn drop(&mut self) {
+            self.0.store(true, Ordering::SeqCst);
+        }
+    }
+
+    async fn spawn_hanging_upstream(dropped: Arc<AtomicBool>) -> String {
+        let app = Router::new().route("/chat/completions", post(move || {
+            let flag = DropFlag(dropped.clone());
+            async move {
+                let _flag = flag;
+                tokio::time::sleep(Duration::from_secs(30)).await;
+                Json(serde_json::json!({}))
+            }
+        }));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        format!("http://{}", addr)
+    }
+
+    fn request() -> ChatRequest {
+        ChatRequest {
+            model: "gpt-4".to_string(),
+            messages: vec![Message { role: "user".to_string(), content: "hi".to_string(), ..Default::default() }],
+            temperature: None,
+            max_tokens: None,
+            stream: None,
+            top_p: None,
+            presence_penalty: None,
+            frequency_penalty: None,
+            stop: None,
+            seed: None,
+            n: None,
+            tools: None,
+            tool_choice: None,
+            llm_message_override: None,
+            rag_category: None,
+            collection: None,
+            rag_hybrid: false,
+            rag_top_k: None,
+            use_rag: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_disconnect_cancels_upstream_call() {
+        let upstream_dropped = Arc::new(AtomicBool::new(false));
+        let base_url = spawn_hanging_upstream(upstream_dropped.clone()).await;
+        let proxy = Arc::new(LiteLLMProxy::new(base_url, None));
+
+        // Forwards like chat_completion_handler, served the same way
+        let app = Router::new().route("/chat", post(move || {
+            let proxy = proxy.clone();
+            async move {
+                proxy.chat_completion(request()).await.unwrap();
+                Json(serde_json::json!({}))
+            }
+        }));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+
+        // The client gives up and closes its connection mid-request
+        let client = Client::builder().timeout(Duration::from_millis(300)).build().unwrap();
+        assert!(client.post(format!("http://{}/chat", addr)).send().await.is_err());
+
+        for _ in 0..50 {
+            if upstream_dropped.load(Ordering::SeqCst) {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+        panic!("upstream request was not aborted");
+    }
+
+    fn completion(model: &str) -> serde_json::Value {
+        serde_json::json!({
+            "id": "chatcmpl-1",
+            "object": "chat.completion",
+            "created": 0,
+            "model": model,
+            "choices": [{"index": 0, "message": {"role": "assistant", "content": "ok"}, "finish_reason": "stop"}],
+        })
+    }
+
+    /// Answers `status` for the first `failures` calls, then a normal reply.
+    async fn spawn_flaky_upstream(failures: usize, status: MockStatus, calls: Arc<AtomicUsize>) -> String {
+        let app = Router::new().route("/chat/completions", post(move || {
+            let call = calls.fetch_add(1, Ordering::SeqCst);
+            async move {
+                if call < failures {
+                    return (status, Json(serde_json::json!({"error": "upstream blip"})));
+                }
+                (MockStatus::OK, Json(completion("gpt-4")))
+            }
+        }));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        format!("http://{}", addr)
+    }
+
+    fn fast_retry() -> RetryPolicy {
+        RetryPolicy { max_attempts: 3, base_delay: Duration::from_millis(1) }
+    }
+
+    #[tokio::test]
+    async fn test_transient_errors_are_retried() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let base_url = spawn_flaky_upstream(2, MockStatus::BAD_GATEWAY, calls.clone()).await;
+        let proxy = LiteLLMProxy::new(base_url, None).with_retry(fast_retry());
+
+        let response = proxy.chat_completion(request()).await.unwrap();
+        assert_eq!(response.choices[0].message.content, "ok");
+        assert_eq!(calls.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test]
+    async fn test_client_errors_are_not_retried() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let base_url = spawn_flaky_upstream(1, MockStatus::BAD_REQUEST, calls.clone()).await;
+        let proxy = LiteLLMProxy::new(base_url, None).with_retry(fast_retry());
+
+        let err = proxy.chat_completion(request()).await.unwrap_err();
+        assert_eq!(err.downcast_ref::<UpstreamStatus>().unwrap().status, StatusCode::BAD_REQUEST);
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn test_slow_upstream_times_out() {
+        let base_url = spawn_hanging_upstream(Arc::new(AtomicBool::new(false))).await;
+        let proxy = LiteLLMProxy::new(base_url, None).with_timeout(Duration::from_millis(200));
+
+        let started = std::time::Instant::now();
+        let err = proxy.chat_completion(request()).await.unwrap_err();
+        assert!(err.downcast_ref::<UpstreamTimeout>().is_some(), "{}", err);
+        // タイムアウトは再送しないので1回分で返る
+        assert!(started.elapsed() < Duration::from_secs(2));
+    }
+
+    /// Rate-limits every model except `available`.
+    async fn spawn_upstream_serving(available: &'static str) -> String {
+        let app = Router::new().route("/chat/completions", post(move |Json(body): Json<serde_json::Value>| async move {
+            if body["model"] == available {
+                (MockStatus::OK, Json(completion(available)))
+            } else {
+                (MockStatus::TOO_MANY_REQUESTS, Json(serde_json::json!({"error": "rate limited"})))
+            }
+        }));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        format!("http://{}", addr)
+    }
+
+    #[tokio::test]
+    async fn test_falls_back_to_next_model() {
+        let base_url = spawn_upstream_serving("gpt-3.5-turbo").await;
+        let proxy = LiteLLMProxy::new(base_url, None)
+            .with_fallbacks(ModelFallbacks::parse("gpt-4:gpt-3.5-turbo"));
+
+        let (response, model) = proxy.chat_completion_with_fallback(request()).await.unwrap();
+        assert_eq!(model, "gpt-3.5-turbo");
+        assert_eq!(response.model, "gpt-3.5-turbo");
+
+        // チェーンが尽きたら最後のエラーを返す
+        let proxy = LiteLLMProxy::new(spawn_upstream_serving("none").await, None)
+            .with_fallbacks(ModelFallbacks::parse("gpt-4:gpt-3.5-turbo"));
+        let err = proxy.chat_completion_with_fallback(request()).await.unwrap_err();
+        assert_eq!(err.downcast_ref::<UpstreamStatus>().unwrap().status, StatusCode::TOO_MANY_REQUESTS);
+    }
+
+    #[test]
+    fn test_parse_fallback_chains() {
+        let fallbacks = ModelFallbacks::parse("gpt-4:gpt-4o-mini:gpt-3.5-turbo, claude-3-opus:claude-3-haiku,solo");
+        assert_eq!(fallbacks.candidates("gpt-4"), vec!["gpt-4", "gpt-4o-mini", "gpt-3.5-turbo"]);
+        assert_eq!(fallbacks.candidates("claude-3-opus"), vec!["claude-3-opus", "claude-3-haiku"]);
+        assert_eq!(fallbacks.candidates("solo"), vec!["solo"]);
+        assert!(ModelFallbacks::parse("").is_empty());
+    }
+
+    #[test]
+    fn test_backoff_grows_exponentially_with_jitter() {
+        let policy = RetryPolicy { max_attempts: 5, base_delay: Duration::from_millis(100) };
+        for attempt in 1..=4 {
+            let nominal = 100.0 * 2f64.powi(attempt as i32 - 1);
+            let delay = policy.delay(attempt).as_secs_f64() * 1000.0;
+            assert!(delay >= nominal * 0.5 && delay < nominal * 1.5, "attempt {}: {}ms", attempt, delay);
+        }
+    }
+}
@@ -1,14 +1,162 @@
-use anyhow::Result;
+use anyhow::{Context, Result};
 use fastembed::{TextEmbedding, UserDefinedEmbeddingModel, TokenizerFiles, InitOptionsUserDefined};
+use lru::LruCache;
+use reqwest::Client;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use std::num::NonZeroUsize;
 use std::path::Path;
+use std::sync::Mutex;
+use std::time::Duration;
+use tokenizers::Tokenizer;
+
+use crate::indexer::chunker::Tokenize;
+use crate::models::{EmbeddingObject, EmbeddingsResponse, EmbeddingsUsage};
+use super::vector_store::DEFAULT_DIMENSION;
 
 const MODEL_DIR: &str = "/app/models/bge-small-en-v1.5";
+/// `/api/v1/embeddings` で名乗るローカルモデル名
+pub const LOCAL_MODEL_NAME: &str = "bge-small-en-v1.5";
 
-pub struct EmbeddingGenerator {
+const DEFAULT_REMOTE_MODEL: &str = "text-embedding-3-small";
+/// bge系の推奨クエリ接頭辞（文書側は付けない）
+const BGE_QUERY_PREFIX: &str = "Represent this sentence for searching relevant passages: ";
+/// 384次元なら1件約1.5KB、1万件で15MB程度
+const DEFAULT_CACHE_SIZE: usize = 10_000;
+
+/// Vectors keyed by SHA-256 of the embedded text.
+type EmbeddingCache = Mutex<LruCache<[u8; 32], Vec<f32>>>;
+
+struct LocalModel {
     model: TextEmbedding,
+    /// Same vocabulary as the model, without its 512-token truncation/padding,
+    /// for measuring chunk sizes.
+    tokenizer: Tokenizer,
+}
+
+enum Backend {
+    Local(Box<LocalModel>),
+    Remote(RemoteEmbeddings),
+}
+
+pub struct EmbeddingGenerator {
+    backend: Backend,
+    dimension: usize,
+    cache: Option<EmbeddingCache>,
+    /// Prepended by `embed_query(s)` / `embed_document(s)`.
+    query_prefix: String,
+    document_prefix: String,
+}
+
+/// OpenAI互換の `/embeddings` エンドポイント
+struct RemoteEmbeddings {
+    client: Client,
+    url: String,
+    api_key: Option<String>,
+    model: String,
+}
+
+#[derive(Deserialize)]
+struct EmbeddingResponse {
+    data: Vec<EmbeddingData>,
+}
+
+#[derive(Deserialize)]
+struct EmbeddingData {
+    embedding: Vec<f32>,
+    #[serde(default)]
+    index: usize,
+}
+
+impl RemoteEmbeddings {
+    async fn embed(&self, texts: Vec<String>) -> Result<Vec<Vec<f32>>> {
+        let expected = texts.len();
+        let mut req = self.client
+            .post(format!("{}/embeddings", self.url))
+            .json(&serde_json::json!({ "model": self.model, "input": texts }));
+        if let Some(key) = &self.api_key {
+            req = req.bearer_auth(key);
+        }
+        let response = req.send().await.context("Embedding API request failed")?;
+        if !response.status().is_success() {
+            let status = response.status();
+            let body = response.text().await.unwrap_or_default();
+            anyhow::bail!("Embedding API error {}: {}", status, body);
+        }
+
+        let mut data = response.json::<EmbeddingResponse>().await
+            .context("Invalid embedding API response")?
+            .data;
+        if data.len() != expected {
+            anyhow::bail!("Embedding API returned {} vectors for {} inputs", data.len(), expected);
+        }
+        data.sort_by_key(|d| d.index);
+        Ok(data.into_iter().map(|d| d.embedding).collect())
+    }
 }
 
 impl EmbeddingGenerator {
+    /// `EMBEDDING_BACKEND=remote` uses `EMBEDDING_API_URL` (+ `EMBEDDING_API_KEY`,
+    /// `EMBEDDING_MODEL`); anything else loads the local ONNX model.
+    /// `EMBEDDING_CACHE_SIZE` sets how many vectors are cached (0 disables).
+    /// `EMBEDDING_QUERY_PREFIX` / `EMBEDDING_DOCUMENT_PREFIX` override the
+    /// model's default prefixes (an empty value turns one off).
+    pub async fn from_env() -> Result<Self> {
+        let generator = if std::env::var("EMBEDDING_BACKEND").as_deref() != Ok("remote") {
+            Self::new().await?
+        } else {
+            let url = std::env::var("EMBEDDING_API_URL")
+                .map_err(|_| anyhow::anyhow!("EMBEDDING_BACKEND=remote requires EMBEDDING_API_URL"))?;
+            let model = std::env::var("EMBEDDING_MODEL").unwrap_or_else(|_| DEFAULT_REMOTE_MODEL.to_string());
+            Self::remote(&url, std::env::var("EMBEDDING_API_KEY").ok(), &model).await?
+        };
+        let cache_size = std::env::var("EMBEDDING_CACHE_SIZE").ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(DEFAULT_CACHE_SIZE);
+        let query_prefix = std::env::var("EMBEDDING_QUERY_PREFIX").unwrap_or(generator.query_prefix.clone());
+        let document_prefix = std::env::var("EMBEDDING_DOCUMENT_PREFIX").unwrap_or(generator.document_prefix.clone());
+        Ok(generator.with_cache(cache_size).with_prefixes(&query_prefix, &document_prefix))
+    }
+
+    /// Instruction prefixes for retrieval models, e.g. `"query: "` / `"passage: "` for e5.
+    pub fn with_prefixes(mut self, query: &str, document: &str) -> Self {
+        self.query_prefix = query.to_string();
+        self.document_prefix = document.to_string();
+        self
+    }
+
+    /// Keep up to `capacity` vectors so identical text (e.g. unchanged
+    /// chunks on reindex) is not embedded again. 0 disables the cache.
+    pub fn with_cache(mut self, capacity: usize) -> Self {
+        self.cache = NonZeroUsize::new(capacity).map(|c| Mutex::new(LruCache::new(c)));
+        self
+    }
+
+    /// Remote backend; the vector dimension is probed with one request.
+    pub async fn remote(url: &str, api_key: Option<String>, model: &str) -> Result<Self> {
+        let remote = RemoteEmbeddings {
+            client: Client::builder().timeout(Duration::from_secs(60)).build()?,
+            url: url.trim_end_matches('/').to_string(),
+            api_key,
+            model: model.to_string(),
+        };
+        let probe = remote.embed(vec!["dimension probe".to_string()]).await
+            .with_context(|| format!("Embedding API not usable at {}", remote.url))?;
+        let dimension = probe.first().map(|v| v.len()).unwrap_or(0);
+        if dimension == 0 {
+            anyhow::bail!("Embedding API returned an empty vector");
+        }
+
+        tracing::info!("Using remote embeddings: {} ({}, {} dims)", remote.url, remote.model, dimension);
+        Ok(Self {
+            backend: Backend::Remote(remote),
+            dimension,
+            cache: None,
+            query_prefix: String::new(),
+            document_prefix: String::new(),
+        })
+    }
+
     pub async fn new() -> Result<Self> {
         tracing::info!("Initializing embedding model from local files...");
 
@@ -43,17 +191,270 @@ impl EmbeddingGenerator {
         let model = TextEmbedding::try_new_from_user_defined(user_model, InitOptionsUserDefined::default())
             .map_err(|e| anyhow::anyhow!("Failed to initialize embedding model: {}", e))?;
 
+        let mut tokenizer = model.tokenizer.clone();
+        tokenizer.with_truncation(None)
+            .map_err(|e| anyhow::anyhow!("Failed to configure tokenizer: {}", e))?;
+        tokenizer.with_padding(None);
+
         tracing::info!("Embedding model initialized successfully");
-        Ok(Self { model })
+        Ok(Self {
+            backend: Backend::Local(Box::new(LocalModel { model, tokenizer: tokenizer.into() })),
+            dimension: DEFAULT_DIMENSION as usize,
+            cache: None,
+            query_prefix: BGE_QUERY_PREFIX.to_string(),
+            document_prefix: String::new(),
+        })
     }
 
+    /// Model the vectors come from: the remote model or `LOCAL_MODEL_NAME`.
+    pub fn model_name(&self) -> &str {
+        match &self.backend {
+            Backend::Local(_) => LOCAL_MODEL_NAME,
+            Backend::Remote(remote) => &remote.model,
+        }
+    }
+
+    /// Raw embeddings (no query/document prefix) in the OpenAI response shape.
+    pub fn openai_embeddings(&self, texts: Vec<String>) -> Result<EmbeddingsResponse> {
+        let prompt_tokens: usize = texts.iter().map(|t| self.token_starts(t).len()).sum();
+        let data = self.generate(texts)?.into_iter().enumerate()
+            .map(|(index, embedding)| EmbeddingObject { object: "embedding".to_string(), index, embedding })
+            .collect();
+        Ok(EmbeddingsResponse {
+            object: "list".to_string(),
+            data,
+            model: self.model_name().to_string(),
+            usage: EmbeddingsUsage { prompt_tokens: prompt_tokens as u32, total_tokens: prompt_tokens as u32 },
+        })
+    }
+
+    /// Length of the vectors `generate` returns.
+    pub fn dimension(&self) -> usize {
+        self.dimension
+    }
+
+    /// Blocking for both backends. The remote one waits on the HTTP call
+    /// via `block_in_place`, so it needs the multi-threaded runtime.
     pub fn generate(&self, texts: Vec<String>) -> Result<Vec<Vec<f32>>> {
-        let embeddings = self.model.embed(texts, None)?;
-        Ok(embeddings)
+        let Some(cache) = &self.cache else {
+            return self.embed(texts);
+        };
+
+        let keys: Vec<[u8; 32]> = texts.iter().map(|t| Sha256::digest(t.as_bytes()).into()).collect();
+        let mut vectors: Vec<Option<Vec<f32>>> = {
+            let mut cache = cache.lock().unwrap();
+            keys.iter().map(|k| cache.get(k).cloned()).collect()
+        };
+
+        let misses: Vec<usize> = (0..texts.len()).filter(|&i| vectors[i].is_none()).collect();
+        if !misses.is_empty() {
+            let computed = self.embed(misses.iter().map(|&i| texts[i].clone()).collect())?;
+            let mut cache = cache.lock().unwrap();
+            for (&i, vector) in misses.iter().zip(computed) {
+                cache.put(keys[i], vector.clone());
+                vectors[i] = Some(vector);
+            }
+        }
+        vectors.into_iter().collect::<Option<Vec<_>>>()
+            .context("Embedding backend returned fewer vectors than inputs")
+    }
+
+    fn embed(&self, texts: Vec<String>) -> Result<Vec<Vec<f32>>> {
+        match &self.backend {
+            Backend::Local(local) => Ok(local.model.embed(texts, None)?),
+            Backend::Remote(remote) => tokio::task::block_in_place(|| {
+                tokio::runtime::Handle::current().block_on(remote.embed(texts))
+            }),
+        }
     }
 
     pub fn generate_single(&self, text: &str) -> Result<Vec<f32>> {
         let embeddings = self.generate(vec![text.to_string()])?;
         Ok(embeddings.into_iter().next().unwrap())
     }
+
+    fn generate_prefixed(&self, prefix: &str, texts: Vec<String>) -> Result<Vec<Vec<f32>>> {
+        if prefix.is_empty() {
+            return self.generate(texts);
+        }
+        self.generate(texts.into_iter().map(|t| format!("{}{}", prefix, t)).collect())
+    }
+
+    /// Search queries (with the query prefix).
+    pub fn embed_queries(&self, queries: Vec<String>) -> Result<Vec<Vec<f32>>> {
+        self.generate_prefixed(&self.query_prefix, queries)
+    }
+
+    pub fn embed_query(&self, query: &str) -> Result<Vec<f32>> {
+        Ok(self.embed_queries(vec![query.to_string()])?.into_iter().next().unwrap())
+    }
+
+    /// Indexed passages (with the document prefix).
+    pub fn embed_documents(&self, texts: Vec<String>) -> Result<Vec<Vec<f32>>> {
+        self.generate_prefixed(&self.document_prefix, texts)
+    }
+
+    pub fn embed_document(&self, text: &str) -> Result<Vec<f32>> {
+        Ok(self.embed_documents(vec![text.to_string()])?.into_iter().next().unwrap())
+    }
+}
+
+impl Tokenize for EmbeddingGenerator {
+    fn token_starts(&self, text: &str) -> Vec<usize> {
+        let Backend::Local(local) = &self.backend else {
+            // リモートモデルのトークナイザは手元にないので1文字1トークンで近似
+            return text.char_indices().map(|(i, _)| i).collect();
+        };
+        match local.tokenizer.encode(text, false) {
+            Ok(encoding) => encoding.get_offsets().iter()
+                .filter(|(start, end)| end > start)
+                .map(|(start, _)| *start)
+                .collect(),
+            Err(e) => {
+                // 数えられない場合は1文字1トークンとみなす
+                tracing::warn!("Tokenization failed, counting chars instead: {}", e);
+                text.char_indices().map(|(i, _)| i).collect()
+            }
+        }
+    }
+}
+
+/// Generator backed by an in-process mock API returning 3-dimensional
+/// vectors, for tests that need embeddings without a model.
+#[cfg(test)]
+pub(crate) async fn test_generator() -> EmbeddingGenerator {
+    counting_test_generator().await.0
+}
+
+/// `test_generator` plus the number of texts the mock API has embedded
+/// (not counting the dimension probe).
+#[cfg(test)]
+pub(crate) async fn counting_test_generator() -> (EmbeddingGenerator, std::sync::Arc<std::sync::atomic::AtomicUsize>) {
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use axum::{routing::post, Json, Router};
+
+    let embedded = Arc::new(AtomicUsize::new(0));
+    let counter = embedded.clone();
+    let app = Router::new().route("/v1/embeddings", post(move |Json(body): Json<serde_json::Value>| async move {
+        let texts = body["input"].as_array().unwrap();
+        counter.fetch_add(texts.len(), Ordering::SeqCst);
+        let data: Vec<_> = texts.iter().enumerate()
+            .map(|(i, text)| serde_json::json!({
+                "index": i,
+                "embedding": [text.as_str().unwrap().len() as f32, 1.0, 0.5],
+            }))
+            .collect();
+        Json(serde_json::json!({ "data": data }))
+    }));
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+    let generator = EmbeddingGenerator::remote(&format!("http://{}/v1", addr), None, "embed-small").await.unwrap();
+    embedded.store(0, Ordering::SeqCst);
+    (generator, embedded)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use axum::{routing::post, Json, Router};
+    use axum_extra::TypedHeader;
+    use axum_extra::headers::{authorization::Bearer, Authorization};
+
+    /// `inputs` counts texts received, i.e. texts actually embedded.
+    async fn spawn_mock_api(inputs: Arc<AtomicUsize>) -> String {
+        let app = Router::new().route("/v1/embeddings", post(
+            move |TypedHeader(auth): TypedHeader<Authorization<Bearer>>, Json(body): Json<serde_json::Value>| async move {
+                assert_eq!(auth.token(), "sk-test");
+                assert_eq!(body["model"], "embed-small");
+                let texts = body["input"].as_array().unwrap().clone();
+                inputs.fetch_add(texts.len(), Ordering::SeqCst);
+                // 逆順で返しても index で並べ直されること
+                let data: Vec<_> = texts.iter().enumerate().rev()
+                    .map(|(i, text)| serde_json::json!({
+                        "index": i,
+                        "embedding": [text.as_str().unwrap().len() as f32, 0.5, 0.25],
+                    }))
+                    .collect();
+                Json(serde_json::json!({ "data": data }))
+            },
+        ));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        format!("http://{}/v1", addr)
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_remote_backend_against_mock_server() {
+        let url = spawn_mock_api(Arc::default()).await;
+        let generator = EmbeddingGenerator::remote(&url, Some("sk-test".to_string()), "embed-small").await.unwrap();
+        assert_eq!(generator.dimension(), 3);
+
+        let vectors = generator.generate(vec!["a".to_string(), "abc".to_string()]).unwrap();
+        assert_eq!(vectors, vec![vec![1.0, 0.5, 0.25], vec![3.0, 0.5, 0.25]]);
+        assert_eq!(generator.generate_single("ab").unwrap()[0], 2.0);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_repeated_text_is_served_from_cache() {
+        let inputs = Arc::new(AtomicUsize::new(0));
+        let url = spawn_mock_api(inputs.clone()).await;
+        let generator = EmbeddingGenerator::remote(&url, Some("sk-test".to_string()), "embed-small").await.unwrap()
+            .with_cache(100);
+        let probe = inputs.load(Ordering::SeqCst);
+
+        let first = generator.generate(vec!["規程A".to_string(), "規程B".to_string()]).unwrap();
+        assert_eq!(inputs.load(Ordering::SeqCst), probe + 2);
+
+        // 2回目は新しいテキストだけ問い合わせる
+        let second = generator.generate(vec!["規程B".to_string(), "規程C".to_string(), "規程A".to_string()]).unwrap();
+        assert_eq!(inputs.load(Ordering::SeqCst), probe + 3);
+        assert_eq!(second[0], first[1]);
+        assert_eq!(second[2], first[0]);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_query_and_document_prefixes_differ() {
+        let url = spawn_mock_api(Arc::default()).await;
+        let generator = EmbeddingGenerator::remote(&url, Some("sk-test".to_string()), "embed-small").await.unwrap()
+            .with_prefixes("query: ", "passage: ");
+
+        // モックはテキスト長を返すので、接頭辞の違いがそのまま出る
+        let query = generator.embed_query("有給休暇").unwrap();
+        let document = generator.embed_document("有給休暇").unwrap();
+        assert_ne!(query, document);
+        assert_eq!(query[0], "query: 有給休暇".len() as f32);
+        assert_eq!(document[0], "passage: 有給休暇".len() as f32);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_openai_embeddings_response_shape() {
+        let url = spawn_mock_api(Arc::default()).await;
+        let generator = EmbeddingGenerator::remote(&url, Some("sk-test".to_string()), "embed-small").await.unwrap()
+            .with_prefixes("query: ", "passage: ");
+
+        let request: crate::models::EmbeddingsRequest =
+            serde_json::from_value(serde_json::json!({ "input": "有給休暇" })).unwrap();
+        let response = generator.openai_embeddings(request.input.into_vec()).unwrap();
+
+        let body = serde_json::to_value(&response).unwrap();
+        assert_eq!(body["object"], "list");
+        assert_eq!(body["model"], "embed-small");
+        assert_eq!(body["data"][0]["object"], "embedding");
+        let embedding = body["data"][0]["embedding"].as_array().unwrap();
+        assert_eq!(embedding.len(), generator.dimension());
+        // 接頭辞なしの生テキストが埋め込まれる
+        assert_eq!(embedding[0], "有給休暇".len() as f64);
+        assert_eq!(body["usage"]["prompt_tokens"], 4);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_remote_backend_unreachable_fails_at_startup() {
+        let err = EmbeddingGenerator::remote("http://127.0.0.1:9", None, "embed-small").await.err().unwrap();
+        assert!(format!("{:#}", err).contains("Embedding API not usable"), "{:#}", err);
+    }
 }
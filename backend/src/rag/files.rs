@@ -0,0 +1,462 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::Result;
+use axum::body::{Body, Bytes};
+use axum::extract::multipart::MultipartError;
+use axum::http::StatusCode;
+use futures::{Stream, StreamExt};
+use tokio::io::AsyncWriteExt;
+use tokio_util::io::ReaderStream;
+
+use crate::indexer::extractor;
+use crate::indexer::walker::SupportedFormat;
+use crate::rag::versioning;
+
+/// アップロードのサイズ上限。`MAX_UPLOAD_BYTES` が1ファイル、
+/// `MAX_UPLOAD_REQUEST_BYTES` がリクエスト全体（既定はファイル上限の4倍）
+#[derive(Debug, Clone, Copy)]
+pub struct UploadLimits {
+    pub max_file_bytes: usize,
+    pub max_request_bytes: usize,
+}
+
+impl Default for UploadLimits {
+    fn default() -> Self {
+        Self { max_file_bytes: 50 * 1024 * 1024, max_request_bytes: 200 * 1024 * 1024 }
+    }
+}
+
+impl UploadLimits {
+    pub fn from_env() -> Self {
+        let parse = |name: &str| std::env::var(name).ok().and_then(|v| v.parse::<usize>().ok()).filter(|&n| n > 0);
+        let max_file_bytes = parse("MAX_UPLOAD_BYTES").unwrap_or(Self::default().max_file_bytes);
+        let max_request_bytes = parse("MAX_UPLOAD_REQUEST_BYTES")
+            .unwrap_or(max_file_bytes.saturating_mul(4))
+            .max(max_file_bytes);
+        Self { max_file_bytes, max_request_bytes }
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum UploadReadError {
+    #[error("File exceeds the upload limit of {0} bytes")]
+    TooLarge(usize),
+    #[error("Failed to read file data: {0}")]
+    Multipart(#[from] MultipartError),
+    #[error("Failed to save file: {0}")]
+    Io(#[from] std::io::Error),
+}
+
+impl UploadReadError {
+    /// 413 for both our per-file cap and axum's request body limit
+    pub fn status(&self) -> StatusCode {
+        match self {
+            Self::TooLarge(_) => StatusCode::PAYLOAD_TOO_LARGE,
+            Self::Multipart(e) => e.status(),
+            Self::Io(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
+
+/// Stream a multipart field chunk by chunk into a temp file next to `dest`,
+/// so memory stays flat whatever the file size. Gives up as soon as it grows
+/// past `max_bytes`. The caller renames the returned temp file into place;
+/// on error nothing is left behind.
+pub async fn stream_to_temp<S>(field: S, dest: &Path, max_bytes: usize) -> Result<PathBuf, UploadReadError>
+where
+    S: Stream<Item = Result<Bytes, MultipartError>> + Unpin,
+{
+    let name = dest.file_name().map(|n| n.to_string_lossy()).unwrap_or_default();
+    let tmp = dest.with_file_name(format!(".{}.{}.part", name, uuid::Uuid::new_v4().simple()));
+    let result = write_limited(field, &tmp, max_bytes).await;
+    if result.is_err() {
+        let _ = tokio::fs::remove_file(&tmp).await;
+    }
+    result.map(|_| tmp)
+}
+
+async fn write_limited<S>(mut field: S, path: &Path, max_bytes: usize) -> Result<(), UploadReadError>
+where
+    S: Stream<Item = Result<Bytes, MultipartError>> + Unpin,
+{
+    let mut file = tokio::fs::File::create(path).await?;
+    let mut written = 0;
+    while let Some(chunk) = field.next().await {
+        let chunk = chunk?;
+        written += chunk.len();
+        if written > max_bytes {
+            return Err(UploadReadError::TooLarge(max_bytes));
+        }
+        file.write_all(&chunk).await?;
+    }
+    file.flush().await?;
+    Ok(())
+}
+
+/// Uploaded file names must be a single plain component: no separators,
+/// no leading dot (hidden files, `..`), no control characters.
+pub fn validate_file_name(name: &str) -> Result<(), String> {
+    if name.is_empty() {
+        return Err("Missing file name".to_string());
+    }
+    if name.contains(['/', '\\']) {
+        return Err(format!("File name must not contain path separators: {:?}", name));
+    }
+    if name.starts_with('.') {
+        return Err(format!("File name must not start with a dot: {:?}", name));
+    }
+    if name.chars().any(char::is_control) {
+        return Err(format!("File name must not contain control characters: {:?}", name));
+    }
+    Ok(())
+}
+
+/// 拡張子から Content-Type を決める。不明なものは octet-stream
+pub fn content_type(path: &Path) -> &'static str {
+    let ext = path.extension()
+        .and_then(|e| e.to_str())
+        .map(|e| e.to_lowercase())
+        .unwrap_or_default();
+    match ext.as_str() {
+        "txt" | "rs" | "py" | "ts" | "toml" => "text/plain; charset=utf-8",
+        "md" => "text/markdown; charset=utf-8",
+        "html" | "htm" => "text/html; charset=utf-8",
+        "js" => "text/javascript; charset=utf-8",
+        "json" => "application/json",
+        "yaml" | "yml" => "application/yaml",
+        "csv" => "text/csv; charset=utf-8",
+        "pdf" => "application/pdf",
+        "docx" => "application/vnd.openxmlformats-officedocument.wordprocessingml.document",
+        "xlsx" => "application/vnd.openxmlformats-officedocument.spreadsheetml.sheet",
+        "pptx" => "application/vnd.openxmlformats-officedocument.presentationml.presentation",
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        _ => "application/octet-stream",
+    }
+}
+
+/// `attachment` with the file name percent-encoded (RFC 6266), since
+/// uploaded names are often Japanese.
+pub fn content_disposition(path: &Path) -> String {
+    let name = path.file_name().map(|n| n.to_string_lossy()).unwrap_or_default();
+    let encoded: String = name.bytes().map(|b| match b {
+        b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => (b as char).to_string(),
+        _ => format!("%{:02X}", b),
+    }).collect();
+    format!("attachment; filename*=UTF-8''{}", encoded)
+}
+
+/// Stream the file's bytes as a response body, without reading it into memory.
+pub async fn download(path: &Path) -> std::io::Result<(&'static str, Body)> {
+    let file = tokio::fs::File::open(path).await?;
+    Ok((content_type(path), Body::from_stream(ReaderStream::new(file))))
+}
+
+#[derive(Debug, thiserror::Error)]
+#[error("Diff is only available for text files")]
+pub struct NotTextFile;
+
+/// Unified diff from version `from` to version `to`, or to the current
+/// file when `to` is `None`.
+pub fn diff_versions(path: &Path, from: u32, to: Option<u32>) -> Result<String> {
+    let old_path = versioning::version_path(path, from)?;
+    let (new_path, new_label) = match to {
+        Some(v) => (versioning::version_path(path, v)?, format!("v{}", v)),
+        None => (path.to_path_buf(), "current".to_string()),
+    };
+    let old = read_text(&old_path).ok_or(NotTextFile)??;
+    let new = read_text(&new_path).ok_or(NotTextFile)??;
+
+    Ok(similar::TextDiff::from_lines(&old, &new)
+        .unified_diff()
+        .header(&format!("v{}", from), &new_label)
+        .to_string())
+}
+
+/// エディタ用にテキスト形式のファイルをデコードして返す。
+/// テキストでない形式は `None`
+pub fn read_text(path: &Path) -> Option<Result<String>> {
+    let ext = path.extension().and_then(|e| e.to_str())?;
+    match SupportedFormat::from_extension(ext)? {
+        SupportedFormat::PlainText | SupportedFormat::Html => Some(extractor::extract_plain_text(path)),
+        _ => None,
+    }
+}
+
+/// Move/rename a file or directory. A moved file takes its `.versions`
+/// history with it; a directory's sidecars move inside it anyway.
+pub fn move_path(from: &Path, to: &Path) -> Result<()> {
+    if to.starts_with(from) {
+        anyhow::bail!("Cannot move a directory into itself");
+    }
+    let is_file = from.is_file();
+    std::fs::rename(from, to)?;
+    if is_file {
+        if let Err(e) = versioning::move_versions(from, to) {
+            tracing::warn!("Failed to move versions of {}: {}", from.display(), e);
+        }
+    }
+    Ok(())
+}
+
+/// Copy a file. The copy starts with no version history, even if a stale
+/// `.versions` entry was left at the destination name.
+pub fn copy_file(from: &Path, to: &Path) -> Result<()> {
+    if !from.is_file() {
+        anyhow::bail!("Only files can be copied");
+    }
+    versioning::delete_versions(to)?;
+    std::fs::copy(from, to)?;
+    Ok(())
+}
+
+/// Overwrite an existing file, saving the prior content as a version first.
+/// Returns the version number the old content was saved under.
+pub fn update_file(path: &Path, content: &str, comment: &str) -> Result<u32> {
+    let version = versioning::save_version(path, comment)?;
+    std::fs::write(path, content)?;
+    Ok(version)
+}
+
+#[derive(Debug, thiserror::Error)]
+#[error("No saved versions to restore from")]
+pub struct NothingToRestore;
+
+/// Soft delete: keep the current content as a version, then remove the
+/// file. Its `.versions` history stays behind so `restore` can bring it back.
+pub fn soft_delete(path: &Path) -> Result<u32> {
+    let version = versioning::save_version(path, "Deleted")?;
+    std::fs::remove_file(path)?;
+    Ok(version)
+}
+
+/// Recreate a deleted file from its latest version. Returns that version.
+pub fn restore(path: &Path) -> Result<u32> {
+    if path.exists() {
+        anyhow::bail!("Already exists: {}", path.display());
+    }
+    let latest = versioning::read_version_meta(path)?
+        .versions.last()
+        .map(|v| v.version)
+        .ok_or(NothingToRestore)?;
+    std::fs::copy(versioning::version_path(path, latest)?, path)?;
+    Ok(latest)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_download_returns_file_bytes() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("manual.pdf");
+        let bytes: Vec<u8> = (0..=255u8).cycle().take(200_000).collect();
+        std::fs::write(&path, &bytes).unwrap();
+
+        let (content_type, body) = download(&path).await.unwrap();
+        assert_eq!(content_type, "application/pdf");
+        let downloaded = axum::body::to_bytes(body, usize::MAX).await.unwrap();
+        assert_eq!(downloaded.as_ref(), bytes.as_slice());
+
+        assert!(read_text(&path).is_none());
+        let text = dir.path().join("規程.md");
+        std::fs::write(&text, "# 就業規則\n").unwrap();
+        assert_eq!(read_text(&text).unwrap().unwrap(), "# 就業規則\n");
+        assert_eq!(content_disposition(&text), "attachment; filename*=UTF-8''%E8%A6%8F%E7%A8%8B.md");
+    }
+
+    #[test]
+    fn test_renamed_file_keeps_version_history() {
+        let dir = tempfile::tempdir().unwrap();
+        let from = dir.path().join("規程.txt");
+        std::fs::write(&from, "v1").unwrap();
+        versioning::save_version(&from, "first").unwrap();
+        std::fs::write(&from, "v2").unwrap();
+        versioning::save_version(&from, "second").unwrap();
+        std::fs::write(&from, "v3").unwrap();
+
+        std::fs::create_dir(dir.path().join("archive")).unwrap();
+        let to = dir.path().join("archive").join("旧規程.txt");
+        move_path(&from, &to).unwrap();
+
+        assert!(!from.exists());
+        let history = versioning::get_version_history(&to).unwrap();
+        let comments: Vec<&str> = history.versions.iter().map(|v| v.comment.as_str()).collect();
+        assert_eq!(comments, vec!["first", "second"]);
+        assert_eq!(versioning::version_count(&from), 0);
+        assert!(!dir.path().join(versioning::VERSIONS_DIR_NAME).exists());
+
+        versioning::rollback_to_version(&to, 1).unwrap();
+        assert_eq!(std::fs::read_to_string(&to).unwrap(), "v1");
+    }
+
+    #[test]
+    fn test_copied_file_starts_without_history() {
+        let dir = tempfile::tempdir().unwrap();
+        let template = dir.path().join("テンプレート.md");
+        std::fs::write(&template, "# 議事録\n").unwrap();
+        versioning::save_version(&template, "first").unwrap();
+
+        let copy = dir.path().join("議事録_0401.md");
+        copy_file(&template, &copy).unwrap();
+
+        assert_eq!(std::fs::read(&template).unwrap(), std::fs::read(&copy).unwrap());
+        assert_eq!(versioning::version_count(&template), 1);
+        assert_eq!(versioning::version_count(&copy), 0);
+        assert!(copy_file(dir.path(), &dir.path().join("dir_copy")).is_err());
+    }
+
+    #[test]
+    fn test_each_edit_records_a_version() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("手順書.md");
+        std::fs::write(&path, "初版").unwrap();
+
+        assert_eq!(update_file(&path, "第2版", "edit 1").unwrap(), 1);
+        assert_eq!(update_file(&path, "第3版", "edit 2").unwrap(), 2);
+
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "第3版");
+        let history = versioning::get_version_history(&path).unwrap();
+        assert_eq!(history.versions.len(), 2);
+        versioning::rollback_to_version(&path, 1).unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "初版");
+    }
+
+    #[test]
+    fn test_diff_between_versions_shows_changed_lines() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("規程.md");
+        std::fs::write(&path, "第1条 目的\n第2条 適用範囲\n").unwrap();
+        update_file(&path, "第1条 目的\n第2条 対象者\n第3条 休日\n", "v1").unwrap();
+        update_file(&path, "第1条 目的\n第3条 休日\n", "v2").unwrap();
+
+        let diff = diff_versions(&path, 1, Some(2)).unwrap();
+        assert!(diff.contains("--- v1\n+++ v2"), "{}", diff);
+        assert!(diff.contains("-第2条 適用範囲\n"), "{}", diff);
+        assert!(diff.contains("+第2条 対象者\n"), "{}", diff);
+        assert!(diff.contains("+第3条 休日\n"), "{}", diff);
+        assert!(!diff.contains(" 第3条"), "{}", diff);
+
+        let to_current = diff_versions(&path, 2, None).unwrap();
+        assert!(to_current.contains("-第2条 対象者\n"), "{}", to_current);
+
+        let err = diff_versions(&path, 9, None).unwrap_err();
+        assert!(err.downcast_ref::<versioning::VersionNotFound>().is_some());
+
+        let pdf = dir.path().join("manual.pdf");
+        std::fs::write(&pdf, b"%PDF-1.4").unwrap();
+        update_file(&pdf, "%PDF-1.5", "v1").unwrap();
+        assert!(diff_versions(&pdf, 1, None).unwrap_err().downcast_ref::<NotTextFile>().is_some());
+    }
+
+    #[test]
+    fn test_custom_comment_appears_in_history() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("就業規則.md");
+        std::fs::write(&path, "初版").unwrap();
+
+        update_file(&path, "改定版", "第5条の誤字を修正").unwrap();
+
+        let history = versioning::get_version_history(&path).unwrap();
+        assert_eq!(history.versions.len(), 1);
+        assert_eq!(history.versions[0].comment, "第5条の誤字を修正");
+    }
+
+    #[test]
+    fn test_deleted_file_can_be_restored() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("議事録.md");
+        std::fs::write(&path, "初版").unwrap();
+        update_file(&path, "最新版", "edit").unwrap();
+
+        soft_delete(&path).unwrap();
+        assert!(!path.exists());
+
+        assert_eq!(restore(&path).unwrap(), 2);
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "最新版");
+        assert!(restore(&path).is_err());
+
+        let never_saved = dir.path().join("new.md");
+        assert!(restore(&never_saved).unwrap_err().downcast_ref::<NothingToRestore>().is_some());
+    }
+
+    /// Minimal upload route: each field is streamed via `stream_to_temp`
+    /// and renamed to its file name in `dir`.
+    fn upload_app(dir: PathBuf, limits: UploadLimits) -> axum::Router {
+        use axum::extract::{DefaultBodyLimit, Multipart};
+
+        axum::Router::new()
+            .route("/upload", axum::routing::post(move |mut multipart: Multipart| async move {
+                while let Some(field) = multipart.next_field().await.map_err(|e| e.status())? {
+                    let dest = dir.join(field.file_name().unwrap_or_default());
+                    let tmp = stream_to_temp(field, &dest, limits.max_file_bytes).await.map_err(|e| e.status())?;
+                    std::fs::rename(tmp, dest).map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+                }
+                Ok::<_, StatusCode>(())
+            }))
+            .layer(DefaultBodyLimit::max(limits.max_request_bytes))
+    }
+
+    fn multipart_request(files: &[(&str, &[u8])]) -> axum::http::Request<Body> {
+        let mut body = Vec::new();
+        for (name, data) in files {
+            body.extend_from_slice(format!(
+                "--XX\r\nContent-Disposition: form-data; name=\"files\"; filename=\"{}\"\r\n\r\n", name,
+            ).as_bytes());
+            body.extend_from_slice(data);
+            body.extend_from_slice(b"\r\n");
+        }
+        body.extend_from_slice(b"--XX--\r\n");
+        axum::http::Request::post("/upload")
+            .header("content-type", "multipart/form-data; boundary=XX")
+            .body(Body::from(body))
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_upload_over_limit_is_rejected() {
+        use tower::ServiceExt;
+
+        let dir = tempfile::tempdir().unwrap();
+        let limits = UploadLimits { max_file_bytes: 1024, max_request_bytes: 4096 };
+        let app = upload_app(dir.path().to_path_buf(), limits);
+
+        let ok = app.clone().oneshot(multipart_request(&[("ok.txt", &[b'a'; 1024])])).await.unwrap();
+        assert_eq!(ok.status(), StatusCode::OK);
+        let over_file = app.clone().oneshot(multipart_request(&[("big.txt", &[b'a'; 1025])])).await.unwrap();
+        assert_eq!(over_file.status(), StatusCode::PAYLOAD_TOO_LARGE);
+        let parts: Vec<(&str, &[u8])> = vec![("part.txt", &[b'a'; 1000]); 5];
+        let over_request = app.oneshot(multipart_request(&parts)).await.unwrap();
+        assert_eq!(over_request.status(), StatusCode::PAYLOAD_TOO_LARGE);
+
+        // Rejected uploads leave no temp files behind
+        let names: Vec<String> = std::fs::read_dir(dir.path()).unwrap()
+            .map(|e| e.unwrap().file_name().to_string_lossy().to_string())
+            .collect();
+        assert!(names.iter().all(|n| !n.ends_with(".part")), "{:?}", names);
+        assert!(!dir.path().join("big.txt").exists());
+    }
+
+    #[tokio::test]
+    async fn test_large_upload_streams_to_disk() {
+        use tower::ServiceExt;
+
+        let dir = tempfile::tempdir().unwrap();
+        let data: Vec<u8> = (0..8 * 1024 * 1024u32).map(|i| (i % 251) as u8).collect();
+        let app = upload_app(dir.path().to_path_buf(), UploadLimits::default());
+
+        let response = app.oneshot(multipart_request(&[("large.pdf", &data)])).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(std::fs::read(dir.path().join("large.pdf")).unwrap(), data);
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn test_unsafe_upload_names_are_refused() {
+        for name in ["../escape.txt", "..\\escape.txt", "docs/a.txt", ".ragignore", "a\0b.txt", "改行\n.txt", ""] {
+            assert!(validate_file_name(name).is_err(), "{:?} should be refused", name);
+        }
+        assert!(validate_file_name("就業規則 2024.v2.pdf").is_ok());
+    }
+}
@@ -0,0 +1,95 @@
+use futures::Stream;
+use serde::Serialize;
+use tokio::sync::broadcast::{self, error::RecvError};
+
+/// Default number of buffered progress events per subscriber.
+pub const DEFAULT_CAPACITY: usize = 256;
+
+/// Progress of an indexing run, broadcast to SSE clients.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum IndexEvent {
+    Started { total_files: usize },
+    FileIndexed { path: String, chunks: usize },
+    /// mtime and size unchanged since the last successful run
+    FileSkipped { path: String },
+    FileFailed { path: String, error: String },
+    Finished { total_files: usize, total_chunks: usize },
+}
+
+/// What a subscriber sees: an event, or notice that it fell behind and
+/// `missed` events were dropped (the client should resync from status).
+#[derive(Debug, Clone, PartialEq)]
+pub enum IndexStreamItem {
+    Event(IndexEvent),
+    Lagged { missed: u64 },
+}
+
+/// Bounded broadcast of index progress. Publishing never waits on
+/// subscribers: a slow one loses its oldest events instead of stalling
+/// the indexer.
+pub struct IndexEvents {
+    sender: broadcast::Sender<IndexEvent>,
+}
+
+impl IndexEvents {
+    pub fn new(capacity: usize) -> Self {
+        let (sender, _) = broadcast::channel(capacity.max(1));
+        Self { sender }
+    }
+
+    pub fn publish(&self, event: IndexEvent) {
+        // Err only means nobody is listening
+        let _ = self.sender.send(event);
+    }
+
+    pub fn subscribe(&self) -> impl Stream<Item = IndexStreamItem> + Send + 'static {
+        futures::stream::unfold(self.sender.subscribe(), |mut rx| async move {
+            match rx.recv().await {
+                Ok(event) => Some((IndexStreamItem::Event(event), rx)),
+                Err(RecvError::Lagged(missed)) => Some((IndexStreamItem::Lagged { missed }, rx)),
+                Err(RecvError::Closed) => None,
+            }
+        })
+    }
+}
+
+impl Default for IndexEvents {
+    fn default() -> Self {
+        Self::new(DEFAULT_CAPACITY)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::StreamExt;
+
+    fn indexed(i: usize) -> IndexEvent {
+        IndexEvent::FileIndexed { path: format!("file{}.txt", i), chunks: 1 }
+    }
+
+    #[tokio::test]
+    async fn test_slow_subscriber_gets_lag_notice() {
+        let events = IndexEvents::new(4);
+        let stream = events.subscribe();
+        futures::pin_mut!(stream);
+
+        // The producer runs ahead of a subscriber that hasn't read anything
+        for i in 0..20 {
+            events.publish(indexed(i));
+        }
+
+        assert_eq!(stream.next().await, Some(IndexStreamItem::Lagged { missed: 16 }));
+        for i in 16..20 {
+            assert_eq!(stream.next().await, Some(IndexStreamItem::Event(indexed(i))));
+        }
+    }
+
+    #[test]
+    fn test_publish_without_subscribers() {
+        let events = IndexEvents::new(1);
+        events.publish(IndexEvent::Started { total_files: 3 });
+        events.publish(IndexEvent::Finished { total_files: 3, total_chunks: 9 });
+    }
+}
@@ -1,22 +1,27 @@
-use std::collections::HashSet;
+use std::any::Any;
+use std::collections::{HashMap, HashSet};
+use std::future::Future;
 use std::path::{Path, PathBuf};
 use std::sync::Arc;
 use std::time::Duration;
 
 use anyhow::Result;
 use chrono::{DateTime, Utc};
-use futures::FutureExt;
+use futures::{FutureExt, Stream, StreamExt};
 use serde::{Deserialize, Serialize};
 use sha2::{Sha256, Digest};
 use tokio::sync::Mutex;
 
 use crate::indexer::walker::{walk_directory, SupportedFormat};
 use crate::indexer::extractor::extract_text;
-use crate::indexer::chunker::chunk_text;
-use crate::models::{FileInfo, DirEntry};
+use crate::indexer::chunker::{ChunkBy, ChunkSettings, TextChunk};
+use crate::models::{FileInfo, DirEntry, FileError, FileSearchHit, FileChunk};
+use crate::telemetry;
 use super::embeddings::EmbeddingGenerator;
-use super::vector_store::VectorStore;
+use super::vector_store::{StagingIsOnlyCopy, VectorStore};
 use super::versioning;
+use super::index_events::{IndexEvent, IndexEvents, IndexStreamItem};
+use super::index_state::{self, FileFingerprint, IndexState};
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct IndexStatus {
@@ -24,25 +29,359 @@ pub struct IndexStatus {
     pub last_indexed_at: Option<DateTime<Utc>>,
     pub total_files: usize,
     pub total_chunks: usize,
-    pub failed_files: Vec<String>,
+    pub failed_files: Vec<FileError>,
+    /// Files not re-embedded: mtime/size unchanged, or every chunk matched
+    /// its stored content hash.
+    #[serde(default)]
+    pub skipped_files: usize,
+    /// Files with at least one new or changed chunk.
+    #[serde(default)]
+    pub reindexed_files: usize,
+    /// Files cut off at `max_chunks_per_file`, with how much was kept.
+    #[serde(default)]
+    pub truncated_files: Vec<String>,
     pub auto_index_interval_minutes: u64,
+    /// Chunk size/overlap used from the next run on (units per `ChunkBy`).
+    pub chunk_size: usize,
+    pub chunk_overlap: usize,
     pub last_error: Option<String>,
+    /// Progress of the current run (reset when a run starts).
+    #[serde(default)]
+    pub current_file: Option<String>,
+    #[serde(default)]
+    pub files_processed: usize,
+    #[serde(default)]
+    pub files_total: usize,
 }
 
+impl IndexStatus {
+    fn reset_progress(&mut self, files_total: usize) {
+        self.current_file = None;
+        self.files_processed = 0;
+        self.files_total = files_total;
+    }
+
+    fn start_file(&mut self, name: &str) {
+        self.current_file = Some(name.to_string());
+    }
+
+    fn finish_file(&mut self) {
+        self.current_file = None;
+        self.files_processed += 1;
+    }
+
+    /// Update either value; the result must keep overlap below the size.
+    fn set_chunking(&mut self, chunk_size: Option<usize>, chunk_overlap: Option<usize>) -> Result<()> {
+        let size = chunk_size.unwrap_or(self.chunk_size);
+        let overlap = chunk_overlap.unwrap_or(self.chunk_overlap);
+        if size == 0 {
+            anyhow::bail!("chunk_size must be greater than 0");
+        }
+        if overlap >= size {
+            anyhow::bail!("chunk_overlap ({}) must be smaller than chunk_size ({})", overlap, size);
+        }
+        self.chunk_size = size;
+        self.chunk_overlap = overlap;
+        Ok(())
+    }
+
+    fn chunk_settings(&self, chunk_by: ChunkBy) -> ChunkSettings {
+        ChunkSettings { chunk_by, max_chunk_size: self.chunk_size, overlap: self.chunk_overlap }
+    }
+}
+
+/// What to do with a file that produces more chunks than the cap.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChunkOverflow {
+    /// Index the first N chunks and note the truncation in status.
+    Truncate,
+    /// Skip the file and report it as failed.
+    Fail,
+}
+
+/// Upper bound on chunks indexed per file, to keep one huge document from
+/// dominating the index.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ChunkLimit {
+    pub max_chunks_per_file: usize,
+    pub on_overflow: ChunkOverflow,
+}
+
+impl ChunkLimit {
+    /// `RAG_MAX_CHUNKS_PER_FILE=2000` enables the cap;
+    /// `RAG_CHUNK_OVERFLOW=fail` rejects oversized files instead of truncating them.
+    pub fn from_env() -> Option<Self> {
+        let max_chunks_per_file = std::env::var("RAG_MAX_CHUNKS_PER_FILE").ok()?.parse().ok()?;
+        let on_overflow = match std::env::var("RAG_CHUNK_OVERFLOW").as_deref() {
+            Ok("fail") => ChunkOverflow::Fail,
+            _ => ChunkOverflow::Truncate,
+        };
+        Some(Self { max_chunks_per_file, on_overflow })
+    }
+
+    /// Apply the cap to a file's chunks. Returns the kept chunks and,
+    /// when truncated, the original chunk count.
+    fn apply(&self, mut chunks: Vec<TextChunk>) -> Result<(Vec<TextChunk>, Option<usize>)> {
+        let total = chunks.len();
+        if total <= self.max_chunks_per_file {
+            return Ok((chunks, None));
+        }
+        match self.on_overflow {
+            ChunkOverflow::Truncate => {
+                chunks.truncate(self.max_chunks_per_file);
+                Ok((chunks, Some(total)))
+            }
+            ChunkOverflow::Fail => anyhow::bail!(
+                "File produced {} chunks, exceeding max_chunks_per_file ({})",
+                total, self.max_chunks_per_file
+            ),
+        }
+    }
+}
+
+/// Result of indexing one file.
+struct ProcessedFile {
+    chunk_ids: Vec<String>,
+    truncated_from: Option<usize>,
+    /// Chunks that were (re-)embedded; 0 means the file was unchanged.
+    embedded_chunks: usize,
+}
+
+enum FileOutcome {
+    /// mtime and size unchanged since the last successful run
+    Unchanged { fingerprint: FileFingerprint, chunks: usize },
+    Processed { fingerprint: Option<FileFingerprint>, processed: ProcessedFile },
+}
+
+/// 同時に処理するファイル数のデフォルト
+pub const DEFAULT_CONCURRENCY: usize = 4;
+
+fn panic_message(panic: &(dyn Any + Send)) -> Option<&str> {
+    panic.downcast_ref::<&str>().copied()
+        .or_else(|| panic.downcast_ref::<String>().map(String::as_str))
+}
+
+/// Drive `jobs` with at most `concurrency` in flight, yielding results as
+/// they finish. A panic in one job becomes that item's error instead of
+/// aborting the rest.
+fn run_concurrently<T, R, Fut>(
+    jobs: Vec<(T, Fut)>,
+    concurrency: usize,
+) -> impl Stream<Item = (T, Result<R>)>
+where
+    Fut: Future<Output = Result<R>>,
+{
+    futures::stream::iter(jobs)
+        .map(|(item, job)| async move {
+            let result = std::panic::AssertUnwindSafe(job).catch_unwind().await.unwrap_or_else(|panic| {
+                let msg = panic_message(&*panic).unwrap_or("unknown error");
+                Err(anyhow::anyhow!("Panicked while indexing: {}", msg))
+            });
+            (item, result)
+        })
+        .buffer_unordered(concurrency.max(1))
+}
+
+/// A chunk that has to be embedded and upserted.
+struct PendingChunk<'a> {
+    id: String,
+    content_hash: String,
+    chunk: &'a TextChunk,
+}
+
+/// Offsets are part of the hash so a chunk whose text is unchanged but
+/// moved within the file still gets its stored offsets updated.
+fn content_hash(chunk: &TextChunk) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(format!("{}:{}:", chunk.start_offset, chunk.end_offset).as_bytes());
+    hasher.update(chunk.text.as_bytes());
+    hex::encode(hasher.finalize())
+}
+
+/// Qdrant only accepts UUID (or integer) point IDs, so the chunk key
+/// `{file_id}_{chunk_index}` is hashed into a UUID. It is stable across runs,
+/// which lets unchanged chunks be found again by ID. The file a point belongs
+/// to is stored as `metadata.file_id`; the ID itself is never parsed.
+pub fn chunk_id(path_id: &str, chunk_index: usize) -> String {
+    let digest = Sha256::digest(format!("{}_{}", path_id, chunk_index).as_bytes());
+    let mut bytes = [0u8; 16];
+    bytes.copy_from_slice(&digest[..16]);
+    uuid::Builder::from_custom_bytes(bytes).into_uuid().to_string()
+}
+
+/// Chunks whose stored content hash is missing or differs.
+fn changed_chunks<'a>(
+    path_id: &str,
+    chunks: &'a [TextChunk],
+    stored: &HashMap<String, String>,
+) -> Vec<PendingChunk<'a>> {
+    chunks.iter()
+        .map(|chunk| PendingChunk {
+            id: chunk_id(path_id, chunk.chunk_index),
+            content_hash: content_hash(chunk),
+            chunk,
+        })
+        .filter(|p| stored.get(&p.id) != Some(&p.content_hash))
+        .collect()
+}
+
+fn truncation_note(name: &str, kept: usize, total: usize) -> String {
+    format!("{}: indexed first {} of {} chunks", name, kept, total)
+}
+
+/// Another run already holds the indexer; callers map this to 409.
+#[derive(Debug, thiserror::Error)]
+#[error("Indexing already in progress")]
+pub struct IndexingInProgress;
+
 pub struct IndexManager {
     status: Mutex<IndexStatus>,
     upload_dir: PathBuf,
     embeddings: Arc<EmbeddingGenerator>,
     vector_store: Arc<VectorStore>,
+    /// Full paths of the files that failed in the most recent run,
+    /// kept so they can be retried without a full reindex.
+    last_failed_paths: Mutex<Vec<PathBuf>>,
+    chunk_limit: Option<ChunkLimit>,
+    /// Sizes live in `IndexStatus` so they can be changed at runtime.
+    chunk_by: ChunkBy,
+    events: IndexEvents,
+    /// Build full reindexes in a staging collection and swap it in on success.
+    staged_reindex: bool,
+    concurrency: usize,
 }
 
-fn file_id(path: &Path) -> String {
+pub fn file_id(path: &Path) -> String {
     let mut hasher = Sha256::new();
     hasher.update(path.to_string_lossy().as_bytes());
     let result = hasher.finalize();
     hex::encode(&result[..8])
 }
 
+/// Path relative to the upload dir, so files sharing a basename in
+/// different subdirectories stay distinguishable in status output.
+fn relative_path(base: &Path, path: &Path) -> String {
+    path.strip_prefix(base)
+        .unwrap_or(path)
+        .to_string_lossy()
+        .to_string()
+}
+
+/// Sort and dedup a file list for stable status output.
+fn sorted_unique(mut names: Vec<String>) -> Vec<String> {
+    names.sort();
+    names.dedup();
+    names
+}
+
+/// Failure entry for status; `{:#}` keeps the whole context chain
+/// (e.g. "PDF extraction failed: ...").
+fn file_error(name: String, error: &anyhow::Error) -> FileError {
+    FileError { name, reason: format!("{:#}", error) }
+}
+
+/// Sort by name and keep one entry per file.
+fn sorted_unique_errors(mut errors: Vec<FileError>) -> Vec<FileError> {
+    errors.sort_by(|a, b| a.name.cmp(&b.name));
+    errors.dedup_by(|a, b| a.name == b.name);
+    errors
+}
+
+/// Files from a previous failure list that are still present and indexable.
+/// Files deleted or renamed since the last run are dropped.
+fn retry_targets(paths: &[PathBuf]) -> Vec<(PathBuf, SupportedFormat)> {
+    paths.iter()
+        .filter(|p| p.is_file())
+        .filter_map(|p| {
+            let ext = p.extension()?.to_str()?;
+            let format = SupportedFormat::from_extension(ext)?;
+            Some((p.clone(), format))
+        })
+        .collect()
+}
+
+/// Indexable files under `base` whose name contains `query`, ignoring case.
+/// Filename lookup only; content search goes through the vector store.
+fn search_files(base: &Path, query: &str) -> Vec<FileSearchHit> {
+    let needle = query.trim().to_lowercase();
+    if needle.is_empty() {
+        return Vec::new();
+    }
+    let mut hits: Vec<FileSearchHit> = walk_directory(base).into_iter()
+        .filter_map(|(path, format)| {
+            let name = path.file_name()?.to_string_lossy().to_string();
+            if !name.to_lowercase().contains(&needle) {
+                return None;
+            }
+            let metadata = std::fs::metadata(&path).ok()?;
+            let vc = versioning::version_count(&path);
+            Some(FileSearchHit {
+                path: relative_path(base, &path),
+                entry: DirEntry {
+                    name,
+                    is_dir: false,
+                    size: Some(metadata.len()),
+                    format: Some(format!("{:?}", format)),
+                    modified_at: metadata.modified().ok().map(|t| t.into()),
+                    version_count: if vc > 0 { Some(vc) } else { None },
+                },
+            })
+        })
+        .collect();
+    hits.sort_by(|a, b| a.path.cmp(&b.path));
+    hits
+}
+
+/// Canonicalize `relative` under `base`. Symlinks are resolved first, so a
+/// link pointing outside `base` is rejected like `..` would be.
+fn resolve_within(base: &Path, relative: &str) -> Result<PathBuf, String> {
+    if relative.is_empty() {
+        return Ok(base.to_path_buf());
+    }
+    let canonical = base.join(relative).canonicalize()
+        .map_err(|e| format!("Invalid path: {}", e))?;
+    let base = base.canonicalize()
+        .map_err(|e| format!("Upload dir error: {}", e))?;
+    if !canonical.starts_with(&base) {
+        return Err("Path traversal not allowed".to_string());
+    }
+    Ok(canonical)
+}
+
+/// Like `resolve_within` for a path that may not exist yet. The parent must
+/// exist inside `base`, and an existing entry at the target (including a
+/// dangling symlink, which `exists()` reports as missing) must not lead out.
+fn resolve_new_within(base: &Path, relative: &str) -> Result<PathBuf, String> {
+    if relative.is_empty() {
+        return Err("Path cannot be empty".to_string());
+    }
+    // Reject obvious traversal attempts
+    if relative.contains("..") {
+        return Err("Path traversal not allowed".to_string());
+    }
+    let target = base.join(relative);
+    let name = target.file_name()
+        .ok_or_else(|| "Invalid path".to_string())?
+        .to_owned();
+    let canonical_base = base.canonicalize()
+        .map_err(|e| format!("Upload dir error: {}", e))?;
+    let parent = target.parent().unwrap_or(base).canonicalize()
+        .map_err(|e| format!("Parent directory does not exist: {}", e))?;
+    if !parent.starts_with(&canonical_base) {
+        return Err("Path traversal not allowed".to_string());
+    }
+    let resolved = parent.join(name);
+    if resolved.symlink_metadata().is_ok_and(|m| m.file_type().is_symlink()) {
+        let escapes = resolved.canonicalize()
+            .map(|real| !real.starts_with(&canonical_base))
+            .unwrap_or(true);
+        if escapes {
+            return Err("Path traversal not allowed".to_string());
+        }
+    }
+    Ok(resolved)
+}
+
 impl IndexManager {
     pub fn new(
         upload_dir: PathBuf,
@@ -57,19 +396,75 @@ impl IndexManager {
                 total_files: 0,
                 total_chunks: 0,
                 failed_files: Vec::new(),
+                skipped_files: 0,
+                reindexed_files: 0,
+                truncated_files: Vec::new(),
                 auto_index_interval_minutes: interval_minutes,
+                chunk_size: ChunkSettings::default().max_chunk_size,
+                chunk_overlap: ChunkSettings::default().overlap,
                 last_error: None,
+                current_file: None,
+                files_processed: 0,
+                files_total: 0,
             }),
-            upload_dir,
+            // Walked paths and paths resolved from requests must share a prefix
+            upload_dir: upload_dir.canonicalize().unwrap_or(upload_dir),
             embeddings,
             vector_store,
+            last_failed_paths: Mutex::new(Vec::new()),
+            chunk_limit: None,
+            chunk_by: ChunkBy::default(),
+            events: IndexEvents::default(),
+            staged_reindex: false,
+            concurrency: DEFAULT_CONCURRENCY,
         }
     }
 
+    /// Number of files embedded/upserted at the same time.
+    pub fn with_concurrency(mut self, concurrency: usize) -> Self {
+        self.concurrency = concurrency.max(1);
+        self
+    }
+
+    pub fn with_staged_reindex(mut self, enabled: bool) -> Self {
+        self.staged_reindex = enabled;
+        self
+    }
+
+    /// Buffer size of the progress broadcast; slower SSE clients are lagged.
+    pub fn with_event_capacity(mut self, capacity: usize) -> Self {
+        self.events = IndexEvents::new(capacity);
+        self
+    }
+
+    pub fn subscribe_events(&self) -> impl futures::Stream<Item = IndexStreamItem> + Send + 'static {
+        self.events.subscribe()
+    }
+
+    pub fn with_chunk_limit(mut self, limit: ChunkLimit) -> Self {
+        self.chunk_limit = Some(limit);
+        self
+    }
+
+    pub fn with_chunking(mut self, chunking: ChunkSettings) -> Self {
+        self.chunk_by = chunking.chunk_by;
+        let status = self.status.get_mut();
+        status.chunk_size = chunking.max_chunk_size;
+        status.chunk_overlap = chunking.overlap;
+        self
+    }
+
     pub fn upload_dir(&self) -> &Path {
         &self.upload_dir
     }
 
+    /// `file_id` of `path` relative to the upload dir, so a walked path and
+    /// the canonical path resolved from a request map to the same points.
+    pub fn file_id_of(&self, path: &Path) -> String {
+        let real = path.canonicalize().unwrap_or_else(|_| path.to_path_buf());
+        file_id(Path::new(&relative_path(&self.upload_dir, &real)))
+    }
+
     pub async fn get_status(&self) -> IndexStatus {
         self.status.lock().await.clone()
     }
@@ -78,6 +473,11 @@ impl IndexManager {
         self.status.lock().await.auto_index_interval_minutes = minutes;
     }
 
+    /// Takes effect on the next run; rejected if overlap >= size.
+    pub async fn set_chunking(&self, chunk_size: Option<usize>, chunk_overlap: Option<usize>) -> Result<()> {
+        self.status.lock().await.set_chunking(chunk_size, chunk_overlap)
+    }
+
     pub async fn is_indexing(&self) -> bool {
         self.status.lock().await.is_indexing
     }
@@ -100,44 +500,13 @@ impl IndexManager {
     /// Resolve a relative path safely, ensuring it stays within upload_dir.
     /// For paths that don't exist yet (mkdir/create), use `safe_resolve_new`.
     pub fn safe_resolve(&self, relative: &str) -> Result<PathBuf, String> {
-        if relative.is_empty() {
-            return Ok(self.upload_dir.clone());
-        }
-        let joined = self.upload_dir.join(relative);
-        let canonical = joined.canonicalize()
-            .map_err(|e| format!("Invalid path: {}", e))?;
-        let base = self.upload_dir.canonicalize()
-            .map_err(|e| format!("Upload dir error: {}", e))?;
-        if !canonical.starts_with(&base) {
-            return Err("Path traversal not allowed".to_string());
-        }
-        Ok(canonical)
+        resolve_within(&self.upload_dir, relative)
     }
 
     /// Resolve a path that may not exist yet (for mkdir/create file).
     /// Validates the parent exists and is within upload_dir.
     pub fn safe_resolve_new(&self, relative: &str) -> Result<PathBuf, String> {
-        if relative.is_empty() {
-            return Err("Path cannot be empty".to_string());
-        }
-        // Reject obvious traversal attempts
-        if relative.contains("..") {
-            return Err("Path traversal not allowed".to_string());
-        }
-        let target = self.upload_dir.join(relative);
-        // Verify the parent directory exists and is within upload_dir
-        if let Some(parent) = target.parent() {
-            if parent != self.upload_dir {
-                let parent_canonical = parent.canonicalize()
-                    .map_err(|e| format!("Parent directory does not exist: {}", e))?;
-                let base = self.upload_dir.canonicalize()
-                    .map_err(|e| format!("Upload dir error: {}", e))?;
-                if !parent_canonical.starts_with(&base) {
-                    return Err("Path traversal not allowed".to_string());
-                }
-            }
-        }
-        Ok(target)
+        resolve_new_within(&self.upload_dir, relative)
     }
 
     /// List entries (files + directories) at a specific path level.
@@ -163,8 +532,8 @@ impl IndexManager {
             let name = entry.file_name().to_string_lossy().to_string();
             let is_dir = metadata.is_dir();
 
-            // Skip .versions directory
-            if versioning::is_versions_dir(&name) {
+            // Skip .versions directory and the index state sidecar
+            if versioning::is_versions_dir(&name) || name == index_state::STATE_FILE_NAME {
                 continue;
             }
 
@@ -204,112 +573,216 @@ impl IndexManager {
         Ok(entries)
     }
 
+    pub fn search_files(&self, query: &str) -> Vec<FileSearchHit> {
+        search_files(&self.upload_dir, query)
+    }
+
+    /// Chunks currently stored for `path`, in chunk order.
+    pub async fn file_chunks(&self, path: &Path) -> Result<Vec<FileChunk>> {
+        let mut chunks: Vec<FileChunk> = self.vector_store.file_points(&self.file_id_of(path)).await?
+            .into_iter()
+            .map(|point| FileChunk {
+                chunk_index: point.metadata["chunk_index"].as_u64().unwrap_or_default() as usize,
+                id: point.id,
+                text: point.text,
+            })
+            .collect();
+        chunks.sort_by_key(|c| c.chunk_index);
+        Ok(chunks)
+    }
+
+    pub async fn failed_paths(&self) -> Vec<PathBuf> {
+        self.last_failed_paths.lock().await.clone()
+    }
+
     pub async fn run_index(&self) -> Result<()> {
+        let result = self.run_exclusive(self.do_index()).await;
+        telemetry::record_index_run(result.is_ok());
+        result
+    }
+
+    /// Re-process only the files that failed during the previous run.
+    pub async fn run_retry_failed(&self) -> Result<()> {
+        self.run_exclusive(self.do_retry_failed()).await
+    }
+
+    /// Reindex one file without walking the upload dir. Its existing chunks
+    /// are deleted first so a file that shrank leaves nothing stale behind.
+    /// Returns the number of chunks now stored for it.
+    pub async fn run_index_file(&self, path: &Path) -> Result<usize> {
+        self.run_exclusive(self.do_index_file(path)).await
+    }
+
+    async fn run_exclusive<T>(&self, job: impl Future<Output = Result<T>>) -> Result<T> {
         {
             let mut status = self.status.lock().await;
             if status.is_indexing {
-                anyhow::bail!("Indexing already in progress");
+                return Err(IndexingInProgress.into());
             }
             status.is_indexing = true;
             status.last_error = None;
-            status.failed_files.clear();
+            status.reset_progress(0);
         }
 
         // Use AssertUnwindSafe + catch_unwind to catch panics (e.g., from chunker)
         // so that is_indexing always resets to false
-        let result = std::panic::AssertUnwindSafe(self.do_index())
+        let result = std::panic::AssertUnwindSafe(job)
             .catch_unwind()
             .await;
 
         match result {
-            Ok(Ok(())) => {
+            Ok(Ok(value)) => {
                 let mut status = self.status.lock().await;
                 status.is_indexing = false;
                 status.last_indexed_at = Some(Utc::now());
                 status.last_error = None;
+                Ok(value)
             }
             Ok(Err(e)) => {
                 let error_msg = format!("Indexing error: {}", e);
                 tracing::error!("{}", error_msg);
                 let mut status = self.status.lock().await;
                 status.is_indexing = false;
-                status.last_error = Some(error_msg);
+                status.last_error = Some(error_msg.clone());
+                anyhow::bail!("{}", error_msg)
             }
             Err(panic_info) => {
-                let panic_msg = if let Some(s) = panic_info.downcast_ref::<&str>() {
-                    format!("Indexing panicked: {}", s)
-                } else if let Some(s) = panic_info.downcast_ref::<String>() {
-                    format!("Indexing panicked: {}", s)
-                } else {
-                    "Indexing panicked with unknown error".to_string()
+                let panic_msg = match panic_message(&*panic_info) {
+                    Some(s) => format!("Indexing panicked: {}", s),
+                    None => "Indexing panicked with unknown error".to_string(),
                 };
                 tracing::error!("{}", panic_msg);
                 let mut status = self.status.lock().await;
                 status.is_indexing = false;
-                status.last_error = Some(panic_msg);
+                status.last_error = Some(panic_msg.clone());
+                anyhow::bail!("{}", panic_msg)
             }
         }
-
-        // Return the original error if any
-        let status = self.status.lock().await;
-        if let Some(ref err) = status.last_error {
-            anyhow::bail!("{}", err);
-        }
-        Ok(())
     }
 
     async fn do_index(&self) -> Result<()> {
         let files = walk_directory(&self.upload_dir);
         tracing::info!("Indexing {} files from {}", files.len(), self.upload_dir.display());
+        self.events.publish(IndexEvent::Started { total_files: files.len() });
+        {
+            let mut status = self.status.lock().await;
+            status.failed_files.clear();
+            status.truncated_files.clear();
+            status.reset_progress(files.len());
+        }
+
+        let staging = if self.staged_reindex {
+            Some(self.vector_store.create_staging().await?)
+        } else {
+            None
+        };
+        let store = staging.as_ref().unwrap_or(&self.vector_store);
+
+        // Read once so every file in this run is split the same way
+        let chunking = self.chunk_settings().await;
+        // A staging collection starts empty, so every file has to be processed
+        let previous_state = if staging.is_some() {
+            IndexState::new(chunking)
+        } else {
+            IndexState::load_for(&self.upload_dir, chunking)
+        };
+        let mut next_state = IndexState::new(chunking);
 
         let mut success_count = 0usize;
         let mut total_chunks = 0usize;
         let mut failed_files = Vec::new();
+        let mut failed_paths = Vec::new();
+        let mut truncated_files = Vec::new();
+        let mut skipped_files = 0usize;
         let mut current_ids: HashSet<String> = HashSet::new();
 
         // Collect all file hashes for files on disk (including ones that fail)
         let existing_file_hashes: HashSet<String> = files.iter()
-            .map(|(path, _)| file_id(path))
+            .map(|(path, _)| self.file_id_of(path))
             .collect();
 
-        for (path, format) in &files {
-            match self.process_file(path, *format).await {
-                Ok(chunk_ids) => {
-                    current_ids.extend(chunk_ids.iter().cloned());
-                    total_chunks += chunk_ids.len();
-                    success_count += 1;
-                }
-                Err(e) => {
-                    tracing::warn!("Failed to index {}: {}", path.display(), e);
-                    failed_files.push(
-                        path.file_name()
-                            .map(|n| n.to_string_lossy().to_string())
-                            .unwrap_or_else(|| path.display().to_string())
-                    );
+        {
+            // Futures are created up front so the stream doesn't hold a closure
+            // borrowing `self` (which trips Send inference in `start_scheduler`)
+            let jobs: Vec<_> = files.iter()
+                .map(|file| (file, self.index_one(store, &previous_state, chunking, &file.0, file.1)))
+                .collect();
+            let results = run_concurrently(jobs, self.concurrency);
+            futures::pin_mut!(results);
+
+            while let Some(((path, _), result)) = results.next().await {
+                let name = relative_path(&self.upload_dir, path);
+                self.status.lock().await.finish_file();
+                match result {
+                    Ok(FileOutcome::Unchanged { fingerprint, chunks }) => {
+                        next_state.record(name.clone(), fingerprint, chunks);
+                        self.events.publish(IndexEvent::FileSkipped { path: name });
+                        total_chunks += chunks;
+                        skipped_files += 1;
+                        success_count += 1;
+                    }
+                    Ok(FileOutcome::Processed { fingerprint, processed }) => {
+                        if let Some(total) = processed.truncated_from {
+                            truncated_files.push(truncation_note(&name, processed.chunk_ids.len(), total));
+                        }
+                        if let Some(fingerprint) = fingerprint {
+                            next_state.record(name.clone(), fingerprint, processed.chunk_ids.len());
+                        }
+                        self.events.publish(IndexEvent::FileIndexed {
+                            path: name,
+                            chunks: processed.chunk_ids.len(),
+                        });
+                        if processed.embedded_chunks == 0 {
+                            skipped_files += 1;
+                        }
+                        current_ids.extend(processed.chunk_ids.iter().cloned());
+                        total_chunks += processed.chunk_ids.len();
+                        success_count += 1;
+                    }
+                    Err(e) => {
+                        tracing::warn!("Failed to index {}: {}", path.display(), e);
+                        let failure = file_error(name, &e);
+                        self.events.publish(IndexEvent::FileFailed {
+                            path: failure.name.clone(),
+                            error: failure.reason.clone(),
+                        });
+                        failed_files.push(failure);
+                        failed_paths.push(path.clone());
+                    }
                 }
             }
         }
+        *self.last_failed_paths.lock().await = failed_paths;
 
-        // Stale cleanup: delete points whose file no longer exists on disk
-        match self.vector_store.scroll_all_point_ids().await {
-            Ok(all_ids) => {
-                let stale_ids: Vec<String> = all_ids.into_iter()
-                    .filter(|id| {
-                        let file_hash = id.split('_').next().unwrap_or("");
-                        !existing_file_hashes.contains(file_hash)
-                    })
-                    .collect();
-
-                if !stale_ids.is_empty() {
-                    tracing::info!("Cleaning up {} stale points", stale_ids.len());
-                    if let Err(e) = self.vector_store.delete_points(stale_ids).await {
-                        tracing::error!("Failed to clean up stale points: {}", e);
-                    }
+        if let Some(staging) = staging {
+            // The staging collection only holds current files, so there is
+            // nothing stale to clean up; swapping it in replaces the old set.
+            // Documents added through the API aren't in the upload dir, so
+            // they are carried over first (as late as possible, so ones added
+            // during the run are included).
+            let promoted = match self.vector_store.copy_api_documents(&staging).await {
+                Ok(copied) => {
+                    tracing::info!("Copied {} API documents into {}", copied, staging.collection_name());
+                    self.vector_store.promote(&staging).await
                 }
+                Err(e) => Err(e.context("Failed to copy API documents into staging")),
+            };
+            if let Err(e) = promoted {
+                // After the old plain collection is dropped, staging is all that's left
+                if e.downcast_ref::<StagingIsOnlyCopy>().is_some() {
+                    tracing::error!("{}", e);
+                } else if let Err(discard_err) = staging.discard().await {
+                    tracing::error!("Failed to discard staging collection: {}", discard_err);
+                }
+                return Err(e);
             }
-            Err(e) => {
-                tracing::error!("Failed to scroll point IDs for cleanup: {}", e);
-            }
+        } else {
+            self.cleanup_stale(&existing_file_hashes).await;
+        }
+
+        // Failed files aren't recorded, so the next run retries them
+        if let Err(e) = next_state.save(&self.upload_dir) {
+            tracing::warn!("Failed to save index state: {}", e);
         }
 
         // Update status
@@ -317,42 +790,241 @@ impl IndexManager {
             let mut status = self.status.lock().await;
             status.total_files = success_count;
             status.total_chunks = total_chunks;
-            status.failed_files = failed_files;
+            status.failed_files = sorted_unique_errors(failed_files);
+            status.skipped_files = skipped_files;
+            status.reindexed_files = success_count - skipped_files;
+            status.truncated_files = sorted_unique(truncated_files);
+        }
+
+        tracing::info!("Indexing complete: {} files ({} unchanged), {} chunks",
+            success_count, skipped_files, total_chunks);
+        self.events.publish(IndexEvent::Finished { total_files: success_count, total_chunks });
+        Ok(())
+    }
+
+    /// Index one file unless its fingerprint matches the previous run.
+    async fn index_one(
+        &self,
+        store: &VectorStore,
+        previous_state: &IndexState,
+        chunking: ChunkSettings,
+        path: &Path,
+        format: SupportedFormat,
+    ) -> Result<FileOutcome> {
+        let name = relative_path(&self.upload_dir, path);
+        let fingerprint = FileFingerprint::of(path);
+        if let Some(fp) = fingerprint {
+            if let Some(chunks) = previous_state.unchanged(&name, fp) {
+                return Ok(FileOutcome::Unchanged { fingerprint: fp, chunks });
+            }
+        }
+
+        self.status.lock().await.start_file(&name);
+        let processed = self.process_file(store, path, format, chunking).await?;
+        Ok(FileOutcome::Processed { fingerprint, processed })
+    }
+
+    /// Delete points whose file no longer exists on disk.
+    async fn cleanup_stale(&self, existing_file_hashes: &HashSet<String>) {
+        let existing = existing_file_hashes.iter().cloned().collect();
+        if let Err(e) = self.vector_store.delete_stale_files(existing).await {
+            tracing::error!("Failed to clean up stale points: {}", e);
+        }
+    }
+
+    async fn do_retry_failed(&self) -> Result<()> {
+        let previous = self.last_failed_paths.lock().await.clone();
+        let targets = retry_targets(&previous);
+        tracing::info!("Retrying {} previously failed files", targets.len());
+        {
+            let mut status = self.status.lock().await;
+            status.failed_files.clear();
+            status.truncated_files.clear();
+            status.reset_progress(targets.len());
+        }
+
+        let mut success_count = 0usize;
+        let mut new_chunks = 0usize;
+        let mut failed_files = Vec::new();
+        let mut failed_paths = Vec::new();
+        let mut truncated_files = Vec::new();
+
+        let chunking = self.chunk_settings().await;
+        for (path, format) in &targets {
+            self.status.lock().await.start_file(&relative_path(&self.upload_dir, path));
+            let processed = self.process_file(&self.vector_store, path, *format, chunking).await;
+            self.status.lock().await.finish_file();
+            match processed {
+                Ok(processed) => {
+                    if let Some(total) = processed.truncated_from {
+                        let name = relative_path(&self.upload_dir, path);
+                        truncated_files.push(truncation_note(&name, processed.chunk_ids.len(), total));
+                    }
+                    new_chunks += processed.chunk_ids.len();
+                    success_count += 1;
+                }
+                Err(e) => {
+                    tracing::warn!("Retry failed for {}: {}", path.display(), e);
+                    failed_files.push(file_error(relative_path(&self.upload_dir, path), &e));
+                    failed_paths.push(path.clone());
+                }
+            }
         }
+        *self.last_failed_paths.lock().await = failed_paths;
 
-        tracing::info!("Indexing complete: {} files, {} chunks", success_count, total_chunks);
+        {
+            let mut status = self.status.lock().await;
+            status.total_files += success_count;
+            status.total_chunks += new_chunks;
+            status.failed_files = sorted_unique_errors(failed_files);
+            status.truncated_files = sorted_unique(truncated_files);
+        }
+
+        tracing::info!("Retry complete: {} recovered, {} still failing",
+            success_count, targets.len() - success_count);
         Ok(())
     }
 
-    async fn process_file(&self, path: &Path, format: SupportedFormat) -> Result<Vec<String>> {
+    async fn do_index_file(&self, path: &Path) -> Result<usize> {
+        let format = path.extension()
+            .and_then(|ext| ext.to_str())
+            .and_then(SupportedFormat::from_extension)
+            .ok_or_else(|| anyhow::anyhow!("Unsupported file type: {}", path.display()))?;
+        let name = relative_path(&self.upload_dir, path);
+        {
+            let mut status = self.status.lock().await;
+            status.reset_progress(1);
+            status.start_file(&name);
+        }
+
+        let removed = self.delete_file_chunks(path).await?;
+        let fingerprint = FileFingerprint::of(path);
+        let chunking = self.chunk_settings().await;
+        let processed = self.process_file(&self.vector_store, path, format, chunking).await;
+        self.status.lock().await.finish_file();
+        let processed = processed?;
+        let chunks = processed.chunk_ids.len();
+
+        // Record the new fingerprint so the next full run skips this file
+        if let Some(fingerprint) = fingerprint {
+            let mut state = IndexState::load_for(&self.upload_dir, chunking);
+            state.record(name.clone(), fingerprint, chunks);
+            if let Err(e) = state.save(&self.upload_dir) {
+                tracing::warn!("Failed to save index state: {}", e);
+            }
+        }
+
+        {
+            let mut status = self.status.lock().await;
+            status.total_chunks = status.total_chunks.saturating_sub(removed) + chunks;
+            status.failed_files.retain(|f| f.name != name);
+        }
+        self.last_failed_paths.lock().await.retain(|p| p != path);
+
+        tracing::info!("Reindexed {}: {} chunks (replaced {})", name, chunks, removed);
+        self.events.publish(IndexEvent::FileIndexed { path: name, chunks });
+        Ok(chunks)
+    }
+
+    /// Drop the chunks of a file that was deleted so search stops returning
+    /// it. Doesn't wait for a running index; if that run already read the
+    /// file, the next run's stale cleanup removes what it re-adds.
+    pub async fn remove_file_chunks(&self, path: &Path) -> Result<usize> {
+        let removed = self.delete_file_chunks(path).await?;
+        let mut status = self.status.lock().await;
+        status.total_chunks = status.total_chunks.saturating_sub(removed);
+        Ok(removed)
+    }
+
+    /// Delete every point stored for `path`; returns how many there were.
+    async fn delete_file_chunks(&self, path: &Path) -> Result<usize> {
+        let ids: Vec<String> = self.vector_store.file_points(&self.file_id_of(path)).await?
+            .into_iter()
+            .map(|point| point.id)
+            .collect();
+        let count = ids.len();
+        self.vector_store.delete_points(ids).await?;
+        Ok(count)
+    }
+
+    async fn chunk_settings(&self) -> ChunkSettings {
+        self.status.lock().await.chunk_settings(self.chunk_by)
+    }
+
+    /// Delete points of the file beyond `chunk_ids`, left over when it now
+    /// splits into fewer chunks (edited, or re-chunked with larger settings).
+    async fn delete_extra_chunks(store: &VectorStore, path_id: &str, chunk_ids: &[String]) -> Result<()> {
+        let current: HashSet<&String> = chunk_ids.iter().collect();
+        let extra: Vec<String> = store.file_points(path_id).await?
+            .into_iter()
+            .map(|point| point.id)
+            .filter(|id| !current.contains(id))
+            .collect();
+        store.delete_points(extra).await
+    }
+
+    async fn process_file(
+        &self,
+        store: &VectorStore,
+        path: &Path,
+        format: SupportedFormat,
+        chunking: ChunkSettings,
+    ) -> Result<ProcessedFile> {
+        let path_id = self.file_id_of(path);
         let text = extract_text(path, format)?;
         if text.trim().is_empty() {
-            return Ok(Vec::new());
+            Self::delete_extra_chunks(store, &path_id, &[]).await?;
+            return Ok(ProcessedFile { chunk_ids: Vec::new(), truncated_from: None, embedded_chunks: 0 });
         }
 
-        let chunks = chunk_text(&text, 1000, 200);
-        let path_id = file_id(path);
-        let mut chunk_ids = Vec::new();
+        let is_markdown = path.extension().is_some_and(|ext| ext.eq_ignore_ascii_case("md"));
+        let chunks = if is_markdown {
+            chunking.chunk_markdown(&text, &*self.embeddings)
+        } else {
+            chunking.chunk(&text, &*self.embeddings)
+        };
+        let (chunks, truncated_from) = match &self.chunk_limit {
+            Some(limit) => limit.apply(chunks)?,
+            None => (chunks, None),
+        };
+        if let Some(total) = truncated_from {
+            tracing::warn!("Truncating {} to {} of {} chunks", path.display(), chunks.len(), total);
+        }
+        let chunk_ids: Vec<String> = chunks.iter()
+            .map(|c| chunk_id(&path_id, c.chunk_index))
+            .collect();
+
+        // Skip chunks whose text is unchanged since they were last stored
+        let stored = store.stored_content_hashes(&chunk_ids).await.unwrap_or_else(|e| {
+            tracing::warn!("Couldn't read stored hashes for {}, re-embedding: {}", path.display(), e);
+            HashMap::new()
+        });
+        let pending = changed_chunks(&path_id, &chunks, &stored);
 
         let batch_size = 32;
-        for batch in chunks.chunks(batch_size) {
-            let texts: Vec<String> = batch.iter().map(|c| c.text.clone()).collect();
-            let embeddings_batch = self.embeddings.generate(texts)?;
-
-            for (chunk, embedding) in batch.iter().zip(embeddings_batch.into_iter()) {
-                let chunk_id = format!("{}_{}", path_id, chunk.chunk_index);
-                let metadata = serde_json::json!({
-                    "file_path": path.to_string_lossy(),
-                    "chunk_index": chunk.chunk_index,
-                    "format": format!("{:?}", format),
-                });
+        for batch in pending.chunks(batch_size) {
+            let texts: Vec<String> = batch.iter().map(|p| p.chunk.text.clone()).collect();
+            let embeddings_batch = self.embeddings.embed_documents(texts)?;
 
-                self.vector_store.add_document(&chunk_id, &chunk.text, embedding, metadata).await?;
-                chunk_ids.push(chunk_id);
-            }
+            let points = batch.iter().zip(embeddings_batch)
+                .map(|(pending, embedding)| {
+                    let metadata = serde_json::json!({
+                        "file_id": path_id,
+                        "file_path": path.to_string_lossy(),
+                        "chunk_index": pending.chunk.chunk_index,
+                        "start_offset": pending.chunk.start_offset,
+                        "end_offset": pending.chunk.end_offset,
+                        "format": format!("{:?}", format),
+                        "content_hash": pending.content_hash,
+                    });
+                    (pending.id.clone(), pending.chunk.text.clone(), embedding, metadata)
+                })
+                .collect();
+            store.add_documents(points).await?;
         }
+        Self::delete_extra_chunks(store, &path_id, &chunk_ids).await?;
 
-        Ok(chunk_ids)
+        Ok(ProcessedFile { chunk_ids, truncated_from, embedded_chunks: pending.len() })
     }
 
     pub fn start_scheduler(manager: Arc<Self>) {
@@ -374,3 +1046,549 @@ impl IndexManager {
         });
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::indexer::chunker::chunk_text;
+    use crate::rag::embeddings;
+    use crate::rag::files;
+    use crate::rag::vector_store;
+    use qdrant_client::qdrant::Distance;
+
+    #[test]
+    fn test_retry_targets_only_previously_failed_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let ok = dir.path().join("ok.txt");
+        let broken = dir.path().join("broken.pdf");
+        let removed = dir.path().join("removed.docx");
+        std::fs::write(&ok, "fine").unwrap();
+        std::fs::write(&broken, "not a pdf").unwrap();
+
+        let targets = retry_targets(&[broken.clone(), removed]);
+
+        assert_eq!(targets.len(), 1);
+        assert_eq!(targets[0].0, broken);
+        assert!(!targets.iter().any(|(p, _)| p == &ok));
+    }
+
+    #[test]
+    fn test_failed_files_keep_subdirectories_distinct() {
+        let base = Path::new("/uploads");
+        let failed = vec![
+            relative_path(base, Path::new("/uploads/b/report.pdf")),
+            relative_path(base, Path::new("/uploads/a/report.pdf")),
+            relative_path(base, Path::new("/uploads/a/report.pdf")),
+        ];
+
+        assert_eq!(sorted_unique(failed), vec!["a/report.pdf", "b/report.pdf"]);
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_run_processes_every_file() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        let in_flight = AtomicUsize::new(0);
+        let max_in_flight = AtomicUsize::new(0);
+        let files: Vec<usize> = (0..10).collect();
+
+        let jobs: Vec<_> = files.into_iter()
+            .map(|i| {
+                let in_flight = &in_flight;
+                let max_in_flight = &max_in_flight;
+                (i, async move {
+                    let now = in_flight.fetch_add(1, Ordering::SeqCst) + 1;
+                    max_in_flight.fetch_max(now, Ordering::SeqCst);
+                    tokio::time::sleep(Duration::from_millis(20)).await;
+                    in_flight.fetch_sub(1, Ordering::SeqCst);
+                    if i == 4 {
+                        panic!("corrupt file {}", i);
+                    }
+                    Ok(i * 10)
+                })
+            })
+            .collect();
+        let results: Vec<(usize, Result<usize>)> = run_concurrently(jobs, 3).collect().await;
+
+        assert_eq!(results.len(), 10);
+        let mut ok: Vec<usize> = results.iter().filter_map(|(_, r)| r.as_ref().ok().copied()).collect();
+        ok.sort();
+        assert_eq!(ok, vec![0, 10, 20, 30, 50, 60, 70, 80, 90]);
+
+        let (_, failed) = results.iter().find(|(i, _)| *i == 4).unwrap();
+        assert!(failed.as_ref().unwrap_err().to_string().contains("corrupt file 4"));
+
+        let max = max_in_flight.load(Ordering::SeqCst);
+        assert!(max > 1 && max <= 3, "max in flight: {}", max);
+    }
+
+    #[test]
+    fn test_file_search_matches_nested_names() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir_all(dir.path().join("人事/2024")).unwrap();
+        std::fs::write(dir.path().join("人事/2024/Shugyo_Kisoku.md"), "就業規則").unwrap();
+        std::fs::write(dir.path().join("人事/kisoku_old.txt"), "旧規則").unwrap();
+        std::fs::write(dir.path().join("経費精算.txt"), "経費").unwrap();
+        std::fs::write(dir.path().join("kisoku.bin"), "unsupported").unwrap();
+        std::fs::create_dir_all(dir.path().join("人事/.versions/kisoku_old.txt")).unwrap();
+        std::fs::write(dir.path().join("人事/.versions/kisoku_old.txt/v1_0.txt"), "旧").unwrap();
+
+        let paths: Vec<String> = search_files(dir.path(), "KISOKU").into_iter().map(|h| h.path).collect();
+        assert_eq!(paths, vec!["人事/2024/Shugyo_Kisoku.md", "人事/kisoku_old.txt"]);
+
+        let hits = search_files(dir.path(), "経費");
+        assert_eq!(hits.len(), 1);
+        assert_eq!(hits[0].entry.name, "経費精算.txt");
+        assert!(search_files(dir.path(), "  ").is_empty());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_symlink_out_of_upload_dir_is_rejected() {
+        use std::os::unix::fs::symlink;
+
+        let upload = tempfile::tempdir().unwrap();
+        let outside = tempfile::tempdir().unwrap();
+        std::fs::write(outside.path().join("secret.txt"), "secret").unwrap();
+        symlink(outside.path(), upload.path().join("escape")).unwrap();
+        symlink(outside.path().join("new.txt"), upload.path().join("dangling.txt")).unwrap();
+        std::fs::create_dir(upload.path().join("docs")).unwrap();
+        symlink(upload.path().join("docs"), upload.path().join("docs_link")).unwrap();
+
+        assert!(resolve_within(upload.path(), "escape/secret.txt").is_err());
+        assert!(resolve_new_within(upload.path(), "escape/new.txt").is_err());
+        assert!(resolve_new_within(upload.path(), "dangling.txt").is_err());
+        assert!(resolve_new_within(upload.path(), "escape").is_err());
+
+        // Links that stay inside are fine
+        let inside = resolve_new_within(upload.path(), "docs_link/new.txt").unwrap();
+        assert_eq!(inside, upload.path().canonicalize().unwrap().join("docs/new.txt"));
+        assert!(resolve_within(upload.path(), "docs_link").is_ok());
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_walked_and_resolved_paths_share_file_id() {
+        use std::os::unix::fs::symlink;
+
+        let root = tempfile::tempdir().unwrap();
+        std::fs::create_dir(root.path().join("real")).unwrap();
+        std::fs::write(root.path().join("real/a.txt"), "規程A").unwrap();
+        symlink(root.path().join("real"), root.path().join("uploads")).unwrap();
+
+        let embeddings = embeddings::test_generator().await;
+        let manager = IndexManager::new(root.path().join("uploads"), Arc::new(embeddings), Arc::new(vector_store::unconnected_store(3)), 60);
+        let resolved = manager.safe_resolve("a.txt").unwrap();
+        let (walked, _) = walk_directory(manager.upload_dir()).remove(0);
+        // What the walker yields for the upload dir as configured
+        let (configured, _) = walk_directory(&root.path().join("uploads")).remove(0);
+
+        assert_eq!(manager.file_id_of(&walked), manager.file_id_of(&resolved));
+        assert_eq!(manager.file_id_of(&configured), manager.file_id_of(&resolved));
+        assert_eq!(manager.file_id_of(&resolved), file_id(Path::new("a.txt")));
+        assert_eq!(relative_path(manager.upload_dir(), &walked), "a.txt");
+    }
+
+    #[tokio::test]
+    async fn test_run_while_indexing_is_typed_error() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("a.txt");
+        std::fs::write(&file, "規程A").unwrap();
+        let embeddings = embeddings::test_generator().await;
+        let manager = IndexManager::new(dir.path().to_path_buf(), Arc::new(embeddings), Arc::new(vector_store::unconnected_store(3)), 60);
+        manager.status.lock().await.is_indexing = true;
+
+        let err = manager.run_index_file(&file).await.unwrap_err();
+        assert!(err.downcast_ref::<IndexingInProgress>().is_some());
+        // The running job's state is left alone
+        assert!(manager.is_indexing().await);
+        assert_eq!(manager.get_status().await.last_error, None);
+    }
+
+    fn idle_status() -> IndexStatus {
+        IndexStatus {
+            is_indexing: false,
+            last_indexed_at: None,
+            total_files: 0,
+            total_chunks: 0,
+            failed_files: Vec::new(),
+            skipped_files: 0,
+            reindexed_files: 0,
+            truncated_files: Vec::new(),
+            auto_index_interval_minutes: 60,
+            chunk_size: 1000,
+            chunk_overlap: 200,
+            last_error: None,
+            current_file: None,
+            files_processed: 0,
+            files_total: 0,
+        }
+    }
+
+    #[test]
+    fn test_progress_resets_between_runs() {
+        let mut status = IndexStatus { is_indexing: true, ..idle_status() };
+
+        status.reset_progress(3);
+        status.start_file("a.txt");
+        assert_eq!(status.current_file.as_deref(), Some("a.txt"));
+        status.finish_file();
+        status.start_file("b.txt");
+        status.finish_file();
+        assert_eq!((status.files_processed, status.files_total), (2, 3));
+        assert_eq!(status.current_file, None);
+
+        status.reset_progress(5);
+        assert_eq!((status.files_processed, status.files_total), (0, 5));
+    }
+
+    #[test]
+    fn test_invalid_chunking_leaves_settings_unchanged() {
+        let mut status = idle_status();
+        status.set_chunking(Some(300), Some(50)).unwrap();
+
+        // overlap >= size is rejected and leaves the settings as they were
+        assert!(status.set_chunking(None, Some(300)).is_err());
+        assert!(status.set_chunking(Some(0), None).is_err());
+        assert_eq!((status.chunk_size, status.chunk_overlap), (300, 50));
+    }
+
+    /// Runs only when `TEST_QDRANT_URL` is set.
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_chunk_settings_apply_to_unchanged_files_on_next_run() {
+        let Ok(url) = std::env::var("TEST_QDRANT_URL") else { return };
+        let embeddings = embeddings::test_generator().await;
+        let store = Arc::new(VectorStore::new(&url, &format!("test_rechunk_{}", uuid::Uuid::new_v4().simple()), 3, Distance::Cosine).await.unwrap());
+        let dir = tempfile::tempdir().unwrap();
+        let rules = dir.path().join("rules.txt");
+        std::fs::write(&rules, "就業規則の本文です。".repeat(200)).unwrap();
+
+        let manager = IndexManager::new(dir.path().to_path_buf(), Arc::new(embeddings), store.clone(), 60);
+        manager.run_index().await.unwrap();
+        let before = manager.file_chunks(&rules).await.unwrap().len();
+
+        // The file itself is untouched; only the settings change
+        manager.set_chunking(Some(300), Some(50)).await.unwrap();
+        manager.run_index().await.unwrap();
+        let smaller = manager.file_chunks(&rules).await.unwrap().len();
+        assert!(smaller > before, "{} -> {}", before, smaller);
+        assert_eq!(manager.get_status().await.reindexed_files, 1);
+
+        // Back to larger chunks: the extra chunks from the previous run are gone
+        manager.set_chunking(Some(1000), Some(200)).await.unwrap();
+        manager.run_index().await.unwrap();
+        assert_eq!(manager.file_chunks(&rules).await.unwrap().len(), before);
+        assert_eq!(manager.get_status().await.total_chunks, before);
+
+        store.discard().await.unwrap();
+    }
+
+    #[test]
+    fn test_chunk_ids_are_stable_uuids() {
+        let path_id = file_id(Path::new("/uploads/manual.txt"));
+        let id = chunk_id(&path_id, 0);
+
+        assert!(uuid::Uuid::parse_str(&id).is_ok(), "{}", id);
+        assert_eq!(id, chunk_id(&path_id, 0));
+        assert_ne!(id, chunk_id(&path_id, 1));
+        assert_ne!(id, chunk_id(&file_id(Path::new("/uploads/other.txt")), 0));
+    }
+
+    #[test]
+    fn test_unchanged_file_needs_no_embedding() {
+        let path_id = file_id(Path::new("/uploads/manual.txt"));
+        let chunks = chunk_text(&"社内マニュアルの本文です。".repeat(300), 1000, 200);
+
+        // First run: nothing stored, every chunk is embedded
+        let first = changed_chunks(&path_id, &chunks, &HashMap::new());
+        assert_eq!(first.len(), chunks.len());
+        let stored: HashMap<String, String> = first.iter()
+            .map(|p| (p.id.clone(), p.content_hash.clone()))
+            .collect();
+
+        // Second run over the same file: zero embedding calls
+        assert!(changed_chunks(&path_id, &chunks, &stored).is_empty());
+
+        // Editing one chunk re-embeds only that chunk
+        let mut edited = chunks.clone();
+        edited[1].text.push_str("追記");
+        let pending = changed_chunks(&path_id, &edited, &stored);
+        assert_eq!(pending.len(), 1);
+        assert_eq!(pending[0].id, chunk_id(&path_id, 1));
+    }
+
+    /// Runs only when `TEST_QDRANT_URL` is set.
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_second_run_makes_no_embedding_calls() {
+        use std::sync::atomic::Ordering;
+
+        let Ok(url) = std::env::var("TEST_QDRANT_URL") else { return };
+        let (embeddings, embedded) = embeddings::counting_test_generator().await;
+        let store = Arc::new(VectorStore::new(&url, &format!("test_rerun_{}", uuid::Uuid::new_v4().simple()), 3, Distance::Cosine).await.unwrap());
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("manual.txt"), "社内マニュアルの本文です。".repeat(300)).unwrap();
+        std::fs::write(dir.path().join("faq.md"), "# FAQ\n\nよくある質問").unwrap();
+
+        let manager = IndexManager::new(dir.path().to_path_buf(), Arc::new(embeddings), store.clone(), 60);
+        manager.run_index().await.unwrap();
+        assert!(embedded.load(Ordering::SeqCst) > 0);
+
+        // Unchanged mtime/size: files are skipped before extraction
+        embedded.store(0, Ordering::SeqCst);
+        manager.run_index().await.unwrap();
+        assert_eq!(embedded.load(Ordering::SeqCst), 0);
+        assert_eq!(manager.get_status().await.skipped_files, 2);
+
+        // Without the state file every chunk still matches its stored hash
+        std::fs::remove_file(dir.path().join(index_state::STATE_FILE_NAME)).unwrap();
+        manager.run_index().await.unwrap();
+        assert_eq!(embedded.load(Ordering::SeqCst), 0);
+        assert_eq!(manager.get_status().await.reindexed_files, 0);
+
+        store.discard().await.unwrap();
+    }
+
+    #[test]
+    fn test_corrupt_file_reports_reason() {
+        let dir = tempfile::tempdir().unwrap();
+        let broken = dir.path().join("scan.pdf");
+        std::fs::write(&broken, "not a pdf").unwrap();
+
+        let err = extract_text(&broken, SupportedFormat::Pdf).unwrap_err();
+        let failure = file_error(relative_path(dir.path(), &broken), &err);
+
+        assert_eq!(failure.name, "scan.pdf");
+        assert!(!failure.reason.trim().is_empty());
+    }
+
+    #[test]
+    fn test_failed_files_sorted_and_deduped_by_name() {
+        let errors = vec![
+            FileError { name: "b.pdf".to_string(), reason: "x".to_string() },
+            FileError { name: "a.pdf".to_string(), reason: "y".to_string() },
+            FileError { name: "b.pdf".to_string(), reason: "x".to_string() },
+        ];
+        let names: Vec<String> = sorted_unique_errors(errors).into_iter().map(|e| e.name).collect();
+        assert_eq!(names, vec!["a.pdf", "b.pdf"]);
+    }
+
+    fn oversized_document_chunks() -> Vec<TextChunk> {
+        let text = "大きな文書の一文です。".repeat(2000);
+        let chunks = chunk_text(&text, 1000, 200);
+        assert!(chunks.len() > 10);
+        chunks
+    }
+
+    #[test]
+    fn test_chunk_limit_truncates_oversized_file() {
+        let chunks = oversized_document_chunks();
+        let total = chunks.len();
+        let limit = ChunkLimit { max_chunks_per_file: 10, on_overflow: ChunkOverflow::Truncate };
+
+        let (kept, truncated_from) = limit.apply(chunks).unwrap();
+
+        assert_eq!(kept.len(), 10);
+        assert_eq!(kept.last().unwrap().chunk_index, 9);
+        assert_eq!(truncated_from, Some(total));
+        assert_eq!(
+            truncation_note("docs/huge.txt", kept.len(), total),
+            format!("docs/huge.txt: indexed first 10 of {} chunks", total)
+        );
+    }
+
+    #[test]
+    fn test_chunk_limit_fail_rejects_oversized_file() {
+        let limit = ChunkLimit { max_chunks_per_file: 10, on_overflow: ChunkOverflow::Fail };
+
+        let err = limit.apply(oversized_document_chunks()).unwrap_err();
+        assert!(err.to_string().contains("max_chunks_per_file (10)"));
+
+        let small = chunk_text("短い文書", 1000, 200);
+        assert_eq!(limit.apply(small).unwrap().1, None);
+    }
+
+    /// Runs only when `TEST_QDRANT_URL` is set.
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_file_chunks_lists_indexed_chunks_of_one_file() {
+        let Ok(url) = std::env::var("TEST_QDRANT_URL") else { return };
+        let embeddings = embeddings::test_generator().await;
+        let store = VectorStore::new(&url, &format!("test_chunks_{}", uuid::Uuid::new_v4().simple()), 3, Distance::Cosine).await.unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        let manual = dir.path().join("manual.md");
+        std::fs::write(&manual, "# 手順\n\n申請書を提出する。\n\n# 承認\n\n上長が承認する。").unwrap();
+        std::fs::write(dir.path().join("other.txt"), "別のファイル").unwrap();
+
+        let store = Arc::new(store);
+        let manager = IndexManager::new(dir.path().to_path_buf(), Arc::new(embeddings), store.clone(), 60);
+        manager.run_index().await.unwrap();
+
+        let chunks = manager.file_chunks(&manual).await.unwrap();
+        assert!(!chunks.is_empty());
+        assert!(chunks.iter().all(|c| !c.text.contains("別のファイル")));
+        let indexes: Vec<usize> = chunks.iter().map(|c| c.chunk_index).collect();
+        assert_eq!(indexes, (0..chunks.len()).collect::<Vec<_>>());
+        assert!(chunks.iter().any(|c| c.text.contains("申請書")));
+
+        store.discard().await.unwrap();
+    }
+
+    /// Runs only when `TEST_QDRANT_URL` is set.
+    #[cfg(unix)]
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_file_chunks_found_through_symlinked_upload_dir() {
+        let Ok(url) = std::env::var("TEST_QDRANT_URL") else { return };
+        let embeddings = embeddings::test_generator().await;
+        let store = Arc::new(VectorStore::new(&url, &format!("test_chunks_link_{}", uuid::Uuid::new_v4().simple()), 3, Distance::Cosine).await.unwrap());
+        let root = tempfile::tempdir().unwrap();
+        std::fs::create_dir(root.path().join("real")).unwrap();
+        std::fs::write(root.path().join("real/manual.txt"), "申請書を提出する").unwrap();
+        std::os::unix::fs::symlink(root.path().join("real"), root.path().join("uploads")).unwrap();
+
+        let manager = IndexManager::new(root.path().join("uploads"), Arc::new(embeddings), store.clone(), 60);
+        manager.run_index().await.unwrap();
+
+        // The chunks handler passes the canonical path from `safe_resolve`
+        let resolved = manager.safe_resolve("manual.txt").unwrap();
+        let texts: Vec<String> = manager.file_chunks(&resolved).await.unwrap().into_iter().map(|c| c.text).collect();
+        assert_eq!(texts, vec!["申請書を提出する"]);
+
+        store.discard().await.unwrap();
+    }
+
+    /// Runs only when `TEST_QDRANT_URL` is set.
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_index_file_replaces_only_that_files_chunks() {
+        let Ok(url) = std::env::var("TEST_QDRANT_URL") else { return };
+        let embeddings = embeddings::test_generator().await;
+        let store = Arc::new(VectorStore::new(&url, &format!("test_index_file_{}", uuid::Uuid::new_v4().simple()), 3, Distance::Cosine).await.unwrap());
+        let dir = tempfile::tempdir().unwrap();
+        let edited = dir.path().join("edited.txt");
+        let other = dir.path().join("other.txt");
+        std::fs::write(&edited, "初版の本文").unwrap();
+        std::fs::write(&other, "触らないファイル").unwrap();
+
+        let manager = IndexManager::new(dir.path().to_path_buf(), Arc::new(embeddings), store.clone(), 60);
+        manager.run_index().await.unwrap();
+        let other_before = manager.file_chunks(&other).await.unwrap();
+
+        std::fs::write(&edited, "第2版の本文").unwrap();
+        assert_eq!(manager.run_index_file(&edited).await.unwrap(), 1);
+
+        let texts: Vec<String> = manager.file_chunks(&edited).await.unwrap().into_iter().map(|c| c.text).collect();
+        assert_eq!(texts, vec!["第2版の本文"]);
+        assert_eq!(manager.file_chunks(&other).await.unwrap(), other_before);
+        assert!(!manager.is_indexing().await);
+
+        store.discard().await.unwrap();
+    }
+
+    /// Runs only when `TEST_QDRANT_URL` is set.
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_index_file_through_relative_upload_dir_replaces_chunks() {
+        let Ok(url) = std::env::var("TEST_QDRANT_URL") else { return };
+        let embeddings = embeddings::test_generator().await;
+        let store = Arc::new(VectorStore::new(&url, &format!("test_index_file_rel_{}", uuid::Uuid::new_v4().simple()), 3, Distance::Cosine).await.unwrap());
+        let dir = tempfile::tempdir_in(".").unwrap();
+        std::fs::write(dir.path().join("edited.txt"), "初版の本文").unwrap();
+        // Like the default `./uploads`
+        let relative = PathBuf::from(".").join(dir.path().file_name().unwrap());
+
+        let manager = IndexManager::new(relative, Arc::new(embeddings), store.clone(), 60);
+        manager.run_index().await.unwrap();
+
+        let resolved = manager.safe_resolve("edited.txt").unwrap();
+        std::fs::write(&resolved, "第2版の本文").unwrap();
+        assert_eq!(manager.run_index_file(&resolved).await.unwrap(), 1);
+
+        let texts: Vec<String> = manager.file_chunks(&resolved).await.unwrap().into_iter().map(|c| c.text).collect();
+        assert_eq!(texts, vec!["第2版の本文"]);
+        // No second copy left under another file_id
+        assert_eq!(store.scroll_all_point_ids().await.unwrap().len(), 1);
+
+        store.discard().await.unwrap();
+    }
+
+    /// Runs only when `TEST_QDRANT_URL` is set.
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_deleted_file_leaves_search_until_restored() {
+        let Ok(url) = std::env::var("TEST_QDRANT_URL") else { return };
+        let embeddings = embeddings::test_generator().await;
+        let store = Arc::new(VectorStore::new(&url, &format!("test_soft_delete_{}", uuid::Uuid::new_v4().simple()), 3, Distance::Cosine).await.unwrap());
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("manual.txt"), "申請書を提出する").unwrap();
+        std::fs::write(dir.path().join("other.txt"), "別のファイル").unwrap();
+
+        let manager = IndexManager::new(dir.path().to_path_buf(), Arc::new(embeddings), store.clone(), 60);
+        manager.run_index().await.unwrap();
+        let manual = manager.safe_resolve("manual.txt").unwrap();
+
+        files::soft_delete(&manual).unwrap();
+        assert_eq!(manager.remove_file_chunks(&manual).await.unwrap(), 1);
+        assert!(manager.file_chunks(&manual).await.unwrap().is_empty());
+        assert_eq!(store.scroll_all_point_ids().await.unwrap().len(), 1);
+
+        files::restore(&manual).unwrap();
+        assert_eq!(manager.run_index_file(&manual).await.unwrap(), 1);
+        let texts: Vec<String> = manager.file_chunks(&manual).await.unwrap().into_iter().map(|c| c.text).collect();
+        assert_eq!(texts, vec!["申請書を提出する"]);
+
+        store.discard().await.unwrap();
+    }
+
+    /// Runs only when `TEST_QDRANT_URL` is set.
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_removed_file_points_deleted_on_next_index() {
+        let Ok(url) = std::env::var("TEST_QDRANT_URL") else { return };
+        let embeddings = embeddings::test_generator().await;
+        let store = Arc::new(VectorStore::new(&url, &format!("test_stale_{}", uuid::Uuid::new_v4().simple()), 3, Distance::Cosine).await.unwrap());
+        let dir = tempfile::tempdir().unwrap();
+        let removed = dir.path().join("removed.txt");
+        let kept = dir.path().join("kept.txt");
+        std::fs::write(&removed, "削除されるファイル").unwrap();
+        std::fs::write(&kept, "残るファイル").unwrap();
+        // Added through the API, not backed by a file
+        let manual_id = uuid::Uuid::new_v4().to_string();
+        store.add_document(&manual_id, "手動追加", vec![0.1, 0.2, 0.3], serde_json::json!({})).await.unwrap();
+
+        let manager = IndexManager::new(dir.path().to_path_buf(), Arc::new(embeddings), store.clone(), 60);
+        manager.run_index().await.unwrap();
+        assert_eq!(manager.file_chunks(&removed).await.unwrap().len(), 1);
+
+        std::fs::remove_file(&removed).unwrap();
+        manager.run_index().await.unwrap();
+
+        assert!(manager.file_chunks(&removed).await.unwrap().is_empty());
+        assert_eq!(manager.file_chunks(&kept).await.unwrap().len(), 1);
+        assert!(store.scroll_all_point_ids().await.unwrap().contains(&manual_id));
+
+        store.discard().await.unwrap();
+    }
+
+    /// Runs only when `TEST_QDRANT_URL` is set.
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_staged_reindex_keeps_api_documents() {
+        let Ok(url) = std::env::var("TEST_QDRANT_URL") else { return };
+        let embeddings = embeddings::test_generator().await;
+        let store = Arc::new(VectorStore::new(&url, &format!("test_staged_{}", uuid::Uuid::new_v4().simple()), 3, Distance::Cosine).await.unwrap());
+        let dir = tempfile::tempdir().unwrap();
+        let manual = dir.path().join("manual.txt");
+        std::fs::write(&manual, "アップロードされたマニュアル").unwrap();
+        // Added through the API, not backed by a file
+        let api_id = uuid::Uuid::new_v4().to_string();
+        store.add_document(&api_id, "API経由で追加した文書", vec![0.1, 0.2, 0.3], serde_json::json!({"title": "社内FAQ"})).await.unwrap();
+
+        let manager = IndexManager::new(dir.path().to_path_buf(), Arc::new(embeddings), store.clone(), 60)
+            .with_staged_reindex(true);
+        // First run replaces the plain collection with an alias, the second re-points it
+        manager.run_index().await.unwrap();
+        manager.run_index().await.unwrap();
+
+        assert!(store.scroll_all_point_ids().await.unwrap().contains(&api_id));
+        let hits = store.search(vec![0.1, 0.2, 0.3], 10, None).await.unwrap();
+        let api_hit = hits.iter().find(|h| h.text == "API経由で追加した文書").unwrap();
+        assert_eq!(api_hit.metadata["title"], "社内FAQ");
+        assert_eq!(manager.file_chunks(&manual).await.unwrap().len(), 1);
+
+        store.drop_live().await.unwrap();
+    }
+}
@@ -0,0 +1,177 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+
+use crate::indexer::chunker::ChunkSettings;
+
+/// Sidecar file in the upload dir recording what the last successful run indexed.
+pub const STATE_FILE_NAME: &str = ".index_state.json";
+
+/// mtime + size from `std::fs::metadata`; a cheap "has this file changed" check.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FileFingerprint {
+    pub modified_ns: u64,
+    pub size: u64,
+}
+
+impl FileFingerprint {
+    pub fn of(path: &Path) -> Option<Self> {
+        let metadata = std::fs::metadata(path).ok()?;
+        let modified = metadata.modified().ok()?.duration_since(UNIX_EPOCH).ok()?;
+        Some(Self { modified_ns: modified.as_nanos() as u64, size: metadata.len() })
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct FileEntry {
+    #[serde(flatten)]
+    fingerprint: FileFingerprint,
+    chunks: usize,
+}
+
+/// Bumped when `file_id` is derived differently, so files recorded under the
+/// old IDs are processed again instead of skipped.
+const FILE_ID_SCHEME: u32 = 1;
+
+/// Per-file fingerprints keyed by path relative to the upload dir.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct IndexState {
+    #[serde(default)]
+    file_id_scheme: u32,
+    /// Chunk settings the recorded files were split with.
+    #[serde(default)]
+    chunking: Option<ChunkSettings>,
+    #[serde(default)]
+    files: HashMap<String, FileEntry>,
+}
+
+fn state_path(upload_dir: &Path) -> PathBuf {
+    upload_dir.join(STATE_FILE_NAME)
+}
+
+impl IndexState {
+    /// Empty state for a run that splits files with `chunking`.
+    pub fn new(chunking: ChunkSettings) -> Self {
+        Self { file_id_scheme: FILE_ID_SCHEME, chunking: Some(chunking), files: HashMap::new() }
+    }
+
+    /// The saved state if it was recorded with the same chunk settings and
+    /// file IDs; otherwise an empty one, so every file is re-chunked.
+    pub fn load_for(upload_dir: &Path, chunking: ChunkSettings) -> Self {
+        let state = Self::load(upload_dir);
+        if state.chunking == Some(chunking) && state.file_id_scheme == FILE_ID_SCHEME {
+            return state;
+        }
+        if !state.files.is_empty() {
+            tracing::info!("Chunk settings changed, re-chunking every file");
+        }
+        Self::new(chunking)
+    }
+
+    /// Missing or unreadable state means "nothing indexed yet".
+    pub fn load(upload_dir: &Path) -> Self {
+        let path = state_path(upload_dir);
+        let Ok(data) = std::fs::read_to_string(&path) else {
+            return Self::default();
+        };
+        serde_json::from_str(&data).unwrap_or_else(|e| {
+            tracing::warn!("Ignoring corrupt index state {}: {}", path.display(), e);
+            Self::default()
+        })
+    }
+
+    /// Write to a temp file and rename over the old state, so a crash
+    /// mid-write never leaves a truncated file behind.
+    pub fn save(&self, upload_dir: &Path) -> Result<()> {
+        let path = state_path(upload_dir);
+        let tmp = path.with_extension("json.tmp");
+        std::fs::write(&tmp, serde_json::to_vec(self)?)?;
+        std::fs::rename(&tmp, &path)?;
+        Ok(())
+    }
+
+    /// Chunk count from the last run if the file's fingerprint is unchanged.
+    pub fn unchanged(&self, key: &str, fingerprint: FileFingerprint) -> Option<usize> {
+        self.files.get(key)
+            .filter(|entry| entry.fingerprint == fingerprint)
+            .map(|entry| entry.chunks)
+    }
+
+    pub fn record(&mut self, key: String, fingerprint: FileFingerprint, chunks: usize) {
+        self.files.insert(key, FileEntry { fingerprint, chunks });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_only_mutated_file_is_reprocessed() {
+        let dir = tempfile::tempdir().unwrap();
+        let a = dir.path().join("a.txt");
+        let b = dir.path().join("b.txt");
+        std::fs::write(&a, "規程A").unwrap();
+        std::fs::write(&b, "規程B").unwrap();
+
+        let mut state = IndexState::default();
+        state.record("a.txt".to_string(), FileFingerprint::of(&a).unwrap(), 1);
+        state.record("b.txt".to_string(), FileFingerprint::of(&b).unwrap(), 2);
+        state.save(dir.path()).unwrap();
+
+        std::fs::write(&b, "規程B（改定版）").unwrap();
+
+        // Reloaded from disk, as after a restart
+        let state = IndexState::load(dir.path());
+        assert_eq!(state.unchanged("a.txt", FileFingerprint::of(&a).unwrap()), Some(1));
+        assert_eq!(state.unchanged("b.txt", FileFingerprint::of(&b).unwrap()), None);
+        assert!(!dir.path().join(".index_state.json.tmp").exists());
+    }
+
+    #[test]
+    fn test_state_from_other_chunk_settings_is_ignored() {
+        let dir = tempfile::tempdir().unwrap();
+        let a = dir.path().join("a.txt");
+        std::fs::write(&a, "規程A").unwrap();
+        let fingerprint = FileFingerprint::of(&a).unwrap();
+        let chunking = ChunkSettings::default();
+
+        let mut state = IndexState::new(chunking);
+        state.record("a.txt".to_string(), fingerprint, 1);
+        state.save(dir.path()).unwrap();
+
+        assert_eq!(IndexState::load_for(dir.path(), chunking).unchanged("a.txt", fingerprint), Some(1));
+        let smaller = ChunkSettings { max_chunk_size: 300, overlap: 50, ..chunking };
+        assert_eq!(IndexState::load_for(dir.path(), smaller).unchanged("a.txt", fingerprint), None);
+    }
+
+    #[test]
+    fn test_state_from_old_file_ids_is_ignored() {
+        let dir = tempfile::tempdir().unwrap();
+        let a = dir.path().join("a.txt");
+        std::fs::write(&a, "規程A").unwrap();
+        let fingerprint = FileFingerprint::of(&a).unwrap();
+        let chunking = ChunkSettings::default();
+
+        // Saved before file IDs were taken relative to the upload dir
+        let mut state = IndexState::new(chunking);
+        state.record("a.txt".to_string(), fingerprint, 1);
+        let mut json = serde_json::to_value(&state).unwrap();
+        json.as_object_mut().unwrap().remove("file_id_scheme");
+        std::fs::write(dir.path().join(STATE_FILE_NAME), json.to_string()).unwrap();
+
+        assert_eq!(IndexState::load_for(dir.path(), chunking).unchanged("a.txt", fingerprint), None);
+    }
+
+    #[test]
+    fn test_corrupt_state_is_ignored() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join(STATE_FILE_NAME), "{not json").unwrap();
+
+        let fingerprint = FileFingerprint { modified_ns: 1, size: 1 };
+        assert_eq!(IndexState::load(dir.path()).unchanged("a.txt", fingerprint), None);
+    }
+}
@@ -0,0 +1,74 @@
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::Result;
+use notify::{EventKind, RecursiveMode, Watcher};
+use tokio::sync::mpsc;
+
+use super::index_manager::IndexManager;
+use super::index_state::STATE_FILE_NAME;
+use super::versioning::VERSIONS_DIR_NAME;
+
+/// 最後の変更からこの時間静かになったらインデックスを実行する
+pub const DEBOUNCE: Duration = Duration::from_secs(2);
+
+/// Changes worth reindexing for: not version snapshots, and not the
+/// indexer's own state file (which every run rewrites).
+fn is_relevant(path: &Path) -> bool {
+    if path.components().any(|c| c.as_os_str() == VERSIONS_DIR_NAME) {
+        return false;
+    }
+    let name = path.file_name().map(|n| n.to_string_lossy()).unwrap_or_default();
+    !name.starts_with(STATE_FILE_NAME)
+}
+
+/// Watch `upload_dir` and run an index shortly after files change.
+/// Bursts of events (e.g. a multi-file upload) collapse into one run.
+pub fn start(manager: Arc<IndexManager>) -> Result<()> {
+    let (tx, mut rx) = mpsc::unbounded_channel();
+    let mut watcher = notify::recommended_watcher(move |res: notify::Result<notify::Event>| {
+        match res {
+            Ok(event) if !matches!(event.kind, EventKind::Access(_)) => {
+                if event.paths.iter().any(|p| is_relevant(p)) {
+                    let _ = tx.send(());
+                }
+            }
+            Ok(_) => {}
+            Err(e) => tracing::warn!("File watch error: {}", e),
+        }
+    })?;
+    watcher.watch(manager.upload_dir(), RecursiveMode::Recursive)?;
+    tracing::info!("Watching {} for changes", manager.upload_dir().display());
+
+    tokio::spawn(async move {
+        // Dropping the watcher stops notifications, so it lives in the task
+        let _watcher = watcher;
+        while rx.recv().await.is_some() {
+            while let Ok(Some(())) = tokio::time::timeout(DEBOUNCE, rx.recv()).await {}
+
+            // Don't drop the change if a run is already going; index again after it
+            while manager.is_indexing().await {
+                tokio::time::sleep(Duration::from_secs(1)).await;
+            }
+            tracing::info!("Files changed, indexing...");
+            if let Err(e) = manager.run_index().await {
+                tracing::error!("Watch-triggered indexing failed: {}", e);
+            }
+        }
+    });
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_versions_and_state_changes_are_ignored() {
+        assert!(is_relevant(Path::new("/uploads/docs/manual.pdf")));
+        assert!(!is_relevant(Path::new("/uploads/docs/.versions/manual.pdf/v1_20240101.pdf")));
+        assert!(!is_relevant(Path::new("/uploads/.index_state.json")));
+        assert!(!is_relevant(Path::new("/uploads/.index_state.json.tmp")));
+    }
+}
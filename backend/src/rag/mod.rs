@@ -2,48 +2,680 @@ pub mod embeddings;
 pub mod vector_store;
 pub mod index_manager;
 pub mod versioning;
+pub mod query_expansion;
+pub mod index_events;
+pub mod index_state;
+pub mod index_watcher;
+pub mod files;
+pub mod rerank;
 
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
 use anyhow::Result;
+use tokio::sync::Mutex;
 use self::embeddings::EmbeddingGenerator;
 use self::vector_store::VectorStore;
+use self::query_expansion::{QueryExpander, average_embeddings};
+use self::rerank::Reranker;
+use crate::models::{ChatRequest, SearchHit};
+
+/// `RAG_MIN_SCORE` 未設定時のしきい値。コサイン類似度が負（逆向き）のものだけを除外する
+pub const DEFAULT_MIN_SCORE: f32 = 0.0;
+
+/// ハイブリッド検索でキーワード一致に与える重み（残りがベクトル類似度）
+pub const DEFAULT_KEYWORD_WEIGHT: f32 = 0.3;
+
+/// Keyword share of the hybrid score, from `RAG_HYBRID_KEYWORD_WEIGHT` (0.0–1.0).
+pub fn keyword_weight_from_env() -> f32 {
+    std::env::var("RAG_HYBRID_KEYWORD_WEIGHT")
+        .ok()
+        .and_then(|v| v.parse::<f32>().ok())
+        .map(|w| w.clamp(0.0, 1.0))
+        .unwrap_or(DEFAULT_KEYWORD_WEIGHT)
+}
+
+/// この類似度（文字トライグラムのJaccard係数）以上のチャンクは重複とみなしてコンテキストから除く
+pub const DEFAULT_DEDUP_THRESHOLD: f32 = 0.9;
+
+/// Near-duplicate cutoff for context chunks, from `RAG_DEDUP_THRESHOLD`
+/// (0.0–1.0; values above 1.0 turn deduplication off).
+pub fn dedup_threshold_from_env() -> f32 {
+    std::env::var("RAG_DEDUP_THRESHOLD")
+        .ok()
+        .and_then(|v| v.parse::<f32>().ok())
+        .map(|t| t.max(0.0))
+        .unwrap_or(DEFAULT_DEDUP_THRESHOLD)
+}
+
+/// Cosine similarity threshold for prompt context, from `RAG_MIN_SCORE`.
+pub fn min_score_from_env() -> f32 {
+    std::env::var("RAG_MIN_SCORE")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(DEFAULT_MIN_SCORE)
+}
+
+/// Chunks per chat request: `default` when the request doesn't say,
+/// never more than `max` so a client can't stuff the prompt.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TopKLimits {
+    pub default: u64,
+    pub max: u64,
+}
+
+impl Default for TopKLimits {
+    fn default() -> Self {
+        Self { default: 3, max: 10 }
+    }
+}
+
+impl TopKLimits {
+    /// `RAG_TOP_K` (default 3) and `RAG_MAX_TOP_K` (default 10).
+    pub fn from_env() -> Self {
+        let defaults = Self::default();
+        let env = |name: &str| std::env::var(name).ok().and_then(|v| v.parse::<u64>().ok());
+        let max = env("RAG_MAX_TOP_K").unwrap_or(defaults.max).max(1);
+        let default = env("RAG_TOP_K").unwrap_or(defaults.default).clamp(1, max);
+        Self { default, max }
+    }
+
+    /// Top-k for a request asking for `requested` chunks.
+    pub fn resolve(&self, requested: Option<u64>) -> u64 {
+        requested.unwrap_or(self.default).clamp(1, self.max)
+    }
+}
+
+/// リクエストで指定されたコレクションが許可リストにない
+#[derive(Debug, thiserror::Error)]
+#[error("Collection not allowed: '{0}'")]
+pub struct CollectionNotAllowed(pub String);
+
+/// Collections a request may target besides the default one. Keeps callers
+/// from creating arbitrary collections in Qdrant.
+#[derive(Debug, Clone, Default)]
+pub struct CollectionAllowlist {
+    names: HashSet<String>,
+}
+
+impl CollectionAllowlist {
+    pub fn new(names: impl IntoIterator<Item = String>) -> Self {
+        Self { names: names.into_iter().collect() }
+    }
+
+    /// `RAG_COLLECTIONS=team_a,team_b`
+    pub fn from_env() -> Self {
+        let names = std::env::var("RAG_COLLECTIONS").unwrap_or_default();
+        Self::new(names.split(',').map(str::trim).filter(|n| !n.is_empty()).map(String::from))
+    }
+
+    pub fn check(&self, name: &str) -> Result<(), CollectionNotAllowed> {
+        if self.names.contains(name) {
+            Ok(())
+        } else {
+            Err(CollectionNotAllowed(name.to_string()))
+        }
+    }
+}
+
+/// Documents embedded per call in `RAGEngine::add_documents`.
+pub const DOCUMENT_BATCH_SIZE: usize = 32;
+
+/// A document for `RAGEngine::add_documents`.
+#[derive(Debug, Clone)]
+pub struct NewDocument {
+    pub id: String,
+    pub text: String,
+    pub metadata: serde_json::Value,
+    /// Allowlisted collection; `None` = default
+    pub collection: Option<String>,
+}
+
+/// Per-request retrieval scope.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SearchOptions<'a> {
+    /// Only chunks whose `metadata.category` matches
+    pub category: Option<&'a str>,
+    /// Allowlisted collection; `None` = default
+    pub collection: Option<&'a str>,
+    /// Also match exact query tokens (SKUs, error codes) in chunk text
+    pub hybrid: bool,
+}
 
 pub struct RAGEngine {
     pub embeddings: Arc<EmbeddingGenerator>,
+    /// デフォルトコレクション（IndexManagerもここに書き込む）
     pub vector_store: Arc<VectorStore>,
+    query_expander: Option<QueryExpander>,
+    reranker: Option<Reranker>,
+    allowlist: CollectionAllowlist,
+    keyword_weight: f32,
+    dedup_threshold: f32,
+    /// 出典表示で `file_path` をこのディレクトリからの相対パスにする
+    source_root: Option<PathBuf>,
+    /// 初回アクセス時に作成したコレクションのキャッシュ
+    collections: Mutex<HashMap<String, Arc<VectorStore>>>,
 }
 
 impl RAGEngine {
     pub async fn new(qdrant_url: &str, collection_name: &str) -> Result<Self> {
-        let embeddings = Arc::new(EmbeddingGenerator::new().await?);
-        let vector_store = Arc::new(VectorStore::new(qdrant_url, collection_name).await?);
+        let embeddings = Arc::new(EmbeddingGenerator::from_env().await?);
+        let vector_store = Arc::new(VectorStore::new(qdrant_url, collection_name, embeddings.dimension() as u64, vector_store::distance_from_env()).await?);
+        Ok(Self::with_store(embeddings, vector_store))
+    }
 
-        Ok(Self {
+    pub fn with_store(embeddings: Arc<EmbeddingGenerator>, vector_store: Arc<VectorStore>) -> Self {
+        Self {
             embeddings,
             vector_store,
-        })
+            query_expander: None,
+            reranker: None,
+            allowlist: CollectionAllowlist::default(),
+            keyword_weight: DEFAULT_KEYWORD_WEIGHT,
+            dedup_threshold: DEFAULT_DEDUP_THRESHOLD,
+            source_root: None,
+            collections: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Allow requests to target these collections besides the default one.
+    pub fn with_collections(mut self, allowlist: CollectionAllowlist) -> Self {
+        self.allowlist = allowlist;
+        self
+    }
+
+    /// Store for the requested collection (`None` = default). Allowlisted
+    /// collections are created in Qdrant on first use.
+    pub async fn store_for(&self, collection: Option<&str>) -> Result<Arc<VectorStore>> {
+        let name = match collection {
+            None => return Ok(self.vector_store.clone()),
+            Some(name) if name == self.vector_store.collection_name() => {
+                return Ok(self.vector_store.clone());
+            }
+            Some(name) => name,
+        };
+        self.allowlist.check(name)?;
+
+        let mut collections = self.collections.lock().await;
+        if let Some(store) = collections.get(name) {
+            return Ok(store.clone());
+        }
+        let store = Arc::new(self.vector_store.for_collection(name).await?);
+        collections.insert(name.to_string(), store.clone());
+        Ok(store)
+    }
+
+    /// Weight of the keyword score in hybrid search (clamped to 0.0–1.0).
+    pub fn with_keyword_weight(mut self, weight: f32) -> Self {
+        self.keyword_weight = weight.clamp(0.0, 1.0);
+        self
+    }
+
+    /// Drop context chunks at least this similar to a higher-ranked one.
+    pub fn with_dedup_threshold(mut self, threshold: f32) -> Self {
+        self.dedup_threshold = threshold;
+        self
+    }
+
+    /// Cite indexed files relative to `root` (the upload directory)
+    /// instead of by file name only.
+    pub fn with_source_root(mut self, root: impl Into<PathBuf>) -> Self {
+        self.source_root = Some(root.into());
+        self
+    }
+
+    /// Enable synonym/acronym expansion of retrieval queries.
+    pub fn with_query_expander(mut self, expander: QueryExpander) -> Self {
+        self.query_expander = Some(expander);
+        self
+    }
+
+    /// Rerank the top vector hits before picking the context chunks.
+    pub fn with_reranker(mut self, reranker: Reranker) -> Self {
+        self.reranker = Some(reranker);
+        self
+    }
+
+    /// Embed the query; with expansion enabled, average over all variants.
+    fn embed_query(&self, query: &str) -> Result<Vec<f32>> {
+        let variants = match self.query_expander {
+            Some(ref expander) => expander.expand(query),
+            None => return self.embeddings.embed_query(query),
+        };
+        if variants.len() == 1 {
+            return self.embeddings.embed_query(query);
+        }
+        tracing::debug!("Expanded query into {} variants", variants.len());
+        let embeddings = self.embeddings.embed_queries(variants)?;
+        average_embeddings(&embeddings)
+            .ok_or_else(|| anyhow::anyhow!("No embeddings returned for query"))
     }
 
     pub async fn add_document(
         &self,
         id: &str,
         text: &str,
-        metadata: serde_json::Value
+        metadata: serde_json::Value,
+        collection: Option<&str>,
     ) -> Result<()> {
-        let embedding = self.embeddings.generate_single(text)?;
-        self.vector_store.add_document(id, text, embedding, metadata).await?;
+        let store = self.store_for(collection).await?;
+        let embedding = self.embeddings.embed_document(text)?;
+        store.add_document(id, text, embedding, metadata).await?;
         Ok(())
     }
 
-    pub async fn retrieve_context(&self, query: &str, top_k: u64) -> Result<String> {
-        let query_embedding = self.embeddings.generate_single(query)?;
-        let results = self.vector_store.search(query_embedding, top_k).await?;
+    /// Add many documents, embedding and upserting `DOCUMENT_BATCH_SIZE` at a
+    /// time. Returns one result per input, in order; a failing batch or a
+    /// disallowed collection only fails its own documents.
+    pub async fn add_documents(&self, documents: Vec<NewDocument>) -> Vec<Result<(), String>> {
+        let mut results: Vec<Result<(), String>> = vec![Ok(()); documents.len()];
+
+        let mut by_collection: Vec<(Option<String>, Vec<usize>)> = Vec::new();
+        for (i, doc) in documents.iter().enumerate() {
+            match by_collection.iter_mut().find(|(c, _)| *c == doc.collection) {
+                Some((_, indexes)) => indexes.push(i),
+                None => by_collection.push((doc.collection.clone(), vec![i])),
+            }
+        }
+
+        for (collection, indexes) in by_collection {
+            let store = match self.store_for(collection.as_deref()).await {
+                Ok(store) => store,
+                Err(e) => {
+                    for &i in &indexes {
+                        results[i] = Err(e.to_string());
+                    }
+                    continue;
+                }
+            };
+            for batch in indexes.chunks(DOCUMENT_BATCH_SIZE) {
+                if let Err(e) = self.add_batch(&store, &documents, batch).await {
+                    for &i in batch {
+                        results[i] = Err(format!("{:#}", e));
+                    }
+                }
+            }
+        }
+        results
+    }
+
+    async fn add_batch(&self, store: &VectorStore, documents: &[NewDocument], batch: &[usize]) -> Result<()> {
+        let texts: Vec<String> = batch.iter().map(|&i| documents[i].text.clone()).collect();
+        let embeddings = self.embeddings.embed_documents(texts)?;
+        let points = batch.iter().zip(embeddings)
+            .map(|(&i, embedding)| {
+                let doc = &documents[i];
+                (doc.id.clone(), doc.text.clone(), embedding, doc.metadata.clone())
+            })
+            .collect();
+        store.add_documents(points).await
+    }
+
+    /// Top-k chunks for the query, with scores and metadata. In hybrid mode
+    /// the score blends vector similarity with the share of query keywords
+    /// found in the chunk text.
+    pub async fn search(&self, query: &str, top_k: u64, options: SearchOptions<'_>) -> Result<Vec<SearchHit>> {
+        let store = self.store_for(options.collection).await?;
+        let query_embedding = self.embed_query(query)?;
+
+        let terms = if options.hybrid { keyword_terms(query) } else { Vec::new() };
+        if terms.is_empty() {
+            return store.search(query_embedding, top_k, options.category).await;
+        }
+
+        let vector_hits = store.search(query_embedding.clone(), top_k, options.category).await?;
+        let keyword_hits = store.keyword_search(query_embedding, top_k, &terms, options.category).await?;
+        Ok(merge_hybrid(vector_hits, keyword_hits, &terms, self.keyword_weight, top_k as usize))
+    }
+
+    /// Prompt context from the top-k chunks. Chunks scoring below `min_score`
+    /// are dropped so unrelated documents don't add noise; returns an empty
+    /// string when nothing qualifies. With a reranker, the top
+    /// `candidates` hits above `min_score` are reordered by rerank score
+    /// before the top-k are kept.
+    pub async fn retrieve_context(
+        &self,
+        query: &str,
+        top_k: u64,
+        min_score: f32,
+        options: SearchOptions<'_>,
+    ) -> Result<String> {
+        let hits = match &self.reranker {
+            None => self.search(query, top_k, options).await?,
+            Some(reranker) => {
+                let candidates: Vec<SearchHit> = self.search(query, top_k.max(reranker.candidates()), options).await?
+                    .into_iter()
+                    .filter(|hit| hit.score >= min_score)
+                    .collect();
+                rerank_or_truncate(reranker, query, candidates, top_k as usize).await
+            }
+        };
+        Ok(build_context(hits, min_score, self.dedup_threshold, self.source_root.as_deref()))
+    }
+}
+
+/// Reranked top-k; if the reranker fails, the vector order is kept rather
+/// than answering without context.
+async fn rerank_or_truncate(reranker: &Reranker, query: &str, mut hits: Vec<SearchHit>, top_k: usize) -> Vec<SearchHit> {
+    match reranker.rerank(query, hits.clone(), top_k).await {
+        Ok(reranked) => reranked,
+        Err(e) => {
+            tracing::warn!("Rerank failed, using vector order: {:#}", e);
+            hits.truncate(top_k);
+            hits
+        }
+    }
+}
+
+/// Prompt context for a chat request. Without an engine, or when the request
+/// sets `use_rag: false`, nothing is embedded or searched.
+pub async fn chat_context(
+    engine: Option<&RAGEngine>,
+    request: &ChatRequest,
+    query: &str,
+    top_k: u64,
+    min_score: f32,
+) -> Result<String> {
+    let Some(engine) = engine.filter(|_| request.use_rag()) else {
+        return Ok(String::new());
+    };
+    let options = SearchOptions {
+        category: request.rag_category.as_deref(),
+        collection: request.collection.as_deref(),
+        hybrid: request.rag_hybrid,
+    };
+    engine.retrieve_context(query, top_k, min_score, options).await
+}
+
+/// どの文書にも出てくるので、一致してもキーワードスコアの根拠にならない英単語
+const KEYWORD_STOP_WORDS: &[&str] = &[
+    "the", "and", "for", "are", "but", "not", "you", "all", "any", "can", "was", "our",
+    "has", "have", "had", "how", "what", "when", "where", "which", "who", "why", "with",
+    "this", "that", "these", "those", "from", "into", "about", "there", "their", "does",
+    "did", "will", "would", "should", "could", "please", "than", "then", "them", "they",
+];
 
-        if results.is_empty() {
-            return Ok(String::new());
+/// 完全一致させたいトークン（型番・エラーコードなど）。英数字と `-` `_` の並びで3文字以上、ストップワードは除く
+fn keyword_terms(query: &str) -> Vec<String> {
+    let mut terms: Vec<String> = Vec::new();
+    for token in query.split(|c: char| !(c.is_ascii_alphanumeric() || c == '-' || c == '_')) {
+        let token = token.trim_matches(|c| c == '-' || c == '_');
+        if token.len() < 3 || KEYWORD_STOP_WORDS.contains(&token.to_ascii_lowercase().as_str()) {
+            continue;
+        }
+        if !terms.iter().any(|t| t == token) {
+            terms.push(token.to_string());
         }
+    }
+    terms
+}
+
+fn keyword_score(text: &str, terms: &[String]) -> f32 {
+    let matched = terms.iter().filter(|t| text.contains(t.as_str())).count();
+    matched as f32 / terms.len() as f32
+}
+
+/// Union of both result lists (deduplicated by text), rescored as
+/// `(1 - weight) * similarity + weight * keyword share`.
+fn merge_hybrid(
+    vector_hits: Vec<SearchHit>,
+    keyword_hits: Vec<SearchHit>,
+    terms: &[String],
+    weight: f32,
+    top_k: usize,
+) -> Vec<SearchHit> {
+    let mut merged: Vec<SearchHit> = Vec::new();
+    for hit in vector_hits.into_iter().chain(keyword_hits) {
+        if !merged.iter().any(|h| h.text == hit.text) {
+            merged.push(hit);
+        }
+    }
+    for hit in &mut merged {
+        hit.score = (1.0 - weight) * hit.score + weight * keyword_score(&hit.text, terms);
+    }
+    merged.sort_by(|a, b| b.score.total_cmp(&a.score));
+    merged.truncate(top_k);
+    merged
+}
+
+/// Distinct character trigrams (whole text if shorter), so Japanese text
+/// without spaces compares as well as English.
+fn trigrams(text: &str) -> HashSet<String> {
+    let chars: Vec<char> = text.split_whitespace().flat_map(|w| w.chars().chain([' '])).collect();
+    if chars.len() < 3 {
+        return HashSet::from([chars.into_iter().collect()]);
+    }
+    chars.windows(3).map(|w| w.iter().collect()).collect()
+}
+
+fn jaccard(a: &HashSet<String>, b: &HashSet<String>) -> f32 {
+    let union = a.union(b).count();
+    if union == 0 {
+        return 1.0;
+    }
+    a.intersection(b).count() as f32 / union as f32
+}
+
+/// Keep hits in rank order, skipping any whose text is at least `threshold`
+/// similar to one already kept.
+fn dedup_hits(hits: Vec<SearchHit>, threshold: f32) -> Vec<SearchHit> {
+    let mut kept: Vec<(SearchHit, HashSet<String>)> = Vec::new();
+    for hit in hits {
+        let grams = trigrams(&hit.text);
+        if kept.iter().any(|(_, other)| jaccard(&grams, other) >= threshold) {
+            tracing::debug!("Dropping near-duplicate context chunk");
+            continue;
+        }
+        kept.push((hit, grams));
+    }
+    kept.into_iter().map(|(hit, _)| hit).collect()
+}
+
+/// What a chunk is cited as: its file (relative to `root` when under it,
+/// otherwise the file name), or the title of a document added through the API.
+fn source_label(metadata: &serde_json::Value, root: Option<&Path>) -> Option<String> {
+    if let Some(file_path) = metadata.get("file_path").and_then(|v| v.as_str()) {
+        let path = Path::new(file_path);
+        let relative = root.and_then(|r| path.strip_prefix(r).ok())
+            .or_else(|| path.file_name().map(Path::new))
+            .unwrap_or(path);
+        return Some(relative.to_string_lossy().into_owned());
+    }
+    metadata.get("title").and_then(|v| v.as_str())
+        .filter(|t| !t.is_empty())
+        .map(str::to_string)
+}
+
+fn build_context(hits: Vec<SearchHit>, min_score: f32, dedup_threshold: f32, source_root: Option<&Path>) -> String {
+    let hits: Vec<SearchHit> = hits.into_iter().filter(|h| h.score >= min_score).collect();
+    let texts: Vec<String> = dedup_hits(hits, dedup_threshold).into_iter()
+        .map(|h| match source_label(&h.metadata, source_root) {
+            Some(source) => format!("[出典: {}]\n{}", source, h.text),
+            None => h.text,
+        })
+        .collect();
+
+    if texts.is_empty() {
+        return String::new();
+    }
+
+    format!("関連情報:\n{}\n\n", texts.join("\n\n"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use qdrant_client::qdrant::Distance;
+
+    fn hit(text: &str, score: f32) -> SearchHit {
+        SearchHit { text: text.to_string(), score, metadata: serde_json::json!({}) }
+    }
+
+    #[test]
+    fn test_hits_below_threshold_excluded() {
+        let hits = vec![hit("関連あり", 0.82), hit("やや関連", 0.55), hit("無関係", 0.12)];
+
+        let context = build_context(hits.clone(), 0.5, DEFAULT_DEDUP_THRESHOLD, None);
+        assert!(context.contains("関連あり"));
+        assert!(context.contains("やや関連"));
+        assert!(!context.contains("無関係"));
+
+        assert!(build_context(hits, DEFAULT_MIN_SCORE, DEFAULT_DEDUP_THRESHOLD, None).contains("無関係"));
+    }
+
+    #[test]
+    fn test_identical_chunks_reach_context_once() {
+        let text = "有給休暇の申請は3日前までに上長へ提出してください。";
+        let hits = vec![hit(text, 0.9), hit(text, 0.89), hit("経費精算は月末締めです。", 0.7)];
+
+        let context = build_context(hits.clone(), 0.0, DEFAULT_DEDUP_THRESHOLD, None);
+        assert_eq!(context.matches(text).count(), 1);
+        assert!(context.contains("経費精算"));
+
+        // Above 1.0 nothing counts as a duplicate
+        assert_eq!(build_context(hits, 0.0, 1.1, None).matches(text).count(), 2);
+    }
+
+    #[test]
+    fn test_context_cites_source_of_each_hit() {
+        let hits = vec![
+            SearchHit {
+                text: "売上は前年比10%増".to_string(),
+                score: 0.9,
+                metadata: serde_json::json!({"file_path": "/app/uploads/2026/report.pdf", "chunk_index": 0}),
+            },
+            SearchHit {
+                text: "VPNの設定手順".to_string(),
+                score: 0.8,
+                metadata: serde_json::json!({"file_path": "/srv/other/setup.md"}),
+            },
+            SearchHit {
+                text: "API経由で追加した文書".to_string(),
+                score: 0.7,
+                metadata: serde_json::json!({"title": "社内FAQ", "category": null}),
+            },
+            hit("出典なし", 0.6),
+        ];
+
+        let context = build_context(hits, 0.0, DEFAULT_DEDUP_THRESHOLD, Some(Path::new("/app/uploads")));
+
+        assert!(context.contains("[出典: 2026/report.pdf]\n売上は前年比10%増"), "{}", context);
+        assert!(context.contains("[出典: setup.md]\nVPNの設定手順"), "{}", context);
+        assert!(context.contains("[出典: 社内FAQ]\nAPI経由で追加した文書"), "{}", context);
+        assert!(context.contains("\n\n出典なし"), "{}", context);
+        assert_eq!(context.matches("[出典:").count(), 3);
+    }
+
+    #[test]
+    fn test_near_duplicate_threshold() {
+        let a = trigrams("The VPN client must be restarted after the update is installed.");
+        let b = trigrams("The VPN client must be restarted after the update is  installed!");
+        let c = trigrams("Expense reports are due at the end of each month.");
+        assert!(jaccard(&a, &b) >= DEFAULT_DEDUP_THRESHOLD);
+        assert!(jaccard(&a, &c) < 0.2);
+    }
+
+    #[test]
+    fn test_keyword_terms_keep_exact_tokens() {
+        assert_eq!(keyword_terms("型番SKU-20481の在庫は？"), vec!["SKU-20481"]);
+        assert_eq!(keyword_terms("error E1042, E1042 and ok"), vec!["error", "E1042"]);
+        assert_eq!(keyword_terms("What does The SKU-20481 cost?"), vec!["SKU-20481", "cost"]);
+        assert!(keyword_terms("有給休暇の申請方法").is_empty());
+    }
+
+    #[test]
+    fn test_hybrid_surfaces_exact_token_match() {
+        let terms = keyword_terms("SKU-20481 の仕様");
+        // Vector search alone: the SKU chunk scores 0.45 and falls outside top 2
+        let vector_hits = vec![hit("製品カタログ概要", 0.80), hit("仕様書の書き方", 0.75)];
+        let keyword_hits = vec![hit("SKU-20481: 定格出力 500W", 0.45)];
+
+        let merged = merge_hybrid(vector_hits, keyword_hits, &terms, DEFAULT_KEYWORD_WEIGHT, 2);
+        assert_eq!(merged.len(), 2);
+        assert_eq!(merged[0].text, "SKU-20481: 定格出力 500W");
+        assert_eq!(merged[1].text, "製品カタログ概要");
+    }
+
+    #[test]
+    fn test_hybrid_dedups_hits_found_by_both() {
+        let terms = keyword_terms("SKU-20481");
+        let both = hit("SKU-20481: 定格出力 500W", 0.6);
+        let merged = merge_hybrid(vec![both.clone()], vec![both], &terms, 0.5, 5);
+        assert_eq!(merged.len(), 1);
+        assert!((merged[0].score - 0.8).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_top_k_clamped_to_max() {
+        let limits = TopKLimits { default: 3, max: 10 };
+        assert_eq!(limits.resolve(None), 3);
+        assert_eq!(limits.resolve(Some(8)), 8);
+        assert_eq!(limits.resolve(Some(500)), 10);
+        assert_eq!(limits.resolve(Some(0)), 1);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_use_rag_false_skips_retrieval() {
+        // Any search would fail against this store, so Ok means nothing was fetched
+        let engine = RAGEngine::with_store(
+            Arc::new(embeddings::test_generator().await),
+            Arc::new(vector_store::unconnected_store(3)),
+        );
+        let mut request: ChatRequest = serde_json::from_value(serde_json::json!({
+            "model": "gpt-4",
+            "messages": [{"role": "user", "content": "物語を書いて"}],
+            "use_rag": false,
+        })).unwrap();
+
+        let context = chat_context(Some(&engine), &request, "物語を書いて", 3, 0.0).await.unwrap();
+        assert_eq!(context, "");
+
+        request.use_rag = None;
+        assert!(chat_context(Some(&engine), &request, "物語を書いて", 3, 0.0).await.is_err());
+    }
+
+    #[test]
+    fn test_collection_allowlist() {
+        let allowlist = CollectionAllowlist::new(["team_a".to_string(), "team_b".to_string()]);
+        assert!(allowlist.check("team_a").is_ok());
+        let err = allowlist.check("team_c").unwrap_err();
+        assert_eq!(err.0, "team_c");
+        assert!(CollectionAllowlist::default().check("team_a").is_err());
+    }
+
+    #[test]
+    fn test_high_threshold_gives_empty_context() {
+        let hits = vec![hit("関連あり", 0.82), hit("やや関連", 0.55)];
+        assert_eq!(build_context(hits, 0.95, DEFAULT_DEDUP_THRESHOLD, None), "");
+    }
+
+    /// Runs only when `TEST_QDRANT_URL` is set.
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_add_documents_inserts_batch() {
+        let Ok(url) = std::env::var("TEST_QDRANT_URL") else { return };
+        let embeddings = Arc::new(embeddings::test_generator().await);
+        let store = Arc::new(VectorStore::new(&url, &format!("test_batch_{}", uuid::Uuid::new_v4().simple()), 3, Distance::Cosine).await.unwrap());
+        let engine = RAGEngine::with_store(embeddings, store.clone());
+
+        let mut documents: Vec<NewDocument> = (0..50).map(|i| NewDocument {
+            id: uuid::Uuid::new_v4().to_string(),
+            text: format!("文書{}", i),
+            metadata: serde_json::json!({"title": format!("文書{}", i)}),
+            collection: None,
+        }).collect();
+        documents.push(NewDocument {
+            id: uuid::Uuid::new_v4().to_string(),
+            text: "許可されていないコレクション".to_string(),
+            metadata: serde_json::json!({}),
+            collection: Some("not_allowed".to_string()),
+        });
+
+        let results = engine.add_documents(documents).await;
+        assert_eq!(results.len(), 51);
+        assert!(results[..50].iter().all(|r| r.is_ok()), "{:?}", results);
+        assert!(results[50].as_ref().unwrap_err().contains("not_allowed"));
+        assert_eq!(store.scroll_all_point_ids().await.unwrap().len(), 50);
 
-        let context = results.join("\n\n");
-        Ok(format!("関連情報:\n{}\n\n", context))
+        store.discard().await.unwrap();
     }
 }
@@ -0,0 +1,139 @@
+use std::collections::HashMap;
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use regex::Regex;
+
+/// Expands acronyms/synonyms in retrieval queries so that a query like
+/// "PTO" can also match documents that only spell out "paid time off".
+///
+/// Loaded from a JSON object mapping a term to its expansions:
+/// `{ "PTO": ["paid time off"], "有給": ["年次有給休暇"] }`
+pub struct QueryExpander {
+    rules: Vec<(Regex, Vec<String>)>,
+}
+
+impl QueryExpander {
+    pub fn new(map: HashMap<String, Vec<String>>) -> Result<Self> {
+        let mut rules = Vec::new();
+        for (term, expansions) in map {
+            let pattern = Regex::new(&term_pattern(&term))
+                .with_context(|| format!("Invalid synonym term: {}", term))?;
+            rules.push((pattern, expansions));
+        }
+        Ok(Self { rules })
+    }
+
+    pub fn from_file(path: &Path) -> Result<Self> {
+        let data = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read synonym file: {}", path.display()))?;
+        let map: HashMap<String, Vec<String>> = serde_json::from_str(&data)
+            .with_context(|| format!("Failed to parse synonym file: {}", path.display()))?;
+        Self::new(map)
+    }
+
+    /// Load from `RAG_SYNONYMS_FILE`. Expansion stays off when unset.
+    pub fn from_env() -> Result<Option<Self>> {
+        match std::env::var("RAG_SYNONYMS_FILE") {
+            Ok(path) => Ok(Some(Self::from_file(Path::new(&path))?)),
+            Err(_) => Ok(None),
+        }
+    }
+
+    /// The original query followed by one variant per matched expansion.
+    pub fn expand(&self, query: &str) -> Vec<String> {
+        let mut variants = vec![query.to_string()];
+        for (pattern, expansions) in &self.rules {
+            if !pattern.is_match(query) {
+                continue;
+            }
+            for expansion in expansions {
+                let variant = pattern.replace_all(query, regex::NoExpand(expansion)).to_string();
+                if !variants.contains(&variant) {
+                    variants.push(variant);
+                }
+            }
+        }
+        variants
+    }
+}
+
+/// ASCII word boundaries so "PTO" matches in "PTOの申請" but not "SPTO".
+/// Only on an edge whose character is ASCII alphanumeric: a boundary never
+/// matches next to CJK text, which has no spaces between words.
+fn term_pattern(term: &str) -> String {
+    let word_edge = |c: Option<char>| c.is_some_and(|c| c.is_ascii_alphanumeric());
+    let boundary = |edge: bool| if edge { r"(?-u:\b)" } else { "" };
+    format!(
+        "(?i){}{}{}",
+        boundary(word_edge(term.chars().next())),
+        regex::escape(term),
+        boundary(word_edge(term.chars().last())),
+    )
+}
+
+/// Component-wise mean of the variant embeddings, L2-normalized.
+pub fn average_embeddings(embeddings: &[Vec<f32>]) -> Option<Vec<f32>> {
+    let first = embeddings.first()?;
+    let mut mean = vec![0.0f32; first.len()];
+    for embedding in embeddings {
+        for (m, v) in mean.iter_mut().zip(embedding) {
+            *m += v;
+        }
+    }
+    let norm = mean.iter().map(|v| v * v).sum::<f32>().sqrt();
+    if norm > 0.0 {
+        mean.iter_mut().for_each(|v| *v /= norm);
+    }
+    Some(mean)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn expander() -> QueryExpander {
+        let mut map = HashMap::new();
+        map.insert("PTO".to_string(), vec!["paid time off".to_string()]);
+        QueryExpander::new(map).unwrap()
+    }
+
+    #[test]
+    fn test_acronym_expanded() {
+        let variants = expander().expand("How do I request pto?");
+        assert_eq!(variants, vec![
+            "How do I request pto?".to_string(),
+            "How do I request paid time off?".to_string(),
+        ]);
+    }
+
+    #[test]
+    fn test_acronym_next_to_japanese_text() {
+        let variants = expander().expand("PTOの申請方法");
+        assert!(variants.contains(&"paid time offの申請方法".to_string()));
+    }
+
+    #[test]
+    fn test_japanese_term_expanded() {
+        let mut map = HashMap::new();
+        map.insert("有給".to_string(), vec!["年次有給休暇".to_string()]);
+        let variants = QueryExpander::new(map).unwrap().expand("有給の申請方法");
+        assert_eq!(variants, vec![
+            "有給の申請方法".to_string(),
+            "年次有給休暇の申請方法".to_string(),
+        ]);
+    }
+
+    #[test]
+    fn test_no_partial_word_match() {
+        assert_eq!(expander().expand("SPTO report").len(), 1);
+    }
+
+    #[test]
+    fn test_average_embeddings_normalized() {
+        let avg = average_embeddings(&[vec![1.0, 0.0], vec![0.0, 1.0]]).unwrap();
+        assert!((avg[0] - avg[1]).abs() < 1e-6);
+        let norm: f32 = avg.iter().map(|v| v * v).sum::<f32>().sqrt();
+        assert!((norm - 1.0).abs() < 1e-6);
+    }
+}
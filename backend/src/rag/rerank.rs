@@ -0,0 +1,232 @@
+use std::path::Path;
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use fastembed::{RerankInitOptionsUserDefined, TextRerank, TokenizerFiles, UserDefinedRerankingModel};
+use reqwest::Client;
+use serde::Deserialize;
+
+use crate::models::SearchHit;
+
+const DEFAULT_MODEL_DIR: &str = "/app/models/bge-reranker-base";
+const DEFAULT_REMOTE_MODEL: &str = "rerank-english-v3.0";
+/// ベクトル検索から取る候補数（top_k より小さければ top_k）
+pub const DEFAULT_RERANK_CANDIDATES: u64 = 20;
+
+enum Backend {
+    Local(Box<TextRerank>),
+    Remote(RemoteReranker),
+}
+
+/// Reorders vector search hits by a cross-encoder relevance score, which
+/// reads query and chunk together and ranks more precisely than embedding
+/// similarity alone.
+pub struct Reranker {
+    backend: Backend,
+    candidates: u64,
+}
+
+/// Cohere/Jina互換の `/rerank` エンドポイント
+struct RemoteReranker {
+    client: Client,
+    url: String,
+    api_key: Option<String>,
+    model: String,
+}
+
+#[derive(Deserialize)]
+struct RerankResponse {
+    results: Vec<RerankResult>,
+}
+
+#[derive(Deserialize)]
+struct RerankResult {
+    index: usize,
+    relevance_score: f32,
+}
+
+impl RemoteReranker {
+    async fn scores(&self, query: &str, documents: Vec<String>) -> Result<Vec<f32>> {
+        let expected = documents.len();
+        let mut req = self.client
+            .post(format!("{}/rerank", self.url))
+            .json(&serde_json::json!({ "model": self.model, "query": query, "documents": documents }));
+        if let Some(key) = &self.api_key {
+            req = req.bearer_auth(key);
+        }
+        let response = req.send().await.context("Rerank API request failed")?;
+        if !response.status().is_success() {
+            let status = response.status();
+            let body = response.text().await.unwrap_or_default();
+            anyhow::bail!("Rerank API error {}: {}", status, body);
+        }
+
+        let results = response.json::<RerankResponse>().await
+            .context("Invalid rerank API response")?
+            .results;
+        // 返ってこなかった文書は最下位
+        let mut scores = vec![f32::NEG_INFINITY; expected];
+        for result in results {
+            let score = scores.get_mut(result.index)
+                .ok_or_else(|| anyhow::anyhow!("Rerank API returned index {} for {} documents", result.index, expected))?;
+            *score = result.relevance_score;
+        }
+        Ok(scores)
+    }
+}
+
+impl Reranker {
+    /// Off unless `RAG_RERANK` is set. `RAG_RERANK=remote` uses `RERANK_API_URL`
+    /// (+ `RERANK_API_KEY`, `RERANK_MODEL`); `RAG_RERANK=local` loads the ONNX
+    /// cross-encoder from `RERANK_MODEL_DIR`. `RAG_RERANK_CANDIDATES` sets how
+    /// many vector hits are reranked.
+    pub fn from_env() -> Result<Option<Self>> {
+        let reranker = match std::env::var("RAG_RERANK").as_deref() {
+            Err(_) | Ok("") | Ok("off") => return Ok(None),
+            Ok("remote") => {
+                let url = std::env::var("RERANK_API_URL")
+                    .map_err(|_| anyhow::anyhow!("RAG_RERANK=remote requires RERANK_API_URL"))?;
+                let model = std::env::var("RERANK_MODEL").unwrap_or_else(|_| DEFAULT_REMOTE_MODEL.to_string());
+                Self::remote(&url, std::env::var("RERANK_API_KEY").ok(), &model)?
+            }
+            Ok("local") => {
+                let dir = std::env::var("RERANK_MODEL_DIR").unwrap_or_else(|_| DEFAULT_MODEL_DIR.to_string());
+                Self::local(Path::new(&dir))?
+            }
+            Ok(other) => anyhow::bail!("Unknown RAG_RERANK: {} (expected local or remote)", other),
+        };
+        let candidates = std::env::var("RAG_RERANK_CANDIDATES").ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(DEFAULT_RERANK_CANDIDATES);
+        Ok(Some(reranker.with_candidates(candidates)))
+    }
+
+    pub fn remote(url: &str, api_key: Option<String>, model: &str) -> Result<Self> {
+        let remote = RemoteReranker {
+            client: Client::builder().timeout(Duration::from_secs(30)).build()?,
+            url: url.trim_end_matches('/').to_string(),
+            api_key,
+            model: model.to_string(),
+        };
+        tracing::info!("Using remote reranker: {} ({})", remote.url, remote.model);
+        Ok(Self { backend: Backend::Remote(remote), candidates: DEFAULT_RERANK_CANDIDATES })
+    }
+
+    /// Cross-encoder exported to ONNX (e.g. bge-reranker-base) with its tokenizer files.
+    pub fn local(model_dir: &Path) -> Result<Self> {
+        let read = |name: &str| std::fs::read(model_dir.join(name))
+            .with_context(|| format!("Failed to read {}", model_dir.join(name).display()));
+
+        let model = UserDefinedRerankingModel {
+            onnx_file: read("model.onnx")?,
+            tokenizer_files: TokenizerFiles {
+                tokenizer_file: read("tokenizer.json")?,
+                config_file: read("config.json")?,
+                special_tokens_map_file: read("special_tokens_map.json")?,
+                tokenizer_config_file: read("tokenizer_config.json")?,
+            },
+        };
+        let model = TextRerank::try_new_from_user_defined(model, RerankInitOptionsUserDefined::default())
+            .map_err(|e| anyhow::anyhow!("Failed to initialize rerank model: {}", e))?;
+
+        tracing::info!("Using local reranker: {}", model_dir.display());
+        Ok(Self { backend: Backend::Local(Box::new(model)), candidates: DEFAULT_RERANK_CANDIDATES })
+    }
+
+    /// Vector hits to fetch and rerank per query.
+    pub fn with_candidates(mut self, candidates: u64) -> Self {
+        self.candidates = candidates.max(1);
+        self
+    }
+
+    pub fn candidates(&self) -> u64 {
+        self.candidates
+    }
+
+    /// One relevance score per document, in input order.
+    async fn scores(&self, query: &str, documents: Vec<String>) -> Result<Vec<f32>> {
+        match &self.backend {
+            Backend::Local(model) => {
+                let mut scores = vec![f32::NEG_INFINITY; documents.len()];
+                let results = model.rerank(query.to_string(), documents, false, None)
+                    .map_err(|e| anyhow::anyhow!("Rerank failed: {}", e))?;
+                for result in results {
+                    scores[result.index] = result.score;
+                }
+                Ok(scores)
+            }
+            Backend::Remote(remote) => remote.scores(query, documents).await,
+        }
+    }
+
+    /// The `top_k` hits with the highest rerank score. Hits keep their vector
+    /// score, so `RAG_MIN_SCORE` still means cosine similarity.
+    pub async fn rerank(&self, query: &str, hits: Vec<SearchHit>, top_k: usize) -> Result<Vec<SearchHit>> {
+        if hits.len() <= 1 {
+            return Ok(hits);
+        }
+        let scores = self.scores(query, hits.iter().map(|h| h.text.clone()).collect()).await?;
+        let mut ranked: Vec<(f32, SearchHit)> = scores.into_iter().zip(hits).collect();
+        ranked.sort_by(|a, b| b.0.total_cmp(&a.0));
+        Ok(ranked.into_iter().take(top_k).map(|(_, hit)| hit).collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{routing::post, Json, Router};
+    use axum_extra::TypedHeader;
+    use axum_extra::headers::{authorization::Bearer, Authorization};
+
+    /// Scores each document by how many query words it contains; results come
+    /// back best first, like the real APIs.
+    async fn spawn_mock_api() -> String {
+        let app = Router::new().route("/v1/rerank", post(
+            |TypedHeader(auth): TypedHeader<Authorization<Bearer>>, Json(body): Json<serde_json::Value>| async move {
+                assert_eq!(auth.token(), "sk-test");
+                assert_eq!(body["model"], "rerank-small");
+                let query = body["query"].as_str().unwrap().to_string();
+                let mut results: Vec<_> = body["documents"].as_array().unwrap().iter().enumerate()
+                    .map(|(i, doc)| {
+                        let doc = doc.as_str().unwrap();
+                        let matched = query.split_whitespace().filter(|w| doc.contains(w)).count();
+                        (i, matched as f32)
+                    })
+                    .collect();
+                results.sort_by(|a, b| b.1.total_cmp(&a.1));
+                let results: Vec<_> = results.into_iter()
+                    .map(|(index, score)| serde_json::json!({ "index": index, "relevance_score": score }))
+                    .collect();
+                Json(serde_json::json!({ "results": results }))
+            },
+        ));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        format!("http://{}/v1", addr)
+    }
+
+    fn hit(text: &str, score: f32) -> SearchHit {
+        SearchHit { text: text.to_string(), score, metadata: serde_json::json!({}) }
+    }
+
+    #[tokio::test]
+    async fn test_rerank_reorders_vector_hits() {
+        let url = spawn_mock_api().await;
+        let reranker = Reranker::remote(&url, Some("sk-test".to_string()), "rerank-small").unwrap();
+        // Vector order puts the general VPN chunk first; the answer is third
+        let hits = vec![
+            hit("VPNの概要と社内ネットワーク", 0.82),
+            hit("経費精算の締め日", 0.80),
+            hit("VPN 接続 エラー 809 は再起動で解消", 0.78),
+        ];
+
+        let reranked = reranker.rerank("VPN 接続 エラー", hits, 2).await.unwrap();
+
+        let texts: Vec<&str> = reranked.iter().map(|h| h.text.as_str()).collect();
+        assert_eq!(texts, vec!["VPN 接続 エラー 809 は再起動で解消", "VPNの概要と社内ネットワーク"]);
+        // Vector scores are kept for the min-score filter
+        assert_eq!(reranked[0].score, 0.78);
+    }
+}
@@ -1,20 +1,198 @@
+use std::time::Duration;
+
 use anyhow::Result;
+use chrono::{DateTime, Utc};
 use qdrant_client::Qdrant;
 use qdrant_client::qdrant::{
-    CreateCollectionBuilder, Distance, VectorParamsBuilder,
+    Condition, CreateAliasBuilder, CreateCollectionBuilder, Distance, Filter, VectorParamsBuilder,
     PointStruct, SearchPointsBuilder,
     ScrollPointsBuilder, PointsIdsList,
-    point_id::PointIdOptions, DeletePointsBuilder,
+    point_id::PointIdOptions, DeletePointsBuilder, GetPointsBuilder,
+    CollectionInfo, vectors_config, PointId, RetrievedPoint, VectorParams,
+    vector_output,
 };
 use serde_json::{Map as JsonMap, Value as JsonValue};
+use std::collections::HashMap;
+
+use crate::models::SearchHit;
+
+/// bge-small-en-v1.5 の出力次元
+pub const DEFAULT_DIMENSION: u64 = 384;
+
+/// Build a hit from a scored point's payload; points without text are skipped.
+fn hit_from_payload(score: f32, mut payload: HashMap<String, qdrant_client::qdrant::Value>) -> Option<SearchHit> {
+    let text = payload.remove("text")?.into_json();
+    let text = text.as_str()?.to_string();
+    let metadata = payload.remove("metadata")
+        .map(|v| v.into_json())
+        .unwrap_or(JsonValue::Null);
+    Some(SearchHit { text, score, metadata })
+}
+
+/// A stored point with its payload, as returned by a scroll.
+#[derive(Debug, Clone, PartialEq)]
+pub struct StoredPoint {
+    pub id: String,
+    pub text: String,
+    pub metadata: JsonValue,
+}
+
+/// Inverse of `point_id_string`: numeric strings are integer IDs, anything
+/// else is sent as a UUID.
+fn point_id(id: &str) -> PointId {
+    let options = match id.parse::<u64>() {
+        Ok(num) => PointIdOptions::Num(num),
+        Err(_) => PointIdOptions::Uuid(id.to_string()),
+    };
+    PointId { point_id_options: Some(options) }
+}
+
+fn point_id_string(id: PointId) -> Option<String> {
+    match id.point_id_options? {
+        PointIdOptions::Uuid(uuid) => Some(uuid),
+        PointIdOptions::Num(num) => Some(num.to_string()),
+    }
+}
+
+/// `VECTOR_DISTANCE` の値（cosine / dot / euclid / manhattan）
+fn parse_distance(value: &str) -> Option<Distance> {
+    match value.trim().to_ascii_lowercase().as_str() {
+        "cosine" => Some(Distance::Cosine),
+        "dot" => Some(Distance::Dot),
+        "euclid" | "euclidean" => Some(Distance::Euclid),
+        "manhattan" => Some(Distance::Manhattan),
+        _ => None,
+    }
+}
+
+/// Metric for new collections, from `VECTOR_DISTANCE` (default cosine).
+pub fn distance_from_env() -> Distance {
+    let Ok(value) = std::env::var("VECTOR_DISTANCE") else {
+        return Distance::Cosine;
+    };
+    parse_distance(&value).unwrap_or_else(|| {
+        tracing::warn!("Unknown VECTOR_DISTANCE '{}', using cosine", value);
+        Distance::Cosine
+    })
+}
+
+fn collection_builder(name: &str, dimension: u64, distance: Distance) -> CreateCollectionBuilder {
+    CreateCollectionBuilder::new(name)
+        .vectors_config(VectorParamsBuilder::new(dimension, distance))
+}
+
+/// Vector params of an existing (single unnamed vector) collection.
+fn existing_params(info: Option<&CollectionInfo>) -> Option<&VectorParams> {
+    let vectors = info?.config.as_ref()?.params.as_ref()?.vectors_config.as_ref()?;
+    match vectors.config.as_ref()? {
+        vectors_config::Config::Params(params) => Some(params),
+        vectors_config::Config::ParamsMap(_) => None,
+    }
+}
+
+/// Vector size of an existing (single unnamed vector) collection.
+fn existing_dimension(info: Option<&CollectionInfo>) -> Option<u64> {
+    existing_params(info).map(|params| params.size)
+}
+
+/// 距離関数が違っても検索自体はできてしまうので、止めずに警告だけ出す
+fn warn_distance_mismatch(name: &str, existing: Option<Distance>, expected: Distance) {
+    if let Some(existing) = existing {
+        if existing != expected {
+            tracing::warn!(
+                "Collection '{}' uses {} distance but VECTOR_DISTANCE is {}; \
+                 scores will follow the collection's metric until it is reindexed into a new collection",
+                name, existing.as_str_name(), expected.as_str_name()
+            );
+        }
+    }
+}
+
+/// 既存コレクションの次元がモデルと違うまま書き込むと検索結果が壊れるので、起動時に止める
+fn check_collection_dimension(name: &str, existing: Option<u64>, expected: u64) -> Result<()> {
+    match existing {
+        Some(size) if size != expected => anyhow::bail!(
+            "Collection '{}' has {}-dimensional vectors but the embedding model produces {}; \
+             reindex into a new collection or switch back to the original model",
+            name, size, expected
+        ),
+        _ => Ok(()),
+    }
+}
+
+/// Restrict a search to chunks whose `metadata.category` equals `category`.
+fn category_filter(category: Option<&str>) -> Option<Filter> {
+    category.map(|c| Filter::must([Condition::matches("metadata.category", c.to_string())]))
+}
+
+/// Points of the file whose `metadata.file_id` is `file_id`.
+fn file_filter(file_id: &str) -> Filter {
+    Filter::must([Condition::matches("metadata.file_id", file_id.to_string())])
+}
+
+/// Indexed-file points whose `metadata.file_id` is not in `existing`.
+/// Points without a `file_id` (documents added through the API) never match.
+fn stale_file_filter(existing: Vec<String>) -> Filter {
+    let mut conditions = vec![Condition::is_empty("metadata.file_id")];
+    if !existing.is_empty() {
+        conditions.push(Condition::matches("metadata.file_id", existing));
+    }
+    Filter::must_not(conditions)
+}
+
+/// Points not backed by an indexed file: documents added through the API.
+fn api_document_filter() -> Filter {
+    Filter::must([Condition::is_empty("metadata.file_id")])
+}
+
+/// Chunks whose text contains any of `terms` (substring match on the payload),
+/// optionally within a category.
+fn keyword_filter(terms: &[String], category: Option<&str>) -> Filter {
+    let mut filter = Filter::should(terms.iter().map(|t| Condition::matches_text("text", t.clone())));
+    if let Some(category_filter) = category_filter(category) {
+        filter.must = category_filter.must;
+    }
+    filter
+}
+
+/// Physical collection a staged reindex writes into before it goes live.
+fn staging_collection_name(live: &str, now: DateTime<Utc>) -> String {
+    format!("{}_staging_{}", live, now.format("%Y%m%d%H%M%S%3f"))
+}
+
+/// `promote` dropped the plain live collection but couldn't alias the name
+/// to the staging collection, which now holds the only copy of the index.
+/// The staging collection must be kept (and aliased by hand) rather than discarded.
+#[derive(Debug, thiserror::Error)]
+#[error("Dropped collection {live} but failed to alias it to {staging}; {staging} is now the only copy: {source}")]
+pub struct StagingIsOnlyCopy {
+    pub live: String,
+    pub staging: String,
+    source: anyhow::Error,
+}
+
+/// What the live collection name currently refers to.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum LiveTarget {
+    /// An alias to a physical (previously staged) collection
+    Alias(String),
+    /// A plain collection created before staged reindexing was enabled
+    Collection,
+    Missing,
+}
 
 pub struct VectorStore {
     client: Qdrant,
     collection_name: String,
+    dimension: u64,
+    distance: Distance,
 }
 
 impl VectorStore {
-    pub async fn new(url: &str, collection_name: &str) -> Result<Self> {
+    /// `dimension` must match the embedding model (`EmbeddingGenerator::dimension`).
+    /// `distance` only applies to collections created here; an existing
+    /// collection keeps its metric.
+    pub async fn new(url: &str, collection_name: &str, dimension: u64, distance: Distance) -> Result<Self> {
         tracing::info!("Building Qdrant client for URL: {}", url);
         let client = match Qdrant::from_url(url).build() {
             Ok(c) => {
@@ -30,6 +208,8 @@ impl VectorStore {
         let store = Self {
             client,
             collection_name: collection_name.to_string(),
+            dimension,
+            distance,
         };
 
         tracing::info!("Checking Qdrant collection...");
@@ -45,12 +225,155 @@ impl VectorStore {
     async fn ensure_collection(&self) -> Result<()> {
         if !self.client.collection_exists(&self.collection_name).await? {
             self.client
-                .create_collection(
-                    CreateCollectionBuilder::new(&self.collection_name)
-                        .vectors_config(VectorParamsBuilder::new(384, Distance::Cosine)),
+                .create_collection(collection_builder(&self.collection_name, self.dimension, self.distance))
+                .await?;
+            return Ok(());
+        }
+
+        let info = self.client.collection_info(self.collection_name.as_str()).await?;
+        let info = info.result.as_ref();
+        check_collection_dimension(&self.collection_name, existing_dimension(info), self.dimension)?;
+        let existing_distance = existing_params(info).and_then(|params| Distance::try_from(params.distance).ok());
+        warn_distance_mismatch(&self.collection_name, existing_distance, self.distance);
+        Ok(())
+    }
+
+    /// Readiness probe: true if Qdrant answers `collection_exists` in time.
+    pub async fn health(&self) -> bool {
+        let probe = self.client.collection_exists(&self.collection_name);
+        matches!(tokio::time::timeout(Duration::from_secs(3), probe).await, Ok(Ok(_)))
+    }
+
+    pub fn dimension(&self) -> u64 {
+        self.dimension
+    }
+
+    pub fn distance(&self) -> Distance {
+        self.distance
+    }
+
+    pub fn collection_name(&self) -> &str {
+        &self.collection_name
+    }
+
+    /// Store for another collection on the same Qdrant, created if missing.
+    pub async fn for_collection(&self, collection_name: &str) -> Result<VectorStore> {
+        let store = VectorStore {
+            client: self.client.clone(),
+            collection_name: collection_name.to_string(),
+            dimension: self.dimension,
+            distance: self.distance,
+        };
+        store.ensure_collection().await?;
+        Ok(store)
+    }
+
+    /// Create an empty staging collection alongside this (live) one.
+    /// Writes to the returned store don't affect searches on the live name
+    /// until `promote` is called.
+    pub async fn create_staging(&self) -> Result<VectorStore> {
+        let staging = VectorStore {
+            client: self.client.clone(),
+            collection_name: staging_collection_name(&self.collection_name, Utc::now()),
+            dimension: self.dimension,
+            distance: self.distance,
+        };
+        staging.ensure_collection().await?;
+        tracing::info!("Created staging collection {}", staging.collection_name);
+        Ok(staging)
+    }
+
+    async fn live_target(&self) -> Result<LiveTarget> {
+        let aliases = self.client.list_aliases().await?;
+        if let Some(alias) = aliases.aliases.iter().find(|a| a.alias_name == self.collection_name) {
+            return Ok(LiveTarget::Alias(alias.collection_name.clone()));
+        }
+        if self.client.collection_exists(&self.collection_name).await? {
+            return Ok(LiveTarget::Collection);
+        }
+        Ok(LiveTarget::Missing)
+    }
+
+    /// Point the live name at `staging` and drop the collection it replaced.
+    /// Re-pointing an existing alias is a single atomic operation in Qdrant,
+    /// so searches see either the old or the new snapshot, never a mix.
+    /// Fails with `StagingIsOnlyCopy` if the old plain collection is already
+    /// gone when aliasing fails.
+    pub async fn promote(&self, staging: &VectorStore) -> Result<()> {
+        let previous = self.live_target().await?;
+        if previous == LiveTarget::Collection {
+            // An alias can't share a name with a collection, so the first
+            // staged run has to drop the original before aliasing.
+            tracing::warn!("Replacing plain collection {} with an alias", self.collection_name);
+            self.client.delete_collection(&self.collection_name).await?;
+        }
+
+        let aliased = self.client
+            .create_alias(CreateAliasBuilder::new(&staging.collection_name, &self.collection_name))
+            .await;
+        if let Err(e) = aliased {
+            if previous == LiveTarget::Collection {
+                return Err(StagingIsOnlyCopy {
+                    live: self.collection_name.clone(),
+                    staging: staging.collection_name.clone(),
+                    source: e.into(),
+                }.into());
+            }
+            return Err(e.into());
+        }
+        tracing::info!("Alias {} now points to {}", self.collection_name, staging.collection_name);
+
+        if let LiveTarget::Alias(old) = previous {
+            if old != staging.collection_name {
+                if let Err(e) = self.client.delete_collection(&old).await {
+                    tracing::error!("Failed to delete previous collection {}: {}", old, e);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Copy documents added through the API (points without `metadata.file_id`)
+    /// into `staging`, so a staged reindex of the upload dir keeps them.
+    /// Returns how many were copied.
+    pub async fn copy_api_documents(&self, staging: &VectorStore) -> Result<usize> {
+        let points: Vec<PointStruct> = self.scroll_all(Some(api_document_filter()), true, true).await?
+            .into_iter()
+            .filter_map(|point| {
+                let vector = match point.vectors?.get_vector()? {
+                    vector_output::Vector::Dense(dense) => dense.data,
+                    _ => return None,
+                };
+                Some(PointStruct::new(point.id?, vector, point.payload))
+            })
+            .collect();
+        let count = points.len();
+        for batch in points.chunks(100) {
+            self.client
+                .upsert_points(
+                    qdrant_client::qdrant::UpsertPointsBuilder::new(&staging.collection_name, batch.to_vec()),
                 )
                 .await?;
         }
+        Ok(count)
+    }
+
+    /// Drop a staging collection after a failed reindex.
+    pub async fn discard(&self) -> Result<()> {
+        self.client.delete_collection(&self.collection_name).await?;
+        tracing::info!("Discarded staging collection {}", self.collection_name);
+        Ok(())
+    }
+
+    /// Reject embeddings whose length doesn't match the collection, so a
+    /// model/collection mismatch fails here instead of deep inside Qdrant.
+    fn check_dimension(&self, id: &str, embedding: &[f32]) -> Result<()> {
+        if embedding.len() as u64 != self.dimension {
+            anyhow::bail!(
+                "Embedding dimension mismatch for '{}' in collection '{}': expected {}, got {}",
+                id, self.collection_name, self.dimension, embedding.len()
+            );
+        }
         Ok(())
     }
 
@@ -61,66 +384,101 @@ impl VectorStore {
         embedding: Vec<f32>,
         metadata: serde_json::Value,
     ) -> Result<()> {
-        let mut payload_map = JsonMap::new();
-        payload_map.insert("text".to_string(), JsonValue::String(text.to_string()));
-        payload_map.insert("metadata".to_string(), metadata);
-        let point = PointStruct::new(id.to_string(), embedding, payload_map);
+        self.add_documents(vec![(id.to_string(), text.to_string(), embedding, metadata)]).await
+    }
+
+    /// Upsert `(id, text, embedding, metadata)` entries in one request.
+    /// Nothing is written if any embedding has the wrong dimension.
+    pub async fn add_documents(&self, documents: Vec<(String, String, Vec<f32>, JsonValue)>) -> Result<()> {
+        if documents.is_empty() {
+            return Ok(());
+        }
+        for (id, _, embedding, _) in &documents {
+            self.check_dimension(id, embedding)?;
+        }
+
+        let points: Vec<PointStruct> = documents.into_iter()
+            .map(|(id, text, embedding, metadata)| {
+                let mut payload_map = JsonMap::new();
+                payload_map.insert("text".to_string(), JsonValue::String(text));
+                payload_map.insert("metadata".to_string(), metadata);
+                PointStruct::new(point_id(&id), embedding, payload_map)
+            })
+            .collect();
 
         self.client
             .upsert_points(
-                qdrant_client::qdrant::UpsertPointsBuilder::new(&self.collection_name, vec![point]),
+                qdrant_client::qdrant::UpsertPointsBuilder::new(&self.collection_name, points),
             )
             .await?;
 
         Ok(())
     }
 
-    pub async fn search(&self, query_vector: Vec<f32>, limit: u64) -> Result<Vec<String>> {
-        let search_result = self
-            .client
-            .search_points(
-                SearchPointsBuilder::new(&self.collection_name, query_vector, limit)
-                    .with_payload(true),
-            )
-            .await?;
+    pub async fn search(
+        &self,
+        query_vector: Vec<f32>,
+        limit: u64,
+        category: Option<&str>,
+    ) -> Result<Vec<SearchHit>> {
+        self.search_filtered(query_vector, limit, category_filter(category)).await
+    }
 
-        let mut results = Vec::new();
-        for point in search_result.result {
-            if let Some(payload) = point.payload.get("text") {
-                if let Some(text) = payload.as_str() {
-                    results.push(text.to_string());
-                }
-            }
+    /// Vector search restricted to chunks containing at least one of `terms`,
+    /// so exact-token matches are scored even when they rank outside the
+    /// plain top-k.
+    pub async fn keyword_search(
+        &self,
+        query_vector: Vec<f32>,
+        limit: u64,
+        terms: &[String],
+        category: Option<&str>,
+    ) -> Result<Vec<SearchHit>> {
+        if terms.is_empty() {
+            return Ok(Vec::new());
         }
+        self.search_filtered(query_vector, limit, Some(keyword_filter(terms, category))).await
+    }
 
-        Ok(results)
+    async fn search_filtered(
+        &self,
+        query_vector: Vec<f32>,
+        limit: u64,
+        filter: Option<Filter>,
+    ) -> Result<Vec<SearchHit>> {
+        let mut request = SearchPointsBuilder::new(&self.collection_name, query_vector, limit)
+            .with_payload(true);
+        if let Some(filter) = filter {
+            request = request.filter(filter);
+        }
+        let search_result = self.client.search_points(request).await?;
+
+        Ok(search_result.result
+            .into_iter()
+            .filter_map(|point| hit_from_payload(point.score, point.payload))
+            .collect())
     }
 
-    pub async fn scroll_all_point_ids(&self) -> Result<Vec<String>> {
-        let mut all_ids = Vec::new();
-        let mut offset: Option<qdrant_client::qdrant::PointId> = None;
+    /// Every point matching `filter` (all points if `None`), page by page.
+    async fn scroll_all(&self, filter: Option<Filter>, with_payload: bool, with_vectors: bool) -> Result<Vec<RetrievedPoint>> {
+        let mut points = Vec::new();
+        let mut offset: Option<PointId> = None;
 
         loop {
             let mut builder = ScrollPointsBuilder::new(&self.collection_name)
                 .limit(100)
-                .with_payload(false);
+                .with_payload(with_payload)
+                .with_vectors(with_vectors);
 
+            if let Some(ref filter) = filter {
+                builder = builder.filter(filter.clone());
+            }
             if let Some(ref off) = offset {
                 builder = builder.offset(off.clone());
             }
 
             let result = self.client.scroll(builder).await?;
-
-            for point in &result.result {
-                if let Some(ref id) = point.id {
-                    if let Some(ref id_options) = id.point_id_options {
-                        match id_options {
-                            PointIdOptions::Uuid(uuid) => all_ids.push(uuid.clone()),
-                            PointIdOptions::Num(num) => all_ids.push(num.to_string()),
-                        }
-                    }
-                }
-            }
+            points.extend(result.result);
 
             offset = result.next_page_offset;
             if offset.is_none() {
@@ -128,7 +486,37 @@ impl VectorStore {
             }
         }
 
-        Ok(all_ids)
+        Ok(points)
+    }
+
+    pub async fn scroll_all_point_ids(&self) -> Result<Vec<String>> {
+        Ok(self.scroll_all(None, false, false).await?
+            .into_iter()
+            .filter_map(|point| point_id_string(point.id?))
+            .collect())
+    }
+
+    /// Points stored for one indexed file (`metadata.file_id`).
+    pub async fn file_points(&self, file_id: &str) -> Result<Vec<StoredPoint>> {
+        Ok(self.scroll_all(Some(file_filter(file_id)), true, false).await?
+            .into_iter()
+            .filter_map(|mut point| {
+                let id = point_id_string(point.id.take()?)?;
+                let hit = hit_from_payload(0.0, point.payload)?;
+                Some(StoredPoint { id, text: hit.text, metadata: hit.metadata })
+            })
+            .collect())
+    }
+
+    /// Delete the points of indexed files that are not in `existing_file_ids`.
+    pub async fn delete_stale_files(&self, existing_file_ids: Vec<String>) -> Result<()> {
+        self.client
+            .delete_points(
+                DeletePointsBuilder::new(&self.collection_name)
+                    .points(stale_file_filter(existing_file_ids)),
+            )
+            .await?;
+        Ok(())
     }
 
     pub async fn delete_points(&self, ids: Vec<String>) -> Result<()> {
@@ -136,12 +524,7 @@ impl VectorStore {
             return Ok(());
         }
 
-        let point_ids: Vec<qdrant_client::qdrant::PointId> = ids
-            .into_iter()
-            .map(|id| qdrant_client::qdrant::PointId {
-                point_id_options: Some(PointIdOptions::Uuid(id)),
-            })
-            .collect();
+        let point_ids: Vec<PointId> = ids.iter().map(|id| point_id(id)).collect();
 
         self.client
             .delete_points(
@@ -152,4 +535,346 @@ impl VectorStore {
 
         Ok(())
     }
+
+    /// `metadata.content_hash` of the given points, for those that exist and have one.
+    pub async fn stored_content_hashes(&self, ids: &[String]) -> Result<HashMap<String, String>> {
+        if ids.is_empty() {
+            return Ok(HashMap::new());
+        }
+        let point_ids: Vec<PointId> = ids.iter().map(|id| point_id(id)).collect();
+        let result = self.client
+            .get_points(
+                GetPointsBuilder::new(&self.collection_name, point_ids)
+                    .with_payload(true)
+                    .with_vectors(false),
+            )
+            .await?;
+
+        Ok(result.result
+            .into_iter()
+            .filter_map(|mut point| {
+                let id = point_id_string(point.id.take()?)?;
+                let metadata = point.payload.remove("metadata")?.into_json();
+                let hash = metadata.get("content_hash")?.as_str()?.to_string();
+                Some((id, hash))
+            })
+            .collect())
+    }
+
+    /// Remove a single document. Returns `false` when no point has that ID.
+    pub async fn delete_document(&self, id: &str) -> Result<bool> {
+        // Point IDs are UUIDs or integers; anything else can't exist in the collection
+        if uuid::Uuid::parse_str(id).is_err() && id.parse::<u64>().is_err() {
+            return Ok(false);
+        }
+
+        let existing = self.client
+            .get_points(
+                GetPointsBuilder::new(&self.collection_name, vec![point_id(id)])
+                    .with_payload(false)
+                    .with_vectors(false),
+            )
+            .await?;
+        if existing.result.is_empty() {
+            return Ok(false);
+        }
+
+        self.delete_points(vec![id.to_string()]).await?;
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+impl VectorStore {
+    /// Delete the live name, whether it is a plain collection or an alias
+    /// (together with the collection it points to).
+    pub(crate) async fn drop_live(&self) -> Result<()> {
+        match self.live_target().await? {
+            LiveTarget::Alias(target) => {
+                self.client.delete_alias(self.collection_name.as_str()).await?;
+                self.client.delete_collection(&target).await?;
+            }
+            LiveTarget::Collection => {
+                self.client.delete_collection(&self.collection_name).await?;
+            }
+            LiveTarget::Missing => {}
+        }
+        Ok(())
+    }
+}
+
+/// A store whose requests fail: nothing listens on the port.
+#[cfg(test)]
+pub(crate) fn unconnected_store(dimension: u64) -> VectorStore {
+    VectorStore {
+        client: Qdrant::from_url("http://127.0.0.1:1").build().unwrap(),
+        collection_name: "test".to_string(),
+        dimension,
+        distance: Distance::Cosine,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_mismatched_collection_dimension_rejected() {
+        let err = check_collection_dimension("documents", Some(384), 768).unwrap_err().to_string();
+        assert!(err.contains("'documents' has 384-dimensional"), "{}", err);
+        assert!(err.contains("produces 768"), "{}", err);
+
+        assert!(check_collection_dimension("documents", Some(768), 768).is_ok());
+        // named vectors etc. are not checked
+        assert!(check_collection_dimension("documents", None, 768).is_ok());
+    }
+
+    #[test]
+    fn test_collection_builder_uses_configured_distance() {
+        let request = collection_builder("documents", 384, Distance::Dot).build();
+        let vectors = request.vectors_config.and_then(|v| v.config);
+        let Some(vectors_config::Config::Params(params)) = vectors else {
+            panic!("expected a single unnamed vector config, got {:?}", vectors);
+        };
+        assert_eq!(params.size, 384);
+        assert_eq!(params.distance(), Distance::Dot);
+    }
+
+    #[test]
+    fn test_parse_distance() {
+        assert_eq!(parse_distance("dot"), Some(Distance::Dot));
+        assert_eq!(parse_distance(" Cosine "), Some(Distance::Cosine));
+        assert_eq!(parse_distance("euclidean"), Some(Distance::Euclid));
+        assert_eq!(parse_distance("manhattan"), Some(Distance::Manhattan));
+        assert_eq!(parse_distance("hamming"), None);
+    }
+
+    #[tokio::test]
+    async fn test_wrong_dimension_rejected_before_upsert() {
+        let store = unconnected_store(DEFAULT_DIMENSION);
+        let err = store
+            .add_document("doc1", "text", vec![0.0; 768], serde_json::json!({}))
+            .await
+            .unwrap_err();
+        let msg = err.to_string();
+        assert!(msg.contains("expected 384"), "{}", msg);
+        assert!(msg.contains("got 768"), "{}", msg);
+        assert!(msg.contains("doc1"), "{}", msg);
+    }
+
+    #[test]
+    fn test_hit_keeps_score_and_metadata() {
+        let mut payload = HashMap::new();
+        payload.insert("text".to_string(), "本文".into());
+        let metadata = serde_json::json!({"file_path": "/uploads/a.txt", "chunk_index": 2});
+        payload.insert("metadata".to_string(), serde_json::from_value::<qdrant_client::qdrant::Value>(metadata.clone()).unwrap());
+
+        let hit = hit_from_payload(0.83, payload).unwrap();
+        assert_eq!(hit.text, "本文");
+        assert_eq!(hit.score, 0.83);
+        assert_eq!(hit.metadata, metadata);
+
+        assert!(hit_from_payload(0.5, HashMap::new()).is_none());
+    }
+
+    #[test]
+    fn test_point_ids_round_trip_as_num_or_uuid() {
+        let uuid = "5c56c793-69f3-4fbf-87e6-c4bf54c28c26";
+        assert_eq!(point_id("42").point_id_options, Some(PointIdOptions::Num(42)));
+        assert_eq!(point_id(uuid).point_id_options, Some(PointIdOptions::Uuid(uuid.to_string())));
+        for id in ["42", uuid] {
+            assert_eq!(point_id_string(point_id(id)).as_deref(), Some(id));
+        }
+    }
+
+    #[test]
+    fn test_staging_name_is_distinct_from_live() {
+        let now = DateTime::parse_from_rfc3339("2024-05-01T12:30:45.123Z").unwrap().with_timezone(&Utc);
+        assert_eq!(staging_collection_name("documents", now), "documents_staging_20240501123045123");
+    }
+
+    #[test]
+    fn test_category_filter_matches_metadata_category() {
+        assert!(category_filter(None).is_none());
+
+        let filter = category_filter(Some("人事")).unwrap();
+        assert_eq!(filter, Filter::must([Condition::matches("metadata.category", "人事".to_string())]));
+    }
+
+    #[test]
+    fn test_stale_file_filter_spares_existing_files_and_api_documents() {
+        let filter = stale_file_filter(vec!["aaaa".to_string(), "bbbb".to_string()]);
+        assert!(filter.must.is_empty());
+        assert_eq!(filter.must_not, vec![
+            Condition::is_empty("metadata.file_id"),
+            Condition::matches("metadata.file_id", vec!["aaaa".to_string(), "bbbb".to_string()]),
+        ]);
+
+        // With no files left, every indexed-file point is stale
+        assert_eq!(stale_file_filter(Vec::new()).must_not, vec![Condition::is_empty("metadata.file_id")]);
+    }
+
+    #[test]
+    fn test_keyword_filter_combines_terms_and_category() {
+        let terms = vec!["SKU-1234".to_string(), "ABX".to_string()];
+        let filter = keyword_filter(&terms, Some("製品"));
+        assert_eq!(filter.should, vec![
+            Condition::matches_text("text", "SKU-1234"),
+            Condition::matches_text("text", "ABX"),
+        ]);
+        assert_eq!(filter.must, vec![Condition::matches("metadata.category", "製品".to_string())]);
+
+        assert!(keyword_filter(&terms, None).must.is_empty());
+    }
+
+    /// Runs only when `TEST_QDRANT_URL` is set.
+    #[tokio::test]
+    async fn test_category_filter_returns_only_matching_hits() {
+        let Ok(url) = std::env::var("TEST_QDRANT_URL") else { return };
+        let store = VectorStore::new(&url, &format!("test_category_{}", uuid::Uuid::new_v4().simple()), DEFAULT_DIMENSION, Distance::Cosine).await.unwrap();
+        for (text, category) in [("経費精算", "経理"), ("有給休暇", "人事"), ("評価制度", "人事")] {
+            let id = uuid::Uuid::new_v4().to_string();
+            store.add_document(&id, text, vec![0.1; 384], serde_json::json!({"category": category})).await.unwrap();
+        }
+
+        let hits = store.search(vec![0.1; 384], 10, Some("人事")).await.unwrap();
+        assert_eq!(hits.len(), 2);
+        assert!(hits.iter().all(|h| h.metadata["category"] == "人事"));
+        assert_eq!(store.search(vec![0.1; 384], 10, None).await.unwrap().len(), 3);
+
+        store.discard().await.unwrap();
+    }
+
+    /// Runs only when `TEST_QDRANT_URL` is set.
+    #[tokio::test]
+    async fn test_reopening_with_other_dimension_fails() {
+        let Ok(url) = std::env::var("TEST_QDRANT_URL") else { return };
+        let name = format!("test_dimension_{}", uuid::Uuid::new_v4().simple());
+        let store = VectorStore::new(&url, &name, DEFAULT_DIMENSION, Distance::Cosine).await.unwrap();
+
+        let err = VectorStore::new(&url, &name, 768, Distance::Cosine).await.err().unwrap();
+        assert!(err.to_string().contains("384-dimensional"), "{}", err);
+        store.discard().await.unwrap();
+    }
+
+    /// Runs only when `TEST_QDRANT_URL` is set.
+    #[tokio::test]
+    async fn test_collections_are_isolated() {
+        let Ok(url) = std::env::var("TEST_QDRANT_URL") else { return };
+        let suffix = uuid::Uuid::new_v4().simple().to_string();
+        let team_a = VectorStore::new(&url, &format!("test_team_a_{}", suffix), DEFAULT_DIMENSION, Distance::Cosine).await.unwrap();
+        let team_b = team_a.for_collection(&format!("test_team_b_{}", suffix)).await.unwrap();
+
+        team_a.add_document(&uuid::Uuid::new_v4().to_string(), "A社の資料", vec![0.1; 384], serde_json::json!({})).await.unwrap();
+        team_b.add_document(&uuid::Uuid::new_v4().to_string(), "B社の資料", vec![0.1; 384], serde_json::json!({})).await.unwrap();
+
+        let hits_a = team_a.search(vec![0.1; 384], 10, None).await.unwrap();
+        let hits_b = team_b.search(vec![0.1; 384], 10, None).await.unwrap();
+        assert_eq!(hits_a.iter().map(|h| h.text.as_str()).collect::<Vec<_>>(), vec!["A社の資料"]);
+        assert_eq!(hits_b.iter().map(|h| h.text.as_str()).collect::<Vec<_>>(), vec!["B社の資料"]);
+
+        team_a.discard().await.unwrap();
+        team_b.discard().await.unwrap();
+    }
+
+    /// Runs only when `TEST_QDRANT_URL` is set.
+    #[tokio::test]
+    async fn test_deleted_document_not_returned_by_search() {
+        let Ok(url) = std::env::var("TEST_QDRANT_URL") else { return };
+        let store = VectorStore::new(&url, &format!("test_delete_{}", uuid::Uuid::new_v4().simple()), DEFAULT_DIMENSION, Distance::Cosine).await.unwrap();
+        let id = uuid::Uuid::new_v4().to_string();
+        store.add_document(&id, "削除対象", vec![0.1; 384], serde_json::json!({})).await.unwrap();
+        assert_eq!(store.search(vec![0.1; 384], 10, None).await.unwrap().len(), 1);
+
+        assert!(store.delete_document(&id).await.unwrap());
+        assert!(store.search(vec![0.1; 384], 10, None).await.unwrap().is_empty());
+
+        // Already gone, or never a valid ID
+        assert!(!store.delete_document(&id).await.unwrap());
+        assert!(!store.delete_document("not-a-uuid").await.unwrap());
+
+        store.discard().await.unwrap();
+    }
+
+    /// Runs only when `TEST_QDRANT_URL` is set.
+    #[tokio::test]
+    async fn test_delete_points_handles_numeric_and_uuid_ids() {
+        let Ok(url) = std::env::var("TEST_QDRANT_URL") else { return };
+        let store = VectorStore::new(&url, &format!("test_delete_ids_{}", uuid::Uuid::new_v4().simple()), DEFAULT_DIMENSION, Distance::Cosine).await.unwrap();
+        let uuid_id = uuid::Uuid::new_v4().to_string();
+        store.add_document(&uuid_id, "UUID", vec![0.1; 384], serde_json::json!({})).await.unwrap();
+        store.add_document("7", "数値ID", vec![0.1; 384], serde_json::json!({})).await.unwrap();
+
+        let mut ids = store.scroll_all_point_ids().await.unwrap();
+        ids.sort();
+        let mut expected = vec!["7".to_string(), uuid_id];
+        expected.sort();
+        assert_eq!(ids, expected);
+
+        store.delete_points(ids).await.unwrap();
+        assert!(store.scroll_all_point_ids().await.unwrap().is_empty());
+
+        store.discard().await.unwrap();
+    }
+
+    /// Runs only when `TEST_QDRANT_URL` is set.
+    #[tokio::test]
+    async fn test_add_documents_batch_searchable() {
+        let Ok(url) = std::env::var("TEST_QDRANT_URL") else { return };
+        let store = VectorStore::new(&url, &format!("test_batch_{}", uuid::Uuid::new_v4().simple()), 3, Distance::Cosine).await.unwrap();
+        let documents: Vec<(String, String, Vec<f32>, JsonValue)> = (0..40)
+            .map(|i| (
+                uuid::Uuid::new_v4().to_string(),
+                format!("chunk {}", i),
+                vec![1.0, i as f32, 0.5],
+                serde_json::json!({"chunk_index": i}),
+            ))
+            .collect();
+        store.add_documents(documents).await.unwrap();
+
+        let hits = store.search(vec![1.0, 0.0, 0.5], 100, None).await.unwrap();
+        let mut texts: Vec<String> = hits.into_iter().map(|h| h.text).collect();
+        texts.sort();
+        let mut expected: Vec<String> = (0..40).map(|i| format!("chunk {}", i)).collect();
+        expected.sort();
+        assert_eq!(texts, expected);
+
+        // One bad embedding rejects the whole batch before anything is sent
+        let mixed = vec![
+            (uuid::Uuid::new_v4().to_string(), "ok".to_string(), vec![0.1, 0.2, 0.3], serde_json::json!({})),
+            (uuid::Uuid::new_v4().to_string(), "bad".to_string(), vec![0.1, 0.2], serde_json::json!({})),
+        ];
+        assert!(store.add_documents(mixed).await.is_err());
+        assert_eq!(store.scroll_all_point_ids().await.unwrap().len(), 40);
+
+        store.discard().await.unwrap();
+    }
+
+    /// Runs only when `TEST_QDRANT_URL` is set (e.g. http://localhost:6334).
+    #[tokio::test]
+    async fn test_live_collection_unchanged_until_promote() {
+        let Ok(url) = std::env::var("TEST_QDRANT_URL") else { return };
+        let live_name = format!("test_live_{}", uuid::Uuid::new_v4().simple());
+        let live = VectorStore::new(&url, &live_name, DEFAULT_DIMENSION, Distance::Cosine).await.unwrap();
+        let old_id = uuid::Uuid::new_v4().to_string();
+        live.add_document(&old_id, "old", vec![0.1; 384], serde_json::json!({})).await.unwrap();
+
+        let staging = live.create_staging().await.unwrap();
+        let new_id = uuid::Uuid::new_v4().to_string();
+        staging.add_document(&new_id, "new", vec![0.2; 384], serde_json::json!({})).await.unwrap();
+
+        // Mid-reindex: the live name still serves the old snapshot only
+        assert_eq!(live.scroll_all_point_ids().await.unwrap(), vec![old_id]);
+
+        live.promote(&staging).await.unwrap();
+        assert_eq!(live.scroll_all_point_ids().await.unwrap(), vec![new_id]);
+
+        // A second staged run replaces the aliased collection and drops the old one
+        let second = live.create_staging().await.unwrap();
+        live.promote(&second).await.unwrap();
+        assert!(!live.client.collection_exists(&staging.collection_name).await.unwrap());
+
+        second.discard().await.unwrap();
+        live.client.delete_alias(live_name.as_str()).await.unwrap();
+    }
 }
@@ -1,4 +1,5 @@
 use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
 
 use anyhow::Result;
 use chrono::{DateTime, Utc};
@@ -8,6 +9,18 @@ use crate::models::{VersionMeta, VersionEntry, FileVersionHistory};
 pub const VERSIONS_DIR_NAME: &str = ".versions";
 pub const MAX_VERSIONS: u32 = 10;
 
+/// 起動時に `MAX_FILE_VERSIONS` から設定。各ファイルの meta.json の上限より優先する
+static CONFIGURED_MAX_VERSIONS: OnceLock<u32> = OnceLock::new();
+
+pub fn max_versions_from_env() -> Option<u32> {
+    std::env::var("MAX_FILE_VERSIONS").ok()?.parse().ok()
+}
+
+/// Deployment-wide version cap; only the first call takes effect.
+pub fn configure_max_versions(max: u32) {
+    let _ = CONFIGURED_MAX_VERSIONS.set(max.max(1));
+}
+
 /// Returns the .versions/ directory for a given file's parent directory.
 fn versions_dir_for(file_path: &Path) -> PathBuf {
     file_path
@@ -35,7 +48,7 @@ pub fn read_version_meta(file_path: &Path) -> Result<VersionMeta> {
         Ok(meta)
     } else {
         Ok(VersionMeta {
-            max_versions: MAX_VERSIONS,
+            max_versions: CONFIGURED_MAX_VERSIONS.get().copied().unwrap_or(MAX_VERSIONS),
             versions: Vec::new(),
         })
     }
@@ -80,8 +93,9 @@ pub fn save_version(file_path: &Path, comment: &str) -> Result<u32> {
     // Determine next version number
     let next_version = meta.versions.last().map(|v| v.version + 1).unwrap_or(1);
 
-    // Enforce MAX_VERSIONS: remove oldest if at cap
-    while meta.versions.len() >= MAX_VERSIONS as usize {
+    // Enforce the per-file cap (or the configured one): remove oldest if at cap
+    meta.max_versions = CONFIGURED_MAX_VERSIONS.get().copied().unwrap_or(meta.max_versions).max(1);
+    while meta.versions.len() >= meta.max_versions as usize {
         let oldest = meta.versions.remove(0);
         if let Some(f) = find_version_file(&ver_dir, oldest.version) {
             let _ = std::fs::remove_file(f);
@@ -127,20 +141,24 @@ pub fn get_version_history(file_path: &Path) -> Result<FileVersionHistory> {
     })
 }
 
+#[derive(Debug, thiserror::Error)]
+#[error("Version {0} not found")]
+pub struct VersionNotFound(pub u32);
+
+/// Stored file for version N of `file_path`.
+pub fn version_path(file_path: &Path, version: u32) -> Result<PathBuf> {
+    let meta = read_version_meta(file_path)?;
+    if !meta.versions.iter().any(|v| v.version == version) {
+        return Err(VersionNotFound(version).into());
+    }
+    find_version_file(&file_version_dir(file_path), version)
+        .ok_or_else(|| anyhow::anyhow!("Version file for v{} not found on disk", version))
+}
+
 /// Rollback: copy version N back to the active file location.
 /// The current file is saved as a new version first (non-destructive).
 pub fn rollback_to_version(file_path: &Path, version: u32) -> Result<()> {
-    let ver_dir = file_version_dir(file_path);
-    let meta = read_version_meta(file_path)?;
-
-    // Find the requested version
-    meta.versions
-        .iter()
-        .find(|v| v.version == version)
-        .ok_or_else(|| anyhow::anyhow!("Version {} not found", version))?;
-
-    let ver_file = find_version_file(&ver_dir, version)
-        .ok_or_else(|| anyhow::anyhow!("Version file for v{} not found on disk", version))?;
+    let ver_file = version_path(file_path, version)?;
 
     // Save current state as a new version before rollback
     if file_path.exists() {
@@ -169,7 +187,12 @@ pub fn delete_versions(file_path: &Path) -> Result<()> {
     if ver_dir.exists() {
         std::fs::remove_dir_all(&ver_dir)?;
     }
-    // If .versions/ parent dir is now empty, remove it too
+    remove_versions_dir_if_empty(file_path);
+    Ok(())
+}
+
+/// If .versions/ next to `file_path` is now empty, remove it too
+fn remove_versions_dir_if_empty(file_path: &Path) {
     let parent_versions = versions_dir_for(file_path);
     if parent_versions.exists() {
         if let Ok(mut entries) = std::fs::read_dir(&parent_versions) {
@@ -178,6 +201,22 @@ pub fn delete_versions(file_path: &Path) -> Result<()> {
             }
         }
     }
+}
+
+/// Carry a file's version history along when it is moved/renamed.
+/// Any stale history already at the destination is replaced.
+pub fn move_versions(from: &Path, to: &Path) -> Result<()> {
+    let from_dir = file_version_dir(from);
+    if !from_dir.exists() {
+        return Ok(());
+    }
+    let to_dir = file_version_dir(to);
+    if to_dir.exists() {
+        std::fs::remove_dir_all(&to_dir)?;
+    }
+    std::fs::create_dir_all(versions_dir_for(to))?;
+    std::fs::rename(&from_dir, &to_dir)?;
+    remove_versions_dir_if_empty(from);
     Ok(())
 }
 
@@ -185,3 +224,44 @@ pub fn delete_versions(file_path: &Path) -> Result<()> {
 pub fn is_versions_dir(name: &str) -> bool {
     name == VERSIONS_DIR_NAME
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_oldest_version_pruned_at_per_file_cap() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("規程.txt");
+        std::fs::write(&path, "v0").unwrap();
+        write_version_meta(&path, &VersionMeta { max_versions: 3, versions: Vec::new() }).unwrap();
+
+        for i in 1..=4 {
+            save_version(&path, &format!("save {}", i)).unwrap();
+            std::fs::write(&path, format!("v{}", i)).unwrap();
+        }
+
+        let meta = read_version_meta(&path).unwrap();
+        let versions: Vec<u32> = meta.versions.iter().map(|v| v.version).collect();
+        assert_eq!(versions, vec![2, 3, 4]);
+        assert!(find_version_file(&file_version_dir(&path), 1).is_none());
+        assert!(rollback_to_version(&path, 1).is_err());
+    }
+
+    #[test]
+    fn test_old_version_readable_without_touching_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("手順書.txt");
+        std::fs::write(&path, "初版").unwrap();
+        save_version(&path, "first").unwrap();
+        std::fs::write(&path, "第2版").unwrap();
+
+        let v1 = version_path(&path, 1).unwrap();
+        assert_eq!(std::fs::read_to_string(v1).unwrap(), "初版");
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "第2版");
+        assert_eq!(version_count(&path), 1);
+
+        let err = version_path(&path, 2).unwrap_err();
+        assert!(err.downcast_ref::<VersionNotFound>().is_some());
+    }
+}
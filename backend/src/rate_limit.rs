@@ -0,0 +1,98 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use tokio::sync::Mutex;
+
+/// これを超えたら満タンのバケットを捨ててメモリを抑える
+const MAX_TRACKED_CLIENTS: usize = 10_000;
+
+#[derive(Debug, Clone, Copy)]
+struct TokenBucket {
+    tokens: f64,
+    last_refill: Instant,
+}
+
+/// クライアント単位のトークンバケット。
+/// 容量は `per_minute`、1分で満タンになる速度で補充する
+#[derive(Debug)]
+pub struct RateLimiter {
+    per_minute: u32,
+    buckets: Mutex<HashMap<String, TokenBucket>>,
+}
+
+impl RateLimiter {
+    pub fn new(per_minute: u32) -> Self {
+        Self {
+            per_minute: per_minute.max(1),
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// `RATE_LIMIT_PER_MINUTE` が未設定・0なら無効
+    pub fn from_env() -> Option<Self> {
+        let per_minute: u32 = std::env::var("RATE_LIMIT_PER_MINUTE").ok()?.parse().ok()?;
+        (per_minute > 0).then(|| Self::new(per_minute))
+    }
+
+    pub fn per_minute(&self) -> u32 {
+        self.per_minute
+    }
+
+    /// 1リクエスト分のトークンを消費する。枯渇時は再試行までの待ち時間を返す
+    pub async fn check(&self, client: &str) -> Result<(), Duration> {
+        self.check_at(client, Instant::now()).await
+    }
+
+    async fn check_at(&self, client: &str, now: Instant) -> Result<(), Duration> {
+        let capacity = self.per_minute as f64;
+        let refill_per_sec = capacity / 60.0;
+        let mut buckets = self.buckets.lock().await;
+
+        if buckets.len() >= MAX_TRACKED_CLIENTS && !buckets.contains_key(client) {
+            buckets.retain(|_, b| {
+                let elapsed = now.saturating_duration_since(b.last_refill).as_secs_f64();
+                b.tokens + elapsed * refill_per_sec < capacity
+            });
+        }
+
+        let bucket = buckets.entry(client.to_string())
+            .or_insert(TokenBucket { tokens: capacity, last_refill: now });
+
+        let elapsed = now.saturating_duration_since(bucket.last_refill).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * refill_per_sec).min(capacity);
+        bucket.last_refill = now;
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            let wait = (1.0 - bucket.tokens) / refill_per_sec;
+            Err(Duration::from_secs_f64(wait))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_request_over_limit_is_rejected() {
+        let limiter = RateLimiter::new(3);
+        let now = Instant::now();
+
+        for _ in 0..3 {
+            assert!(limiter.check_at("key:a", now).await.is_ok());
+        }
+        let retry_after = limiter.check_at("key:a", now).await.unwrap_err();
+        assert_eq!(retry_after.as_secs_f64().round(), 20.0);
+
+        // 別クライアントは独立
+        assert!(limiter.check_at("ip:10.0.0.1", now).await.is_ok());
+
+        // 20秒ごとに1トークン補充される
+        let later = now + Duration::from_secs(21);
+        assert!(limiter.check_at("key:a", later).await.is_ok());
+        assert!(limiter.check_at("key:a", later).await.is_err());
+    }
+}
@@ -0,0 +1,78 @@
+use std::time::Duration;
+
+use anyhow::Result;
+use metrics::{counter, histogram};
+use metrics_exporter_prometheus::{Matcher, PrometheusBuilder, PrometheusHandle};
+
+pub const CHAT_REQUESTS: &str = "llm_proxy_chat_requests_total";
+pub const PII_ENTITIES_MASKED: &str = "llm_proxy_pii_entities_masked_total";
+pub const DANGEROUS_PATTERNS_REMOVED: &str = "llm_proxy_dangerous_patterns_removed_total";
+/// `SANITIZER_MODE=report` で検出のみ（除去していない）の件数
+pub const DANGEROUS_PATTERNS_FOUND: &str = "llm_proxy_dangerous_patterns_found_total";
+pub const INDEX_RUNS: &str = "llm_proxy_index_runs_total";
+pub const LLM_LATENCY: &str = "llm_proxy_llm_latency_seconds";
+
+/// LLM呼び出しのレイテンシ用バケット（秒）
+const LLM_LATENCY_BUCKETS: &[f64] = &[0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0, 120.0];
+
+fn builder() -> Result<PrometheusBuilder> {
+    Ok(PrometheusBuilder::new()
+        .set_buckets_for_metric(Matcher::Full(LLM_LATENCY.to_string()), LLM_LATENCY_BUCKETS)?)
+}
+
+/// グローバルなPrometheusレコーダーを登録し、`/metrics` の描画用ハンドルを返す
+pub fn install() -> Result<PrometheusHandle> {
+    Ok(builder()?.install_recorder()?)
+}
+
+pub fn record_chat_request() {
+    counter!(CHAT_REQUESTS).increment(1);
+}
+
+pub fn record_pii_masked(count: usize) {
+    counter!(PII_ENTITIES_MASKED).increment(count as u64);
+}
+
+pub fn record_dangerous_patterns_removed(count: usize) {
+    counter!(DANGEROUS_PATTERNS_REMOVED).increment(count as u64);
+}
+
+pub fn record_dangerous_patterns_found(count: usize) {
+    counter!(DANGEROUS_PATTERNS_FOUND).increment(count as u64);
+}
+
+pub fn record_index_run(success: bool) {
+    let result = if success { "success" } else { "error" };
+    counter!(INDEX_RUNS, "result" => result).increment(1);
+}
+
+pub fn record_llm_latency(elapsed: Duration) {
+    histogram!(LLM_LATENCY).record(elapsed.as_secs_f64());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render_prometheus_text_format() {
+        let recorder = builder().unwrap().build_recorder();
+        let handle = recorder.handle();
+
+        metrics::with_local_recorder(&recorder, || {
+            record_chat_request();
+            record_chat_request();
+            record_pii_masked(3);
+            record_index_run(true);
+            record_llm_latency(Duration::from_millis(700));
+        });
+
+        let output = handle.render();
+        assert!(output.contains("# TYPE llm_proxy_chat_requests_total counter"));
+        assert!(output.contains("llm_proxy_chat_requests_total 2"));
+        assert!(output.contains("llm_proxy_pii_entities_masked_total 3"));
+        assert!(output.contains("llm_proxy_index_runs_total{result=\"success\"} 1"));
+        assert!(output.contains("# TYPE llm_proxy_llm_latency_seconds histogram"));
+        assert!(output.contains("llm_proxy_llm_latency_seconds_bucket{le=\"1\"} 1"));
+    }
+}
@@ -0,0 +1,45 @@
+import type { NextRequest } from 'next/server';
+
+// ブラウザは同一オリジンの /api/* を叩き、APIキーはここでサーバー側から付与する。
+// キーがクライアントバンドルに含まれないよう NEXT_PUBLIC_ は付けない
+const BACKEND_API_URL = process.env.BACKEND_API_URL || 'http://localhost:8080/api';
+const BACKEND_API_KEY = process.env.BACKEND_API_KEY;
+
+export const dynamic = 'force-dynamic';
+
+// 転送しないホップバイホップ系ヘッダー
+const DROPPED_REQUEST_HEADERS = ['host', 'connection', 'authorization', 'cookie', 'content-length'];
+const DROPPED_RESPONSE_HEADERS = ['connection', 'transfer-encoding', 'content-encoding', 'content-length'];
+
+async function proxy(req: NextRequest, { params }: { params: { path: string[] } }) {
+  const target = new URL(`${BACKEND_API_URL.replace(/\/$/, '')}/${params.path.map(encodeURIComponent).join('/')}`);
+  target.search = req.nextUrl.search;
+
+  const headers = new Headers(req.headers);
+  DROPPED_REQUEST_HEADERS.forEach(name => headers.delete(name));
+  if (BACKEND_API_KEY) {
+    headers.set('Authorization', `Bearer ${BACKEND_API_KEY}`);
+  }
+
+  const hasBody = req.method !== 'GET' && req.method !== 'HEAD';
+  // アップロードやSSEをバッファせずそのまま流す（Node の fetch でストリームを送るには duplex が必要）
+  const init: RequestInit & { duplex: 'half' } = {
+    method: req.method,
+    headers,
+    body: hasBody ? req.body : undefined,
+    duplex: 'half',
+    cache: 'no-store',
+    redirect: 'manual',
+  };
+  const upstream = await fetch(target, init);
+
+  const responseHeaders = new Headers(upstream.headers);
+  DROPPED_RESPONSE_HEADERS.forEach(name => responseHeaders.delete(name));
+  return new Response(upstream.body, {
+    status: upstream.status,
+    statusText: upstream.statusText,
+    headers: responseHeaders,
+  });
+}
+
+export { proxy as GET, proxy as POST, proxy as PUT, proxy as PATCH, proxy as DELETE };
@@ -54,6 +54,9 @@ function badgeColor(format: string | undefined): string {
   return map[format] || 'bg-gray-100 text-gray-700';
 }
 
+// /rag/formats が取れるまでの既定値
+const DEFAULT_ACCEPT = '.pdf,.docx,.xlsx,.pptx,.html,.htm,.txt,.md,.rs,.py,.js,.ts,.json,.yaml,.yml,.toml';
+
 export default function DocumentManager() {
   const [entries, setEntries] = useState<DirEntry[]>([]);
   const [currentPath, setCurrentPath] = useState('');
@@ -62,6 +65,7 @@ export default function DocumentManager() {
   const [isUploading, setIsUploading] = useState(false);
   const [error, setError] = useState<string | null>(null);
   const [unavailable, setUnavailable] = useState(false);
+  const [acceptExtensions, setAcceptExtensions] = useState(DEFAULT_ACCEPT);
   const fileInputRef = useRef<HTMLInputElement>(null);
   const pollRef = useRef<NodeJS.Timeout | null>(null);
 
@@ -109,6 +113,11 @@ export default function DocumentManager() {
   useEffect(() => {
     fetchEntries('');
     fetchStatus();
+    api.getSupportedFormats()
+      .then(formats => setAcceptExtensions(
+        formats.flatMap(f => f.extensions).map(ext => `.${ext}`).join(',')
+      ))
+      .catch(() => {});
   }, [fetchStatus]);
 
   // Re-fetch entries when path changes
@@ -320,7 +329,7 @@ export default function DocumentManager() {
             type="file"
             multiple
             className="hidden"
-            accept=".pdf,.docx,.xlsx,.pptx,.txt,.md,.rs,.py,.js,.ts,.json,.yaml,.yml,.toml"
+            accept={acceptExtensions}
             onChange={handleFileInput}
           />
         </div>
@@ -607,7 +616,9 @@ export default function DocumentManager() {
                   {status.is_indexing ? (
                     <>
                       <Loader2 className="w-4 h-4 animate-spin text-blue-500" />
-                      <span className="text-blue-600">作成中</span>
+                      <span className="text-blue-600">
+                        作成中{status.files_total > 0 && ` (${status.files_processed}/${status.files_total})`}
+                      </span>
                     </>
                   ) : (
                     <span className="text-green-600">待機中</span>
@@ -628,6 +639,11 @@ export default function DocumentManager() {
               </div>
             </div>
 
+            {/* 処理中のファイル */}
+            {status.is_indexing && status.current_file && (
+              <div className="text-sm text-gray-600 break-all">処理中: {status.current_file}</div>
+            )}
+
             {/* インデックスエラー */}
             {status.last_error && (
               <div className="bg-red-50 border border-red-300 rounded-lg p-4">
@@ -645,7 +661,10 @@ export default function DocumentManager() {
                 <div className="text-sm font-medium text-red-700 mb-1">失敗したファイル:</div>
                 <ul className="text-sm text-red-600 list-disc list-inside">
                   {status.failed_files.map((f, i) => (
-                    <li key={i}>{f}</li>
+                    <li key={i}>
+                      {f.name}
+                      <span className="text-red-500 break-all">: {f.reason}</span>
+                    </li>
                   ))}
                 </ul>
               </div>
@@ -76,14 +76,14 @@ export default function LogViewer() {
                     {format(new Date(log.timestamp), 'yyyy-MM-dd HH:mm:ss')}
                   </td>
                   <td className="px-6 py-4 text-sm max-w-md truncate">
-                    {log.original_input}
+                    {log.original_input ?? log.masked_input}
                   </td>
                   <td className="px-6 py-4 text-sm max-w-md truncate">
-                    {log.final_output}
+                    {log.final_output ?? log.llm_output}
                   </td>
                   <td className="px-6 py-4 text-sm">
                     <span className="px-2 py-1 bg-yellow-100 text-yellow-800 rounded text-xs">
-                      {Object.keys(log.pii_mappings).length}件
+                      {log.pii_count ?? Object.keys(log.pii_mappings).length}件
                     </span>
                   </td>
                 </tr>
@@ -1,10 +1,9 @@
 import axios from 'axios';
-import type { ChatRequest, ModelInfo, Document, LogQuery, LogResponse, FileInfo, IndexStatus, IndexConfigUpdate, UploadResponse, DirEntry, CreateDirRequest, CreateFileRequest, FileVersionHistory, RollbackRequest, RollbackResponse } from '@/types';
-
-const API_BASE_URL = process.env.NEXT_PUBLIC_API_URL || 'http://localhost:8080/api';
+import type { ChatRequest, ModelInfo, Document, LogQuery, LogResponse, StatsQuery, LogStats, FileInfo, IndexStatus, SupportedFormatInfo, IndexConfigUpdate, UploadResponse, DirEntry, FileSearchHit, CreateDirRequest, CreateFileRequest, UpdateFileRequest, MoveRequest, CopyRequest, RestoreRequest, FileVersionHistory, RollbackRequest, RollbackResponse } from '@/types';
 
+// Next.js のルートハンドラ（app/api/[...path]）経由でバックエンドへ。APIキーはそちらで付与する
 const apiClient = axios.create({
-  baseURL: API_BASE_URL,
+  baseURL: '/api',
   headers: {
     'Content-Type': 'application/json',
   },
@@ -29,12 +28,21 @@ export const api = {
     return response.data;
   },
 
+  async deleteDocument(id: string): Promise<void> {
+    await apiClient.delete(`/v1/documents/${encodeURIComponent(id)}`);
+  },
+
   // ログ
   async queryLogs(query: LogQuery): Promise<LogResponse> {
     const response = await apiClient.get('/v1/logs', { params: query });
     return response.data;
   },
 
+  async getStats(query: StatsQuery = {}): Promise<LogStats> {
+    const response = await apiClient.get('/v1/stats', { params: query });
+    return response.data;
+  },
+
   // ヘルスチェック
   async healthCheck() {
     const response = await apiClient.get('/health');
@@ -42,10 +50,10 @@ export const api = {
   },
 
   // RAG管理
-  async uploadFiles(files: File[], path?: string): Promise<UploadResponse> {
+  async uploadFiles(files: File[], path?: string, comment?: string): Promise<UploadResponse> {
     const formData = new FormData();
     files.forEach(file => formData.append('files', file));
-    const params = path ? { path } : {};
+    const params = { ...(path ? { path } : {}), ...(comment ? { comment } : {}) };
     const response = await apiClient.post('/v1/rag/upload', formData, {
       headers: { 'Content-Type': 'multipart/form-data' },
       params,
@@ -59,8 +67,27 @@ export const api = {
     return response.data;
   },
 
-  async deleteEntry(path: string): Promise<void> {
-    await apiClient.delete(`/v1/rag/files/${encodeURIComponent(path)}`);
+  async searchFiles(q: string): Promise<FileSearchHit[]> {
+    const response = await apiClient.get('/v1/rag/search-files', { params: { q } });
+    return response.data;
+  },
+
+  async deleteEntry(path: string, purge = false): Promise<void> {
+    await apiClient.delete(`/v1/rag/files/${encodeURIComponent(path)}`, {
+      params: purge ? { purge: true } : {},
+    });
+  },
+
+  async restoreFile(path: string): Promise<void> {
+    await apiClient.post('/v1/rag/restore', { path } as RestoreRequest);
+  },
+
+  async moveEntry(from: string, to: string): Promise<void> {
+    await apiClient.post('/v1/rag/move', { from, to } as MoveRequest);
+  },
+
+  async copyFile(from: string, to: string): Promise<void> {
+    await apiClient.post('/v1/rag/copy', { from, to } as CopyRequest);
   },
 
   async createDir(path: string): Promise<void> {
@@ -71,6 +98,13 @@ export const api = {
     await apiClient.post('/v1/rag/files/create', { path, content } as CreateFileRequest);
   },
 
+  async updateFile(path: string, content: string, comment?: string): Promise<void> {
+    await apiClient.put(
+      `/v1/rag/files/${encodeURIComponent(path)}`,
+      { content, comment } as UpdateFileRequest
+    );
+  },
+
   async triggerIndex(): Promise<void> {
     await apiClient.post('/v1/rag/index');
   },
@@ -80,6 +114,11 @@ export const api = {
     return response.data;
   },
 
+  async getSupportedFormats(): Promise<SupportedFormatInfo[]> {
+    const response = await apiClient.get('/v1/rag/formats');
+    return response.data;
+  },
+
   async updateIndexConfig(config: IndexConfigUpdate): Promise<void> {
     await apiClient.put('/v1/rag/config', config);
   },
@@ -89,6 +128,36 @@ export const api = {
     return response.data;
   },
 
+  async downloadFile(path: string): Promise<Blob> {
+    const response = await apiClient.get(`/v1/rag/files/${encodeURIComponent(path)}/download`, {
+      responseType: 'blob',
+    });
+    return response.data;
+  },
+
+  async getFileContent(path: string): Promise<string> {
+    const response = await apiClient.get(`/v1/rag/files/${encodeURIComponent(path)}/content`, {
+      responseType: 'text',
+    });
+    return response.data;
+  },
+
+  async getFileVersionContent(path: string, version: number): Promise<Blob> {
+    const response = await apiClient.get(
+      `/v1/rag/files/${encodeURIComponent(path)}/versions/${version}`,
+      { responseType: 'blob' }
+    );
+    return response.data;
+  },
+
+  async diffFileVersions(path: string, from: number, to?: number): Promise<string> {
+    const response = await apiClient.get(`/v1/rag/files/${encodeURIComponent(path)}/diff`, {
+      params: to === undefined ? { from } : { from, to },
+      responseType: 'text',
+    });
+    return response.data;
+  },
+
   async rollbackFile(path: string, version: number, reindex: boolean): Promise<RollbackResponse> {
     const response = await apiClient.post(
       `/v1/rag/files/${encodeURIComponent(path)}/rollback`,
@@ -1,6 +1,8 @@
 export interface Message {
-  role: 'user' | 'assistant' | 'system';
+  role: 'user' | 'assistant' | 'system' | 'tool';
   content: string;
+  tool_calls?: unknown[];
+  tool_call_id?: string;
   timestamp?: Date;
 }
 
@@ -10,6 +12,22 @@ export interface ChatRequest {
   temperature?: number;
   max_tokens?: number;
   stream?: boolean;
+  top_p?: number;
+  presence_penalty?: number;
+  frequency_penalty?: number;
+  stop?: string | string[];
+  seed?: number;
+  n?: number;
+  tools?: unknown[];
+  tool_choice?: unknown;
+  llm_message_override?: string;
+  rag_category?: string;
+  collection?: string;
+  rag_hybrid?: boolean;
+  /** context chunks to retrieve (server clamps to RAG_MAX_TOP_K) */
+  rag_top_k?: number;
+  /** false = skip retrieval for this request */
+  use_rag?: boolean;
 }
 
 export interface ChatResponse {
@@ -18,6 +36,50 @@ export interface ChatResponse {
   created: number;
   model: string;
   choices: Choice[];
+  pii_masked_count?: number;
+  pii_entities?: PIIEntitySpan[];
+  usage?: Usage;
+  estimated_cost_usd?: number;
+  sanitizer_findings?: SanitizerFinding[];
+}
+
+export interface SearchHit {
+  text: string;
+  score: number;
+  metadata: Record<string, unknown> | null;
+}
+
+export interface RagSearchRequest {
+  query: string;
+  top_k?: number;
+  category?: string;
+  collection?: string;
+  hybrid?: boolean;
+}
+
+export interface RagSearchResponse {
+  query: string;
+  hits: SearchHit[];
+}
+
+export interface SanitizerFinding {
+  category: string;
+  matched_text: string;
+  byte_start: number;
+  byte_end: number;
+  choice_index: number;
+}
+
+export interface PIIEntitySpan {
+  category: string;
+  byte_start: number;
+  byte_end: number;
+}
+
+export interface Usage {
+  prompt_tokens: number;
+  completion_tokens: number;
+  total_tokens: number;
 }
 
 export interface Choice {
@@ -38,24 +100,39 @@ export interface Document {
   title: string;
   content: string;
   category?: string;
+  collection?: string;
   created_at?: Date;
 }
 
 export interface LogEntry {
   id: string;
   timestamp: Date;
-  original_input: string;
+  model?: string;
+  /** null when logged with LOG_STORE_RAW=false (unless fetched with decrypt) */
+  original_input: string | null;
   masked_input: string;
   rag_context?: string;
   llm_output: string;
-  final_output: string;
+  final_output: string | null;
   pii_mappings: Record<string, string>;
+  latency_ms?: number;
+  prompt_tokens?: number;
+  completion_tokens?: number;
+  estimated_cost_usd?: number;
+  dangerous_patterns_removed?: number;
+  pii_count?: number;
 }
 
 export interface LogQuery {
   start_date?: string;
   end_date?: string;
   search_term?: string;
+  /** 'fts' = Postgres full-text search (to_tsquery syntax); default is substring match */
+  mode?: 'ilike' | 'fts';
+  model?: string;
+  min_pii_count?: number;
+  sort?: 'timestamp' | 'pii_count';
+  decrypt?: boolean;
   limit?: number;
   offset?: number;
 }
@@ -65,6 +142,19 @@ export interface LogResponse {
   total: number;
 }
 
+export interface StatsQuery {
+  start_date?: string;
+  end_date?: string;
+}
+
+export interface LogStats {
+  total_requests: number;
+  requests_per_model: { model?: string; requests: number }[];
+  pii_entities_masked: number;
+  dangerous_patterns_removed: number;
+  avg_latency_ms?: number;
+}
+
 // RAG Management types
 
 export interface FileInfo {
@@ -74,24 +164,59 @@ export interface FileInfo {
   modified_at: string;
 }
 
+export interface SupportedFormatInfo {
+  format: string;
+  extensions: string[];
+}
+
+export interface FileError {
+  name: string;
+  reason: string;
+}
+
 export interface IndexStatus {
   is_indexing: boolean;
   last_indexed_at: string | null;
   total_files: number;
   total_chunks: number;
-  failed_files: string[];
+  failed_files: FileError[];
+  skipped_files: number;
+  reindexed_files: number;
+  truncated_files: string[];
   auto_index_interval_minutes: number;
+  chunk_size: number;
+  chunk_overlap: number;
   upload_dir: string;
   last_error: string | null;
+  current_file: string | null;
+  files_processed: number;
+  files_total: number;
 }
 
+// GET /v1/rag/index/events (SSE "progress" events)
+export type IndexEvent =
+  | { type: 'started'; total_files: number }
+  | { type: 'file_indexed'; path: string; chunks: number }
+  | { type: 'file_skipped'; path: string }
+  | { type: 'file_failed'; path: string; error: string }
+  | { type: 'finished'; total_files: number; total_chunks: number };
+
 export interface IndexConfigUpdate {
-  auto_index_interval_minutes: number;
+  auto_index_interval_minutes?: number;
+  chunk_size?: number;
+  chunk_overlap?: number;
 }
 
 export interface UploadResponse {
   uploaded_files: string[];
   total_files_in_dir: number;
+  validation?: FileValidation[];
+}
+
+export interface FileValidation {
+  name: string;
+  extractable: boolean;
+  error?: string;
 }
 
 // Directory browsing types
@@ -105,6 +230,24 @@ export interface DirEntry {
   version_count?: number;
 }
 
+export interface FileSearchHit extends DirEntry {
+  path: string;
+}
+
+export interface MoveRequest {
+  from: string;
+  to: string;
+}
+
+export interface CopyRequest {
+  from: string;
+  to: string;
+}
+
+export interface RestoreRequest {
+  path: string;
+}
+
 export interface CreateDirRequest {
   path: string;
 }
@@ -114,6 +257,11 @@ export interface CreateFileRequest {
   content: string;
 }
 
+export interface UpdateFileRequest {
+  content: string;
+  comment?: string;
+}
+
 // Version management types
 
 export interface VersionEntry {